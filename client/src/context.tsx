@@ -13,16 +13,45 @@ import { ChatMessage } from "./routes/Chat";
 // #[derive(Serialize)]
 // #[serde(tag = "type")]
 // enum ClientMessage {
-//     ChatMessage{ message: Arc<ChatMessage>},
+//     ChatMessage{ message: Arc<ChatMessage>, muted: bool },
 //     AddUser { name: Arc<str>},
 //     RemoveUser{name: Arc<str>},
 //     SynchronizeMessage { message: Arc<ChatMessage> },
+//     MessageRequest { message: Arc<ChatMessage>, muted: bool },
+//     ContactsChanged,
+//     DeliveryFailed { message: Arc<ChatMessage> },
+//     SystemNotice { text: Arc<str> },
+//     SessionCreated { token: Arc<str> },
+//     ServerShutdown { reconnect_after: u64 },
 // }
 type Message =
-  | { type: "ChatMessage"; message: ChatMessage }
+  // Muted messages are shown without notifying
+  | { type: "ChatMessage"; message: ChatMessage; muted: boolean }
+  // A contact came online
   | { type: "AddUser"; name: string }
+  // A contact went offline
   | { type: "RemoveUser"; name: string }
-  | { type: "SynchronizeMessage"; message: ChatMessage };
+  | { type: "SynchronizeMessage"; message: ChatMessage }
+  // A message from someone who is not a contact yet
+  | { type: "MessageRequest"; message: ChatMessage; muted: boolean }
+  | { type: "ContactsChanged" }
+  // A message sent from any of our devices did not arrive
+  | { type: "DeliveryFailed"; message: ChatMessage }
+  // An announcement from the operators
+  | { type: "SystemNotice"; text: string }
+  // The answer to CreateSession, for the REST API
+  | { type: "SessionCreated"; token: string }
+  // reconnect_after is in milliseconds
+  | { type: "ServerShutdown"; reconnect_after: number };
+
+// Equivalent to the server-side contacts response
+export type ContactList = {
+  contacts: { name: string; online: boolean }[];
+  incoming: { name: string; messages: ChatMessage[] }[];
+  outgoing: string[];
+  blocked: string[];
+  muted: string[];
+};
 
 const [name, setName] = createSignal<string | null>(
   localStorage.getItem("name")
@@ -39,6 +68,12 @@ createEffect(() => {
 });
 
 const [socket, setSocket] = createSignal<WebSocket | undefined>();
+// Authenticates the REST API. Every socket asks for a new one when it opens.
+const [token, setToken] = createSignal<string | null>(null);
+
+function authorization(token: string) {
+  return { Authorization: `Bearer ${token}` };
+}
 // Not sure if using a map is better
 const messagesByUser = new Map<string, Signal<ChatMessage[]>>();
 
@@ -61,6 +96,21 @@ function addChatMessage(message: ChatMessage, contact: string) {
   setMessages((messages) => [message, ...messages]);
 }
 
+function markFailed(message: ChatMessage) {
+  const signal = messagesByUser.get(message.recipient);
+  if (signal === undefined) return;
+
+  const [, setMessages] = signal;
+  // Messages have no id, but one user sending two at the same millisecond is unlikely
+  setMessages((messages) =>
+    messages.map((entry) =>
+      entry.time_utc === message.time_utc && entry.text === message.text
+        ? { ...entry, failed: true }
+        : entry
+    )
+  );
+}
+
 // Some things to ensure security in production
 const isSecureRequired =
   window.location.protocol === "https:" ||
@@ -74,27 +124,73 @@ console.debug("Backend at", backendUrl);
 const socketUrl = new URL(backendUrl.href);
 socketUrl.protocol = isSecureRequired ? "wss:" : "ws:";
 
-async function fetchUsers() {
-  const response = await fetch(backendUrl + "users");
-  const data = await response.json();
-  return data;
+async function fetchContacts(token: string): Promise<ContactList> {
+  const response = await fetch(`${backendUrl}contacts`, {
+    headers: authorization(token),
+  });
+  if (!response.ok) throw new Error("Loading contacts failed");
+  return await response.json();
 }
 
-const [users, { mutate }] = createResource<string[]>(fetchUsers);
+// Loaded again whenever there is a new session, which is after signing in and reconnecting
+const [contacts, { mutate, refetch }] = createResource(token, fetchContacts);
 
-function addUser(name: string) {
+function setOnline(contact: string, online: boolean) {
   mutate((previous) => {
-    if (previous === undefined) return [name];
-    const index = previous.indexOf(name);
-    if (index === -1) return [...previous, name];
-    return previous;
+    if (previous === undefined) return previous;
+    return {
+      ...previous,
+      contacts: previous.contacts.map((entry) =>
+        entry.name === contact ? { ...entry, online } : entry
+      ),
+    };
   });
 }
 
-function removeUser(name: string) {
-  mutate((previous) => previous?.filter((user) => user !== name));
+/**
+ * Changes the contacts of the current user, e.g. `requests/bob/accept` with the method POST.
+ * All devices are told by the server to load the contacts again after that.
+ */
+async function changeContacts(path: string, method: string) {
+  const current = token();
+  if (current === null) return;
+
+  const response = await fetch(`${backendUrl}contacts/${path}`, {
+    method,
+    headers: authorization(current),
+  });
+  if (!response.ok) throw new Error(`Changing contacts failed: ${path}`);
 }
 
+const contactActions = {
+  request: (contact: string) =>
+    changeContacts(`requests/${encodeURIComponent(contact)}`, "POST"),
+  accept: (contact: string) => {
+    // The messages sent with the request are part of the chat now
+    const request = contacts()?.incoming.find(
+      (request) => request.name === contact
+    );
+    for (const message of request?.messages ?? [])
+      addChatMessage(message, contact);
+    return changeContacts(
+      `requests/${encodeURIComponent(contact)}/accept`,
+      "POST"
+    );
+  },
+  decline: (contact: string) =>
+    changeContacts(`requests/${encodeURIComponent(contact)}/decline`, "POST"),
+  remove: (contact: string) =>
+    changeContacts(encodeURIComponent(contact), "DELETE"),
+  block: (contact: string) =>
+    changeContacts(`blocked/${encodeURIComponent(contact)}`, "PUT"),
+  unblock: (contact: string) =>
+    changeContacts(`blocked/${encodeURIComponent(contact)}`, "DELETE"),
+  mute: (contact: string) =>
+    changeContacts(`muted/${encodeURIComponent(contact)}`, "PUT"),
+  unmute: (contact: string) =>
+    changeContacts(`muted/${encodeURIComponent(contact)}`, "DELETE"),
+};
+
 async function handleMessage(event: MessageEvent) {
   if (typeof event.data !== "string")
     throw new Error("Message is not a string");
@@ -109,20 +205,66 @@ async function handleMessage(event: MessageEvent) {
       addChatMessage(message.message, message.message.sender);
       break;
     case "AddUser":
-      addUser(message.name);
+      setOnline(message.name, true);
       break;
     case "RemoveUser":
-      removeUser(message.name);
+      setOnline(message.name, false);
       break;
     case "SynchronizeMessage":
       // If we get a message from us but a different client, we need to use the find the intended recipient
       // of the message to fin the chat partner
       addChatMessage(message.message, message.message.recipient);
       break;
+    case "MessageRequest":
+      // Shown with the request until it is accepted
+      refetch();
+      break;
+    case "ContactsChanged":
+      refetch();
+      break;
+    case "DeliveryFailed":
+      markFailed(message.message);
+      break;
+    case "SystemNotice":
+      alert(message.text);
+      break;
+    case "SessionCreated":
+      setToken(message.token);
+      break;
+    case "ServerShutdown":
+      // The server closes the socket after this message, so try again once it is expected to be back
+      setTimeout(reconnect, message.reconnect_after);
+      break;
   }
 }
 
-const state = { socket, name, setName, messagesByUser, users };
+function openSocket(id: string) {
+  console.debug("Opening socket");
+  const newSocket = new WebSocket(`${socketUrl.href}messages/${id}`);
+  newSocket.addEventListener("message", handleMessage);
+  newSocket.addEventListener("open", () =>
+    newSocket.send(JSON.stringify({ type: "CreateSession" }))
+  );
+  setSocket(newSocket);
+  return newSocket;
+}
+
+function reconnect() {
+  const id = name();
+  if (id === null) return;
+
+  socket()?.removeEventListener("message", handleMessage);
+  openSocket(id);
+}
+
+const state = {
+  socket,
+  name,
+  setName,
+  messagesByUser,
+  contacts,
+  contactActions,
+};
 const Context = createContext(state);
 
 // Close socket if name goes to null. Meaning the user signs out
@@ -137,6 +279,15 @@ createEffect<ReturnType<typeof name>>((previous) => {
   currentSocket.close();
   setSocket(undefined);
 
+  // Signing out ends the session, so that the token can't be used anymore
+  const current = token();
+  setToken(null);
+  if (current !== null)
+    fetch(`${backendUrl}session`, {
+      method: "DELETE",
+      headers: authorization(current),
+    });
+
   return value;
 }, name());
 
@@ -148,11 +299,7 @@ createEffect<WebSocket | undefined>((previous) => {
   previous?.removeEventListener("message", handleMessage);
   previous?.close();
 
-  console.debug("Opening socket");
-  const newSocket = new WebSocket(`${socketUrl.href}messages/${id}`);
-  newSocket.addEventListener("message", handleMessage);
-  setSocket(newSocket);
-  return newSocket;
+  return openSocket(id);
 }, socket());
 
 // Custom hooks and component to simplify usage
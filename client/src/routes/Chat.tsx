@@ -14,6 +14,10 @@ export type ChatMessage = {
    * UTC unix timestamp in milliseconds as it comes out of Date.now()
    */
   time_utc: number;
+  /**
+   * Only set on the client for sent messages the server could not deliver
+   */
+  failed?: boolean;
 };
 
 /**
@@ -126,6 +130,11 @@ export default function Chat() {
                   }}
                 >
                   {message.text}
+                  <Show when={message.failed}>
+                    <span class="block text-xs text-slate-300">
+                      Not delivered
+                    </span>
+                  </Show>
                 </li>
               )}
             </For>
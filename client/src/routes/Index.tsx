@@ -7,7 +7,7 @@ import TopAppBar from "../components/TopAppBar";
  * Main page where users land on after set-up and see a list of available chats
  */
 export default function Index() {
-  const { socket, name, setName, users } = useAppContext();
+  const { socket, name, setName, contacts, contactActions } = useAppContext();
   const navigate = useNavigate();
 
   if (socket() === undefined || name() === null)
@@ -17,8 +17,17 @@ export default function Index() {
     if (name() === null) navigate("/setup");
   });
 
-  // Available chats
-  const usersWithoutSelf = () => users()?.filter((user) => user !== name());
+  function handleSubmit(event: SubmitEvent) {
+    if (!(event.target instanceof HTMLFormElement))
+      throw new Error("Invalid event target for form submission");
+
+    event.preventDefault();
+    const contact: string = event.target.contact.value;
+    if (contact.length === 0 || contact === name()) return;
+
+    contactActions.request(contact);
+    event.target.reset();
+  }
 
   return (
     <>
@@ -41,16 +50,135 @@ export default function Index() {
         }
       />
       <main class="bg-slate-100 px-4 pb-2 h-full">
-        <Show when={!users.loading} fallback={<p>Loading...</p>}>
+        <form onSubmit={handleSubmit} class="flex gap-2 py-2">
+          <label for="contact" class="sr-only">
+            Name of the contact to add
+          </label>
+          <input
+            type="text"
+            name="contact"
+            id="contact"
+            placeholder="Add a contact by name..."
+            class="block w-full rounded-2xl px-4 border-0 py-1.5 text-gray-900 ring-1 ring-inset ring-gray-300 placeholder:text-gray-400 focus:ring-2 focus:ring-inset focus:ring-orange-600 sm:text-sm sm:leading-6"
+          />
+          <button
+            type="submit"
+            class="rounded-2xl bg-orange-600 px-3 py-1.5 text-sm font-semibold text-white hover:bg-orange-500"
+          >
+            Add
+          </button>
+        </form>
+        <Show when={!contacts.loading} fallback={<p>Loading...</p>}>
+          <Show when={contacts()?.incoming.length}>
+            <h2 class="text-sm font-medium text-slate-600 px-4 py-2">
+              Requests
+            </h2>
+            <ul class="bg-slate-50 rounded-3xl flex flex-col mb-2">
+              <For each={contacts()?.incoming}>
+                {(request) => (
+                  <li class="text-base pl-4 pr-2 py-2 min-h-14 flex items-center gap-2">
+                    <div class="flex-1">
+                      <p>{request.name}</p>
+                      <For each={request.messages}>
+                        {(message) => (
+                          <p class="text-sm text-slate-600">{message.text}</p>
+                        )}
+                      </For>
+                    </div>
+                    <button
+                      onClick={() => contactActions.accept(request.name)}
+                      class="rounded-2xl bg-orange-600 px-3 py-1 text-sm text-white"
+                    >
+                      Accept
+                    </button>
+                    <button
+                      onClick={() => contactActions.decline(request.name)}
+                      class="rounded-2xl px-3 py-1 text-sm text-slate-900"
+                    >
+                      Decline
+                    </button>
+                    <button
+                      onClick={() => contactActions.block(request.name)}
+                      class="rounded-2xl px-3 py-1 text-sm text-slate-900"
+                    >
+                      Block
+                    </button>
+                  </li>
+                )}
+              </For>
+            </ul>
+          </Show>
           <Show
-            when={usersWithoutSelf() && usersWithoutSelf()!.length > 0}
-            fallback={<p>No one available to chat</p>}
+            when={contacts()?.contacts.length}
+            fallback={<p>No contacts yet. Add someone to start chatting</p>}
           >
-            <ul class="bg-slate-50 rounded-3xl flex flex-col h-full">
-              <For each={usersWithoutSelf()}>
-                {(user) => (
-                  <li class="text-base pl-4 pr-6 py-2 min-h-14 content-center">
-                    <a href={`/chats/${user}`}>{user}</a>
+            <ul class="bg-slate-50 rounded-3xl flex flex-col mb-2">
+              <For each={contacts()?.contacts}>
+                {(contact) => (
+                  <li class="text-base pl-4 pr-6 py-2 min-h-14 flex items-center gap-2">
+                    <span
+                      class="size-2 rounded-full"
+                      classList={{
+                        "bg-green-500": contact.online,
+                        "bg-slate-300": !contact.online,
+                      }}
+                    >
+                      <span class="sr-only">
+                        {contact.online ? "online" : "offline"}
+                      </span>
+                    </span>
+                    <a href={`/chats/${contact.name}`} class="flex-1">
+                      {contact.name}
+                    </a>
+                    <Show
+                      when={contacts()?.muted.includes(contact.name)}
+                      fallback={
+                        <button
+                          onClick={() => contactActions.mute(contact.name)}
+                          class="rounded-2xl px-3 py-1 text-sm text-slate-900"
+                        >
+                          Mute
+                        </button>
+                      }
+                    >
+                      <button
+                        onClick={() => contactActions.unmute(contact.name)}
+                        class="rounded-2xl px-3 py-1 text-sm text-slate-600"
+                      >
+                        Unmute
+                      </button>
+                    </Show>
+                    <button
+                      onClick={() => contactActions.block(contact.name)}
+                      class="rounded-2xl px-3 py-1 text-sm text-slate-900"
+                    >
+                      Block
+                    </button>
+                  </li>
+                )}
+              </For>
+            </ul>
+          </Show>
+          <Show when={contacts()?.outgoing.length}>
+            <p class="text-sm text-slate-600 px-4 py-2">
+              Waiting for {contacts()?.outgoing.join(", ")} to accept
+            </p>
+          </Show>
+          <Show when={contacts()?.blocked.length}>
+            <h2 class="text-sm font-medium text-slate-600 px-4 py-2">
+              Blocked
+            </h2>
+            <ul class="bg-slate-50 rounded-3xl flex flex-col">
+              <For each={contacts()?.blocked}>
+                {(blocked) => (
+                  <li class="text-base pl-4 pr-2 py-2 min-h-14 flex items-center gap-2">
+                    <span class="flex-1">{blocked}</span>
+                    <button
+                      onClick={() => contactActions.unblock(blocked)}
+                      class="rounded-2xl px-3 py-1 text-sm text-slate-900"
+                    >
+                      Unblock
+                    </button>
                   </li>
                 )}
               </For>
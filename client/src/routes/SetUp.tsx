@@ -27,7 +27,10 @@ function Alert() {
             Don't share sensitive information
           </h3>
           <div class="mt-2 text-sm text-yellow-700">
-            <p>Messages are not stored on the server.</p>
+            <p>
+              All messages are stored on the server, so that they can be
+              searched, until the retention of the conversation runs out.
+            </p>
             <p>
               But anyone using the same name as you will also receive your
               messages you send and receive.
@@ -0,0 +1,125 @@
+//! Compares the websocket encodings in size and the time it takes to encode and decode them.
+//!
+//! The server is not a library, so the messages are copies of the ones it sends with the same
+//! serde attributes. The sizes are printed before the timings:
+//!
+//! ```sh
+//! cargo bench --bench encoding
+//! ```
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
+struct ChatMessage {
+    recipient: String,
+    sender: String,
+    text: String,
+    time_utc: i64,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type")]
+enum ClientMessage {
+    ChatMessage { message: ChatMessage, muted: bool },
+    AddUser { name: String },
+}
+
+#[derive(Clone, Copy)]
+enum Encoding {
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl Encoding {
+    const ALL: [(&'static str, Self); 3] = [
+        ("json", Self::Json),
+        ("msgpack", Self::MessagePack),
+        ("cbor", Self::Cbor),
+    ];
+
+    fn encode<T: Serialize>(self, value: &T) -> Vec<u8> {
+        match self {
+            Self::Json => serde_json::to_vec(value).unwrap(),
+            Self::MessagePack => rmp_serde::to_vec_named(value).unwrap(),
+            Self::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(value, &mut bytes).unwrap();
+                bytes
+            }
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> T {
+        match self {
+            Self::Json => serde_json::from_slice(bytes).unwrap(),
+            Self::MessagePack => rmp_serde::from_slice(bytes).unwrap(),
+            Self::Cbor => ciborium::from_reader(bytes).unwrap(),
+        }
+    }
+}
+
+fn chat_message(text: &str) -> ChatMessage {
+    ChatMessage {
+        recipient: "alice".to_owned(),
+        sender: "bob".to_owned(),
+        text: text.to_owned(),
+        time_utc: 1_718_000_000_000,
+    }
+}
+
+fn encoding(c: &mut Criterion) {
+    let texts = [
+        ("short", "See you at 8?".to_owned()),
+        ("long", "Sounds good, I'll bring snacks. ".repeat(16)),
+    ];
+    let mut messages: Vec<_> = texts
+        .iter()
+        .map(|(name, text)| {
+            let message = ClientMessage::ChatMessage {
+                message: chat_message(text),
+                muted: false,
+            };
+            (*name, message)
+        })
+        .collect();
+    let presence = ClientMessage::AddUser {
+        name: "alice".to_owned(),
+    };
+    messages.push(("presence", presence));
+
+    for (message_name, message) in &messages {
+        let sizes: Vec<_> = Encoding::ALL
+            .iter()
+            .map(|(name, encoding)| format!("{name} {} bytes", encoding.encode(message).len()))
+            .collect();
+        println!("{message_name}: {}", sizes.join(", "));
+    }
+
+    let mut group = c.benchmark_group("encode");
+    for (message_name, message) in &messages {
+        for (name, encoding) in Encoding::ALL {
+            group.bench_function(format!("{message_name}/{name}"), |b| {
+                b.iter(|| encoding.encode(black_box(message)))
+            });
+        }
+    }
+    group.finish();
+
+    // Clients only send chat messages without the envelope
+    let mut group = c.benchmark_group("decode");
+    for (message_name, text) in &texts {
+        for (name, encoding) in Encoding::ALL {
+            let bytes = encoding.encode(&chat_message(text));
+            group.bench_function(format!("{message_name}/{name}"), |b| {
+                b.iter(|| encoding.decode::<ChatMessage>(black_box(&bytes)))
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, encoding);
+criterion_main!(benches);
@@ -0,0 +1,75 @@
+//! Compares encoding a message for every device of a user with encoding it once and sharing the
+//! frame between the sockets, which is what the user actor does.
+//!
+//! Like in the encoding benchmark, the message is a copy of the one the server sends:
+//!
+//! ```sh
+//! cargo bench --bench fan_out
+//! ```
+
+use axum::extract::ws::Message;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use serde::Serialize;
+use std::sync::Arc;
+
+#[derive(Serialize)]
+struct ChatMessage {
+    recipient: Arc<str>,
+    sender: String,
+    text: String,
+    time_utc: i64,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type")]
+enum ClientMessage {
+    ChatMessage {
+        message: Arc<ChatMessage>,
+        muted: bool,
+    },
+}
+
+fn encode(message: &ClientMessage) -> Message {
+    Message::Text(serde_json::to_string(message).unwrap().into())
+}
+
+fn fan_out(c: &mut Criterion) {
+    let message = ClientMessage::ChatMessage {
+        message: Arc::new(ChatMessage {
+            recipient: "alice".into(),
+            sender: "bob".to_owned(),
+            text: "Sounds good, I'll bring snacks. ".repeat(4),
+            time_utc: 1_718_000_000_000,
+        }),
+        muted: false,
+    };
+
+    let mut group = c.benchmark_group("fan_out");
+    for devices in [1, 3, 10] {
+        group.bench_with_input(
+            BenchmarkId::new("per_socket", devices),
+            &devices,
+            |b, &devices| {
+                b.iter(|| {
+                    (0..devices)
+                        .map(|_| encode(black_box(&message)))
+                        .collect::<Vec<_>>()
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("shared", devices),
+            &devices,
+            |b, &devices| {
+                b.iter(|| {
+                    let frame = encode(black_box(&message));
+                    (0..devices).map(|_| frame.clone()).collect::<Vec<_>>()
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, fan_out);
+criterion_main!(benches);
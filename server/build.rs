@@ -0,0 +1,10 @@
+fn main() {
+    // The gRPC service and client are generated from the schema, with a bundled protoc so that
+    // building does not depend on one being installed
+    #[cfg(feature = "grpc")]
+    {
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("protoc should be bundled");
+        std::env::set_var("PROTOC", protoc);
+        tonic_build::compile_protos("proto/melt.proto").expect("the gRPC schema should compile");
+    }
+}
@@ -0,0 +1,135 @@
+//! Chats through the gRPC API of a running server, to try it without a generated client of your own.
+//!
+//! Connects through the `Chat` call and asks it for a session, lists the contacts and the
+//! conversation with the recipient with it, then prints everything the server sends through the
+//! call. Every line typed into the terminal is sent to the
+//! recipient with `SendMessage`, except for `/block`, `/unblock`, `/mute`, `/unmute` and `/read`
+//! followed by a name, which go through the call:
+//!
+//! ```sh
+//! cargo run --features grpc &
+//! cargo run --features grpc --example grpc_client -- http://localhost:50051 alice bob
+//! ```
+
+use proto::client_frame::Kind;
+use proto::melt_client::MeltClient;
+use proto::server_frame::Kind as ServerKind;
+use proto::{
+    ChatMessage, ClientFrame, CreateSession, HistoryRequest, ListUsersRequest, ServerFrame,
+};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::mpsc;
+use tonic::metadata::MetadataValue;
+use tonic::Request;
+
+mod proto {
+    tonic::include_proto!("melt");
+}
+
+#[tokio::main]
+async fn main() {
+    let mut arguments = std::env::args().skip(1);
+    let server = arguments
+        .next()
+        .unwrap_or_else(|| "http://localhost:50051".to_owned());
+    let name = arguments.next().expect("the user name should be given");
+    let recipient = arguments.next().expect("the recipient should be given");
+
+    let mut client = MeltClient::connect(server)
+        .await
+        .expect("should be able to connect to the server");
+
+    let (frames, receiver) = mpsc::channel(8);
+    let requests = futures_util::stream::unfold(receiver, |mut receiver| async move {
+        let frame = receiver.recv().await?;
+        Some((frame, receiver))
+    });
+    let mut request = Request::new(requests);
+    request.metadata_mut().insert(
+        "user",
+        name.parse().expect("the name should be valid metadata"),
+    );
+    let mut responses = client.chat(request).await.unwrap().into_inner();
+    println!("Connected as {name}");
+
+    let create_session = ClientFrame {
+        kind: Some(Kind::CreateSession(CreateSession {})),
+    };
+    frames.send(create_session).await.unwrap();
+    let token = loop {
+        match responses.message().await.unwrap() {
+            Some(ServerFrame {
+                kind: Some(ServerKind::SessionCreated(token)),
+            }) => break token,
+            Some(frame) => println!("{:?}", frame.kind),
+            None => return println!("Chat ended"),
+        }
+    };
+    let authorization: MetadataValue<_> = format!("Bearer {token}")
+        .parse()
+        .expect("the token should be valid metadata");
+
+    let mut request = Request::new(ListUsersRequest {});
+    request
+        .metadata_mut()
+        .insert("authorization", authorization.clone());
+    let users = client.list_users(request).await.unwrap();
+    println!("Contacts: {:?}", users.into_inner().contacts);
+    let mut request = Request::new(HistoryRequest {
+        contact: recipient.clone(),
+    });
+    request
+        .metadata_mut()
+        .insert("authorization", authorization);
+    let history = client.history(request).await.unwrap();
+    println!("Conversation: {:?}", history.into_inner().messages);
+
+    tokio::spawn(async move {
+        loop {
+            match responses.message().await {
+                Ok(Some(frame)) => println!("{:?}", frame.kind),
+                Ok(None) => break println!("Chat ended"),
+                Err(status) => break println!("Chat ended: {status}"),
+            }
+        }
+    });
+
+    let mut input = BufReader::new(tokio::io::stdin()).lines();
+    while let Ok(Some(line)) = input.next_line().await {
+        let command = line.split_once(' ').and_then(|(command, name)| {
+            let name = name.to_owned();
+            match command {
+                "/block" => Some(Kind::Block(name)),
+                "/unblock" => Some(Kind::Unblock(name)),
+                "/mute" => Some(Kind::Mute(name)),
+                "/unmute" => Some(Kind::Unmute(name)),
+                "/read" => Some(Kind::MarkRead(name)),
+                "/archive" => Some(Kind::Archive(name)),
+                "/unarchive" => Some(Kind::Unarchive(name)),
+                _ => None,
+            }
+        });
+        if let Some(command) = command {
+            let frame = ClientFrame {
+                kind: Some(command),
+            };
+            frames.send(frame).await.unwrap();
+            continue;
+        }
+
+        let time_utc = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+        let message = ChatMessage {
+            recipient: recipient.clone(),
+            sender: name.clone(),
+            text: line,
+            time_utc,
+        };
+        if let Err(status) = client.send_message(message).await {
+            println!("Not sent: {status}");
+        }
+    }
+}
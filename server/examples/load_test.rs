@@ -0,0 +1,116 @@
+//! Measures how many chat messages a running server routes per second.
+//!
+//! Connects users that each send messages to the next user and waits until all messages arrived.
+//! Compare runs against servers started with different `DELIVERY_SHARDS` to see how routing scales
+//! with the number of shards:
+//!
+//! ```sh
+//! DELIVERY_SHARDS=1 cargo run --release &
+//! cargo run --release --example load_test -- ws://localhost:3000 200 500
+//! ```
+
+use futures_util::{SinkExt, StreamExt};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::task::JoinSet;
+use tokio_tungstenite::tungstenite::Message;
+
+#[tokio::main]
+async fn main() {
+    let mut arguments = std::env::args().skip(1);
+    let url = arguments
+        .next()
+        .unwrap_or_else(|| "ws://localhost:3000".to_owned());
+    let users: usize = arguments.next().map_or(100, |users| {
+        users.parse().expect("users should be a number")
+    });
+    let messages: usize = arguments.next().map_or(100, |messages| {
+        messages.parse().expect("messages should be a number")
+    });
+    assert!(users >= 2, "at least two users are needed to send messages");
+
+    // Unique names so that runs don't interfere with users of previous runs
+    let run = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    let names: Vec<String> = (0..users)
+        .map(|user| format!("load-{run}-{user}"))
+        .collect();
+
+    let mut sockets = Vec::with_capacity(users);
+    for name in &names {
+        let (socket, _) = tokio_tungstenite::connect_async(format!("{url}/messages/{name}"))
+            .await
+            .expect("should be able to connect to the server");
+        sockets.push(socket);
+    }
+    println!("Connected {users} users, sending {messages} messages each");
+
+    let start = Instant::now();
+    let mut senders = JoinSet::new();
+    let mut receivers = JoinSet::new();
+    for (user, socket) in sockets.into_iter().enumerate() {
+        let sender = names[user].clone();
+        let recipient = names[(user + 1) % users].clone();
+        let (mut sink, mut stream) = socket.split();
+
+        senders.spawn(async move {
+            for message in 0..messages {
+                let text = format!(
+                    r#"{{"recipient":"{recipient}","sender":"{sender}","text":"{message}","time_utc":0}}"#
+                );
+                sink.send(Message::Text(text))
+                    .await
+                    .expect("should be able to send messages");
+            }
+            // Closing the connection before all messages arrived would remove the user
+            sink
+        });
+
+        // Every user receives as many messages as it sends
+        receivers.spawn(async move {
+            let mut received = 0;
+            while received < messages {
+                let Some(Ok(message)) = stream.next().await else {
+                    panic!("connection closed after receiving {received} messages");
+                };
+                if let Message::Text(text) = message {
+                    // The users of a run are not contacts of each other, so their messages arrive
+                    // as requests. Contact lists changing are sent as well.
+                    let is_message = text.contains(r#""type":"ChatMessage""#)
+                        || text.contains(r#""type":"MessageRequest""#);
+                    if is_message {
+                        received += 1;
+                    }
+                }
+            }
+        });
+    }
+
+    let mut sinks = Vec::with_capacity(users);
+    while let Some(result) = senders.join_next().await {
+        sinks.push(result.expect("sending should not fail"));
+    }
+    println!("Sent all messages after {:?}", start.elapsed());
+
+    let received = tokio::time::timeout(Duration::from_secs(60), async {
+        while let Some(result) = receivers.join_next().await {
+            result.expect("receiving should not fail");
+        }
+    })
+    .await;
+    if received.is_err() {
+        panic!("not all messages arrived within 60 seconds");
+    }
+
+    let elapsed = start.elapsed();
+    let total = users * messages;
+    println!(
+        "Routed {total} messages in {elapsed:?}, {:.0} messages per second",
+        total as f64 / elapsed.as_secs_f64()
+    );
+
+    for mut sink in sinks {
+        let _ = sink.close().await;
+    }
+}
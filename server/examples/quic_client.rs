@@ -0,0 +1,259 @@
+//! Chats through a WebTransport session with a running server, to try the transport without a
+//! browser.
+//!
+//! Prints everything the server sends, with datagrams marked as such, and sends every line typed
+//! into the terminal as a chat message to the recipient:
+//!
+//! ```sh
+//! cargo run --features quic &
+//! cargo run --features quic --example quic_client -- localhost:4433 alice bob
+//! ```
+//!
+//! Without a certificate file as fourth argument, the certificate of the server is not verified,
+//! which is only fine for the self-signed certificate the server generates for development.
+//!
+//! The HTTP/3 client of the h3 crate can't request WebTransport sessions yet, so this speaks the
+//! little HTTP/3 a session needs itself: the settings, one CONNECT request and the stream header.
+
+use quinn::crypto::rustls::QuicClientConfig;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, RootCertStore, SignatureScheme};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+
+/// WebTransport sessions are set up through HTTP/3
+const ALPN: &[u8] = b"h3";
+
+#[tokio::main]
+async fn main() {
+    let mut arguments = std::env::args().skip(1);
+    let server = arguments
+        .next()
+        .unwrap_or_else(|| "localhost:4433".to_owned());
+    let name = arguments.next().expect("the user name should be given");
+    let recipient = arguments.next().expect("the recipient should be given");
+    let certificate = arguments.next();
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .unwrap();
+    let mut tls = match certificate {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for certificate in CertificateDer::pem_file_iter(path).unwrap() {
+                roots.add(certificate.unwrap()).unwrap();
+            }
+            builder.with_root_certificates(roots).with_no_client_auth()
+        }
+        None => builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(SkipVerification(provider)))
+            .with_no_client_auth(),
+    };
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+
+    let mut endpoint = quinn::Endpoint::client(([0, 0, 0, 0], 0).into()).unwrap();
+    endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(
+        QuicClientConfig::try_from(tls).unwrap(),
+    )));
+
+    let address = tokio::net::lookup_host(&server)
+        .await
+        .unwrap()
+        .next()
+        .expect("the server address should resolve");
+    let host = server.split(':').next().unwrap();
+    let connection = endpoint
+        .connect(address, host)
+        .unwrap()
+        .await
+        .expect("should be able to connect to the server");
+
+    // The settings tell the server that extended CONNECT, datagrams and WebTransport are supported
+    let mut control = connection.open_uni().await.unwrap();
+    let mut settings = Vec::new();
+    for (id, value) in [(0x08, 1), (0x33, 1), (0x2b60_3742, 1)] {
+        settings.extend(varint(id));
+        settings.extend(varint(value));
+    }
+    control.write_all(&[0x00]).await.unwrap();
+    control.write_all(&frame(0x04, &settings)).await.unwrap();
+
+    // The session is requested with CONNECT to the path of the user, like the websocket route
+    let (mut request, mut response) = connection.open_bi().await.unwrap();
+    request
+        .write_all(&frame(0x01, &connect_headers(host, &name)))
+        .await
+        .unwrap();
+    loop {
+        let kind = read_varint(&mut response).await;
+        let mut payload = vec![0; read_varint(&mut response).await as usize];
+        response.read_exact(&mut payload).await.unwrap();
+        if kind != 0x01 {
+            continue;
+        }
+        // :status 200 from the static table
+        if payload.get(2) != Some(&0xd9) {
+            println!("The server refused the session");
+            return;
+        }
+        break;
+    }
+
+    // Streams of the session start with their type and the id of the session
+    let session = request.id().index() << 2;
+    let (mut send, receive) = connection.open_bi().await.unwrap();
+    let mut header = varint(0x41);
+    header.extend(varint(session));
+    send.write_all(&header).await.unwrap();
+    println!("Connected as {name}");
+
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(receive).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            println!("{line}");
+        }
+    });
+
+    let datagrams = connection.clone();
+    tokio::spawn(async move {
+        // Datagrams start with the id of the session too, which is a single byte for the first
+        while let Ok(datagram) = datagrams.read_datagram().await {
+            println!("datagram: {}", String::from_utf8_lossy(&datagram[1..]));
+        }
+    });
+
+    let mut input = BufReader::new(tokio::io::stdin()).lines();
+    while let Ok(Some(text)) = input.next_line().await {
+        let time_utc = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        let message = serde_json::json!({
+            "recipient": recipient,
+            "sender": name,
+            "text": text,
+            "time_utc": time_utc,
+        });
+        send.write_all(format!("{message}\n").as_bytes())
+            .await
+            .unwrap();
+    }
+
+    let _ = send.finish();
+    let error = connection.closed().await;
+    println!("Connection closed: {error}");
+    drop((control, request));
+}
+
+/// The QPACK field section of the CONNECT request, only using the static table
+fn connect_headers(host: &str, name: &str) -> Vec<u8> {
+    let path = format!("/messages/{name}");
+    // No dynamic table, then :method CONNECT and :scheme https
+    let mut headers = vec![0x00, 0x00, 0xcf, 0xd7];
+    // :authority and :path with values
+    for (index, value) in [(0x50, host), (0x51, path.as_str())] {
+        headers.push(index);
+        headers.extend(string_length(value.len()));
+        headers.extend(value.as_bytes());
+    }
+    // :protocol is not in the static table, its name length of 9 takes two bytes
+    headers.extend([0x27, 0x02]);
+    headers.extend(b":protocol");
+    headers.push(12);
+    headers.extend(b"webtransport");
+    headers
+}
+
+/// The length of a string without Huffman coding, an integer with a 7 bit prefix
+fn string_length(length: usize) -> Vec<u8> {
+    if length < 0x7f {
+        return vec![length as u8];
+    }
+    let mut bytes = vec![0x7f];
+    let mut rest = length - 0x7f;
+    while rest >= 0x80 {
+        bytes.push((rest % 0x80) as u8 | 0x80);
+        rest /= 0x80;
+    }
+    bytes.push(rest as u8);
+    bytes
+}
+
+fn varint(value: u64) -> Vec<u8> {
+    match value {
+        0..=0x3f => vec![value as u8],
+        0x40..=0x3fff => (value as u16 | 0x4000).to_be_bytes().to_vec(),
+        _ => (value as u32 | 0x8000_0000).to_be_bytes().to_vec(),
+    }
+}
+
+async fn read_varint(stream: &mut quinn::RecvStream) -> u64 {
+    let first = stream.read_u8().await.unwrap();
+    let mut value = u64::from(first & 0x3f);
+    for _ in 1..(1 << (first >> 6)) {
+        value = (value << 8) | u64::from(stream.read_u8().await.unwrap());
+    }
+    value
+}
+
+fn frame(kind: u64, payload: &[u8]) -> Vec<u8> {
+    let mut frame = varint(kind);
+    frame.extend(varint(payload.len() as u64));
+    frame.extend(payload);
+    frame
+}
+
+/// Accepts any certificate, as long as the handshake is signed with its key
+#[derive(Debug)]
+struct SkipVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for SkipVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            certificate,
+            signature,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            certificate,
+            signature,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
@@ -0,0 +1,512 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io::{self, BufRead, BufReader};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use time::OffsetDateTime;
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::oneshot;
+
+use super::framework::{self, Actor, Addr, Context};
+use super::HandleError;
+use crate::telemetry;
+
+/// Something that happened which operators might need to look into later
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type")]
+pub(crate) enum AuditEvent {
+    /// A websocket connected as the user
+    Login {
+        user: Arc<str>,
+        address: IpAddr,
+    },
+    /// A banned user or address tried to connect
+    LoginRefused {
+        user: Arc<str>,
+        address: IpAddr,
+    },
+    /// A request to the admin API without the right token
+    FailedAuthentication {
+        address: Option<IpAddr>,
+        path: String,
+    },
+    /// A socket sent a message in the name of someone else
+    SpoofedSender {
+        user: Arc<str>,
+        claimed_sender: String,
+        address: IpAddr,
+    },
+    NameBanned {
+        user: Arc<str>,
+    },
+    NameUnbanned {
+        user: Arc<str>,
+    },
+    AddressBanned {
+        address: IpAddr,
+    },
+    AddressUnbanned {
+        address: IpAddr,
+    },
+    /// An operator closed the socket with the id or all sockets of the user
+    ForcedDisconnect {
+        user: Arc<str>,
+        socket: Option<Arc<str>>,
+    },
+    MessagesDeleted {
+        user: Arc<str>,
+    },
+    /// An operator imported an archive of the user
+    ArchiveImported {
+        user: Arc<str>,
+    },
+    Announcement {
+        text: Arc<str>,
+    },
+}
+
+impl AuditEvent {
+    /// The name used to filter events by type, which is the same as the `type` field
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Login { .. } => "Login",
+            Self::LoginRefused { .. } => "LoginRefused",
+            Self::FailedAuthentication { .. } => "FailedAuthentication",
+            Self::SpoofedSender { .. } => "SpoofedSender",
+            Self::NameBanned { .. } => "NameBanned",
+            Self::NameUnbanned { .. } => "NameUnbanned",
+            Self::AddressBanned { .. } => "AddressBanned",
+            Self::AddressUnbanned { .. } => "AddressUnbanned",
+            Self::ForcedDisconnect { .. } => "ForcedDisconnect",
+            Self::MessagesDeleted { .. } => "MessagesDeleted",
+            Self::ArchiveImported { .. } => "ArchiveImported",
+            Self::Announcement { .. } => "Announcement",
+        }
+    }
+
+    /// The user the event is about, if it is about one
+    fn user(&self) -> Option<&str> {
+        match self {
+            Self::Login { user, .. }
+            | Self::LoginRefused { user, .. }
+            | Self::SpoofedSender { user, .. }
+            | Self::NameBanned { user }
+            | Self::NameUnbanned { user }
+            | Self::ForcedDisconnect { user, .. }
+            | Self::MessagesDeleted { user }
+            | Self::ArchiveImported { user } => Some(user),
+            Self::FailedAuthentication { .. }
+            | Self::AddressBanned { .. }
+            | Self::AddressUnbanned { .. }
+            | Self::Announcement { .. } => None,
+        }
+    }
+}
+
+/// One line of the audit log
+// The event is not flattened into the record, as that does not support deserializing the
+// timestamp, like internally tagged enums
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct AuditRecord {
+    #[serde(with = "time::serde::timestamp::milliseconds")]
+    pub(crate) time_utc: OffsetDateTime,
+    /// The server instance the event happened on
+    pub(crate) node: Arc<str>,
+    pub(crate) event: AuditEvent,
+}
+
+/// Which records to look for. Unset fields match everything.
+#[derive(Debug)]
+pub(crate) struct AuditQuery {
+    pub(crate) user: Option<String>,
+    pub(crate) event: Option<String>,
+    pub(crate) from: Option<OffsetDateTime>,
+    pub(crate) to: Option<OffsetDateTime>,
+    /// At most this many of the latest matching records are returned
+    pub(crate) limit: usize,
+}
+
+impl AuditQuery {
+    fn matches(&self, record: &AuditRecord) -> bool {
+        self.user
+            .as_deref()
+            .is_none_or(|user| record.event.user() == Some(user))
+            && self
+                .event
+                .as_deref()
+                .is_none_or(|event| record.event.name() == event)
+            && self.from.is_none_or(|from| record.time_utc >= from)
+            && self.to.is_none_or(|to| record.time_utc < to)
+    }
+}
+
+/// When the log file is rotated and how many of the rotated files are kept
+#[derive(Clone, Debug)]
+pub(crate) struct Rotation {
+    pub(crate) max_bytes: u64,
+    pub(crate) max_files: usize,
+}
+
+enum Message {
+    Record(AuditEvent),
+    Query(AuditQuery, oneshot::Sender<Vec<AuditRecord>>),
+}
+
+/// The audit log actor appends security relevant events as JSON lines to a file of its own, so
+/// that they are not lost in the debug output and can't be changed through the app.
+/// Writing through a single actor keeps the lines from interleaving and the rotation from racing
+/// with writes.
+struct AuditLog {
+    path: PathBuf,
+    node: Arc<str>,
+    rotation: Rotation,
+    /// Opened when the actor starts and after each rotation
+    file: Option<File>,
+    /// Size of the current file, to know when to rotate it without asking the file system
+    size: u64,
+}
+
+impl AuditLog {
+    /// The path of the rotated file with the number, where 1 is the most recent one
+    fn rotated_path(&self, number: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", number));
+        path.into()
+    }
+
+    async fn open(&mut self) {
+        // Append only, so nothing already written is ever overwritten
+        let result = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await;
+        let file = match result {
+            Ok(file) => file,
+            Err(error) => {
+                tracing::error!("Error opening audit log {:?}: {}", self.path, error);
+                telemetry::record_actor_error(Self::NAME, "open");
+                return;
+            }
+        };
+
+        self.size = match file.metadata().await {
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        };
+        self.file = Some(file);
+    }
+
+    /// Shifts the rotated files by one, dropping the oldest, and starts a new file
+    async fn rotate(&mut self) {
+        self.file = None;
+        if self.rotation.max_files == 0 {
+            let _ = fs::remove_file(&self.path).await;
+        } else {
+            for number in (1..self.rotation.max_files).rev() {
+                let _ = fs::rename(self.rotated_path(number), self.rotated_path(number + 1)).await;
+            }
+            if let Err(error) = fs::rename(&self.path, self.rotated_path(1)).await {
+                tracing::error!("Error rotating audit log: {}", error);
+                telemetry::record_actor_error(Self::NAME, "rotate");
+            }
+        }
+
+        self.open().await;
+    }
+
+    async fn record(&mut self, event: AuditEvent) {
+        let record = AuditRecord {
+            time_utc: OffsetDateTime::now_utc(),
+            node: self.node.clone(),
+            event,
+        };
+        let mut line = match serde_json::to_vec(&record) {
+            Ok(line) => line,
+            Err(error) => {
+                tracing::error!("Error serializing audit record: {:?}", error);
+                telemetry::record_actor_error(Self::NAME, "serialize");
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        if self.size > 0 && self.size + line.len() as u64 > self.rotation.max_bytes {
+            self.rotate().await;
+        }
+
+        let Some(file) = &mut self.file else {
+            tracing::error!("Audit log is not open, dropping {:?}", record);
+            telemetry::record_actor_error(Self::NAME, "write");
+            return;
+        };
+
+        // Flushed right away as the records are needed the most after something went wrong
+        let result = async {
+            file.write_all(&line).await?;
+            file.flush().await
+        }
+        .await;
+        match result {
+            Ok(()) => self.size += line.len() as u64,
+            Err(error) => {
+                tracing::error!("Error writing audit log: {}", error);
+                telemetry::record_actor_error(Self::NAME, "write");
+            }
+        }
+    }
+
+    /// Opens the rotated files from oldest to newest and the current file last, and reads them on
+    /// a thread for blocking work to keep recording meanwhile. The files are opened here so that a
+    /// rotation while they are read doesn't move them away.
+    async fn query(&self, query: AuditQuery, respond: oneshot::Sender<Vec<AuditRecord>>) {
+        let mut paths: Vec<_> = (1..=self.rotation.max_files)
+            .rev()
+            .map(|number| self.rotated_path(number))
+            .collect();
+        paths.push(self.path.clone());
+
+        let mut files = Vec::with_capacity(paths.len());
+        for path in paths {
+            match File::open(&path).await {
+                Ok(file) => files.push((path, file.into_std().await)),
+                // Rotated files that don't exist yet
+                Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+                Err(error) => {
+                    tracing::error!("Error opening audit log {:?}: {}", path, error);
+                    telemetry::record_actor_error(Self::NAME, "read");
+                }
+            }
+        }
+
+        tokio::task::spawn_blocking(move || {
+            let records = read_records(files, &query, &respond);
+            if respond.send(records).is_err() {
+                tracing::error!("Error sending audit records back");
+                telemetry::record_actor_error(AuditLog::NAME, "respond");
+            }
+        });
+    }
+}
+
+/// Reads the files line by line, keeping only the latest of the matching records. Stops early if
+/// no one is waiting for them anymore.
+fn read_records(
+    files: Vec<(PathBuf, std::fs::File)>,
+    query: &AuditQuery,
+    respond: &oneshot::Sender<Vec<AuditRecord>>,
+) -> Vec<AuditRecord> {
+    let mut records = VecDeque::with_capacity(query.limit.min(1024));
+    for (path, file) in files {
+        for line in BufReader::new(file).lines() {
+            if respond.is_closed() {
+                return Vec::new();
+            }
+
+            let line = match line {
+                Ok(line) => line,
+                Err(error) => {
+                    tracing::error!("Error reading audit log {:?}: {}", path, error);
+                    telemetry::record_actor_error(AuditLog::NAME, "read");
+                    break;
+                }
+            };
+            let record = match serde_json::from_str::<AuditRecord>(&line) {
+                Ok(record) => record,
+                Err(error) => {
+                    tracing::warn!("Skipping unreadable audit record: {}", error);
+                    continue;
+                }
+            };
+            if query.matches(&record) {
+                records.push_back(record);
+                if records.len() > query.limit {
+                    records.pop_front();
+                }
+            }
+        }
+    }
+
+    records.into()
+}
+
+impl Actor for AuditLog {
+    type Message = Message;
+    const NAME: &'static str = "audit_log";
+
+    async fn started(&mut self, _context: &mut Context<Self>) {
+        self.open().await;
+    }
+
+    async fn handle(&mut self, message: Message, _context: &mut Context<Self>) {
+        match message {
+            Message::Record(event) => self.record(event).await,
+            Message::Query(query, respond) => self.query(query, respond).await,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Handle {
+    address: Addr<AuditLog>,
+    /// How long to wait for queries to be answered
+    request_timeout: Duration,
+}
+
+impl Handle {
+    pub(crate) fn new(
+        path: PathBuf,
+        rotation: Rotation,
+        node: Arc<str>,
+        request_timeout: Duration,
+    ) -> Self {
+        let audit_log = AuditLog {
+            path,
+            node,
+            rotation,
+            file: None,
+            size: 0,
+        };
+
+        let address = framework::spawn(audit_log);
+
+        Self {
+            address,
+            request_timeout,
+        }
+    }
+
+    /// Appends the event to the log without waiting for it to be written, so that recording
+    /// never holds up the actors
+    pub(crate) fn record(&self, event: AuditEvent) {
+        if let Err(error) = self.address.notify(Message::Record(event)) {
+            tracing::error!("Error sending event to audit log: {}", error);
+        }
+    }
+
+    pub(crate) async fn query(&self, query: AuditQuery) -> Result<Vec<AuditRecord>, HandleError> {
+        self.address
+            .ask(
+                |respond| Message::Query(query, respond),
+                self.request_timeout,
+            )
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    /// An audit log in a directory of its own
+    fn audit_log(rotation: Rotation) -> (PathBuf, Handle) {
+        let directory = std::env::temp_dir().join(format!("melt-audit-{}", nanoid::nanoid!()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("audit.log");
+        let handle = Handle::new(path.clone(), rotation, "a".into(), Duration::from_secs(5));
+        (directory, handle)
+    }
+
+    fn login(user: &str) -> AuditEvent {
+        AuditEvent::Login {
+            user: user.into(),
+            address: ADDRESS,
+        }
+    }
+
+    fn query(limit: usize) -> AuditQuery {
+        AuditQuery {
+            user: None,
+            event: None,
+            from: None,
+            to: None,
+            limit,
+        }
+    }
+
+    fn users(records: &[AuditRecord]) -> Vec<&str> {
+        let events = records.iter().map(|record| &record.event);
+        events.filter_map(AuditEvent::user).collect()
+    }
+
+    #[tokio::test]
+    async fn rotates_the_file_and_drops_the_oldest() {
+        // Every record is too big to go into a file that is not empty
+        let rotation = Rotation {
+            max_bytes: 1,
+            max_files: 2,
+        };
+        let (directory, audit_log) = audit_log(rotation);
+        for user in ["alice", "bob", "carol", "dave"] {
+            audit_log.record(login(user));
+        }
+
+        let records = audit_log.query(query(10)).await.unwrap();
+        assert_eq!(users(&records), ["bob", "carol", "dave"]);
+        let mut files: Vec<_> = std::fs::read_dir(&directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        files.sort();
+        assert_eq!(files, ["audit.log", "audit.log.1", "audit.log.2"]);
+        let newest_rotated = std::fs::read_to_string(directory.join("audit.log.1")).unwrap();
+        assert!(newest_rotated.contains("carol"));
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn queries_filter_by_user_event_and_time() {
+        let rotation = Rotation {
+            max_bytes: 1024,
+            max_files: 2,
+        };
+        let (directory, audit_log) = audit_log(rotation);
+        audit_log.record(login("alice"));
+        audit_log.record(AuditEvent::LoginRefused {
+            user: "alice".into(),
+            address: ADDRESS,
+        });
+        audit_log.record(login("bob"));
+        audit_log.record(AuditEvent::AddressBanned { address: ADDRESS });
+
+        let by_user = AuditQuery {
+            user: Some("alice".to_owned()),
+            ..query(10)
+        };
+        let records = audit_log.query(by_user).await.unwrap();
+        assert_eq!(users(&records), ["alice", "alice"]);
+
+        let by_event = AuditQuery {
+            event: Some("Login".to_owned()),
+            ..query(10)
+        };
+        let records = audit_log.query(by_event).await.unwrap();
+        assert_eq!(users(&records), ["alice", "bob"]);
+
+        // The latest ones are kept
+        let records = audit_log.query(query(2)).await.unwrap();
+        assert_eq!(records[0].event.name(), "Login");
+        assert_eq!(records[1].event.name(), "AddressBanned");
+
+        let now = OffsetDateTime::now_utc();
+        let from_later = AuditQuery {
+            from: Some(now + time::Duration::hours(1)),
+            ..query(10)
+        };
+        assert!(audit_log.query(from_later).await.unwrap().is_empty());
+        let to_later = AuditQuery {
+            to: Some(now + time::Duration::hours(1)),
+            ..query(10)
+        };
+        assert_eq!(audit_log.query(to_later).await.unwrap().len(), 4);
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,132 @@
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub(crate) enum CompressionError {
+    #[error("Error compressing message: {0}")]
+    Compress(#[from] flate2::CompressError),
+    #[error("Error decompressing message: {0}")]
+    Decompress(#[from] flate2::DecompressError),
+    #[error("Message is larger than {0} bytes after decompressing")]
+    TooLarge(usize),
+    #[error("The client ended its deflate stream")]
+    StreamEnded,
+}
+
+/// Compresses the messages of one socket like permessage-deflate does. Each direction is a single
+/// raw deflate stream that is flushed after every message, so that later messages refer back to
+/// the repetitive parts of earlier ones. Browsers decompress it with a
+/// `DecompressionStream("deflate-raw")` for the whole socket.
+pub(crate) struct Deflate {
+    compress: Compress,
+    decompress: Decompress,
+    /// How large a message from the client may get when decompressed, against decompression bombs
+    max_message_bytes: usize,
+}
+
+impl Deflate {
+    pub(crate) fn new(level: u32, max_message_bytes: usize) -> Self {
+        Self {
+            compress: Compress::new(Compression::new(level), false),
+            decompress: Decompress::new(false),
+            max_message_bytes,
+        }
+    }
+
+    pub(super) fn compress(&mut self, message: &[u8]) -> Result<Vec<u8>, CompressionError> {
+        let mut output = Vec::with_capacity(message.len() / 2 + 64);
+        let mut consumed = 0;
+        loop {
+            let before = self.compress.total_in();
+            self.compress
+                .compress_vec(&message[consumed..], &mut output, FlushCompress::Sync)?;
+            consumed += (self.compress.total_in() - before) as usize;
+            // The flush is only complete if it did not fill the output
+            if consumed == message.len() && output.len() < output.capacity() {
+                return Ok(output);
+            }
+            output.reserve(output.capacity());
+        }
+    }
+
+    /// Never holds more than one byte over the limit in memory, however much the frame inflates
+    pub(super) fn decompress(&mut self, frame: &[u8]) -> Result<Vec<u8>, CompressionError> {
+        let limit = self.max_message_bytes + 1;
+        let mut output = Vec::with_capacity((frame.len() * 4).max(64).min(limit));
+        let mut consumed = 0;
+        // Inflating may stop before the output is full, so it is only done once it stops making
+        // progress
+        loop {
+            let before = (self.decompress.total_in(), self.decompress.total_out());
+            let status = self.decompress.decompress_vec(
+                &frame[consumed..],
+                &mut output,
+                FlushDecompress::Sync,
+            )?;
+            consumed += (self.decompress.total_in() - before.0) as usize;
+            if output.len() > self.max_message_bytes {
+                return Err(CompressionError::TooLarge(self.max_message_bytes));
+            }
+
+            if output.len() == output.capacity() {
+                output.reserve_exact(output.capacity().min(limit - output.len()));
+            } else if before == (self.decompress.total_in(), self.decompress.total_out()) {
+                // Data after the end of the stream can't be decompressed anymore
+                if status == Status::StreamEnd && consumed < frame.len() {
+                    return Err(CompressionError::StreamEnded);
+                }
+                return Ok(output);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_MESSAGE_BYTES: usize = 64 * 1024;
+
+    #[test]
+    fn decompresses_what_the_other_side_compressed() {
+        let (mut client, mut server) = (
+            Deflate::new(6, MAX_MESSAGE_BYTES),
+            Deflate::new(6, MAX_MESSAGE_BYTES),
+        );
+        let message = br#"{"recipient":"bob","sender":"alice","text":"Hi","time_utc":0}"#;
+
+        let first = client.compress(message).unwrap();
+        let second = client.compress(message).unwrap();
+        // The second message refers back to the first one
+        assert!(second.len() < first.len());
+        assert_eq!(server.decompress(&first).unwrap(), message);
+        assert_eq!(server.decompress(&second).unwrap(), message);
+    }
+
+    #[test]
+    fn rejects_messages_that_inflate_past_the_limit() {
+        let (mut client, mut server) = (
+            Deflate::new(9, usize::MAX),
+            Deflate::new(6, MAX_MESSAGE_BYTES),
+        );
+        let bomb = client.compress(&vec![0; 16 * 1024 * 1024]).unwrap();
+        assert!(bomb.len() < MAX_MESSAGE_BYTES);
+
+        let result = server.decompress(&bomb);
+        assert!(matches!(
+            result,
+            Err(CompressionError::TooLarge(MAX_MESSAGE_BYTES))
+        ));
+
+        // Exactly at the limit is fine
+        let (mut client, mut server) = (
+            Deflate::new(9, usize::MAX),
+            Deflate::new(6, MAX_MESSAGE_BYTES),
+        );
+        let largest = client.compress(&vec![0; MAX_MESSAGE_BYTES]).unwrap();
+        assert_eq!(
+            server.decompress(&largest).unwrap().len(),
+            MAX_MESSAGE_BYTES
+        );
+    }
+}
@@ -1,15 +1,190 @@
-use crate::actor::{user, ChatMessage};
-use std::collections::HashMap;
-use std::error::Error;
+use crate::actor::framework::{self, Actor, Addr, Context};
+use crate::actor::socket::SocketInfo;
+use crate::actor::supervisor::{self, EscalationError, RestartPolicy};
+use crate::actor::{user, ChatMessage, HandleError, Notice};
+use crate::cluster::{self, Subscription};
+use crate::push::Pusher;
+use crate::storage::contacts::DeliveryFilters;
+use crate::storage::conversations::Conversation;
+use crate::storage::Storage;
+use crate::telemetry;
+use bytes::Bytes;
+use futures_util::future::try_join_all;
+use futures_util::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::{DefaultHasher, Entry};
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::num::NonZeroUsize;
 use std::sync::Arc;
-use tokio::sync::{mpsc, oneshot};
+use std::time::Duration;
+use tokio::sync::{broadcast, oneshot, Mutex};
+use tokio::task::{JoinHandle, JoinSet};
 
+#[allow(clippy::enum_variant_names)]
 enum Message {
     //TODO handle case where a user actor is not found
     SendMessage(Arc<ChatMessage>),
     GetOrInsertUser(Arc<str>, oneshot::Sender<user::Handle>),
-    GetUsers(oneshot::Sender<Arc<[Arc<str>]>>),
+    /// Responds with the user if it is connected to this node
+    #[cfg(feature = "grpc")]
+    GetUser(Arc<str>, oneshot::Sender<Option<user::Handle>>),
+    /// Responds with the users out of the given ones that are connected to any node
+    FilterAvailable(Vec<Arc<str>>, oneshot::Sender<Vec<Arc<str>>>),
+    /// Passes the notice on to all devices of the user
+    Notify(Arc<str>, Notice),
+    /// Tells the user, if it is connected to this node, that one of its contacts became available
+    /// or unavailable
+    ContactPresence {
+        user: Arc<str>,
+        contact: Arc<str>,
+        is_available: bool,
+    },
     RemoveUser(Arc<str>),
+    /// Tells the contacts of the user that it went offline unless it is still connected to another
+    /// node. Sent after [`Message::RemoveUser`] to the same shard.
+    UserOffline(Arc<str>),
+    /// Responds with the users of the shard that are connected to this node
+    ListUsers(oneshot::Sender<Vec<(Arc<str>, user::Handle)>>),
+    /// Passes the announcement on to all users of the shard that are connected to this node
+    Announce(Arc<str>),
+    /// Sent by live user actors after the delivery service was restarted to rebuild the registry
+    RegisterUser(Arc<str>, user::Handle),
+    /// Answered right away to check that the actor is still processing its mailbox
+    Probe(oneshot::Sender<()>),
+    Shutdown {
+        reconnect_after: Duration,
+        respond: oneshot::Sender<()>,
+    },
+    /// A [`ClusterMessage`] from another node
+    Cluster(Bytes),
+    /// The cluster bus got its connection back after losing it. Only the first shard listens for
+    /// this, to tell the other nodes once.
+    Reconnected,
+    /// Forgets the users of the other nodes and announces the users of the shard again, as the
+    /// cluster may have missed that some of them joined or left
+    Resynchronize,
+}
+
+/// What the delivery services of the server instances in a cluster tell each other.
+/// The directory of which user is connected to which node is built from these.
+// Not internally tagged like the client messages as that does not support deserializing the
+// timestamps of chat messages
+#[derive(Serialize, Deserialize)]
+enum ClusterMessage {
+    UserJoined {
+        name: Arc<str>,
+        node: Arc<str>,
+    },
+    UserLeft {
+        name: Arc<str>,
+        node: Arc<str>,
+    },
+    /// The node does not know the users on the other nodes yet, so they announce them again
+    NodeStarted {
+        node: Arc<str>,
+    },
+    /// The users connected to the node are gone with it
+    NodeStopped {
+        node: Arc<str>,
+    },
+    /// A message for a user connected to the node it was published to
+    Deliver {
+        message: Arc<ChatMessage>,
+        /// The node of the sender, if it waits for this node to accept the message before storing
+        /// it. Only one of the nodes the recipient is connected to is asked, so that the message
+        /// is stored once.
+        #[serde(default)]
+        origin: Option<Arc<str>>,
+    },
+    /// The recipient's node delivered a message the origin asked about, which was not blocked
+    Accepted {
+        message: Arc<ChatMessage>,
+    },
+    /// The recipient left the node before it got the message the origin asked about, so the
+    /// origin keeps it like for recipients that are not connected anywhere
+    NotConnected {
+        message: Arc<ChatMessage>,
+    },
+    /// A notice for a user connected to the node it was published to
+    Notify {
+        recipient: Arc<str>,
+        notice: Notice,
+    },
+    /// An announcement from the operators to the users of all nodes
+    Announcement {
+        node: Arc<str>,
+        text: Arc<str>,
+    },
+}
+
+/// Directory events go to all nodes
+const DIRECTORY_SUBJECT: &str = "melt.directory";
+
+fn node_subject(node: &str) -> String {
+    format!("melt.nodes.{}", node)
+}
+
+/// The shard responsible for the user with the name
+fn shard_of(name: &str, shards: usize) -> usize {
+    // The default hasher is not randomized, so every shard and handle agrees on the result
+    let mut hasher = DefaultHasher::new();
+    name.hash(&mut hasher);
+    (hasher.finish() % shards as u64) as usize
+}
+
+async fn publish(node: &cluster::Node, subject: String, message: &ClusterMessage) {
+    let payload = match serde_json::to_vec(message) {
+        Ok(payload) => payload,
+        Err(error) => {
+            tracing::error!("Error serializing cluster message: {:?}", error);
+            telemetry::record_actor_error(DeliveryService::NAME, "serialize");
+            return;
+        }
+    };
+
+    let result = node.bus.publish(subject, payload.into()).await;
+    if let Err(error) = result {
+        tracing::error!("Error publishing to cluster: {}", error);
+        telemetry::record_actor_error(DeliveryService::NAME, "publish");
+    }
+}
+
+/// What became of a message for a user of a shard
+#[derive(PartialEq, Eq)]
+enum Delivery {
+    Delivered,
+    /// The recipient blocked the sender
+    Blocked,
+    /// The recipient is not connected to this node
+    NotConnected,
+}
+
+/// The directory and node subjects a shard listens to besides its mailbox. Subscribed to before
+/// any shard starts, so that no shard misses what the other nodes publish while it is starting.
+struct Subscriptions {
+    cluster: Option<(Subscription, Subscription)>,
+}
+
+impl Subscriptions {
+    async fn subscribe(handle: &Handle) -> Self {
+        let bus = &handle.node.bus;
+        let directory = bus.subscribe(DIRECTORY_SUBJECT.to_owned()).await;
+        let node = bus.subscribe(node_subject(&handle.node.id)).await;
+        let cluster = match (directory, node) {
+            (Ok(directory), Ok(node)) => Some((directory, node)),
+            (Err(error), _) | (_, Err(error)) => {
+                tracing::error!(
+                    "Error subscribing to cluster, running standalone: {}",
+                    error
+                );
+                telemetry::record_actor_error(DeliveryService::NAME, "subscribe");
+                None
+            }
+        };
+
+        Self { cluster }
+    }
 }
 
 /// The delivery service actor is responsible for sending messages between user actors.
@@ -19,58 +194,454 @@ enum Message {
 /// See: https://www.wevolver.com/article/mesh-topology).
 /// Instead, we use a start topology with the delivery service actor in the center.
 /// Currently, the delivery service also acts as kind of registry for user actors.
+/// To not have every message go through a single actor, the delivery service is split into shards
+/// that each are responsible for the users whose name hashes to them. The [`Handle`] routes
+/// requests to the right shard.
 struct DeliveryService {
-    receiver: mpsc::Receiver<Message>,
-    sender: mpsc::Sender<Message>,
+    handle: Handle,
+    /// The index of this shard
+    shard: usize,
+    /// Taken when the shard starts
+    subscriptions: Option<Subscriptions>,
+    /// Users of this shard connected to this node
     users_by_name: HashMap<Arc<str>, user::Handle>,
+    /// Whose messages the users connected to this node want to receive. Checked here instead of in
+    /// the user actors so that senders can be told about blocked messages right away.
+    filters_by_user: HashMap<Arc<str>, DeliveryFilters>,
+    /// Users of this shard connected to other nodes. A user with multiple devices can be connected
+    /// to multiple nodes.
+    nodes_by_remote_user: HashMap<Arc<str>, HashSet<Arc<str>>>,
 }
 
 impl DeliveryService {
     fn get_handle(&self) -> Handle {
-        self.sender.clone().into()
+        self.handle.clone()
     }
 
-    async fn add_available_contact(&self, user_name: Arc<str>) {
-        // Notifying all users of new users kind of defeats the purpose of a start topology, but it
-        // will only get send to the client through websockets, so it shouldn't cause a server
-        // memory problem. Additionally, this is app is only limited in scope anyway.
-        // Also, this loop can be parallelized.
-        for user in self.users_by_name.values() {
-            let result = user.add_contact(user_name.clone()).await;
+    /// Whether the user belongs to this shard
+    fn owns(&self, name: &str) -> bool {
+        shard_of(name, self.handle.shards.len()) == self.shard
+    }
+
+    async fn publish(&self, subject: String, message: &ClusterMessage) {
+        publish(&self.handle.node, subject, message).await;
+    }
+
+    /// Tells the contacts of the user that are connected to this node that the user came or went.
+    /// Every node does this for its own users, as every node learns about users coming and going
+    /// through the directory.
+    async fn change_presence(&self, name: Arc<str>, is_available: bool) {
+        let contacts = match self.handle.storage.contacts(name.clone()).await {
+            Ok(contacts) => contacts,
+            Err(error) => {
+                tracing::error!("Error loading contacts to notify: {}", error);
+                telemetry::record_actor_error(Self::NAME, "load_contacts");
+                return;
+            }
+        };
+
+        for contact in contacts {
+            // Not waiting for space in the other shard's mailbox, as it might be waiting on this
+            // one's
+            let result = self
+                .handle
+                .shard(&contact)
+                .notify(Message::ContactPresence {
+                    user: contact,
+                    contact: name.clone(),
+                    is_available,
+                });
             if let Err(error) = result {
-                tracing::error!("Error adding sending new contact to user: {}", error);
+                tracing::error!("Error sending presence to shard: {}", error);
+                telemetry::record_actor_error(Self::NAME, "presence");
+            }
+        }
+    }
+
+    /// Loads who the user blocked or muted, which changes along with the contacts
+    async fn load_filters(&mut self, name: Arc<str>) {
+        match self.handle.storage.delivery_filters(name.clone()).await {
+            Ok(filters) => {
+                self.filters_by_user.insert(name, filters);
+            }
+            Err(error) => {
+                tracing::error!("Error loading blocked and muted users: {}", error);
+                telemetry::record_actor_error(Self::NAME, "load_filters");
+            }
+        }
+    }
+
+    async fn notify_locally(&mut self, recipient: &Arc<str>, notice: Notice) {
+        let Some(user) = self.users_by_name.get(recipient).cloned() else {
+            return;
+        };
+
+        if let Notice::ContactsChanged = notice {
+            self.load_filters(recipient.clone()).await;
+        }
+
+        if let Err(error) = user.notify(notice).await {
+            tracing::error!("Error sending notice to user: {}", error);
+            telemetry::record_actor_error(Self::NAME, "notify");
+        }
+    }
+
+    async fn announce_locally(&self, text: Arc<str>) {
+        for user in self.users_by_name.values() {
+            let notice = Notice::Announcement { text: text.clone() };
+            if let Err(error) = user.notify(notice).await {
+                tracing::error!("Error sending announcement to user: {}", error);
+                telemetry::record_actor_error(Self::NAME, "announce");
+            }
+        }
+    }
+
+    async fn announce_user(&self, name: Arc<str>) {
+        let node = self.handle.node.id.clone();
+        let message = ClusterMessage::UserJoined { name, node };
+        self.publish(DIRECTORY_SUBJECT.to_owned(), &message).await;
+    }
+
+    async fn deliver_locally(&self, message: Arc<ChatMessage>) -> Delivery {
+        let Some(receiver) = self.users_by_name.get(&message.recipient) else {
+            return Delivery::NotConnected;
+        };
+
+        let filters = self.filters_by_user.get(&message.recipient);
+        let sender = message.sender.as_str();
+        if filters.is_some_and(|filters| filters.blocked.contains(sender)) {
+            tracing::debug!("Dropping message from blocked user");
+            metrics::counter!(telemetry::MESSAGES_DROPPED, "reason" => "blocked").increment(1);
+            return Delivery::Blocked;
+        }
+
+        let is_muted = filters.is_some_and(|filters| filters.muted.contains(sender));
+        let result = receiver.receive_message(message, is_muted).await;
+        if let Err(error) = result {
+            tracing::error!("Error sending message to user: {}", error);
+            telemetry::record_actor_error(Self::NAME, "deliver_message");
+            metrics::counter!(telemetry::MESSAGES_DROPPED, "reason" => "recipient_unavailable")
+                .increment(1);
+            return Delivery::Delivered;
+        }
+
+        metrics::counter!(telemetry::MESSAGES_ROUTED).increment(1);
+        Delivery::Delivered
+    }
+
+    /// Adds the message to the history in the background, as the messages behind it don't need to
+    /// wait for the disk. Only the node the sender is connected to stores it, not the nodes it is
+    /// forwarded to, and only after it was delivered without being blocked.
+    fn store(&self, message: Arc<ChatMessage>) {
+        let handle = self.handle.clone();
+        tokio::spawn(async move {
+            match handle.storage.store_message(message).await {
+                Ok(conversations) => handle.notify_conversations(conversations).await,
+                Err(error) => {
+                    tracing::error!("Error storing message: {}", error);
+                    telemetry::record_actor_error(DeliveryService::NAME, "store_message");
+                }
+            }
+        });
+    }
+
+    /// Keeps the message for a recipient that is not connected to any node, who gets it from the
+    /// history, and then tells the recipient's browsers about it. Checks whether the recipient
+    /// blocked the sender first, as their filters are only loaded while they are connected.
+    fn store_for_offline(&self, message: Arc<ChatMessage>) {
+        let handle = self.handle.clone();
+        tokio::spawn(async move {
+            let recipient = message.recipient.clone();
+            let is_blocked = match handle.storage.delivery_filters(recipient).await {
+                Ok(filters) => filters.blocked.contains(message.sender.as_str()),
+                Err(error) => {
+                    tracing::error!("Error loading blocked users: {}", error);
+                    telemetry::record_actor_error(DeliveryService::NAME, "delivery_filters");
+                    handle.reject(message);
+                    return;
+                }
+            };
+            if is_blocked {
+                tracing::debug!("Dropping message from blocked user");
+                metrics::counter!(telemetry::MESSAGES_DROPPED, "reason" => "blocked").increment(1);
+                handle.reject(message);
+                return;
+            }
+
+            match handle.storage.store_message(message.clone()).await {
+                Ok(conversations) => handle.notify_conversations(conversations).await,
+                Err(error) => {
+                    tracing::error!("Error storing message: {}", error);
+                    telemetry::record_actor_error(DeliveryService::NAME, "store_message");
+                    handle.reject(message);
+                    return;
+                }
+            }
+            if let Some(pusher) = &handle.pusher {
+                pusher.push(message);
+            }
+        });
+    }
+
+    fn is_known(&self, name: &str) -> bool {
+        self.users_by_name.contains_key(name) || self.nodes_by_remote_user.contains_key(name)
+    }
+
+    async fn remove_remote_user(&mut self, name: Arc<str>, node: &str) {
+        let Some(nodes) = self.nodes_by_remote_user.get_mut(&name) else {
+            return;
+        };
+
+        nodes.remove(node);
+        if nodes.is_empty() {
+            self.nodes_by_remote_user.remove(&name);
+            if !self.is_known(&name) {
+                self.change_presence(name, false).await;
             }
         }
     }
+
+    async fn forget_node(&mut self, node: &str) {
+        let names: Vec<_> = self
+            .nodes_by_remote_user
+            .iter()
+            .filter(|(_, nodes)| nodes.contains(node))
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in names {
+            self.remove_remote_user(name, node).await;
+        }
+    }
+
+    async fn process_cluster_message(&mut self, payload: Bytes) {
+        let message = match serde_json::from_slice::<ClusterMessage>(&payload) {
+            Ok(message) => message,
+            Err(error) => {
+                tracing::error!("Error deserializing cluster message: {:?}", error);
+                telemetry::record_actor_error(Self::NAME, "deserialize");
+                return;
+            }
+        };
+
+        match message {
+            ClusterMessage::UserJoined { node, .. }
+            | ClusterMessage::UserLeft { node, .. }
+            | ClusterMessage::NodeStarted { node }
+            | ClusterMessage::NodeStopped { node }
+            | ClusterMessage::Announcement { node, .. }
+                if node == self.handle.node.id =>
+            {
+                // Directory events are also received by the node that published them
+            }
+            // Every shard receives every cluster message, which keeps the shards independent of
+            // each other at the cost of deserializing the messages once per shard
+            ClusterMessage::UserJoined { name, .. } | ClusterMessage::UserLeft { name, .. }
+                if !self.owns(&name) => {}
+            ClusterMessage::Deliver { message, .. }
+            | ClusterMessage::Accepted { message }
+            | ClusterMessage::NotConnected { message }
+                if !self.owns(&message.recipient) => {}
+            ClusterMessage::Notify { recipient, .. } if !self.owns(&recipient) => {}
+            ClusterMessage::UserJoined { name, node } => {
+                if !self.is_known(&name) {
+                    self.change_presence(name.clone(), true).await;
+                }
+                self.nodes_by_remote_user
+                    .entry(name)
+                    .or_default()
+                    .insert(node);
+            }
+            ClusterMessage::UserLeft { name, node } => {
+                self.remove_remote_user(name, &node).await;
+            }
+            ClusterMessage::NodeStarted { node } => {
+                tracing::info!("Node {} joined the cluster", node);
+                // Users of a previous run of the node are stale if it crashed without stopping
+                self.forget_node(&node).await;
+                for name in self.users_by_name.keys() {
+                    self.announce_user(name.clone()).await;
+                }
+            }
+            ClusterMessage::NodeStopped { node } => {
+                tracing::info!("Node {} left the cluster", node);
+                self.forget_node(&node).await;
+            }
+            ClusterMessage::Deliver { message, origin } => {
+                // Only delivered locally to not send the message in circles
+                match self.deliver_locally(message.clone()).await {
+                    Delivery::Delivered => {
+                        if let Some(origin) = origin {
+                            let accepted = ClusterMessage::Accepted { message };
+                            self.publish(node_subject(&origin), &accepted).await;
+                        }
+                    }
+                    Delivery::Blocked => self.handle.reject(message),
+                    // The recipient left in the meantime. Only the node the origin asked keeps
+                    // the message, the others were only asked to deliver it.
+                    Delivery::NotConnected => {
+                        tracing::debug!("User left before the message from the cluster arrived");
+                        if let Some(origin) = origin {
+                            let not_connected = ClusterMessage::NotConnected { message };
+                            self.publish(node_subject(&origin), &not_connected).await;
+                        }
+                    }
+                }
+            }
+            ClusterMessage::Accepted { message } => self.store(message),
+            ClusterMessage::NotConnected { message } => self.store_for_offline(message),
+            ClusterMessage::Notify { recipient, notice } => {
+                self.notify_locally(&recipient, notice).await;
+            }
+            ClusterMessage::Announcement { text, .. } => self.announce_locally(text).await,
+        }
+    }
 }
 
-async fn run_actor(mut actor: DeliveryService) {
-    while let Some(message) = actor.receiver.recv().await {
+impl Actor for DeliveryService {
+    type Message = Message;
+    const NAME: &'static str = "delivery_service";
+
+    async fn started(&mut self, context: &mut Context<Self>) {
+        let Some(subscriptions) = self.subscriptions.take() else {
+            return;
+        };
+
+        if let Some((directory, node)) = subscriptions.cluster {
+            let messages = stream::select(directory.into_stream(), node.into_stream());
+            context.forward(messages.map(Message::Cluster));
+        }
+
+        if self.shard == 0 {
+            let reconnections = self.handle.node.bus.reconnections();
+            context.forward(reconnections.map(|()| Message::Reconnected));
+        }
+    }
+
+    async fn handle(&mut self, message: Message, context: &mut Context<Self>) {
         tracing::debug!("Processing message");
         match message {
             Message::SendMessage(message) => {
-                let receiver = actor.users_by_name.get(&message.recipient);
-                let Some(receiver) = receiver else {
-                    //TODO send error to user if receiver is not found
-                    tracing::error!("User not found");
-                    continue;
+                let remote_nodes = self.nodes_by_remote_user.get(&message.recipient);
+                let delivery = self.deliver_locally(message.clone()).await;
+                match delivery {
+                    // Not sent to the other devices of the recipient either
+                    Delivery::Blocked => {
+                        self.handle.reject(message);
+                        return;
+                    }
+                    Delivery::NotConnected if remote_nodes.is_none() => {
+                        self.store_for_offline(message);
+                        return;
+                    }
+                    Delivery::Delivered => self.store(message.clone()),
+                    // Stored once a node of the recipient accepted it, as only that node knows
+                    // whether the recipient blocked the sender
+                    Delivery::NotConnected => {}
+                }
+                let Some(remote_nodes) = remote_nodes else {
+                    return;
                 };
 
-                let result = receiver.receive_message(message).await;
+                let mut origin =
+                    (delivery == Delivery::NotConnected).then(|| self.handle.node.id.clone());
+                for node in remote_nodes {
+                    let deliver = ClusterMessage::Deliver {
+                        message: message.clone(),
+                        origin: origin.take(),
+                    };
+                    self.publish(node_subject(node), &deliver).await;
+                    metrics::counter!(telemetry::MESSAGES_ROUTED).increment(1);
+                }
+            }
+            Message::Cluster(payload) => self.process_cluster_message(payload).await,
+            Message::Reconnected => {
+                tracing::info!("Announcing the users of this node again after reconnecting");
+                // Other nodes forget the users of this node and announce their users again, like
+                // after a restart
+                let node = self.handle.node.id.clone();
+                let started = ClusterMessage::NodeStarted { node };
+                self.publish(DIRECTORY_SUBJECT.to_owned(), &started).await;
+
+                for shard in self.handle.shards.iter() {
+                    if let Err(error) = shard.notify(Message::Resynchronize) {
+                        tracing::error!("Error resynchronizing shard: {}", error);
+                        telemetry::record_actor_error(Self::NAME, "resynchronize");
+                    }
+                }
+            }
+            Message::Resynchronize => {
+                let names: Vec<_> = self
+                    .nodes_by_remote_user
+                    .drain()
+                    .map(|(name, _)| name)
+                    .collect();
+                for name in names {
+                    if !self.is_known(&name) {
+                        self.change_presence(name, false).await;
+                    }
+                }
+
+                for name in self.users_by_name.keys() {
+                    self.announce_user(name.clone()).await;
+                }
+            }
+            Message::Notify(recipient, notice) => {
+                self.notify_locally(&recipient, notice.clone()).await;
+                let Some(remote_nodes) = self.nodes_by_remote_user.get(&recipient) else {
+                    return;
+                };
+
+                let notify = ClusterMessage::Notify { recipient, notice };
+                for node in remote_nodes {
+                    self.publish(node_subject(node), &notify).await;
+                }
+            }
+            Message::ContactPresence {
+                user,
+                contact,
+                is_available,
+            } => {
+                let Some(user) = self.users_by_name.get(&user) else {
+                    return;
+                };
+
+                let result = if is_available {
+                    user.add_contact(contact).await
+                } else {
+                    user.remove_contact(contact).await
+                };
                 if let Err(error) = result {
-                    tracing::error!("Error sending message to user: {}", error);
+                    tracing::error!("Error sending contact presence to user: {}", error);
+                    telemetry::record_actor_error(Self::NAME, "presence");
+                }
+            }
+            #[cfg(feature = "grpc")]
+            Message::GetUser(user_name, respond) => {
+                let user = self.users_by_name.get(user_name.as_ref()).cloned();
+                if respond.send(user).is_err() {
+                    tracing::error!("Error sending user back");
+                    telemetry::record_actor_error(Self::NAME, "respond");
                 }
             }
             Message::GetOrInsertUser(user_name, respond) => {
-                let entry = actor.users_by_name.get(user_name.as_ref());
+                let entry = self.users_by_name.get(user_name.as_ref());
 
                 let user = match entry.cloned() {
                     Some(user) => user,
                     None => {
-                        let user = user::Handle::new(user_name.clone(), actor.get_handle());
-                        actor.users_by_name.insert(user_name.clone(), user.clone());
-                        // Add new contact
-                        actor.add_available_contact(user_name.clone()).await;
+                        let is_known = self.is_known(&user_name);
+                        let user = user::Handle::new(
+                            user_name.clone(),
+                            self.get_handle(),
+                            self.handle.storage.clone(),
+                        );
+                        self.users_by_name.insert(user_name.clone(), user.clone());
+                        metrics::gauge!(telemetry::USER_ACTORS).increment(1);
+                        self.load_filters(user_name.clone()).await;
+                        if !is_known {
+                            self.change_presence(user_name.clone(), true).await;
+                        }
+                        self.announce_user(user_name.clone()).await;
 
                         user
                     }
@@ -78,19 +649,86 @@ async fn run_actor(mut actor: DeliveryService) {
 
                 let result = respond.send(user.clone());
 
-                if result.is_ok() {
+                if result.is_err() {
                     tracing::error!("Error sending user handle back");
+                    telemetry::record_actor_error(Self::NAME, "respond");
                 }
             }
-            Message::GetUsers(responder) => {
-                let users = actor.users_by_name.keys().cloned().collect();
-                let result = responder.send(users);
+            Message::FilterAvailable(names, respond) => {
+                let available = names.into_iter().filter(|name| self.is_known(name));
+                let result = respond.send(available.collect());
                 if result.is_err() {
-                    tracing::error!("Error sending users back");
+                    tracing::error!("Error sending available users back");
+                    telemetry::record_actor_error(Self::NAME, "respond");
                 }
             }
             Message::RemoveUser(name) => {
-                let _ = actor.users_by_name.remove(&name);
+                if self.users_by_name.remove(&name).is_some() {
+                    metrics::gauge!(telemetry::USER_ACTORS).decrement(1);
+                }
+                self.filters_by_user.remove(&name);
+                let node = self.handle.node.id.clone();
+                let message = ClusterMessage::UserLeft {
+                    name: name.clone(),
+                    node,
+                };
+                self.publish(DIRECTORY_SUBJECT.to_owned(), &message).await;
+            }
+            Message::UserOffline(name) => {
+                // The user might still be connected to another node or have reconnected already
+                if !self.is_known(&name) {
+                    self.change_presence(name, false).await;
+                }
+            }
+            Message::ListUsers(respond) => {
+                let users = self
+                    .users_by_name
+                    .iter()
+                    .map(|(name, user)| (name.clone(), user.clone()));
+                if respond.send(users.collect()).is_err() {
+                    tracing::error!("Error sending users back");
+                    telemetry::record_actor_error(Self::NAME, "respond");
+                }
+            }
+            Message::Announce(text) => self.announce_locally(text).await,
+            Message::RegisterUser(name, user) => {
+                if let Entry::Vacant(entry) = self.users_by_name.entry(name.clone()) {
+                    entry.insert(user);
+                    metrics::gauge!(telemetry::USER_ACTORS).increment(1);
+                    self.load_filters(name.clone()).await;
+                }
+                self.announce_user(name).await;
+            }
+            Message::Probe(respond) => {
+                let _ = respond.send(());
+            }
+            Message::Shutdown {
+                reconnect_after,
+                respond,
+            } => {
+                tracing::info!("Shutting down {} users", self.users_by_name.len());
+                // Messages queued before the shutdown have already been routed at this point as the
+                // mailbox is processed in order. The users do the same for their sockets.
+                let mut drained = Vec::with_capacity(self.users_by_name.len());
+                for user in self.users_by_name.values() {
+                    match user.shutdown(reconnect_after).await {
+                        Ok(receiver) => drained.push(receiver),
+                        Err(error) => {
+                            tracing::error!("Error shutting down user: {}", error);
+                            telemetry::record_actor_error(Self::NAME, "shutdown");
+                        }
+                    }
+                }
+
+                // Waiting on the users in a separate task would not be any faster as the actor stops
+                // anyway
+                for receiver in drained {
+                    // An error means the user stopped without responding, which is fine for shutdown
+                    let _ = receiver.await;
+                }
+
+                let _ = respond.send(());
+                context.stop();
             }
         }
     }
@@ -98,71 +736,569 @@ async fn run_actor(mut actor: DeliveryService) {
 
 #[derive(Clone)]
 pub(crate) struct Handle {
-    sender: mpsc::Sender<Message>,
+    shards: Arc<[Addr<DeliveryService>]>,
+    node: cluster::Node,
+    /// How long to wait for the actor to answer requests
+    request_timeout: Duration,
+    /// Notifies user actors that the delivery service was restarted and lost its registry
+    restarts: broadcast::Sender<()>,
+    storage: Storage,
+    /// Notifies recipients that are not connected anywhere
+    pusher: Option<Pusher>,
 }
 
-impl Default for Handle {
-    fn default() -> Self {
-        Self::new()
-    }
-}
+impl Handle {
+    /// Starts the delivery service shards under supervision. The returned task resolves with an
+    /// error if the delivery service keeps crashing and was not restarted again.
+    pub(crate) fn new(
+        request_timeout: Duration,
+        restart_policy: RestartPolicy,
+        shards: NonZeroUsize,
+        node: cluster::Node,
+        storage: Storage,
+        pusher: Option<Pusher>,
+    ) -> (Self, JoinHandle<Result<(), EscalationError>>) {
+        let (addresses, mailboxes): (Vec<_>, Vec<_>) = (0..shards.get())
+            .map(|_| {
+                let (address, mailbox) = framework::mailbox();
+                // The mailbox outlives the shard so that it can be passed on to the restarted
+                // shard after a crash and existing handles stay valid
+                (address, Arc::new(Mutex::new(mailbox)))
+            })
+            .unzip();
+        let (restarts, _) = broadcast::channel(1);
+        let handle = Self {
+            shards: addresses.into(),
+            node,
+            request_timeout,
+            restarts,
+            storage,
+            pusher,
+        };
 
-#[derive(Debug, thiserror::Error)]
-pub(crate) enum HandleError {
-    #[error("Error sending message to actor")]
-    SendError(#[from] mpsc::error::SendError<Message>),
-    #[error("Error receiving answer with user from actor")]
-    ReceiveError(#[from] oneshot::error::RecvError),
-}
+        let mut is_restart = false;
+        let start_handle = handle.clone();
+        let supervisor = supervisor::supervise(DeliveryService::NAME, restart_policy, move || {
+            let mailboxes = mailboxes.clone();
+            let handle = start_handle.clone();
+            let is_restart = std::mem::replace(&mut is_restart, true);
+            async move {
+                // The locks are released when the previous shards are dropped after a crash
+                let mut locked = Vec::with_capacity(mailboxes.len());
+                for mailbox in mailboxes {
+                    locked.push(mailbox.lock_owned().await);
+                }
 
-impl Handle {
-    pub(crate) fn new() -> Self {
-        let (sender, receiver) = mpsc::channel(8);
+                let mut subscriptions = Vec::with_capacity(locked.len());
+                for _ in 0..locked.len() {
+                    subscriptions.push(Subscriptions::subscribe(&handle).await);
+                }
 
-        let delivery_service = DeliveryService {
-            receiver,
-            users_by_name: HashMap::new(),
-            sender: sender.clone(),
-        };
+                // Other nodes forget the users of the previous run of this node and announce their
+                // users again
+                let node = handle.node.id.clone();
+                let started = ClusterMessage::NodeStarted { node };
+                publish(&handle.node, DIRECTORY_SUBJECT.to_owned(), &started).await;
 
-        tokio::spawn(run_actor(delivery_service));
+                if is_restart {
+                    // Users re-register through the mailboxes, which are only processed after the
+                    // shards started
+                    let _ = handle.restarts.send(());
+                }
+                metrics::gauge!(telemetry::USER_ACTORS).set(0.0);
 
-        Self { sender }
+                let mut shards = JoinSet::new();
+                for (shard, (mut mailbox, subscriptions)) in
+                    locked.into_iter().zip(subscriptions).enumerate()
+                {
+                    let delivery_service = DeliveryService {
+                        handle: handle.clone(),
+                        shard,
+                        subscriptions: Some(subscriptions),
+                        users_by_name: HashMap::new(),
+                        filters_by_user: HashMap::new(),
+                        nodes_by_remote_user: HashMap::new(),
+                    };
+                    shards.spawn(async move {
+                        framework::run(delivery_service, &mut mailbox).await;
+                    });
+                }
+
+                // A crashing shard takes the others down with it, as the cluster and the users are
+                // told that the whole delivery service restarted. The remaining shards are aborted
+                // when the set is dropped.
+                while let Some(result) = shards.join_next().await {
+                    if let Err(error) = result {
+                        if error.is_panic() {
+                            std::panic::resume_unwind(error.into_panic());
+                        }
+                    }
+                }
+            }
+        });
+
+        (handle, supervisor)
+    }
+
+    /// Tells all devices of the sender that the message did not arrive
+    fn reject(&self, message: Arc<ChatMessage>) {
+        let sender: Arc<str> = message.sender.as_str().into();
+        // Not waiting for space in the other shard's mailbox, as it might be waiting on this one's
+        let result = self.shard(&sender).notify(Message::Notify(
+            sender.clone(),
+            Notice::DeliveryFailed { message },
+        ));
+        if let Err(error) = result {
+            tracing::error!("Error sending delivery failure to shard: {}", error);
+            telemetry::record_actor_error(DeliveryService::NAME, "reject");
+        }
+    }
+
+    fn shard(&self, name: &str) -> &Addr<DeliveryService> {
+        &self.shards[shard_of(name, self.shards.len())]
+    }
+
+    pub(super) fn subscribe_to_restarts(&self) -> broadcast::Receiver<()> {
+        self.restarts.subscribe()
+    }
+
+    pub(super) async fn register_user(
+        &self,
+        name: Arc<str>,
+        user: user::Handle,
+    ) -> Result<(), HandleError> {
+        self.shard(&name)
+            .tell(Message::RegisterUser(name, user))
+            .await
     }
 
     pub(crate) async fn get_or_insert(
         &self,
         user_name: Arc<str>,
     ) -> Result<user::Handle, HandleError> {
-        let (sender, receiver) = oneshot::channel();
+        self.shard(&user_name)
+            .ask(
+                |respond| Message::GetOrInsertUser(user_name, respond),
+                self.request_timeout,
+            )
+            .await
+    }
 
-        self.sender
-            .send(Message::GetOrInsertUser(user_name, sender))
-            .await?;
+    /// The user if it is connected to this node, without creating it otherwise
+    #[cfg(feature = "grpc")]
+    pub(crate) async fn get(
+        &self,
+        user_name: Arc<str>,
+    ) -> Result<Option<user::Handle>, HandleError> {
+        self.shard(&user_name)
+            .ask(
+                |respond| Message::GetUser(user_name, respond),
+                self.request_timeout,
+            )
+            .await
+    }
 
-        let handle = receiver.await?;
+    /// The users out of the given ones that are connected to any node
+    pub(crate) async fn filter_available(
+        &self,
+        names: Vec<Arc<str>>,
+    ) -> Result<HashSet<Arc<str>>, HandleError> {
+        let mut names_by_shard = vec![Vec::new(); self.shards.len()];
+        for name in names {
+            names_by_shard[shard_of(&name, self.shards.len())].push(name);
+        }
 
-        Ok(handle)
+        let shards = self
+            .shards
+            .iter()
+            .zip(names_by_shard)
+            .filter(|(_, names)| !names.is_empty())
+            .map(|(shard, names)| {
+                shard.ask(
+                    |respond| Message::FilterAvailable(names, respond),
+                    self.request_timeout,
+                )
+            });
+        let available = try_join_all(shards).await?;
+        Ok(available.into_iter().flatten().collect())
     }
 
-    pub(crate) async fn get_users(&self) -> Result<Arc<[Arc<str>]>, HandleError> {
-        let (sender, receiver) = oneshot::channel();
-        self.sender.send(Message::GetUsers(sender)).await?;
-        let users = receiver.await?;
-        Ok(users)
+    /// Passes the notice on to all devices of the user, wherever they are connected
+    pub(crate) async fn notify(&self, user: Arc<str>, notice: Notice) -> Result<(), HandleError> {
+        self.shard(&user).tell(Message::Notify(user, notice)).await
     }
 
-    pub(super) async fn send_message(&self, message: Arc<ChatMessage>) -> Result<(), impl Error> {
-        self.sender.send(Message::SendMessage(message)).await
+    /// Pushes the changed conversations to the devices of the users they belong to
+    pub(crate) async fn notify_conversations(&self, conversations: Vec<(Arc<str>, Conversation)>) {
+        for (user, conversation) in conversations {
+            let conversation = Arc::new(conversation);
+            let result = self
+                .notify(user, Notice::ConversationUpdated { conversation })
+                .await;
+            if let Err(error) = result {
+                tracing::error!("Error notifying about updated conversation: {}", error);
+                telemetry::record_actor_error(DeliveryService::NAME, "notify");
+            }
+        }
     }
 
-    pub(super) async fn remove_user(&self, name: Arc<str>) -> Result<(), impl Error> {
-        self.sender.send(Message::RemoveUser(name)).await
+    /// The users connected to this node with their sockets. Users that disconnect while they are
+    /// being asked are left out.
+    pub(crate) async fn connected_users(
+        &self,
+    ) -> Result<Vec<(Arc<str>, Vec<SocketInfo>)>, HandleError> {
+        let shards = self
+            .shards
+            .iter()
+            .map(|shard| shard.ask(Message::ListUsers, self.request_timeout));
+        let users = try_join_all(shards).await?;
+
+        let mut connected = Vec::new();
+        for (name, user) in users.into_iter().flatten() {
+            match user.sockets(self.request_timeout).await {
+                Ok(sockets) => connected.push((name, sockets)),
+                Err(error @ HandleError::Timeout(_)) => return Err(error),
+                Err(error) => tracing::debug!("Skipping user that went away: {}", error),
+            }
+        }
+        Ok(connected)
+    }
+
+    /// Sends the announcement to all users on all nodes
+    pub(crate) async fn announce(&self, text: Arc<str>) -> Result<(), HandleError> {
+        let node = self.node.id.clone();
+        let announcement = ClusterMessage::Announcement {
+            node,
+            text: text.clone(),
+        };
+        publish(&self.node, DIRECTORY_SUBJECT.to_owned(), &announcement).await;
+
+        for shard in self.shards.iter() {
+            shard.tell(Message::Announce(text.clone())).await?;
+        }
+        Ok(())
+    }
+
+    /// Round-trips a message through every shard. This only resolves in time if none of them is
+    /// stuck.
+    pub(crate) async fn probe(&self, timeout: Duration) -> Result<(), HandleError> {
+        let shards = self
+            .shards
+            .iter()
+            .map(|shard| shard.ask(Message::Probe, timeout));
+        try_join_all(shards).await?;
+        Ok(())
+    }
+
+    /// Notifies all users and their sockets that the server is shutting down and waits until they
+    /// have been drained. The delivery service stops after that.
+    pub(crate) async fn shutdown(&self, reconnect_after: Duration) -> Result<(), HandleError> {
+        let node = self.node.id.clone();
+        let stopped = ClusterMessage::NodeStopped { node };
+        publish(&self.node, DIRECTORY_SUBJECT.to_owned(), &stopped).await;
+
+        // The shards drain their users at the same time
+        let mut drained = Vec::with_capacity(self.shards.len());
+        for shard in self.shards.iter() {
+            let (sender, receiver) = oneshot::channel();
+            shard
+                .tell(Message::Shutdown {
+                    reconnect_after,
+                    respond: sender,
+                })
+                .await?;
+            drained.push(receiver);
+        }
+
+        for receiver in drained {
+            receiver.await?;
+        }
+        Ok(())
+    }
+
+    pub(super) async fn send_message(&self, message: Arc<ChatMessage>) -> Result<(), HandleError> {
+        self.shard(&message.recipient)
+            .tell(Message::SendMessage(message))
+            .await
+    }
+
+    pub(super) async fn remove_user(&self, name: Arc<str>) -> Result<(), HandleError> {
+        self.shard(&name).tell(Message::RemoveUser(name)).await
+    }
+
+    pub(super) async fn user_offline(&self, name: Arc<str>) -> Result<(), HandleError> {
+        self.shard(&name).tell(Message::UserOffline(name)).await
     }
 }
 
-impl From<mpsc::Sender<Message>> for Handle {
-    fn from(sender: mpsc::Sender<Message>) -> Self {
-        Self { sender }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cluster::memory::MemoryBus;
+    use crate::config::Config;
+    use crate::push::{HttpPushService, Vapid};
+    use crate::storage::push::PushSubscription;
+    use crate::storage::tests::{message, storage};
+    use axum::http::header::{AUTHORIZATION, CONTENT_ENCODING};
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::post;
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    // The keys of the example in RFC 8291
+    const SERVER_PRIVATE_KEY: &str = "yfWPiYE-n46HLnH0KqZOF1fJJU3MYrct3AELtAQ-oRw";
+    const SERVER_PUBLIC_KEY: &str =
+        "BP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK6PBru3jl7A8";
+    const BROWSER_PUBLIC_KEY: &str =
+        "BCVxsr7N_eNgVRqvHtD0zTZsEc6-VV-JvLexhqUzORcxaOzi6-AYWXvTBHm4bjyPjs7Vd8pZGH6SRpkNtoIAiw4";
+    const AUTH_SECRET: &str = "BTBZMqHH6r4Tts7J_aSIgg";
+
+    /// A node of a cluster on the bus with its own database
+    fn node(id: &str, bus: &MemoryBus, storage: Storage, pusher: Option<Pusher>) -> Handle {
+        let node = cluster::Node {
+            id: id.into(),
+            bus: Arc::new(bus.clone()),
+        };
+        let config = Config::default();
+        let shards = NonZeroUsize::new(2).unwrap();
+        let (handle, _) = Handle::new(
+            config.request_timeout,
+            config.restart_policy,
+            shards,
+            node,
+            storage,
+            pusher,
+        );
+        handle
+    }
+
+    /// Waits until the users are in the cluster directory of the node
+    async fn wait_until_known(handle: &Handle, name: &str) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let available = handle.filter_available(vec![name.into()]).await.unwrap();
+                if available.contains(name) {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("user should be announced in time");
+    }
+
+    async fn wait_until_stored(storage: &Storage, user: &str, peer: &str) -> String {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(text) = last_message_from(storage, user, peer).await {
+                    return text;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("message should be stored in time")
+    }
+
+    /// A push service on localhost that passes on what is posted to it
+    async fn mock_push_service() -> (String, mpsc::UnboundedReceiver<(HeaderMap, Bytes)>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let push = move |headers: HeaderMap, body: Bytes| {
+            let _ = sender.send((headers, body));
+            async { StatusCode::CREATED }
+        };
+        let app = axum::Router::new().route("/push/{id}", post(push));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (format!("http://{address}/push/bob"), receiver)
+    }
+
+    async fn last_message_from(storage: &Storage, user: &str, peer: &str) -> Option<String> {
+        let conversations = storage.conversations(user.into()).await.unwrap();
+        conversations
+            .into_iter()
+            .find(|conversation| &*conversation.name == peer)
+            .and_then(|conversation| conversation.last_message)
+            .map(|message| message.text)
+    }
+
+    #[test]
+    fn users_are_spread_over_the_shards() {
+        let names: Vec<_> = (0..100).map(|user| format!("user-{user}")).collect();
+        let shards: HashSet<_> = names.iter().map(|name| shard_of(name, 4)).collect();
+        assert_eq!(shards, HashSet::from([0, 1, 2, 3]));
+        for name in &names {
+            assert_eq!(shard_of(name, 4), shard_of(name, 4));
+        }
+    }
+
+    #[tokio::test]
+    async fn each_user_has_one_actor_however_often_it_is_asked_for() {
+        let config = Config::default();
+        let (handle, _) = Handle::new(
+            config.request_timeout,
+            config.restart_policy,
+            NonZeroUsize::new(4).unwrap(),
+            cluster::Node {
+                id: "a".into(),
+                bus: Arc::new(MemoryBus::default()),
+            },
+            storage(),
+            None,
+        );
+
+        let mut users = Vec::new();
+        for name in ["alice", "bob", "carol", "alice", "bob", "carol"] {
+            // Through clones of the handle like the transports use
+            let handle = handle.clone();
+            users.push(handle.get_or_insert(name.into()).await.unwrap());
+        }
+
+        let mut connected: Vec<_> = handle
+            .connected_users()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(name, _)| name.to_string())
+            .collect();
+        connected.sort();
+        assert_eq!(connected, ["alice", "bob", "carol"]);
+    }
+
+    #[test]
+    fn routing_updates_the_metrics() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let metrics = recorder.handle();
+        // The recorder is only installed for this thread, which runs all actors of the test
+        let rendered = metrics::with_local_recorder(&recorder, || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async {
+                let storage = storage();
+                let handle = node("a", &MemoryBus::default(), storage.clone(), None);
+                let _alice = handle.get_or_insert("alice".into()).await.unwrap();
+                let _bob = handle.get_or_insert("bob".into()).await.unwrap();
+
+                let hi = message("alice", "bob", "Hi Bob");
+                handle.send_message(hi).await.unwrap();
+                wait_until_stored(&storage, "bob", "alice").await;
+                metrics.render()
+            })
+        });
+
+        assert!(rendered.contains("user_actors 2\n"), "{rendered}");
+        assert!(rendered.contains("messages_routed_total 1\n"), "{rendered}");
+        assert!(
+            rendered.contains("actors{actor=\"user\"} 2\n"),
+            "{rendered}"
+        );
+        assert!(
+            rendered.contains("actors{actor=\"delivery_service\"} 2\n"),
+            "{rendered}"
+        );
+    }
+
+    #[tokio::test]
+    async fn messages_blocked_on_another_node_are_not_stored() {
+        let bus = MemoryBus::default();
+        let (sender_storage, recipient_storage) = (storage(), storage());
+        recipient_storage
+            .block("bob".into(), "alice".into())
+            .await
+            .unwrap();
+        let sender_node = node("a", &bus, sender_storage.clone(), None);
+        let recipient_node = node("b", &bus, recipient_storage, None);
+
+        let _bob = recipient_node.get_or_insert("bob".into()).await.unwrap();
+        wait_until_known(&sender_node, "bob").await;
+
+        let blocked = message("alice", "bob", "blocked");
+        sender_node.send_message(blocked).await.unwrap();
+        let accepted = message("carol", "bob", "accepted");
+        sender_node.send_message(accepted).await.unwrap();
+
+        // Bob's node answers in order, so the blocked message was rejected by the time the
+        // accepted one is stored
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while last_message_from(&sender_storage, "bob", "carol")
+                .await
+                .is_none()
+            {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("accepted message should be stored in time");
+        assert_eq!(
+            last_message_from(&sender_storage, "bob", "alice").await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn messages_to_offline_users_are_stored_and_then_pushed() {
+        let storage = storage();
+        let (endpoint, mut pushed) = mock_push_service().await;
+        let subscription = PushSubscription {
+            endpoint,
+            p256dh: BROWSER_PUBLIC_KEY.to_owned(),
+            auth: AUTH_SECRET.to_owned(),
+        };
+        storage
+            .add_push_subscription("bob".into(), subscription)
+            .await
+            .unwrap();
+        let vapid = Vapid::new(SERVER_PRIVATE_KEY, SERVER_PUBLIC_KEY, None).unwrap();
+        // The mock push service is on localhost over plain HTTP
+        let service = HttpPushService::new(true).unwrap();
+        let pusher = Pusher::new(storage.clone(), vapid, service);
+        let handle = node("a", &MemoryBus::default(), storage.clone(), Some(pusher));
+
+        let hi = message("alice", "bob", "Hi Bob");
+        handle.send_message(hi).await.unwrap();
+
+        let (headers, body) = tokio::time::timeout(Duration::from_secs(5), pushed.recv())
+            .await
+            .expect("message should be pushed in time")
+            .unwrap();
+        assert_eq!(headers[CONTENT_ENCODING], "aes128gcm");
+        let authorization = headers[AUTHORIZATION].to_str().unwrap();
+        assert!(authorization.starts_with("vapid t="));
+        assert!(authorization.ends_with(&format!("k={SERVER_PUBLIC_KEY}")));
+        assert!(!body.is_empty());
+        // Stored before it was pushed
+        assert_eq!(
+            last_message_from(&storage, "bob", "alice").await.as_deref(),
+            Some("Hi Bob")
+        );
+    }
+
+    #[tokio::test]
+    async fn messages_for_users_who_just_left_another_node_are_stored() {
+        let bus = MemoryBus::default();
+        let (sender_storage, recipient_storage) = (storage(), storage());
+        let sender_node = node("a", &bus, sender_storage.clone(), None);
+        let recipient_node = node("b", &bus, recipient_storage, None);
+        // Both nodes listen to the cluster once they know about each other's users
+        let _carol = recipient_node.get_or_insert("carol".into()).await.unwrap();
+        wait_until_known(&sender_node, "carol").await;
+
+        // The directory of the sender's node still lists Bob, who already left node b
+        let joined = ClusterMessage::UserJoined {
+            name: "bob".into(),
+            node: "b".into(),
+        };
+        publish(&sender_node.node, DIRECTORY_SUBJECT.to_owned(), &joined).await;
+        wait_until_known(&sender_node, "bob").await;
+
+        let hi = message("alice", "bob", "Hi Bob");
+        sender_node.send_message(hi).await.unwrap();
+
+        let text = wait_until_stored(&sender_storage, "bob", "alice").await;
+        assert_eq!(text, "Hi Bob");
     }
 }
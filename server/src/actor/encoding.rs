@@ -0,0 +1,124 @@
+use axum::extract::ws::Message as WebSocketMessage;
+use axum::http::HeaderValue;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use thiserror::Error;
+
+/// How messages are encoded on a websocket. Clients choose one through the websocket subprotocol,
+/// all of them go through the same serde derives.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum Encoding {
+    /// Text frames, for clients that don't ask for anything else
+    #[default]
+    Json,
+    /// Binary frames with structs as maps, so that fields are named like in JSON
+    MessagePack,
+    /// Binary frames
+    Cbor,
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum EncodingError {
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("MessagePack encoding error: {0}")]
+    MessagePackEncode(#[from] rmp_serde::encode::Error),
+    #[error("MessagePack decoding error: {0}")]
+    MessagePackDecode(#[from] rmp_serde::decode::Error),
+    #[error("CBOR encoding error: {0}")]
+    CborEncode(#[from] ciborium::ser::Error<std::io::Error>),
+    #[error("CBOR decoding error: {0}")]
+    CborDecode(#[from] ciborium::de::Error<std::io::Error>),
+}
+
+impl Encoding {
+    /// The websocket subprotocols of the encodings. The first one the client offers is picked, so
+    /// they are ordered from the most to the least compact.
+    pub(crate) const PROTOCOLS: [&'static str; 3] = ["melt.msgpack", "melt.cbor", "melt.json"];
+
+    /// The subprotocols of the encodings with compressed messages, see
+    /// [`Deflate`](super::compression::Deflate)
+    pub(crate) const COMPRESSED_PROTOCOLS: [&'static str; 3] = [
+        "melt.msgpack+deflate",
+        "melt.cbor+deflate",
+        "melt.json+deflate",
+    ];
+
+    /// The encoding for the subprotocol that was agreed on with the client, if any, and whether
+    /// messages are compressed
+    pub(crate) fn from_protocol(protocol: Option<&HeaderValue>) -> (Self, bool) {
+        let protocol = protocol.map(HeaderValue::as_bytes).unwrap_or_default();
+        let (protocol, is_compressed) = match protocol.strip_suffix(b"+deflate") {
+            Some(protocol) => (protocol, true),
+            None => (protocol, false),
+        };
+
+        let encoding = match protocol {
+            b"melt.msgpack" => Self::MessagePack,
+            b"melt.cbor" => Self::Cbor,
+            _ => Self::Json,
+        };
+        (encoding, is_compressed)
+    }
+
+    pub(super) fn encode<T: Serialize>(self, value: &T) -> Result<WebSocketMessage, EncodingError> {
+        let message = match self {
+            Self::Json => WebSocketMessage::Text(serde_json::to_string(value)?.into()),
+            Self::MessagePack => WebSocketMessage::Binary(rmp_serde::to_vec_named(value)?.into()),
+            Self::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(value, &mut bytes)?;
+                WebSocketMessage::Binary(bytes.into())
+            }
+        };
+        Ok(message)
+    }
+
+    pub(super) fn decode<T: DeserializeOwned>(self, frame: &[u8]) -> Result<T, EncodingError> {
+        let value = match self {
+            Self::Json => serde_json::from_slice(frame)?,
+            Self::MessagePack => rmp_serde::from_slice(frame)?,
+            Self::Cbor => ciborium::from_reader(frame)?,
+        };
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actor::ChatMessage;
+    use time::OffsetDateTime;
+
+    #[test]
+    fn binary_encodings_decode_what_they_encode() {
+        let time_utc = OffsetDateTime::from_unix_timestamp(1_718_000_000).unwrap();
+        let message = ChatMessage {
+            recipient: "bob".into(),
+            sender: "alice".to_owned(),
+            text: "Sounds good, I'll bring snacks 🍿".to_owned(),
+            time_utc,
+        };
+
+        for encoding in [Encoding::MessagePack, Encoding::Cbor] {
+            let WebSocketMessage::Binary(frame) = encoding.encode(&message).unwrap() else {
+                panic!("{encoding:?} should be encoded as a binary frame");
+            };
+            let decoded: ChatMessage = encoding.decode(&frame).unwrap();
+            assert_eq!(&*decoded.recipient, "bob");
+            assert_eq!(decoded.sender, "alice");
+            assert_eq!(decoded.text, message.text);
+            assert_eq!(decoded.time_utc, time_utc);
+        }
+    }
+
+    #[test]
+    fn picks_the_encoding_of_the_protocol() {
+        let protocol =
+            |protocol| Encoding::from_protocol(Some(&HeaderValue::from_static(protocol)));
+        assert_eq!(protocol("melt.msgpack"), (Encoding::MessagePack, false));
+        assert_eq!(protocol("melt.cbor+deflate"), (Encoding::Cbor, true));
+        assert_eq!(protocol("melt.json"), (Encoding::Json, false));
+        assert_eq!(Encoding::from_protocol(None), (Encoding::Json, false));
+    }
+}
@@ -0,0 +1,251 @@
+use axum::response::sse::{Event, KeepAlive, Sse};
+use futures_util::{future, stream, Stream, StreamExt};
+use nanoid::nanoid;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+use super::audit_log;
+use super::framework::{self, Actor, Addr, Context};
+use super::socket::{
+    Client, ClientMessage, CloseReason, Connection, Frame, SocketId, SocketInfo, TooSlow,
+    SEND_QUEUE_CAPACITY, SEND_TIMEOUT,
+};
+use super::{user, HandleError};
+use crate::telemetry;
+
+/// Events waiting to be written to the response before sending to the stream waits
+const EVENT_BUFFER: usize = 8;
+
+enum Message {
+    Send(Arc<Frame>),
+    Disconnect(CloseReason),
+    Shutdown {
+        reconnect_after: Duration,
+        respond: oneshot::Sender<()>,
+    },
+    /// A chat message or command the client posted for this stream
+    Received(String),
+    /// The response ended, which is the only sign that the client went away
+    Disconnected,
+}
+
+/// The event streams on this instance by socket id, to find the stream that posted messages
+/// belong to
+#[derive(Clone, Default)]
+pub(crate) struct EventStreams(Arc<Mutex<HashMap<Arc<str>, Handle>>>);
+
+impl EventStreams {
+    pub(crate) fn get(&self, id: &str) -> Option<Handle> {
+        self.0.lock().unwrap().get(id).cloned()
+    }
+
+    fn insert(&self, handle: Handle) {
+        self.0.lock().unwrap().insert(handle.id.0.clone(), handle);
+    }
+
+    fn remove(&self, id: &SocketId) {
+        self.0.lock().unwrap().remove(&id.0);
+    }
+}
+
+/// An event stream actor represents a server-sent events response to a users device. It is the
+/// same to the user actor as a websocket, but the client sends through separate POST requests.
+struct EventStream {
+    client: Client,
+    /// Dropped to end the response
+    sender: Option<mpsc::Sender<Event>>,
+    streams: EventStreams,
+}
+
+impl EventStream {
+    async fn send_to_stream(&mut self, frame: &Frame) -> Result<(), TooSlow> {
+        let Some(sender) = &self.sender else {
+            return Ok(());
+        };
+
+        let event = match frame.json() {
+            Ok(json) => Event::default().data(json.as_str()),
+            Err(error) => {
+                tracing::error!("Error serializing message: {:?}", error);
+                telemetry::record_actor_error(Self::NAME, "serialize");
+                return Ok(());
+            }
+        };
+
+        let result = tokio::time::timeout(SEND_TIMEOUT, sender.send(event))
+            .await
+            .map_err(|_| TooSlow)?;
+        if result.is_err() {
+            tracing::error!("Error sending message through event stream");
+            telemetry::record_actor_error(Self::NAME, "send");
+        }
+        Ok(())
+    }
+
+    /// Ends the response and stops the actor
+    async fn close(&mut self, context: &mut Context<Self>) {
+        self.sender = None;
+        self.client.remove_from_user(Self::NAME).await;
+        context.stop();
+    }
+}
+
+impl Actor for EventStream {
+    type Message = Message;
+    const NAME: &'static str = "event_stream";
+
+    async fn started(&mut self, _context: &mut Context<Self>) {
+        metrics::gauge!(telemetry::EVENT_STREAMS).increment(1);
+    }
+
+    async fn handle(&mut self, message: Message, context: &mut Context<Self>) {
+        match message {
+            Message::Send(frame) => {
+                if self.send_to_stream(&frame).await.is_err() {
+                    tracing::info!("Disconnecting event stream that is too slow");
+                    self.close(context).await;
+                }
+            }
+            Message::Disconnect(CloseReason::Operator) => {
+                tracing::info!("Disconnecting event stream on behalf of an operator");
+                self.close(context).await;
+            }
+            Message::Disconnect(CloseReason::TooSlow) => {
+                tracing::info!("Disconnecting event stream that is too slow");
+                self.close(context).await;
+            }
+            Message::Shutdown {
+                reconnect_after,
+                respond,
+            } => {
+                let message = ClientMessage::ServerShutdown {
+                    reconnect_after: reconnect_after.as_millis() as u64,
+                };
+                let _ = self.send_to_stream(&message.into()).await;
+                self.close(context).await;
+                let _ = respond.send(());
+            }
+            Message::Received(json) => {
+                if let Some(reply) = self.client.process(Self::NAME, &json).await {
+                    // A client that is too slow is disconnected with the next frame sent to it
+                    let _ = self.send_to_stream(&reply.into()).await;
+                }
+            }
+            Message::Disconnected => {
+                tracing::info!("Event stream disconnected");
+                self.close(context).await;
+            }
+        }
+    }
+
+    async fn stopped(&mut self) {
+        self.streams.remove(&self.client.id);
+        metrics::gauge!(telemetry::EVENT_STREAMS).decrement(1);
+    }
+}
+
+/// Notifies the actor once the response is dropped, whether it ended or the client went away
+struct Connected(Addr<EventStream>);
+
+impl Drop for Connected {
+    fn drop(&mut self) {
+        let _ = self.0.notify(Message::Disconnected);
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Handle {
+    id: SocketId,
+    remote: IpAddr,
+    address: Addr<EventStream>,
+}
+
+impl Handle {
+    /// Returns the response to stream the events through. Its first event is named `socket` and
+    /// carries the id to post messages with.
+    pub(crate) fn new(
+        user: user::Handle,
+        audit_log: audit_log::Handle,
+        name: Arc<str>,
+        remote: IpAddr,
+        streams: EventStreams,
+    ) -> (Self, Sse<impl Stream<Item = Result<Event, Infallible>>>) {
+        let id = SocketId(nanoid!().into());
+
+        let (sender, receiver) = mpsc::channel(EVENT_BUFFER);
+        let event_stream = EventStream {
+            client: Client {
+                id: id.clone(),
+                user,
+                name,
+                remote,
+                audit_log,
+            },
+            sender: Some(sender),
+            streams: streams.clone(),
+        };
+
+        let address = framework::spawn_with_capacity(event_stream, SEND_QUEUE_CAPACITY);
+        let handle = Self {
+            id: id.clone(),
+            remote,
+            address: address.clone(),
+        };
+        streams.insert(handle.clone());
+
+        let socket = Event::default().event("socket").data(&*id.0);
+        let events = stream::unfold(
+            (receiver, Connected(address)),
+            |(mut receiver, connected)| async move {
+                let event = receiver.recv().await?;
+                Some((event, (receiver, connected)))
+            },
+        );
+        let events = stream::once(future::ready(socket)).chain(events).map(Ok);
+
+        // Keeps proxies from closing the response while nothing happens
+        let response = Sse::new(events).keep_alive(KeepAlive::default());
+        (handle, response)
+    }
+
+    /// Passes on what the client posted, which is processed the same way as a websocket frame
+    pub(crate) async fn receive(&self, json: String) -> Result<(), HandleError> {
+        self.address.tell(Message::Received(json)).await
+    }
+}
+
+impl Connection for Handle {
+    fn id(&self) -> &SocketId {
+        &self.id
+    }
+
+    fn info(&self) -> SocketInfo {
+        SocketInfo {
+            id: self.id.0.clone(),
+            transport: EventStream::NAME,
+            remote: self.remote,
+        }
+    }
+
+    fn send(&self, frame: Arc<Frame>) -> Result<(), HandleError> {
+        self.address.try_tell(Message::Send(frame))
+    }
+
+    fn close(&self, reason: CloseReason) -> Result<(), HandleError> {
+        self.address.notify(Message::Disconnect(reason))
+    }
+
+    fn shutdown(&self, reconnect_after: Duration) -> Result<oneshot::Receiver<()>, HandleError> {
+        let (sender, receiver) = oneshot::channel();
+        self.address
+            .notify(Message::Shutdown {
+                reconnect_after,
+                respond: sender,
+            })
+            .map(|_| receiver)
+    }
+}
@@ -0,0 +1,414 @@
+use super::HandleError;
+use crate::telemetry;
+use futures_util::{Stream, StreamExt};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot, OwnedSemaphorePermit, Semaphore, TryAcquireError};
+use tokio::task::AbortHandle;
+
+/// Small mailboxes make slow actors push back on the actors sending to them early
+pub(crate) const MAILBOX_CAPACITY: usize = 8;
+
+/// The pattern every actor in this app follows: a private message enum, a mailbox that is processed
+/// one message at a time and cloneable addresses to send messages to it.
+/// Each actor module wraps an [`Addr`] in a handle to offer a typed API to the rest of the app.
+pub(crate) trait Actor: Sized + Send + 'static {
+    type Message: Send + 'static;
+
+    /// Used to label logs and metrics
+    const NAME: &'static str;
+
+    /// Called before the first message is processed
+    fn started(&mut self, _context: &mut Context<Self>) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    fn handle(
+        &mut self,
+        message: Self::Message,
+        context: &mut Context<Self>,
+    ) -> impl Future<Output = ()> + Send;
+
+    /// Called after the actor processed its last message
+    fn stopped(&mut self) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+}
+
+/// The address of an actor to send messages to.
+/// The actor stops once all addresses to it are dropped and its mailbox is empty.
+pub(crate) struct Addr<A: Actor> {
+    sender: mpsc::UnboundedSender<Envelope<A::Message>>,
+    /// Limits the messages sent with [`Addr::tell`] that wait in the mailbox
+    capacity: Arc<Semaphore>,
+}
+
+// Derive would require the actor to be Clone
+impl<A: Actor> Clone for Addr<A> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+            capacity: self.capacity.clone(),
+        }
+    }
+}
+
+impl<A: Actor> Addr<A> {
+    /// Sends a message to the actor without waiting for it to be processed.
+    /// This waits if the mailbox is full.
+    pub(crate) async fn tell(&self, message: A::Message) -> Result<(), HandleError> {
+        let start = Instant::now();
+        let permit = self.capacity.clone().acquire_owned().await;
+        telemetry::record_send_duration(A::NAME, start.elapsed());
+        let permit = permit.map_err(|_| HandleError::SendError)?;
+        self.send(message, Some(permit))
+    }
+
+    /// Sends a message to the actor if there is space in the mailbox, and fails with
+    /// [`HandleError::Full`] otherwise
+    pub(crate) fn try_tell(&self, message: A::Message) -> Result<(), HandleError> {
+        let permit = match self.capacity.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(TryAcquireError::NoPermits) => return Err(HandleError::Full),
+            Err(TryAcquireError::Closed) => return Err(HandleError::SendError),
+        };
+        self.send(message, Some(permit))
+    }
+
+    /// Sends a message without waiting for space in the mailbox.
+    /// Messages flow from websockets through users to the delivery service and back, so if actors
+    /// waited for space in both directions, two of them could end up waiting on each other forever.
+    /// Everything sent back towards the users uses this instead of [`Addr::tell`]. The sockets at
+    /// the end of that chain use [`Addr::try_tell`] instead, so a slow client can't make its
+    /// mailbox grow without limit.
+    /// The message is still queued behind the ones sent earlier, whichever way they were sent.
+    pub(crate) fn notify(&self, message: A::Message) -> Result<(), HandleError> {
+        self.send(message, None)
+    }
+
+    fn send(
+        &self,
+        message: A::Message,
+        permit: Option<OwnedSemaphorePermit>,
+    ) -> Result<(), HandleError> {
+        let envelope = Envelope {
+            message,
+            _permit: permit,
+        };
+        Ok(self.sender.send(envelope)?)
+    }
+
+    /// Sends a message that carries a channel to respond on and waits for the answer.
+    /// The timeout covers waiting for space in the mailbox as well as for the answer, so callers
+    /// don't hang if the actor is busy or stuck.
+    pub(crate) async fn ask<R>(
+        &self,
+        create_message: impl FnOnce(oneshot::Sender<R>) -> A::Message,
+        timeout: Duration,
+    ) -> Result<R, HandleError> {
+        let (respond, receiver) = oneshot::channel();
+        let exchange = async {
+            self.tell(create_message(respond)).await?;
+            Ok(receiver.await?)
+        };
+
+        match tokio::time::timeout(timeout, exchange).await {
+            Ok(result) => result,
+            Err(_) => {
+                telemetry::record_request_timeout(A::NAME);
+                Err(HandleError::Timeout(timeout))
+            }
+        }
+    }
+}
+
+/// A message in the mailbox. Messages sent with [`Addr::tell`] hold on to their share of the
+/// mailbox capacity until the actor picks them up.
+struct Envelope<M> {
+    message: M,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+/// The receiving end of an actor's mailbox.
+/// It is separate from the actor so that it can outlive the actor, e.g. to restart it after a crash
+/// without invalidating the addresses.
+pub(crate) struct Mailbox<A: Actor> {
+    /// A single queue for all messages, so that they are processed in the order they were sent
+    receiver: mpsc::UnboundedReceiver<Envelope<A::Message>>,
+    capacity: Arc<Semaphore>,
+    /// Weak to not keep the actor alive through its own mailbox
+    address: WeakAddr<A>,
+}
+
+pub(crate) fn mailbox<A: Actor>() -> (Addr<A>, Mailbox<A>) {
+    mailbox_with_capacity(MAILBOX_CAPACITY)
+}
+
+fn mailbox_with_capacity<A: Actor>(capacity: usize) -> (Addr<A>, Mailbox<A>) {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let capacity = Arc::new(Semaphore::new(capacity));
+    let mailbox = Mailbox {
+        receiver,
+        capacity: capacity.clone(),
+        address: WeakAddr {
+            sender: sender.downgrade(),
+            capacity: capacity.clone(),
+        },
+    };
+    let address = Addr { sender, capacity };
+    (address, mailbox)
+}
+
+impl<A: Actor> Mailbox<A> {
+    async fn recv(&mut self) -> Option<A::Message> {
+        let envelope = self.receiver.recv().await?;
+        Some(envelope.message)
+    }
+
+    fn len(&self) -> usize {
+        self.receiver.len()
+    }
+}
+
+// Senders waiting for space would otherwise wait forever once nobody processes the mailbox anymore
+impl<A: Actor> Drop for Mailbox<A> {
+    fn drop(&mut self) {
+        self.capacity.close();
+    }
+}
+
+struct WeakAddr<A: Actor> {
+    sender: mpsc::WeakUnboundedSender<Envelope<A::Message>>,
+    capacity: Arc<Semaphore>,
+}
+
+// Derive would require the actor to be Clone
+impl<A: Actor> Clone for WeakAddr<A> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+            capacity: self.capacity.clone(),
+        }
+    }
+}
+
+impl<A: Actor> WeakAddr<A> {
+    fn upgrade(&self) -> Option<Addr<A>> {
+        Some(Addr {
+            sender: self.sender.upgrade()?,
+            capacity: self.capacity.clone(),
+        })
+    }
+}
+
+/// Gives actors access to themselves while they process a message
+pub(crate) struct Context<A: Actor> {
+    address: WeakAddr<A>,
+    is_stopping: bool,
+    tasks: Vec<AbortHandle>,
+}
+
+impl<A: Actor> Context<A> {
+    /// The actor's own address. Only `None` while it is stopping because all other addresses are
+    /// gone.
+    pub(crate) fn address(&self) -> Option<Addr<A>> {
+        self.address.upgrade()
+    }
+
+    /// Stops the actor after the current message
+    pub(crate) fn stop(&mut self) {
+        self.is_stopping = true;
+    }
+
+    /// Sends every item of the stream to the actor in a separate task. This is how actors react to
+    /// other sources of events, like a socket, while still handling one message at a time.
+    /// The task ends with the actor.
+    pub(crate) fn forward<S>(&mut self, stream: S)
+    where
+        S: Stream<Item = A::Message> + Send + 'static,
+    {
+        let address = self.address.clone();
+        let task = tokio::spawn(async move {
+            let mut stream = std::pin::pin!(stream);
+            while let Some(message) = stream.next().await {
+                let Some(address) = address.upgrade() else {
+                    break;
+                };
+
+                if address.tell(message).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        self.tasks.push(task.abort_handle());
+    }
+}
+
+// Dropping the context instead of aborting at the end of the run loop also ends the tasks if the
+// actor panics
+impl<A: Actor> Drop for Context<A> {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// Spawns the actor with a new mailbox
+pub(crate) fn spawn<A: Actor>(actor: A) -> Addr<A> {
+    spawn_with_capacity(actor, MAILBOX_CAPACITY)
+}
+
+/// Spawns the actor with a mailbox that holds more or less than the usual number of messages sent
+/// with [`Addr::tell`] or [`Addr::try_tell`]
+pub(crate) fn spawn_with_capacity<A: Actor>(actor: A, capacity: usize) -> Addr<A> {
+    let (address, mut mailbox) = mailbox_with_capacity(capacity);
+    tokio::spawn(async move { run(actor, &mut mailbox).await });
+    address
+}
+
+/// Processes the mailbox with the actor until it stops
+pub(crate) async fn run<A: Actor>(mut actor: A, mailbox: &mut Mailbox<A>) {
+    let mut context = Context {
+        address: mailbox.address.clone(),
+        is_stopping: false,
+        tasks: Vec::new(),
+    };
+
+    let _running = Running::new(A::NAME);
+    actor.started(&mut context).await;
+
+    while !context.is_stopping {
+        let Some(message) = mailbox.recv().await else {
+            break;
+        };
+
+        telemetry::record_mailbox_depth(A::NAME, mailbox.len());
+        actor.handle(message, &mut context).await;
+    }
+
+    drop(context);
+    actor.stopped().await;
+}
+
+/// Counts the actor as running until it is dropped, which also happens if the actor panics
+struct Running(&'static str);
+
+impl Running {
+    fn new(actor: &'static str) -> Self {
+        metrics::gauge!(telemetry::ACTORS, "actor" => actor).increment(1);
+        Self(actor)
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        metrics::gauge!(telemetry::ACTORS, "actor" => self.0).decrement(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records what it receives and waits for a go before processing anything
+    struct Recorder {
+        start: Option<oneshot::Receiver<()>>,
+        received: mpsc::UnboundedSender<u32>,
+    }
+
+    impl Actor for Recorder {
+        type Message = u32;
+        const NAME: &'static str = "recorder";
+
+        async fn started(&mut self, _context: &mut Context<Self>) {
+            if let Some(start) = self.start.take() {
+                let _ = start.await;
+            }
+        }
+
+        /// Stops at 0
+        async fn handle(&mut self, message: u32, context: &mut Context<Self>) {
+            if message == 0 {
+                context.stop();
+            }
+            let _ = self.received.send(message);
+        }
+    }
+
+    fn recorder(
+        capacity: usize,
+    ) -> (
+        Addr<Recorder>,
+        oneshot::Sender<()>,
+        mpsc::UnboundedReceiver<u32>,
+    ) {
+        let (start, start_receiver) = oneshot::channel();
+        let (received, receiver) = mpsc::unbounded_channel();
+        let actor = Recorder {
+            start: Some(start_receiver),
+            received,
+        };
+        (spawn_with_capacity(actor, capacity), start, receiver)
+    }
+
+    #[tokio::test]
+    async fn notifications_keep_their_order_with_tells() {
+        let (address, start, mut received) = recorder(8);
+
+        address.tell(1).await.unwrap();
+        address.notify(2).unwrap();
+        address.tell(3).await.unwrap();
+        address.notify(4).unwrap();
+        start.send(()).unwrap();
+        drop(address);
+
+        let mut messages = Vec::new();
+        while let Some(message) = received.recv().await {
+            messages.push(message);
+        }
+        assert_eq!(messages, [1, 2, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn try_tell_fails_once_the_mailbox_is_full() {
+        let (address, start, mut received) = recorder(2);
+
+        address.try_tell(1).unwrap();
+        address.try_tell(2).unwrap();
+        assert!(matches!(address.try_tell(3), Err(HandleError::Full)));
+        // Notifications don't count towards the capacity
+        address.notify(4).unwrap();
+
+        start.send(()).unwrap();
+        assert_eq!(received.recv().await, Some(1));
+        assert_eq!(received.recv().await, Some(2));
+        assert_eq!(received.recv().await, Some(4));
+        // Processed messages give their space back
+        address.try_tell(5).unwrap();
+        assert_eq!(received.recv().await, Some(5));
+    }
+
+    #[tokio::test]
+    async fn waiting_tell_fails_once_the_actor_stopped() {
+        let (address, start, _received) = recorder(1);
+
+        address.tell(0).await.unwrap();
+        let waiting = tokio::spawn({
+            let address = address.clone();
+            async move { address.tell(1).await }
+        });
+        start.send(()).unwrap();
+
+        let result = tokio::time::timeout(Duration::from_secs(1), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+        // It either got the space before the actor stopped or found the mailbox gone
+        if result.is_ok() {
+            assert!(matches!(address.tell(2).await, Err(HandleError::SendError)));
+        }
+    }
+}
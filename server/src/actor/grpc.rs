@@ -0,0 +1,265 @@
+use futures_util::{stream, Stream, StreamExt};
+use nanoid::nanoid;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tonic::{Status, Streaming};
+
+use super::audit_log;
+use super::framework::{self, Actor, Addr, Context};
+use super::socket::{
+    Client, ClientMessage, CloseReason, Command, Connection, Frame, SocketId, SocketInfo, TooSlow,
+    SEND_QUEUE_CAPACITY, SEND_TIMEOUT,
+};
+use super::{user, HandleError};
+use crate::grpc::proto::client_frame::Kind;
+use crate::grpc::proto::{ClientFrame, ServerFrame};
+use crate::telemetry;
+
+/// Frames waiting to be written to the response before sending to the stream waits
+const FRAME_BUFFER: usize = 8;
+
+enum Message {
+    Send(Arc<Frame>),
+    Disconnect(CloseReason),
+    Shutdown {
+        reconnect_after: Duration,
+        respond: oneshot::Sender<()>,
+    },
+    /// A frame the client sent through the request stream
+    Received(Result<ClientFrame, Status>),
+    /// The response ended, which means the client went away
+    Disconnected,
+}
+
+/// A gRPC actor represents a `Chat` call from a users device, which carries the same messages as a
+/// websocket as protobuf frames
+struct Grpc {
+    client: Client,
+    /// Dropped to end the response
+    sender: Option<mpsc::Sender<Result<ServerFrame, Status>>>,
+    /// Only set until the actor started and forwards it to itself
+    frames: Option<Streaming<ClientFrame>>,
+}
+
+impl Grpc {
+    async fn send_to_stream(&mut self, frame: Result<ServerFrame, Status>) -> Result<(), TooSlow> {
+        let Some(sender) = &self.sender else {
+            return Ok(());
+        };
+
+        let result = tokio::time::timeout(SEND_TIMEOUT, sender.send(frame))
+            .await
+            .map_err(|_| TooSlow)?;
+        if result.is_err() {
+            tracing::error!("Error sending message through gRPC stream");
+            telemetry::record_actor_error(Self::NAME, "send");
+        }
+        Ok(())
+    }
+
+    async fn process_frame(&mut self, frame: ClientFrame) {
+        let command = match frame.kind {
+            Some(Kind::Message(message)) => {
+                let message = match crate::grpc::chat_message_from_proto(message) {
+                    Ok(message) => message,
+                    Err(error) => {
+                        tracing::error!("Error decoding message: {}", error);
+                        telemetry::record_actor_error(Self::NAME, "deserialize");
+                        return;
+                    }
+                };
+                if let Some(reply) = self.client.process_message(Self::NAME, message).await {
+                    // A client that is too slow is disconnected with the next frame sent to it
+                    let _ = self.send_to_stream(Ok((&reply).into())).await;
+                }
+                return;
+            }
+            Some(Kind::Block(name)) => Command::Block { name: name.into() },
+            Some(Kind::Unblock(name)) => Command::Unblock { name: name.into() },
+            Some(Kind::Mute(name)) => Command::Mute { name: name.into() },
+            Some(Kind::Unmute(name)) => Command::Unmute { name: name.into() },
+            Some(Kind::MarkRead(name)) => Command::MarkRead { name: name.into() },
+            Some(Kind::Archive(name)) => Command::Archive { name: name.into() },
+            Some(Kind::Unarchive(name)) => Command::Unarchive { name: name.into() },
+            Some(Kind::SaveDraft(draft)) => Command::SaveDraft {
+                name: draft.name.into(),
+                text: draft.text,
+            },
+            Some(Kind::CreateSession(_)) => Command::CreateSession,
+            None => {
+                tracing::error!("Received empty frame");
+                telemetry::record_actor_error(Self::NAME, "deserialize");
+                return;
+            }
+        };
+
+        self.client.run_command(Self::NAME, command).await;
+    }
+
+    /// Ends the response and stops the actor
+    async fn close(&mut self, context: &mut Context<Self>) {
+        self.sender = None;
+        self.client.remove_from_user(Self::NAME).await;
+        context.stop();
+    }
+}
+
+impl Actor for Grpc {
+    type Message = Message;
+    const NAME: &'static str = "grpc";
+
+    async fn started(&mut self, context: &mut Context<Self>) {
+        metrics::gauge!(telemetry::GRPC_STREAMS).increment(1);
+        let Some(frames) = self.frames.take() else {
+            return;
+        };
+
+        // The end of the requests is not forwarded, as clients may keep listening after they are
+        // done sending. Whether they are gone is only known from the response.
+        context.forward(frames.map(Message::Received));
+    }
+
+    async fn handle(&mut self, message: Message, context: &mut Context<Self>) {
+        match message {
+            Message::Send(frame) => {
+                if self
+                    .send_to_stream(Ok(frame.message().into()))
+                    .await
+                    .is_err()
+                {
+                    tracing::info!("Disconnecting gRPC stream that is too slow");
+                    self.close(context).await;
+                }
+            }
+            Message::Disconnect(CloseReason::Operator) => {
+                tracing::info!("Disconnecting gRPC stream on behalf of an operator");
+                let status = Status::permission_denied("Disconnected by an operator");
+                let _ = self.send_to_stream(Err(status)).await;
+                self.close(context).await;
+            }
+            // The status would only wait behind the frames the client does not take
+            Message::Disconnect(CloseReason::TooSlow) => {
+                tracing::info!("Disconnecting gRPC stream that is too slow");
+                self.close(context).await;
+            }
+            Message::Shutdown {
+                reconnect_after,
+                respond,
+            } => {
+                let message = ClientMessage::ServerShutdown {
+                    reconnect_after: reconnect_after.as_millis() as u64,
+                };
+                let _ = self.send_to_stream(Ok((&message).into())).await;
+                self.close(context).await;
+                let _ = respond.send(());
+            }
+            Message::Received(Ok(frame)) => self.process_frame(frame).await,
+            // Stop actor on error
+            Message::Received(Err(status)) => {
+                tracing::error!("Error receiving from gRPC stream: {}", status);
+                telemetry::record_actor_error(Self::NAME, "receive");
+                self.close(context).await;
+            }
+            Message::Disconnected => {
+                tracing::info!("gRPC stream disconnected");
+                self.close(context).await;
+            }
+        }
+    }
+
+    async fn stopped(&mut self) {
+        metrics::gauge!(telemetry::GRPC_STREAMS).decrement(1);
+    }
+}
+
+/// Notifies the actor once the response is dropped, whether it ended or the client went away
+struct Connected(Addr<Grpc>);
+
+impl Drop for Connected {
+    fn drop(&mut self) {
+        let _ = self.0.notify(Message::Disconnected);
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Handle {
+    id: SocketId,
+    remote: IpAddr,
+    address: Addr<Grpc>,
+}
+
+impl Handle {
+    /// Returns the response stream of the call
+    pub(crate) fn new(
+        frames: Streaming<ClientFrame>,
+        user: user::Handle,
+        audit_log: audit_log::Handle,
+        name: Arc<str>,
+        remote: IpAddr,
+    ) -> (Self, impl Stream<Item = Result<ServerFrame, Status>>) {
+        let id = SocketId(nanoid!().into());
+
+        let (sender, receiver) = mpsc::channel(FRAME_BUFFER);
+        let grpc = Grpc {
+            client: Client {
+                id: id.clone(),
+                user,
+                name,
+                remote,
+                audit_log,
+            },
+            sender: Some(sender),
+            frames: Some(frames),
+        };
+
+        let address = framework::spawn_with_capacity(grpc, SEND_QUEUE_CAPACITY);
+        let responses = stream::unfold(
+            (receiver, Connected(address.clone())),
+            |(mut receiver, connected)| async move {
+                let frame = receiver.recv().await?;
+                Some((frame, (receiver, connected)))
+            },
+        );
+
+        let handle = Self {
+            id,
+            remote,
+            address,
+        };
+        (handle, responses)
+    }
+}
+
+impl Connection for Handle {
+    fn id(&self) -> &SocketId {
+        &self.id
+    }
+
+    fn info(&self) -> SocketInfo {
+        SocketInfo {
+            id: self.id.0.clone(),
+            transport: Grpc::NAME,
+            remote: self.remote,
+        }
+    }
+
+    fn send(&self, frame: Arc<Frame>) -> Result<(), HandleError> {
+        self.address.try_tell(Message::Send(frame))
+    }
+
+    fn close(&self, reason: CloseReason) -> Result<(), HandleError> {
+        self.address.notify(Message::Disconnect(reason))
+    }
+
+    fn shutdown(&self, reconnect_after: Duration) -> Result<oneshot::Receiver<()>, HandleError> {
+        let (sender, receiver) = oneshot::channel();
+        self.address
+            .notify(Message::Shutdown {
+                reconnect_after,
+                respond: sender,
+            })
+            .map(|_| receiver)
+    }
+}
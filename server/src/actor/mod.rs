@@ -1,16 +1,119 @@
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
+use std::time::Duration;
 use time::OffsetDateTime;
+use tokio::sync::{mpsc, oneshot};
 
+use crate::storage::conversations::Conversation;
+use crate::storage::retention::Retention;
+
+pub(super) mod audit_log;
+pub(super) mod compression;
 pub(super) mod delivery_service;
+pub(super) mod encoding;
+pub(super) mod event_stream;
+pub(super) mod framework;
+#[cfg(feature = "grpc")]
+pub(super) mod grpc;
+#[cfg(feature = "quic")]
+pub(super) mod quic;
+pub(super) mod socket;
+pub(super) mod supervisor;
+pub(super) mod sweeper;
 pub(super) mod user;
 pub(super) mod websocket;
 
 #[derive(Serialize, Deserialize, Debug)]
-pub(in crate::actor) struct ChatMessage {
-    pub(super) recipient: Arc<str>,
-    sender: String,
-    text: String,
-    #[serde(with = "time::serde::timestamp::milliseconds")]
-    time_utc: OffsetDateTime,
+pub(crate) struct ChatMessage {
+    pub(crate) recipient: Arc<str>,
+    pub(crate) sender: String,
+    pub(crate) text: String,
+    #[serde(with = "milliseconds")]
+    pub(crate) time_utc: OffsetDateTime,
+}
+
+/// Timestamps in milliseconds as `i64`. The `time` crate's own module uses `i128`, which binary
+/// encodings can only write as bytes instead of a number and internally tagged enums can't read.
+pub(crate) mod milliseconds {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+    use time::OffsetDateTime;
+
+    pub(crate) fn serialize<S: Serializer>(
+        time: &OffsetDateTime,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_i64((time.unix_timestamp_nanos() / 1_000_000) as i64)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<OffsetDateTime, D::Error> {
+        let milliseconds = i64::deserialize(deserializer)?;
+        OffsetDateTime::from_unix_timestamp_nanos(milliseconds as i128 * 1_000_000)
+            .map_err(de::Error::custom)
+    }
+
+    /// The same for timestamps that may be missing
+    pub(crate) mod option {
+        use serde::{Deserialize, Deserializer, Serialize, Serializer};
+        use time::OffsetDateTime;
+
+        #[derive(Serialize, Deserialize)]
+        struct Milliseconds(#[serde(with = "super")] OffsetDateTime);
+
+        pub(crate) fn serialize<S: Serializer>(
+            time: &Option<OffsetDateTime>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            time.map(Milliseconds).serialize(serializer)
+        }
+
+        pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<OffsetDateTime>, D::Error> {
+            let time = Option::<Milliseconds>::deserialize(deserializer)?;
+            Ok(time.map(|Milliseconds(time)| time))
+        }
+    }
+}
+
+/// Something other than a chat message that all devices of a user need to know about
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) enum Notice {
+    /// Contacts, contact requests, blocked or muted users changed and need to be loaded again
+    ContactsChanged,
+    /// A message the user sent could not be delivered
+    DeliveryFailed { message: Arc<ChatMessage> },
+    /// An operator closes the socket with the id or all sockets of the user
+    Disconnect { socket: Option<Arc<str>> },
+    /// An announcement from the operators to everyone
+    Announcement { text: Arc<str> },
+    /// How long the messages of the conversation with the user are kept changed
+    RetentionChanged {
+        name: Arc<str>,
+        retention: Retention,
+    },
+    /// Messages of the user's conversations were deleted because their retention ran out
+    MessagesExpired { messages: Vec<Arc<ChatMessage>> },
+    /// The latest message or the unread counter of a conversation of the user changed
+    ConversationUpdated { conversation: Arc<Conversation> },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum HandleError {
+    #[error("Error sending message to actor")]
+    SendError,
+    #[error("Error receiving answer from actor")]
+    ReceiveError(#[from] oneshot::error::RecvError),
+    #[error("Actor did not answer within {0:?}")]
+    Timeout(Duration),
+    #[error("Mailbox of actor is full")]
+    Full,
+}
+
+// The message types are private to the actors, so the message that could not be sent is not passed on
+impl<T> From<mpsc::error::SendError<T>> for HandleError {
+    fn from(_: mpsc::error::SendError<T>) -> Self {
+        Self::SendError
+    }
 }
@@ -0,0 +1,292 @@
+use bytes::Bytes;
+use futures_util::{future, stream, StreamExt};
+use h3_datagram::datagram_handler::DatagramSender;
+use h3_quinn::datagram::SendDatagramHandler;
+use h3_webtransport::server::WebTransportSession;
+use nanoid::nanoid;
+use quinn::VarInt;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::oneshot;
+use tokio_util::codec::{FramedRead, LinesCodec, LinesCodecError};
+
+use super::audit_log;
+use super::framework::{self, Actor, Addr, Context};
+use super::socket::{
+    Client, ClientMessage, CloseReason, Connection, Frame, SocketId, SocketInfo, TooSlow,
+    SEND_QUEUE_CAPACITY, SEND_TIMEOUT,
+};
+use super::{user, HandleError};
+use crate::telemetry;
+
+/// Longest line a client may send, which is plenty for a chat message
+const MAX_LINE_LENGTH: usize = 64 * 1024;
+
+/// Application close codes, the same numbers as the websocket close codes for the same reasons
+pub(crate) mod close_code {
+    use quinn::VarInt;
+
+    pub(crate) const POLICY: VarInt = VarInt::from_u32(1008);
+    pub(crate) const ERROR: VarInt = VarInt::from_u32(1011);
+    pub(crate) const RESTART: VarInt = VarInt::from_u32(1012);
+    pub(crate) const AGAIN: VarInt = VarInt::from_u32(1013);
+}
+
+/// The WebTransport session a client set up over HTTP/3
+pub(crate) type Session = WebTransportSession<h3_quinn::Connection, Bytes>;
+
+pub(crate) type SendStream =
+    h3_webtransport::stream::SendStream<h3_quinn::SendStream<Bytes>, Bytes>;
+
+pub(crate) type RecvStream = h3_webtransport::stream::RecvStream<h3_quinn::RecvStream, Bytes>;
+
+/// The lines a client sends through its stream
+pub(crate) type Lines = FramedRead<RecvStream, LinesCodec>;
+
+pub(crate) fn lines(receive: RecvStream) -> Lines {
+    FramedRead::new(receive, LinesCodec::new_with_max_length(MAX_LINE_LENGTH))
+}
+
+/// How long a shutting down connection waits for the client to read the last message
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+enum Message {
+    Send(Arc<Frame>),
+    Disconnect(CloseReason),
+    Shutdown {
+        reconnect_after: Duration,
+        respond: oneshot::Sender<()>,
+    },
+    /// A line the client sent through the stream
+    Received(Result<String, LinesCodecError>),
+    /// The client closed the stream or the connection was lost
+    Disconnected,
+}
+
+/// A QUIC actor represents a WebTransport session with a users device. The client opens a
+/// bidirectional stream which carries the same JSON messages as a websocket, one per line.
+/// Presence changes, which are superseded by the next one anyway, go as datagrams if they fit.
+struct Quic {
+    client: Client,
+    /// The connection the session runs on, as each connection only carries one session
+    connection: quinn::Connection,
+    /// Kept for as long as the actor runs, as dropping it ends the session
+    _session: Session,
+    datagrams: DatagramSender<SendDatagramHandler, Bytes>,
+    send: SendStream,
+    /// Only set until the actor started and forwards it to itself
+    lines: Option<Lines>,
+}
+
+impl Quic {
+    async fn send_to_client(&mut self, frame: &Frame) -> Result<(), TooSlow> {
+        let json = match frame.json() {
+            Ok(json) => json,
+            Err(error) => {
+                tracing::error!("Error serializing message: {:?}", error);
+                telemetry::record_actor_error(Self::NAME, "serialize");
+                return Ok(());
+            }
+        };
+
+        let is_presence = matches!(
+            frame.message(),
+            ClientMessage::AddUser { .. } | ClientMessage::RemoveUser { .. }
+        );
+        // The datagram starts with the id of the session, which takes at most 8 bytes
+        let fits_datagram = self
+            .connection
+            .max_datagram_size()
+            .is_some_and(|size| json.len() + 8 <= size);
+        if is_presence && fits_datagram {
+            if let Err(error) = self.datagrams.send_datagram(json.clone().into()) {
+                tracing::error!("Error sending datagram: {:?}", error);
+                telemetry::record_actor_error(Self::NAME, "datagram");
+            }
+            return Ok(());
+        }
+
+        let write = async {
+            self.send.write_all(json.as_bytes()).await?;
+            self.send.write_all(b"\n").await
+        };
+        let result = tokio::time::timeout(SEND_TIMEOUT, write)
+            .await
+            .map_err(|_| TooSlow)?;
+        if let Err(error) = result {
+            tracing::error!("Error sending message through QUIC stream: {:?}", error);
+            telemetry::record_actor_error(Self::NAME, "send");
+        }
+        Ok(())
+    }
+
+    fn close(&mut self, code: VarInt, reason: &'static str) {
+        self.connection.close(code, reason.as_bytes());
+    }
+
+    async fn disconnect(&mut self, reason: CloseReason, context: &mut Context<Self>) {
+        match reason {
+            CloseReason::Operator => {
+                tracing::info!("Disconnecting WebTransport session on behalf of an operator");
+                self.close(close_code::POLICY, "Disconnected by an operator");
+            }
+            CloseReason::TooSlow => {
+                tracing::info!("Disconnecting WebTransport session that is too slow");
+                self.close(close_code::AGAIN, "Too slow");
+            }
+        }
+        self.client.remove_from_user(Self::NAME).await;
+        context.stop();
+    }
+}
+
+impl Actor for Quic {
+    type Message = Message;
+    const NAME: &'static str = "quic";
+
+    async fn started(&mut self, context: &mut Context<Self>) {
+        metrics::gauge!(telemetry::QUIC_CONNECTIONS).increment(1);
+        let Some(lines) = self.lines.take() else {
+            return;
+        };
+
+        // The end of the stream is forwarded too to clean up if the connection was lost
+        let lines = lines
+            .map(Message::Received)
+            .chain(stream::once(future::ready(Message::Disconnected)));
+        context.forward(lines);
+    }
+
+    async fn handle(&mut self, message: Message, context: &mut Context<Self>) {
+        match message {
+            Message::Send(frame) => {
+                if self.send_to_client(&frame).await.is_err() {
+                    self.disconnect(CloseReason::TooSlow, context).await;
+                }
+            }
+            Message::Disconnect(reason) => self.disconnect(reason, context).await,
+            Message::Shutdown {
+                reconnect_after,
+                respond,
+            } => {
+                let message = ClientMessage::ServerShutdown {
+                    reconnect_after: reconnect_after.as_millis() as u64,
+                };
+                let _ = self.send_to_client(&message.into()).await;
+                // Closing the connection right away would drop what the client has not received yet,
+                // so the client gets a moment to close it after reading the end of the stream
+                if self.send.shutdown().await.is_ok() {
+                    let _ = tokio::time::timeout(FLUSH_TIMEOUT, self.connection.closed()).await;
+                }
+                self.close(close_code::RESTART, "Server shutting down");
+
+                let _ = respond.send(());
+                context.stop();
+            }
+            Message::Received(Ok(line)) => {
+                if let Some(reply) = self.client.process(Self::NAME, &line).await {
+                    // A client that is too slow is disconnected with the next frame sent to it
+                    let _ = self.send_to_client(&reply.into()).await;
+                }
+            }
+            // Stop actor on error, which includes lines that are too long
+            Message::Received(Err(error)) => {
+                tracing::error!("Error receiving from WebTransport stream: {:?}", error);
+                telemetry::record_actor_error(Self::NAME, "receive");
+                self.close(close_code::POLICY, "Unreadable message");
+                self.client.remove_from_user(Self::NAME).await;
+                context.stop();
+            }
+            Message::Disconnected => {
+                tracing::info!("WebTransport session closed");
+                self.close(VarInt::from_u32(0), "");
+                self.client.remove_from_user(Self::NAME).await;
+                context.stop();
+            }
+        }
+    }
+
+    async fn stopped(&mut self) {
+        metrics::gauge!(telemetry::QUIC_CONNECTIONS).decrement(1);
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Handle {
+    id: SocketId,
+    remote: IpAddr,
+    address: Addr<Quic>,
+}
+
+impl Handle {
+    /// Takes over the session with the stream the client opened in it
+    pub(crate) fn new(
+        connection: quinn::Connection,
+        session: Session,
+        send: SendStream,
+        lines: Lines,
+        user: user::Handle,
+        audit_log: audit_log::Handle,
+        name: Arc<str>,
+    ) -> Self {
+        let id = SocketId(nanoid!().into());
+        let remote = connection.remote_address().ip();
+
+        let quic = Quic {
+            client: Client {
+                id: id.clone(),
+                user,
+                name,
+                remote,
+                audit_log,
+            },
+            connection,
+            datagrams: session.datagram_sender(),
+            _session: session,
+            send,
+            lines: Some(lines),
+        };
+
+        let address = framework::spawn_with_capacity(quic, SEND_QUEUE_CAPACITY);
+
+        Self {
+            id,
+            remote,
+            address,
+        }
+    }
+}
+
+impl Connection for Handle {
+    fn id(&self) -> &SocketId {
+        &self.id
+    }
+
+    fn info(&self) -> SocketInfo {
+        SocketInfo {
+            id: self.id.0.clone(),
+            transport: Quic::NAME,
+            remote: self.remote,
+        }
+    }
+
+    fn send(&self, frame: Arc<Frame>) -> Result<(), HandleError> {
+        self.address.try_tell(Message::Send(frame))
+    }
+
+    fn close(&self, reason: CloseReason) -> Result<(), HandleError> {
+        self.address.notify(Message::Disconnect(reason))
+    }
+
+    fn shutdown(&self, reconnect_after: Duration) -> Result<oneshot::Receiver<()>, HandleError> {
+        let (sender, receiver) = oneshot::channel();
+        self.address
+            .notify(Message::Shutdown {
+                reconnect_after,
+                respond: sender,
+            })
+            .map(|_| receiver)
+    }
+}
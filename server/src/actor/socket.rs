@@ -0,0 +1,399 @@
+use axum::extract::ws::{Message as WebSocketMessage, Utf8Bytes};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+use super::audit_log::{self, AuditEvent};
+use super::encoding::{Encoding, EncodingError};
+use super::{user, ChatMessage, HandleError, Notice};
+use crate::storage::conversations::Conversation;
+use crate::storage::retention::Retention;
+use crate::telemetry;
+
+/// What the server sends to the clients, the same for every transport
+#[derive(Serialize)]
+#[serde(tag = "type")]
+pub(crate) enum ClientMessage {
+    ChatMessage {
+        message: Arc<ChatMessage>,
+        /// The client should not notify about messages from muted users
+        muted: bool,
+    },
+    AddUser {
+        name: Arc<str>,
+    },
+    RemoveUser {
+        name: Arc<str>,
+    },
+    SynchronizeMessage {
+        message: Arc<ChatMessage>,
+    },
+    /// A message from someone who is not a contact yet, which makes it a contact request
+    MessageRequest {
+        message: Arc<ChatMessage>,
+        muted: bool,
+    },
+    /// The client should load its contacts again
+    ContactsChanged,
+    /// A message sent from any of the user's devices did not reach the recipient. This is the
+    /// same whether the recipient is not connected or blocked the user.
+    DeliveryFailed {
+        message: Arc<ChatMessage>,
+    },
+    /// An announcement from the operators
+    SystemNotice {
+        text: Arc<str>,
+    },
+    /// How long the messages of the conversation with the user are kept changed
+    RetentionChanged {
+        name: Arc<str>,
+        retention: Retention,
+    },
+    /// The messages were deleted because their retention ran out, and should be deleted by the
+    /// client too
+    MessagesExpired {
+        messages: Vec<Arc<ChatMessage>>,
+    },
+    /// The latest message or the unread counter of a conversation changed, so that the list of
+    /// conversations stays up to date without loading it again
+    ConversationUpdated {
+        conversation: Arc<Conversation>,
+    },
+    /// A token for the REST API, only sent to the socket that asked for it
+    SessionCreated {
+        token: Arc<str>,
+    },
+    /// Sent before the server closes the socket because it is shutting down
+    ServerShutdown {
+        /// Milliseconds the client should wait before trying to reconnect
+        reconnect_after: u64,
+    },
+}
+
+impl ClientMessage {
+    /// What the clients are told about the notice. Disconnects are done instead of told.
+    pub(super) fn from_notice(notice: Notice) -> Option<Self> {
+        let message = match notice {
+            Notice::ContactsChanged => Self::ContactsChanged,
+            Notice::DeliveryFailed { message } => Self::DeliveryFailed { message },
+            Notice::Announcement { text } => Self::SystemNotice { text },
+            Notice::RetentionChanged { name, retention } => {
+                Self::RetentionChanged { name, retention }
+            }
+            Notice::MessagesExpired { messages } => Self::MessagesExpired { messages },
+            Notice::ConversationUpdated { conversation } => {
+                Self::ConversationUpdated { conversation }
+            }
+            Notice::Disconnect { .. } => return None,
+        };
+        Some(message)
+    }
+}
+
+/// A message on its way to the clients. It is encoded at most once per encoding and the frames
+/// are shared, no matter to how many sockets it goes.
+pub(crate) struct Frame {
+    message: ClientMessage,
+    /// Indexed by the encoding and only filled once a socket needs it
+    encoded: [OnceLock<Result<WebSocketMessage, EncodingError>>; Encoding::PROTOCOLS.len()],
+}
+
+impl Frame {
+    #[cfg(any(feature = "quic", feature = "grpc"))]
+    pub(super) fn message(&self) -> &ClientMessage {
+        &self.message
+    }
+
+    /// The websocket frame in the encoding, which is cheap to clone
+    pub(super) fn encoded(&self, encoding: Encoding) -> Result<&WebSocketMessage, &EncodingError> {
+        self.encoded[encoding as usize]
+            .get_or_init(|| encoding.encode(&self.message))
+            .as_ref()
+    }
+
+    /// The message as JSON, for the transports that always send text
+    pub(super) fn json(&self) -> Result<&Utf8Bytes, &EncodingError> {
+        match self.encoded(Encoding::Json)? {
+            WebSocketMessage::Text(json) => Ok(json),
+            _ => unreachable!("JSON is always encoded as a text frame"),
+        }
+    }
+}
+
+impl From<ClientMessage> for Frame {
+    fn from(message: ClientMessage) -> Self {
+        Self {
+            message,
+            encoded: Default::default(),
+        }
+    }
+}
+
+/// What a client can ask for through the socket besides sending chat messages. Changes to contacts
+/// and conversations are synced to all devices of the user.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type")]
+pub(super) enum Command {
+    Block {
+        name: Arc<str>,
+    },
+    Unblock {
+        name: Arc<str>,
+    },
+    Mute {
+        name: Arc<str>,
+    },
+    Unmute {
+        name: Arc<str>,
+    },
+    /// The user read the messages from the user with the name
+    MarkRead {
+        name: Arc<str>,
+    },
+    /// Hides the conversation with the user from the list
+    Archive {
+        name: Arc<str>,
+    },
+    Unarchive {
+        name: Arc<str>,
+    },
+    /// What the user has written to the user with the name so far. Empty to discard it.
+    SaveDraft {
+        name: Arc<str>,
+        text: String,
+    },
+    /// Asks for a token that authenticates the user with the REST API
+    CreateSession,
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub(crate) struct SocketId(pub(super) Arc<str>);
+
+/// A connection as operators see it
+pub(crate) struct SocketInfo {
+    pub(crate) id: Arc<str>,
+    /// Which kind of connection it is, like `websocket`
+    pub(crate) transport: &'static str,
+    /// Where the client connected from
+    pub(crate) remote: IpAddr,
+}
+
+/// Who is on the other end of a socket and where to pass on what they send. Every transport's
+/// actor checks the frames from its client through this, so that they are all treated the same.
+pub(super) struct Client {
+    pub(super) id: SocketId,
+    /// The user that is connected through the socket
+    pub(super) user: user::Handle,
+    /// The name of the user, which every message sent through the socket needs to be from
+    pub(super) name: Arc<str>,
+    pub(super) remote: IpAddr,
+    pub(super) audit_log: audit_log::Handle,
+}
+
+impl Client {
+    /// Passes a chat message or command from the client on to the user. Returns what to answer
+    /// the client with, if anything.
+    pub(super) async fn process(&self, actor: &'static str, json: &str) -> Option<ClientMessage> {
+        self.process_encoded(actor, Encoding::Json, json.as_bytes())
+            .await
+    }
+
+    /// Like [`Client::process`] for frames in any encoding
+    pub(super) async fn process_encoded(
+        &self,
+        actor: &'static str,
+        encoding: Encoding,
+        frame: &[u8],
+    ) -> Option<ClientMessage> {
+        let result = encoding.decode::<ChatMessage>(frame);
+        match result {
+            Ok(message) => self.process_message(actor, message).await,
+            // Chat messages are far more common, so commands are only tried after them
+            Err(error) => match encoding.decode::<Command>(frame) {
+                Ok(command) => {
+                    self.run_command(actor, command).await;
+                    None
+                }
+                Err(_) => {
+                    tracing::error!("Error deserializing message: {:?}", error);
+                    telemetry::record_actor_error(actor, "deserialize");
+                    None
+                }
+            },
+        }
+    }
+
+    /// Like [`Client::process`] for transports that decode the message themselves
+    pub(super) async fn process_message(
+        &self,
+        actor: &'static str,
+        message: ChatMessage,
+    ) -> Option<ClientMessage> {
+        if *message.sender != *self.name {
+            tracing::warn!("Rejecting message with spoofed sender");
+            telemetry::record_actor_error(actor, "spoofed_sender");
+            self.audit_log.record(AuditEvent::SpoofedSender {
+                user: self.name.clone(),
+                claimed_sender: message.sender.clone(),
+                address: self.remote,
+            });
+            return Some(ClientMessage::DeliveryFailed {
+                message: message.into(),
+            });
+        }
+
+        let result = self
+            .user
+            .process_socket_message(Some(self.id.clone()), message.into())
+            .await;
+        if let Err(error) = result {
+            tracing::error!("Error processing message: {:?}", error);
+            telemetry::record_actor_error(actor, "process_message");
+        }
+        None
+    }
+
+    pub(super) async fn run_command(&self, actor: &'static str, command: Command) {
+        let result = self.user.run_command(self.id.clone(), command).await;
+        if let Err(error) = result {
+            tracing::error!("Error running command: {:?}", error);
+            telemetry::record_actor_error(actor, "command");
+        }
+    }
+
+    pub(super) async fn remove_from_user(&self, actor: &'static str) {
+        let result = self.user.remove_socket(self.id.clone()).await;
+        if let Err(error) = result {
+            tracing::error!("Error removing socket from user: {:?}", error);
+            telemetry::record_actor_error(actor, "remove_socket");
+        }
+    }
+}
+
+/// Frames waiting in the mailbox of a socket before its client counts as too slow
+pub(crate) const SEND_QUEUE_CAPACITY: usize = 64;
+
+/// How long writing a frame to a client may take before it counts as too slow
+pub(super) const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Writing a frame to the client took longer than [`SEND_TIMEOUT`]
+#[derive(Debug, thiserror::Error)]
+#[error("Client did not take the frame in time")]
+pub(super) struct TooSlow;
+
+/// Why the server closes a connection
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum CloseReason {
+    /// An operator disconnected the device
+    Operator,
+    /// The client did not keep up with the frames sent to it
+    TooSlow,
+}
+
+/// A transport through which a device of a user is connected. The user actor only talks to its
+/// devices through this, so it does not need to know whether they use a websocket, server-sent
+/// events or something else.
+/// The methods only hand the message to the connection and don't wait for it to be sent, as
+/// everything flows downstream towards the sockets here.
+pub(crate) trait Connection: Send + Sync + 'static {
+    fn id(&self) -> &SocketId;
+
+    /// What operators get to see about the connection
+    fn info(&self) -> SocketInfo;
+
+    /// Fails with [`HandleError::Full`] if [`SEND_QUEUE_CAPACITY`] frames are already waiting
+    fn send(&self, frame: Arc<Frame>) -> Result<(), HandleError>;
+
+    fn close(&self, reason: CloseReason) -> Result<(), HandleError>;
+
+    /// Tells the client that the server is shutting down and closes the connection.
+    /// The receiver resolves once that is done.
+    fn shutdown(&self, reconnect_after: Duration) -> Result<oneshot::Receiver<()>, HandleError>;
+}
+
+/// A connection to one of the devices of a user, whichever transport it uses
+#[derive(Clone)]
+pub(crate) struct Handle {
+    connection: Arc<dyn Connection>,
+    /// Set once the client fell behind, after which frames are dropped until the connection is
+    /// closed
+    is_too_slow: Arc<AtomicBool>,
+}
+
+impl<C: Connection> From<C> for Handle {
+    fn from(connection: C) -> Self {
+        Self {
+            connection: Arc::new(connection),
+            is_too_slow: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+impl Handle {
+    pub(super) fn id(&self) -> &SocketId {
+        self.connection.id()
+    }
+
+    pub(super) fn info(&self) -> SocketInfo {
+        self.connection.info()
+    }
+
+    /// Sends a frame the user may send to other sockets as well.
+    /// A client that does not keep up is disconnected instead of queueing its frames without limit.
+    pub(super) fn send(&self, frame: Arc<Frame>) -> Result<(), HandleError> {
+        if self.is_too_slow.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        match self.connection.send(frame) {
+            Err(HandleError::Full) => {
+                self.is_too_slow.store(true, Ordering::Relaxed);
+                let info = self.info();
+                tracing::warn!("Disconnecting client that is too slow from {}", info.remote);
+                metrics::counter!(telemetry::SLOW_CLIENTS, "transport" => info.transport)
+                    .increment(1);
+                self.connection.close(CloseReason::TooSlow)
+            }
+            result => result,
+        }
+    }
+
+    /// Closes the socket on behalf of an operator
+    pub(super) fn close(&self) -> Result<(), HandleError> {
+        self.connection.close(CloseReason::Operator)
+    }
+
+    /// Returns a receiver that resolves once the socket has been told about the shutdown and closed
+    pub(super) fn shutdown(
+        &self,
+        reconnect_after: Duration,
+    ) -> Result<oneshot::Receiver<()>, HandleError> {
+        self.connection.shutdown(reconnect_after)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_are_encoded_once_per_encoding() {
+        let frame = Frame::from(ClientMessage::SystemNotice {
+            text: "Maintenance at noon".into(),
+        });
+
+        let json = frame.encoded(Encoding::Json).unwrap();
+        let cbor = frame.encoded(Encoding::Cbor).unwrap();
+        assert!(std::ptr::eq(json, frame.encoded(Encoding::Json).unwrap()));
+        assert!(std::ptr::eq(cbor, frame.encoded(Encoding::Cbor).unwrap()));
+        // The transports that always send text share the JSON frame
+        let text = frame.json().unwrap().as_str();
+        assert!(std::ptr::eq(text, json.to_text().unwrap()));
+        // Encodings no socket asked for are left out
+        let message_pack = &frame.encoded[Encoding::MessagePack as usize];
+        assert!(message_pack.get().is_none());
+    }
+}
@@ -0,0 +1,138 @@
+use crate::telemetry;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+/// How often an actor may crash before the supervisor gives up on it
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct RestartPolicy {
+    pub(crate) max_restarts: usize,
+    /// Crashes older than this are forgotten
+    pub(crate) window: Duration,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("{actor} crashed {crashes} times within {window:?}, giving up")]
+pub(crate) struct EscalationError {
+    actor: &'static str,
+    crashes: usize,
+    window: Duration,
+}
+
+/// Spawns the actor created by `start` and restarts it when it panics.
+/// `start` is called again for every restart and is responsible for rebuilding the actor state.
+/// Resolves once the actor stops on its own or with an error if it crashed more often than the
+/// policy allows.
+pub(super) fn supervise<F, Fut>(
+    actor: &'static str,
+    policy: RestartPolicy,
+    mut start: F,
+) -> JoinHandle<Result<(), EscalationError>>
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut crashes = VecDeque::with_capacity(policy.max_restarts + 1);
+        loop {
+            let result = tokio::spawn(start()).await;
+            let error = match result {
+                Ok(()) => {
+                    tracing::debug!("{} stopped", actor);
+                    return Ok(());
+                }
+                Err(error) if error.is_panic() => error,
+                Err(error) => {
+                    tracing::debug!("{} was cancelled: {}", actor, error);
+                    return Ok(());
+                }
+            };
+
+            tracing::error!("{} crashed: {}", actor, error);
+            telemetry::record_actor_restart(actor);
+
+            let now = Instant::now();
+            crashes.push_back(now);
+            while crashes
+                .front()
+                .is_some_and(|crash| now.duration_since(*crash) > policy.window)
+            {
+                crashes.pop_front();
+            }
+
+            if crashes.len() > policy.max_restarts {
+                return Err(EscalationError {
+                    actor,
+                    crashes: crashes.len(),
+                    window: policy.window,
+                });
+            }
+
+            tracing::info!("Restarting {}", actor);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    const POLICY: RestartPolicy = RestartPolicy {
+        max_restarts: 2,
+        window: Duration::from_secs(60),
+    };
+
+    #[tokio::test]
+    async fn restarts_crashed_actors() {
+        let starts = Arc::new(AtomicUsize::new(0));
+        let counted = starts.clone();
+        let supervisor = supervise("test", POLICY, move || {
+            let start = counted.fetch_add(1, Ordering::SeqCst);
+            async move { assert!(start >= POLICY.max_restarts, "crash {start}") }
+        });
+
+        assert!(supervisor.await.unwrap().is_ok());
+        assert_eq!(starts.load(Ordering::SeqCst), POLICY.max_restarts + 1);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_too_many_crashes() {
+        let starts = Arc::new(AtomicUsize::new(0));
+        let counted = starts.clone();
+        let supervisor = supervise("test", POLICY, move || {
+            let start = counted.fetch_add(1, Ordering::SeqCst);
+            async move { panic!("crash {start}") }
+        });
+
+        let error = supervisor.await.unwrap().unwrap_err();
+        assert_eq!(error.crashes, POLICY.max_restarts + 1);
+        assert_eq!(starts.load(Ordering::SeqCst), POLICY.max_restarts + 1);
+    }
+
+    #[tokio::test]
+    async fn forgets_crashes_outside_of_the_window() {
+        let policy = RestartPolicy {
+            max_restarts: 1,
+            window: Duration::from_millis(20),
+        };
+        let starts = Arc::new(AtomicUsize::new(0));
+        let counted = starts.clone();
+        let supervisor = supervise("test", policy, move || {
+            let start = counted.fetch_add(1, Ordering::SeqCst);
+            async move {
+                // Crashes once per window, which never adds up to too many
+                if start < 5 {
+                    tokio::time::sleep(policy.window * 2).await;
+                    panic!("crash {start}");
+                }
+            }
+        });
+
+        assert!(supervisor.await.unwrap().is_ok());
+        assert_eq!(starts.load(Ordering::SeqCst), 6);
+    }
+}
@@ -0,0 +1,117 @@
+use futures_util::stream;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use time::OffsetDateTime;
+use tokio::time::MissedTickBehavior;
+
+use super::framework::{self, Actor, Addr, Context};
+use super::{delivery_service, ChatMessage, Notice};
+use crate::storage::Storage;
+use crate::telemetry;
+
+enum Message {
+    Sweep,
+}
+
+/// The sweeper actor deletes the messages that are past the retention of their conversation at
+/// a fixed interval and tells the users of the conversations through their user actors, so that
+/// their devices delete them too. Disappearing messages are gone within one interval after their
+/// timer ran out.
+struct Sweeper {
+    storage: Storage,
+    delivery_service: delivery_service::Handle,
+    interval: Duration,
+}
+
+impl Sweeper {
+    async fn sweep(&self) {
+        let expired = match self.storage.delete_expired(OffsetDateTime::now_utc()).await {
+            Ok(expired) => expired,
+            Err(error) => {
+                tracing::error!("Error deleting expired messages: {}", error);
+                telemetry::record_actor_error(Self::NAME, "delete_expired");
+                return;
+            }
+        };
+        let messages = expired.messages;
+        if messages.is_empty() {
+            return;
+        }
+
+        tracing::debug!("Deleted {} expired messages", messages.len());
+        metrics::counter!(telemetry::MESSAGES_EXPIRED).increment(messages.len() as u64);
+        let mut messages_by_user: HashMap<Arc<str>, Vec<Arc<ChatMessage>>> = HashMap::new();
+        for message in messages {
+            let message = Arc::new(message);
+            messages_by_user
+                .entry(message.sender.as_str().into())
+                .or_default()
+                .push(message.clone());
+            messages_by_user
+                .entry(message.recipient.clone())
+                .or_default()
+                .push(message);
+        }
+
+        for (user, messages) in messages_by_user {
+            let result = self
+                .delivery_service
+                .notify(user, Notice::MessagesExpired { messages })
+                .await;
+            if let Err(error) = result {
+                tracing::error!("Error notifying about expired messages: {}", error);
+                telemetry::record_actor_error(Self::NAME, "notify");
+            }
+        }
+        // The conversations show an earlier message or none now
+        self.delivery_service
+            .notify_conversations(expired.conversations)
+            .await;
+    }
+}
+
+impl Actor for Sweeper {
+    type Message = Message;
+    const NAME: &'static str = "sweeper";
+
+    async fn started(&mut self, context: &mut Context<Self>) {
+        let mut interval = tokio::time::interval(self.interval);
+        // A slow sweep is not made up for with a burst of sweeps
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let ticks = stream::unfold(interval, |mut interval| async move {
+            interval.tick().await;
+            Some((Message::Sweep, interval))
+        });
+        context.forward(ticks);
+    }
+
+    async fn handle(&mut self, message: Message, _context: &mut Context<Self>) {
+        match message {
+            Message::Sweep => self.sweep().await,
+        }
+    }
+}
+
+/// Keeps the sweeper running until it is dropped
+pub(crate) struct Handle {
+    _address: Addr<Sweeper>,
+}
+
+impl Handle {
+    pub(crate) fn new(
+        storage: Storage,
+        delivery_service: delivery_service::Handle,
+        interval: Duration,
+    ) -> Self {
+        let sweeper = Sweeper {
+            storage,
+            delivery_service,
+            interval,
+        };
+
+        Self {
+            _address: framework::spawn(sweeper),
+        }
+    }
+}
@@ -1,167 +1,523 @@
-use std::error::Error;
+use std::collections::HashSet;
 use std::sync::Arc;
+use std::time::Duration;
 
-use crate::actor::websocket::SocketId;
-use tokio::sync::mpsc::{self};
+use crate::actor::framework::{self, Actor, Addr, Context};
+use crate::actor::socket::{self, ClientMessage, Command, Frame, SocketId, SocketInfo};
+use crate::storage::contacts::RequestOutcome;
+use crate::storage::conversations::Conversation;
+use crate::storage::{Storage, StorageError};
+use crate::telemetry;
+use futures_util::stream;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::oneshot;
 
-use super::{delivery_service, websocket, ChatMessage};
+use super::{delivery_service, ChatMessage, HandleError, Notice};
 
 /// A user actor represents a user that can send and receive messages
 /// It also keeps track of all sockets that are connected to it as a user can have multiple devices connected a once
 /// and the messages are sent to all of them.
 struct User {
     delivery_service: delivery_service::Handle,
+    storage: Storage,
     name: Arc<str>,
-    receiver: mpsc::Receiver<Message>,
-    sockets: Vec<websocket::Handle>,
+    sockets: Vec<socket::Handle>,
+    /// Kept in memory as they are needed for every message sent and received
+    contacts: HashSet<Arc<str>>,
 }
 
+impl User {
+    async fn load_contacts(&mut self) {
+        match self.storage.contacts(self.name.clone()).await {
+            Ok(contacts) => self.contacts = contacts.into_iter().collect(),
+            Err(error) => {
+                tracing::error!("Error loading contacts: {}", error);
+                telemetry::record_actor_error(Self::NAME, "load_contacts");
+            }
+        }
+    }
+
+    async fn notify_changed(&self, users: impl IntoIterator<Item = Arc<str>>) {
+        for user in users {
+            let result = self
+                .delivery_service
+                .notify(user, Notice::ContactsChanged)
+                .await;
+            if let Err(error) = result {
+                tracing::error!("Error notifying about changed contacts: {}", error);
+                telemetry::record_actor_error(Self::NAME, "notify");
+            }
+        }
+    }
+
+    /// Sends the changed conversation to all devices of the user except the one that changed it,
+    /// like the messages the user sends
+    async fn sync_conversation(
+        &self,
+        source: &SocketId,
+        result: Result<Option<Conversation>, StorageError>,
+    ) {
+        let conversation = match result {
+            Ok(Some(conversation)) => Arc::new(conversation),
+            Ok(None) => return,
+            Err(error) => {
+                tracing::error!("Error updating conversation: {}", error);
+                telemetry::record_actor_error(Self::NAME, "update_conversation");
+                return;
+            }
+        };
+
+        let frame = Arc::new(Frame::from(ClientMessage::ConversationUpdated {
+            conversation,
+        }));
+        for socket in &self.sockets {
+            if socket.id() == source {
+                continue;
+            }
+
+            let result = socket.send(frame.clone());
+            if let Err(error) = result {
+                tracing::error!("Error sending conversation to socket: {}", error);
+                telemetry::record_actor_error(Self::NAME, "sync_conversation");
+            }
+        }
+    }
+
+    /// Hands a token for the REST API to the device that asked for it, and only to that one
+    async fn create_session(&self, source: &SocketId) {
+        let token = match self.storage.create_session(self.name.clone()).await {
+            Ok(token) => token,
+            Err(error) => {
+                tracing::error!("Error creating session: {}", error);
+                telemetry::record_actor_error(Self::NAME, "create_session");
+                return;
+            }
+        };
+
+        let Some(socket) = self.sockets.iter().find(|socket| socket.id() == source) else {
+            return;
+        };
+        let frame = Arc::new(Frame::from(ClientMessage::SessionCreated {
+            token: token.into(),
+        }));
+        if let Err(error) = socket.send(frame) {
+            tracing::error!("Error sending session to socket: {}", error);
+            telemetry::record_actor_error(Self::NAME, "create_session");
+        }
+    }
+
+    /// Changes the blocked or muted users like the REST API does, or the user's side of a
+    /// conversation
+    async fn run_command(&self, source: SocketId, command: Command) {
+        let name = self.name.clone();
+        let (result, other) = match command {
+            Command::CreateSession => return self.create_session(&source).await,
+            // Only the user's side of the conversation changes
+            Command::MarkRead { name: sender } => {
+                let result = self.storage.mark_read(name, sender).await;
+                return self.sync_conversation(&source, result).await;
+            }
+            Command::Archive { name: other } => {
+                let result = self.storage.set_archived(name, other, true).await;
+                return self.sync_conversation(&source, result).await;
+            }
+            Command::Unarchive { name: other } => {
+                let result = self.storage.set_archived(name, other, false).await;
+                return self.sync_conversation(&source, result).await;
+            }
+            Command::SaveDraft { name: other, text } => {
+                let result = self.storage.save_draft(name, other, text).await;
+                return self.sync_conversation(&source, result).await;
+            }
+            Command::Block { name: other } | Command::Mute { name: other }
+                if other == self.name =>
+            {
+                tracing::warn!("User tried to block or mute themselves");
+                return;
+            }
+            // The blocked user loses the contact too
+            Command::Block { name: other } => {
+                let result = self.storage.block(name, other.clone()).await;
+                (result, Some(other))
+            }
+            Command::Unblock { name: other } => {
+                let result = self.storage.unblock(name, other.clone()).await;
+                (result.map(|_| ()), Some(other))
+            }
+            // Muting is not visible to the muted user
+            Command::Mute { name: other } => (self.storage.mute(name, other).await, None),
+            Command::Unmute { name: other } => {
+                let result = self.storage.unmute(name, other).await;
+                (result.map(|_| ()), None)
+            }
+        };
+
+        if let Err(error) = result {
+            tracing::error!("Error running command: {}", error);
+            telemetry::record_actor_error(Self::NAME, "command");
+            return;
+        }
+
+        // Syncs the lists to all devices and reloads what the delivery service checks messages
+        // against
+        self.notify_changed([self.name.clone()].into_iter().chain(other))
+            .await;
+    }
+
+    /// Sending a message to someone who is not a contact asks them to become one. The message
+    /// itself is stored like any other and shows up with the request.
+    async fn request_contact(&mut self, recipient: Arc<str>) {
+        let request = self
+            .storage
+            .request_contact(self.name.clone(), recipient.clone());
+        let outcome = match request.await {
+            Ok(outcome) => outcome,
+            Err(error) => {
+                tracing::error!("Error storing contact request: {}", error);
+                telemetry::record_actor_error(Self::NAME, "request_contact");
+                return;
+            }
+        };
+
+        if outcome == RequestOutcome::Blocked {
+            return;
+        }
+
+        // Updates this user's cache as well as the lists on all devices of both users
+        self.notify_changed([self.name.clone(), recipient]).await;
+    }
+}
+
+#[allow(clippy::enum_variant_names)]
 enum Message {
-    AddSocket(websocket::Handle),
-    ProcessSocketMessage(SocketId, Arc<ChatMessage>),
-    ReceiveMessage(Arc<ChatMessage>),
+    AddSocket(socket::Handle),
+    /// A message the user sent. Not synchronized to the socket it came through, if any.
+    ProcessSocketMessage(Option<SocketId>, Arc<ChatMessage>),
+    ReceiveMessage {
+        message: Arc<ChatMessage>,
+        is_muted: bool,
+    },
+    /// A command from the socket with the id
+    Command(SocketId, Command),
     RemoveSocket(SocketId),
     AddContact(Arc<str>),
     RemoveContact(Arc<str>),
+    Shutdown {
+        reconnect_after: Duration,
+        respond: oneshot::Sender<()>,
+    },
+    Notice(Notice),
+    GetSockets(oneshot::Sender<Vec<SocketInfo>>),
+    /// The delivery service lost track of this user because it crashed and was restarted
+    DeliveryServiceRestarted,
 }
 
-async fn run_actor(mut actor: User) {
-    while let Some(message) = actor.receiver.recv().await {
+impl Actor for User {
+    type Message = Message;
+    const NAME: &'static str = "user";
+
+    async fn started(&mut self, context: &mut Context<Self>) {
+        self.load_contacts().await;
+
+        let restarts = self.delivery_service.subscribe_to_restarts();
+        let restarts = stream::unfold(restarts, |mut restarts| async move {
+            match restarts.recv().await {
+                Ok(()) | Err(RecvError::Lagged(_)) => {
+                    Some((Message::DeliveryServiceRestarted, restarts))
+                }
+                Err(RecvError::Closed) => None,
+            }
+        });
+        context.forward(restarts);
+    }
+
+    async fn handle(&mut self, message: Message, context: &mut Context<Self>) {
         match message {
             Message::AddSocket(socket) => {
-                actor.sockets.push(socket);
+                self.sockets.push(socket);
             }
             Message::ProcessSocketMessage(source, message) => {
                 // Synchronize message to all other connected sockets for this user
-                for socket in &actor.sockets {
-                    if socket.id == source {
+                let frame = Arc::new(Frame::from(ClientMessage::SynchronizeMessage {
+                    message: message.clone(),
+                }));
+                for socket in &self.sockets {
+                    if source.as_ref() == Some(socket.id()) {
                         continue;
                     }
 
                     tracing::debug!("Syncing message");
-                    let result = socket.synchronize_message(message.clone()).await;
+                    let result = socket.send(frame.clone());
                     if let Err(error) = result {
                         tracing::error!("Error sending message to socket: {}", error);
+                        telemetry::record_actor_error(Self::NAME, "sync_message");
                     }
                 }
 
+                if !self.contacts.contains(&message.recipient) {
+                    self.request_contact(message.recipient.clone()).await;
+                }
+
                 // Send message to the user it is intended for through delivery service
-                let result = actor.delivery_service.send_message(message).await;
+                let result = self.delivery_service.send_message(message).await;
                 let Err(error) = result else {
-                    continue;
+                    return;
                 };
                 tracing::error!("Error sending message to delivery service: {:?}", error);
+                telemetry::record_actor_error(Self::NAME, "send_message");
             }
-            Message::ReceiveMessage(message) => {
-                // Creating a reference that is easier to clone for each loop iteration
+            Message::ReceiveMessage { message, is_muted } => {
+                let is_request = !self.contacts.contains(message.sender.as_str());
+                let message = if is_request {
+                    ClientMessage::MessageRequest {
+                        message,
+                        muted: is_muted,
+                    }
+                } else {
+                    ClientMessage::ChatMessage {
+                        message,
+                        muted: is_muted,
+                    }
+                };
+                // Encoded once for all devices on the same encoding instead of once per socket
                 //TODO this can easily be parallelized as it is fire and forget
-                for socket in &actor.sockets {
-                    let result = socket.send_message(message.clone()).await;
+                let frame = Arc::new(Frame::from(message));
+                for socket in &self.sockets {
+                    let result = socket.send(frame.clone());
                     if let Err(error) = result {
                         tracing::error!("Error sending message to socket: {}", error);
+                        telemetry::record_actor_error(Self::NAME, "receive_message");
                     }
                 }
             }
+            Message::Command(source, command) => self.run_command(source, command).await,
             Message::RemoveSocket(handle_id) => {
                 // Not using retain as it would need to go through all elements, and we can be fairly
                 // sure that the socket is only once in the list. Meaning after it was found the
                 // iterations would be useless.
-                let position = actor
+                let position = self
                     .sockets
                     .iter()
-                    .position(|handle| handle.id == handle_id);
+                    .position(|handle| *handle.id() == handle_id);
                 let Some(position) = position else {
                     // This is weird
                     tracing::warn!("Socket not found for deletion");
-                    continue;
+                    telemetry::record_actor_error(Self::NAME, "remove_socket");
+                    return;
                 };
 
-                let _ = actor.sockets.remove(position);
+                let _ = self.sockets.remove(position);
 
                 // If the socket is the last one, we can remove the user from the delivery service
-                if actor.sockets.is_empty() {
+                if self.sockets.is_empty() {
                     tracing::debug!("All sockets closed, removing user from delivery service");
-                    let result = actor.delivery_service.remove_user(actor.name.clone()).await;
+                    let result = self.delivery_service.remove_user(self.name.clone()).await;
                     // Shut down
                     if let Err(error) = result {
                         tracing::error!("Error removing user from delivery service: {:?}", error);
+                        telemetry::record_actor_error(Self::NAME, "remove_user");
+                    }
+                    let result = self.delivery_service.user_offline(self.name.clone()).await;
+                    if let Err(error) = result {
+                        tracing::error!("Error telling contacts the user went offline: {}", error);
+                        telemetry::record_actor_error(Self::NAME, "user_offline");
                     }
                     // Shut down anyway?
-                    break;
+                    context.stop();
                 }
             }
             Message::AddContact(user_name) => {
-                for socket in &actor.sockets {
-                    let result = socket.add_contact(user_name.clone()).await;
+                let frame = Arc::new(Frame::from(ClientMessage::AddUser { name: user_name }));
+                for socket in &self.sockets {
+                    let result = socket.send(frame.clone());
                     if let Err(error) = result {
                         tracing::error!("Error adding user to socket: {}", error);
+                        telemetry::record_actor_error(Self::NAME, "add_contact");
                     }
                 }
             }
             Message::RemoveContact(user_name) => {
-                for socket in &actor.sockets {
-                    let result = socket.remove_contact(user_name.clone()).await;
+                let frame = Arc::new(Frame::from(ClientMessage::RemoveUser { name: user_name }));
+                for socket in &self.sockets {
+                    let result = socket.send(frame.clone());
                     if let Err(error) = result {
                         tracing::error!("Error removing user from socket: {}", error);
+                        telemetry::record_actor_error(Self::NAME, "remove_contact");
+                    }
+                }
+            }
+            Message::Shutdown {
+                reconnect_after,
+                respond,
+            } => {
+                let mut closed = Vec::with_capacity(self.sockets.len());
+                for socket in &self.sockets {
+                    match socket.shutdown(reconnect_after) {
+                        Ok(receiver) => closed.push(receiver),
+                        Err(error) => {
+                            tracing::error!("Error shutting down socket: {}", error);
+                            telemetry::record_actor_error(Self::NAME, "shutdown");
+                        }
+                    }
+                }
+
+                for receiver in closed {
+                    let _ = receiver.await;
+                }
+
+                let _ = respond.send(());
+                context.stop();
+            }
+            Message::Notice(notice) => {
+                let target = match &notice {
+                    Notice::ContactsChanged => {
+                        self.load_contacts().await;
+                        None
+                    }
+                    Notice::Disconnect { socket } => socket.clone(),
+                    Notice::DeliveryFailed { .. }
+                    | Notice::Announcement { .. }
+                    | Notice::RetentionChanged { .. }
+                    | Notice::MessagesExpired { .. }
+                    | Notice::ConversationUpdated { .. } => None,
+                };
+
+                let frame = ClientMessage::from_notice(notice)
+                    .map(|message| Arc::new(Frame::from(message)));
+                let sockets = self
+                    .sockets
+                    .iter()
+                    .filter(|socket| target.as_ref().is_none_or(|id| socket.id().0 == *id));
+                for socket in sockets {
+                    let result = match &frame {
+                        Some(frame) => socket.send(frame.clone()),
+                        None => socket.close(),
+                    };
+                    if let Err(error) = result {
+                        tracing::error!("Error sending notice to socket: {}", error);
+                        telemetry::record_actor_error(Self::NAME, "notify");
                     }
                 }
             }
+            Message::GetSockets(respond) => {
+                let sockets = self.sockets.iter().map(socket::Handle::info).collect();
+                if respond.send(sockets).is_err() {
+                    tracing::error!("Error sending sockets back");
+                    telemetry::record_actor_error(Self::NAME, "respond");
+                }
+            }
+            Message::DeliveryServiceRestarted => {
+                let Some(address) = context.address() else {
+                    return;
+                };
+
+                tracing::debug!("Registering user with restarted delivery service");
+                let result = self
+                    .delivery_service
+                    .register_user(self.name.clone(), Handle { address })
+                    .await;
+                if let Err(error) = result {
+                    tracing::error!("Error registering user with delivery service: {}", error);
+                    telemetry::record_actor_error(Self::NAME, "register_user");
+                }
+            }
         }
     }
 }
 
 #[derive(Clone)]
 pub(crate) struct Handle {
-    sender: mpsc::Sender<Message>,
+    address: Addr<User>,
 }
 
 impl Handle {
-    pub(crate) fn new(name: Arc<str>, delivery_service: delivery_service::Handle) -> Self {
-        let (sender, receiver) = mpsc::channel(8);
-
+    pub(crate) fn new(
+        name: Arc<str>,
+        delivery_service: delivery_service::Handle,
+        storage: Storage,
+    ) -> Self {
         let actor = User {
             delivery_service,
+            storage,
             name,
-            receiver,
             // We know from context that a user will be only created if there is at least one socket
             sockets: Vec::with_capacity(1),
+            contacts: HashSet::new(),
         };
 
-        tokio::spawn(run_actor(actor));
+        let address = framework::spawn(actor);
 
-        Self { sender }
+        Self { address }
     }
 
-    pub(crate) async fn add_socket(&self, socket: websocket::Handle) -> Result<(), impl Error> {
-        self.sender.send(Message::AddSocket(socket)).await
+    pub(crate) async fn add_socket(&self, socket: socket::Handle) -> Result<(), HandleError> {
+        self.address.tell(Message::AddSocket(socket)).await
     }
 
     pub(super) async fn process_socket_message(
         &self,
-        source: SocketId,
+        source: Option<SocketId>,
         message: Arc<ChatMessage>,
-    ) -> Result<(), impl Error + Send + Sync> {
-        self.sender
-            .send(Message::ProcessSocketMessage(source, message))
+    ) -> Result<(), HandleError> {
+        self.address
+            .tell(Message::ProcessSocketMessage(source, message))
             .await
     }
 
+    /// Sends the message as the user from outside of the sockets, so all of them get it synchronized
+    #[cfg(feature = "grpc")]
+    pub(crate) async fn send_message(&self, message: Arc<ChatMessage>) -> Result<(), HandleError> {
+        self.process_socket_message(None, message).await
+    }
+
     pub(super) async fn receive_message(
         &self,
         message: Arc<ChatMessage>,
-    ) -> Result<(), impl Error> {
-        self.sender.send(Message::ReceiveMessage(message)).await
+        is_muted: bool,
+    ) -> Result<(), HandleError> {
+        self.address
+            .notify(Message::ReceiveMessage { message, is_muted })
+    }
+
+    pub(super) async fn run_command(
+        &self,
+        source: SocketId,
+        command: Command,
+    ) -> Result<(), HandleError> {
+        self.address.tell(Message::Command(source, command)).await
+    }
+
+    pub(super) async fn remove_socket(&self, socket_id: SocketId) -> Result<(), HandleError> {
+        self.address.tell(Message::RemoveSocket(socket_id)).await
+    }
+
+    pub(super) async fn add_contact(&self, user_name: Arc<str>) -> Result<(), HandleError> {
+        self.address.notify(Message::AddContact(user_name))
     }
 
-    pub(super) async fn remove_socket(&self, socket_id: SocketId) -> Result<(), impl Error> {
-        self.sender.send(Message::RemoveSocket(socket_id)).await
+    pub(super) async fn remove_contact(&self, user_name: Arc<str>) -> Result<(), HandleError> {
+        self.address.notify(Message::RemoveContact(user_name))
     }
 
-    pub(super) async fn add_contact(&self, user_name: Arc<str>) -> Result<(), impl Error> {
-        self.sender.send(Message::AddContact(user_name)).await
+    pub(super) async fn notify(&self, notice: Notice) -> Result<(), HandleError> {
+        self.address.notify(Message::Notice(notice))
     }
 
-    pub(super) async fn remove_contact(&self, user_name: Arc<str>) -> Result<(), impl Error> {
-        self.sender.send(Message::RemoveContact(user_name)).await
+    pub(super) async fn sockets(&self, timeout: Duration) -> Result<Vec<SocketInfo>, HandleError> {
+        self.address.ask(Message::GetSockets, timeout).await
+    }
+
+    /// Returns a receiver that resolves once all sockets of the user have been closed
+    pub(super) async fn shutdown(
+        &self,
+        reconnect_after: Duration,
+    ) -> Result<oneshot::Receiver<()>, HandleError> {
+        let (sender, receiver) = oneshot::channel();
+        self.address
+            .notify(Message::Shutdown {
+                reconnect_after,
+                respond: sender,
+            })
+            .map(|_| receiver)
     }
 }
@@ -1,166 +1,306 @@
-use ::axum::extract::ws::Message as WebSocketMessage;
+use ::axum::extract::ws::{close_code, CloseFrame, Message as WebSocketMessage};
 use axum::extract::ws as axum;
+use futures_util::stream::{self, SplitSink, SplitStream};
+use futures_util::{future, SinkExt, StreamExt};
 use nanoid::nanoid;
-use serde::Serialize;
+use std::net::IpAddr;
 use std::sync::Arc;
-use tokio::sync::mpsc;
+use std::time::Duration;
+use tokio::sync::oneshot;
 
-use super::{user, ChatMessage};
+use super::audit_log;
+use super::compression::{CompressionError, Deflate};
+use super::encoding::Encoding;
+use super::framework::{self, Actor, Addr, Context};
+use super::socket::{
+    Client, ClientMessage, CloseReason, Connection, Frame, SocketId, SocketInfo, TooSlow,
+    SEND_QUEUE_CAPACITY, SEND_TIMEOUT,
+};
+use super::{user, HandleError};
+use crate::telemetry;
 
 enum Message {
-    SendMessage(Arc<ChatMessage>),
-    AddContact { name: Arc<str> },
-    RemoveContact { name: Arc<str> },
-    SynchronizeMessage { message: Arc<ChatMessage> },
+    Send(Arc<Frame>),
+    Disconnect(CloseReason),
+    Shutdown {
+        reconnect_after: Duration,
+        respond: oneshot::Sender<()>,
+    },
+    /// A frame the client sent through the socket
+    Received(Result<WebSocketMessage, ::axum::Error>),
+    /// The client went away without closing the socket
+    Disconnected,
 }
 
-#[derive(Serialize)]
-#[serde(tag = "type")]
-enum ClientMessage {
-    ChatMessage { message: Arc<ChatMessage> },
-    AddUser { name: Arc<str> },
-    RemoveUser { name: Arc<str> },
-    SynchronizeMessage { message: Arc<ChatMessage> },
-}
-
-#[derive(Clone, PartialEq, Eq)]
-pub(super) struct SocketId(Arc<str>);
-
 /// A websocket actor represents a single websocket connection to a users device
 struct WebSocket {
-    id: SocketId,
-    socket: axum::WebSocket,
-    receiver: mpsc::Receiver<Message>,
-    /// The user that is connected through this websocket
-    user: user::Handle,
+    client: Client,
+    /// What the client agreed on through the subprotocol
+    encoding: Encoding,
+    /// Set if the client agreed on compressed messages, which are then binary frames
+    compression: Option<Deflate>,
+    sink: SplitSink<axum::WebSocket, WebSocketMessage>,
+    /// Only set until the actor started and forwards it to itself
+    stream: Option<SplitStream<axum::WebSocket>>,
 }
 
-async fn process_socket_message(id: SocketId, user: &user::Handle, json: String) {
-    //TODO validate message sender name and send back error if not
-    let result = serde_json::from_str::<ChatMessage>(&json);
-    let message = match result {
-        Ok(message) => message,
-        Err(error) => {
-            tracing::error!("Error deserializing message: {:?}", error);
-            return;
+impl WebSocket {
+    async fn process_socket_message(&mut self, frame: &[u8]) {
+        let reply = self
+            .client
+            .process_encoded(Self::NAME, self.encoding, frame)
+            .await;
+        if let Some(reply) = reply {
+            // A client that is too slow is disconnected with the next frame sent to it
+            let _ = self.send_to_socket(&reply.into()).await;
         }
-    };
-
-    let result = user.process_socket_message(id, message.into()).await;
-    if let Err(error) = result {
-        tracing::error!("Error processing message: {:?}", error);
     }
-}
 
-async fn send_to_socket(socket: &mut axum::WebSocket, message: ClientMessage) {
-    let json = serde_json::to_string(&message);
-    let result = match json {
-        Ok(json) => socket.send(WebSocketMessage::Text(json)).await,
-        Err(error) => {
-            tracing::error!("Error serializing message: {:?}", error);
+    /// Decompresses the message and closes the socket if that is not possible, as the following
+    /// messages can't be decompressed either then
+    async fn process_compressed_message(&mut self, frame: &[u8], context: &mut Context<Self>) {
+        let Some(compression) = &mut self.compression else {
             return;
-        }
-    };
+        };
 
-    if let Err(error) = result {
-        tracing::error!("Error sending message through websocket: {:?}", error);
+        let (code, reason) = match compression.decompress(frame) {
+            Ok(message) => return self.process_socket_message(&message).await,
+            Err(error @ CompressionError::TooLarge(_)) => {
+                tracing::warn!("Closing websocket: {}", error);
+                telemetry::record_actor_error(Self::NAME, "message_too_large");
+                (close_code::SIZE, "Message too big")
+            }
+            Err(error) => {
+                tracing::error!("Error decompressing message: {}", error);
+                telemetry::record_actor_error(Self::NAME, "decompress");
+                (close_code::INVALID, "Message could not be decompressed")
+            }
+        };
+        self.close(code, reason).await;
+        self.client.remove_from_user(Self::NAME).await;
+        context.stop();
     }
-}
 
-async fn process_actor_message(socket: &mut axum::WebSocket, message: Message) {
-    match message {
-        Message::SendMessage(message) => {
-            let message = ClientMessage::ChatMessage { message };
-            send_to_socket(socket, message).await;
-        }
-        Message::AddContact { name } => {
-            let message = ClientMessage::AddUser { name };
-            send_to_socket(socket, message).await;
+    async fn send_to_socket(&mut self, frame: &Frame) -> Result<(), TooSlow> {
+        let frame = match frame.encoded(self.encoding) {
+            Ok(frame) => frame.clone(),
+            Err(error) => {
+                tracing::error!("Error serializing message: {:?}", error);
+                telemetry::record_actor_error(Self::NAME, "serialize");
+                return Ok(());
+            }
+        };
+
+        // Only the encoded frame is shared between sockets, as each compresses with its own history
+        let frame = match &mut self.compression {
+            None => frame,
+            Some(compression) => match compression.compress(&frame.into_data()) {
+                Ok(compressed) => WebSocketMessage::Binary(compressed.into()),
+                Err(error) => {
+                    tracing::error!("Error compressing message: {}", error);
+                    telemetry::record_actor_error(Self::NAME, "compress");
+                    return Ok(());
+                }
+            },
+        };
+
+        let result = tokio::time::timeout(SEND_TIMEOUT, self.sink.send(frame))
+            .await
+            .map_err(|_| TooSlow)?;
+
+        if let Err(error) = result {
+            tracing::error!("Error sending message through websocket: {:?}", error);
+            telemetry::record_actor_error(Self::NAME, "send");
         }
-        Message::RemoveContact { name } => {
-            let message = ClientMessage::RemoveUser { name };
-            send_to_socket(socket, message).await;
+        Ok(())
+    }
+
+    async fn close(&mut self, code: u16, reason: &'static str) {
+        let close = WebSocketMessage::Close(Some(CloseFrame {
+            code,
+            reason: reason.into(),
+        }));
+        match tokio::time::timeout(SEND_TIMEOUT, self.sink.send(close)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(error)) => {
+                tracing::error!("Error closing websocket: {:?}", error);
+                telemetry::record_actor_error(Self::NAME, "close");
+            }
+            Err(_) => tracing::warn!("Websocket was not closed in time"),
         }
-        Message::SynchronizeMessage { message } => {
-            let message = ClientMessage::SynchronizeMessage { message };
-            send_to_socket(socket, message).await;
+    }
+
+    async fn disconnect(&mut self, reason: CloseReason, context: &mut Context<Self>) {
+        match reason {
+            CloseReason::Operator => {
+                tracing::info!("Disconnecting websocket on behalf of an operator");
+                self.close(close_code::POLICY, "Disconnected by an operator")
+                    .await;
+            }
+            // The close frame would only wait behind the frames the client does not take
+            CloseReason::TooSlow => tracing::info!("Disconnecting websocket that is too slow"),
         }
+        self.client.remove_from_user(Self::NAME).await;
+        context.stop();
     }
 }
 
-async fn run_actor(mut actor: WebSocket) {
-    loop {
-        tokio::select! {
-            Some(message) = actor.receiver.recv() => process_actor_message(&mut actor.socket, message).await,
-            // Stop actor on error
-            Some(Ok(message)) = actor.socket.recv() => {
-                match message {
-                    WebSocketMessage::Close(_) => {
-                        //TODO remove socket from user to clean up or we have a memory leak
-                        tracing::info!("Closing websocket");
-                        let result = actor.user.remove_socket(actor.id.clone()).await;
-                        let Err(error) = result else { break };
-                        tracing::error!("Error removing socket from user: {:?}", error);
-                        break;
-                    },
-                    WebSocketMessage::Text(text) => process_socket_message(actor.id.clone(), &actor.user, text.into()).await,
-                    other => tracing::error!("Unexpected message type: {:?}", other),
+impl Actor for WebSocket {
+    type Message = Message;
+    const NAME: &'static str = "websocket";
+
+    async fn started(&mut self, context: &mut Context<Self>) {
+        metrics::gauge!(telemetry::WEBSOCKET_CONNECTIONS).increment(1);
+        let Some(stream) = self.stream.take() else {
+            return;
+        };
+
+        // The end of the stream is forwarded too to clean up if the connection dropped without
+        // closing
+        let stream = stream
+            .map(Message::Received)
+            .chain(stream::once(future::ready(Message::Disconnected)));
+        context.forward(stream);
+    }
+
+    async fn handle(&mut self, message: Message, context: &mut Context<Self>) {
+        match message {
+            Message::Send(frame) => {
+                if self.send_to_socket(&frame).await.is_err() {
+                    self.disconnect(CloseReason::TooSlow, context).await;
+                }
+            }
+            Message::Disconnect(reason) => self.disconnect(reason, context).await,
+            Message::Shutdown {
+                reconnect_after,
+                respond,
+            } => {
+                let message = ClientMessage::ServerShutdown {
+                    reconnect_after: reconnect_after.as_millis() as u64,
+                };
+                let _ = self.send_to_socket(&message.into()).await;
+                self.close(close_code::RESTART, "Server shutting down")
+                    .await;
+
+                let _ = respond.send(());
+                context.stop();
+            }
+            Message::Received(Ok(message)) => match message {
+                WebSocketMessage::Close(_) => {
+                    tracing::info!("Closing websocket");
+                    self.client.remove_from_user(Self::NAME).await;
+                    context.stop();
+                }
+                WebSocketMessage::Binary(bytes) if self.compression.is_some() => {
+                    self.process_compressed_message(&bytes, context).await;
+                }
+                // Text frames are always uncompressed JSON, which clients may send on compressed
+                // sockets as well, as browsers can't flush a compression stream after each message
+                WebSocketMessage::Text(text) if self.encoding == Encoding::Json => {
+                    self.process_socket_message(text.as_bytes()).await;
+                }
+                WebSocketMessage::Binary(bytes) if self.encoding != Encoding::Json => {
+                    self.process_socket_message(&bytes).await;
+                }
+                other => {
+                    tracing::error!("Unexpected message type: {:?}", other);
+                    telemetry::record_actor_error(Self::NAME, "unexpected_frame");
                 }
             },
-            else => break,
+            // Stop actor on error
+            Message::Received(Err(error)) => {
+                tracing::error!("Error receiving from websocket: {:?}", error);
+                telemetry::record_actor_error(Self::NAME, "receive");
+                self.client.remove_from_user(Self::NAME).await;
+                context.stop();
+            }
+            Message::Disconnected => {
+                tracing::info!("Websocket disconnected");
+                self.client.remove_from_user(Self::NAME).await;
+                context.stop();
+            }
         }
     }
+
+    async fn stopped(&mut self) {
+        metrics::gauge!(telemetry::WEBSOCKET_CONNECTIONS).decrement(1);
+    }
 }
 
 #[derive(Clone)]
 pub(crate) struct Handle {
-    pub(super) id: SocketId,
-    sender: mpsc::Sender<Message>,
+    id: SocketId,
+    remote: IpAddr,
+    address: Addr<WebSocket>,
 }
 
 impl Handle {
-    pub(crate) fn new(socket: axum::WebSocket, user: user::Handle) -> Self {
-        let (sender, receiver) = mpsc::channel(8);
+    pub(crate) fn new(
+        socket: axum::WebSocket,
+        user: user::Handle,
+        audit_log: audit_log::Handle,
+        name: Arc<str>,
+        remote: IpAddr,
+        encoding: Encoding,
+        compression: Option<Deflate>,
+    ) -> Self {
         // I'd prefer a simple index to a full string to identify a socket for a user but this is
         // simpler to set up right now. Feel free to find a better solution.
         let id = SocketId(nanoid!().into());
 
+        let (sink, stream) = socket.split();
         let socket = WebSocket {
-            id: id.clone(),
-            socket,
-            receiver,
-            user,
+            client: Client {
+                id: id.clone(),
+                user,
+                name,
+                remote,
+                audit_log,
+            },
+            encoding,
+            compression,
+            sink,
+            stream: Some(stream),
         };
 
-        tokio::spawn(run_actor(socket));
+        let address = framework::spawn_with_capacity(socket, SEND_QUEUE_CAPACITY);
+
+        Self {
+            address,
+            id,
+            remote,
+        }
+    }
+}
 
-        Self { sender, id }
+impl Connection for Handle {
+    fn id(&self) -> &SocketId {
+        &self.id
     }
 
-    pub(super) async fn send_message(
-        &self,
-        message: Arc<ChatMessage>,
-    ) -> Result<(), impl std::error::Error> {
-        self.sender.send(Message::SendMessage(message)).await
+    fn info(&self) -> SocketInfo {
+        SocketInfo {
+            id: self.id.0.clone(),
+            transport: WebSocket::NAME,
+            remote: self.remote,
+        }
     }
 
-    pub(super) async fn add_contact(&self, name: Arc<str>) -> Result<(), impl std::error::Error> {
-        self.sender.send(Message::AddContact { name }).await
+    fn send(&self, frame: Arc<Frame>) -> Result<(), HandleError> {
+        self.address.try_tell(Message::Send(frame))
     }
 
-    pub(super) async fn remove_contact(
-        &self,
-        name: Arc<str>,
-    ) -> Result<(), impl std::error::Error> {
-        self.sender.send(Message::RemoveContact { name }).await
+    fn close(&self, reason: CloseReason) -> Result<(), HandleError> {
+        self.address.notify(Message::Disconnect(reason))
     }
 
-    pub(super) async fn synchronize_message(
-        &self,
-        message: Arc<ChatMessage>,
-    ) -> Result<(), impl std::error::Error> {
-        self.sender
-            .send(Message::SynchronizeMessage { message })
-            .await
+    fn shutdown(&self, reconnect_after: Duration) -> Result<oneshot::Receiver<()>, HandleError> {
+        let (sender, receiver) = oneshot::channel();
+        self.address
+            .notify(Message::Shutdown {
+                reconnect_after,
+                respond: sender,
+            })
+            .map(|_| receiver)
     }
 }
@@ -0,0 +1,458 @@
+use crate::actor::audit_log::{AuditEvent, AuditQuery, AuditRecord};
+use crate::actor::{HandleError, Notice};
+use crate::archive;
+use crate::storage::{from_milliseconds, StorageError};
+use crate::AppState;
+use axum::extract::{ConnectInfo, DefaultBodyLimit, OriginalUri, Path, Query, Request, State};
+use axum::http::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use axum::http::{HeaderMap, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post, put};
+use axum::{Json, Router};
+use ring::digest::{self, SHA256};
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use time::OffsetDateTime;
+
+#[derive(Serialize)]
+pub(crate) struct Socket {
+    id: Arc<str>,
+    transport: &'static str,
+    address: IpAddr,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ConnectedUser {
+    name: Arc<str>,
+    sockets: Vec<Socket>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct Ban<T> {
+    banned: T,
+    #[serde(with = "time::serde::timestamp::milliseconds")]
+    time_utc: OffsetDateTime,
+}
+
+#[derive(Serialize)]
+pub(crate) struct Bans {
+    names: Vec<Ban<Arc<str>>>,
+    addresses: Vec<Ban<IpAddr>>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ImportSummary {
+    user: Arc<str>,
+    messages: usize,
+    /// Messages that were already stored
+    skipped_messages: usize,
+    /// Added messages whose id was taken by a different message, which got a new id
+    renumbered_messages: usize,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct Announcement {
+    text: String,
+}
+
+/// Filters for the audit log. Times are UTC unix timestamps in milliseconds.
+#[derive(Deserialize)]
+pub(crate) struct AuditParameters {
+    user: Option<String>,
+    event: Option<String>,
+    /// Inclusive
+    from: Option<i64>,
+    /// Exclusive
+    to: Option<i64>,
+    limit: Option<usize>,
+}
+
+/// How many records the audit log endpoint responds with if not asked for a different number
+const DEFAULT_AUDIT_LIMIT: usize = 1000;
+
+/// The API for operators to act on abuse. Every request needs the admin token as bearer token.
+pub(crate) fn router(state: AppState) -> Router<AppState> {
+    Router::new()
+        .route("/users", get(get_users))
+        .route("/users/{name}", delete(disconnect_user))
+        .route("/users/{name}/sockets/{socket}", delete(disconnect_socket))
+        .route("/users/{name}/messages", delete(delete_messages))
+        .route("/bans", get(get_bans))
+        .route("/bans/names/{name}", put(ban_name).delete(unban_name))
+        .route(
+            "/bans/addresses/{address}",
+            put(ban_address).delete(unban_address),
+        )
+        .route("/announcements", post(announce))
+        .route("/audit", get(get_audit_log))
+        // Archives hold all messages of a user, which can be more than requests usually have
+        .route("/import", post(import).layer(DefaultBodyLimit::disable()))
+        .route_layer(middleware::from_fn_with_state(state, authenticate))
+}
+
+async fn authenticate(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let Some(token) = &state.config.admin_token else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    if !is_authorized(request.headers(), token) {
+        // The path without the prefix the router is nested under otherwise
+        let path = request
+            .extensions()
+            .get::<OriginalUri>()
+            .map_or(request.uri(), |OriginalUri(uri)| uri)
+            .path()
+            .to_owned();
+        tracing::warn!("Unauthorized request to {}", path);
+        let address = request
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(address)| address.ip());
+        state
+            .audit_log
+            .record(AuditEvent::FailedAuthentication { address, path });
+        return (StatusCode::UNAUTHORIZED, [(WWW_AUTHENTICATE, "Bearer")]).into_response();
+    }
+
+    next.run(request).await
+}
+
+fn is_authorized(headers: &HeaderMap, token: &str) -> bool {
+    let given = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let Some(given) = given else {
+        return false;
+    };
+
+    // Compares every byte of digests, which have the same length whatever was sent, so that
+    // neither the length nor the content of the token can be told from the response time
+    let given = digest::digest(&SHA256, given.as_bytes());
+    let expected = digest::digest(&SHA256, token.as_bytes());
+    given
+        .as_ref()
+        .iter()
+        .zip(expected.as_ref())
+        .fold(0, |difference, (given, expected)| {
+            difference | (given ^ expected)
+        })
+        == 0
+}
+
+fn internal_error(error: StorageError) -> StatusCode {
+    tracing::error!("Error accessing the database: {}", error);
+    StatusCode::INTERNAL_SERVER_ERROR
+}
+
+fn actor_error(error: HandleError) -> StatusCode {
+    match error {
+        HandleError::Timeout(_) => {
+            tracing::warn!("Error reaching the delivery service: {}", error);
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+        error => {
+            tracing::error!("Error reaching the delivery service: {:?}", error);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+/// Closes the socket with the id or all sockets of the user, on whichever node they are connected
+async fn disconnect(
+    state: &AppState,
+    name: Arc<str>,
+    socket: Option<Arc<str>>,
+) -> Result<(), HandleError> {
+    state.audit_log.record(AuditEvent::ForcedDisconnect {
+        user: name.clone(),
+        socket: socket.clone(),
+    });
+    state
+        .delivery_service
+        .notify(name, Notice::Disconnect { socket })
+        .await
+}
+
+/// The users connected to the server instance that answers the request
+pub(crate) async fn get_users(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<ConnectedUser>>, StatusCode> {
+    let users = state
+        .delivery_service
+        .connected_users()
+        .await
+        .map_err(actor_error)?;
+
+    let users = users
+        .into_iter()
+        .map(|(name, sockets)| ConnectedUser {
+            name,
+            sockets: sockets
+                .into_iter()
+                .map(|socket| Socket {
+                    id: socket.id,
+                    transport: socket.transport,
+                    address: socket.remote,
+                })
+                .collect(),
+        })
+        .collect();
+    Ok(Json(users))
+}
+
+/// Responds with `202 Accepted` as the sockets are closed after responding
+pub(crate) async fn disconnect_user(
+    Path(name): Path<String>,
+    State(state): State<AppState>,
+) -> StatusCode {
+    match disconnect(&state, name.into(), None).await {
+        Ok(()) => StatusCode::ACCEPTED,
+        Err(error) => actor_error(error),
+    }
+}
+
+pub(crate) async fn disconnect_socket(
+    Path((name, socket)): Path<(String, String)>,
+    State(state): State<AppState>,
+) -> StatusCode {
+    match disconnect(&state, name.into(), Some(socket.into())).await {
+        Ok(()) => StatusCode::ACCEPTED,
+        Err(error) => actor_error(error),
+    }
+}
+
+/// Deletes the messages the user sent, including the ones sent with contact requests
+pub(crate) async fn delete_messages(
+    Path(name): Path<String>,
+    State(state): State<AppState>,
+) -> StatusCode {
+    let name: Arc<str> = name.into();
+    let deleted = match state.storage.delete_messages_from(name.clone()).await {
+        Ok(deleted) => deleted,
+        Err(error) => return internal_error(error),
+    };
+    state
+        .audit_log
+        .record(AuditEvent::MessagesDeleted { user: name });
+
+    // The messages are shown with the requests, which the clients load again
+    for recipient in deleted.request_recipients {
+        let result = state
+            .delivery_service
+            .notify(recipient, Notice::ContactsChanged)
+            .await;
+        if let Err(error) = result {
+            tracing::error!("Error notifying about deleted messages: {}", error);
+        }
+    }
+    state
+        .delivery_service
+        .notify_conversations(deleted.conversations)
+        .await;
+    StatusCode::NO_CONTENT
+}
+
+/// Stores an archive from `GET /export` of this or another deployment, keeping the message ids
+/// where they are free
+pub(crate) async fn import(State(state): State<AppState>, body: String) -> Response {
+    let (user, archive) = match archive::read(&body) {
+        Ok(read) => read,
+        Err(error) => return (StatusCode::BAD_REQUEST, error.to_string()).into_response(),
+    };
+
+    let imported = match state.storage.import(user.clone(), archive).await {
+        Ok(imported) => imported,
+        Err(error) => return internal_error(error).into_response(),
+    };
+    state
+        .audit_log
+        .record(AuditEvent::ArchiveImported { user: user.clone() });
+
+    // Connected devices don't know about the imported contacts yet
+    let result = state
+        .delivery_service
+        .notify(user.clone(), Notice::ContactsChanged)
+        .await;
+    if let Err(error) = result {
+        tracing::error!("Error notifying about imported contacts: {}", error);
+    }
+    state
+        .delivery_service
+        .notify_conversations(imported.conversations)
+        .await;
+
+    Json(ImportSummary {
+        user,
+        messages: imported.messages,
+        skipped_messages: imported.skipped_messages,
+        renumbered_messages: imported.renumbered_messages,
+    })
+    .into_response()
+}
+
+pub(crate) async fn get_bans(State(state): State<AppState>) -> Result<Json<Bans>, StatusCode> {
+    let bans = state.storage.bans().await.map_err(internal_error)?;
+
+    Ok(Json(Bans {
+        names: bans
+            .names
+            .into_iter()
+            .map(|ban| Ban {
+                banned: ban.banned,
+                time_utc: ban.time_utc,
+            })
+            .collect(),
+        addresses: bans
+            .addresses
+            .into_iter()
+            .map(|ban| Ban {
+                banned: ban.banned,
+                time_utc: ban.time_utc,
+            })
+            .collect(),
+    }))
+}
+
+/// Keeps the user from connecting and disconnects all of their sockets
+pub(crate) async fn ban_name(
+    Path(name): Path<String>,
+    State(state): State<AppState>,
+) -> StatusCode {
+    let name: Arc<str> = name.into();
+    if let Err(error) = state.storage.ban_name(name.clone()).await {
+        return internal_error(error);
+    }
+    state
+        .audit_log
+        .record(AuditEvent::NameBanned { user: name.clone() });
+
+    match disconnect(&state, name, None).await {
+        Ok(()) => StatusCode::NO_CONTENT,
+        Err(error) => actor_error(error),
+    }
+}
+
+pub(crate) async fn unban_name(
+    Path(name): Path<String>,
+    State(state): State<AppState>,
+) -> StatusCode {
+    let name: Arc<str> = name.into();
+    match state.storage.unban_name(name.clone()).await {
+        Ok(true) => {
+            state
+                .audit_log
+                .record(AuditEvent::NameUnbanned { user: name });
+            StatusCode::NO_CONTENT
+        }
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(error) => internal_error(error),
+    }
+}
+
+/// Keeps the address from connecting and disconnects the sockets connected from it to this server
+/// instance
+pub(crate) async fn ban_address(
+    Path(address): Path<IpAddr>,
+    State(state): State<AppState>,
+) -> StatusCode {
+    if let Err(error) = state.storage.ban_address(address).await {
+        return internal_error(error);
+    }
+    state
+        .audit_log
+        .record(AuditEvent::AddressBanned { address });
+
+    let users = match state.delivery_service.connected_users().await {
+        Ok(users) => users,
+        Err(error) => return actor_error(error),
+    };
+    for (name, sockets) in users {
+        for socket in sockets {
+            if socket.remote != address {
+                continue;
+            }
+
+            if let Err(error) = disconnect(&state, name.clone(), Some(socket.id)).await {
+                return actor_error(error);
+            }
+        }
+    }
+    StatusCode::NO_CONTENT
+}
+
+pub(crate) async fn unban_address(
+    Path(address): Path<IpAddr>,
+    State(state): State<AppState>,
+) -> StatusCode {
+    match state.storage.unban_address(address).await {
+        Ok(true) => {
+            state
+                .audit_log
+                .record(AuditEvent::AddressUnbanned { address });
+            StatusCode::NO_CONTENT
+        }
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(error) => internal_error(error),
+    }
+}
+
+/// Shows the text to everyone connected to any server instance
+pub(crate) async fn announce(
+    State(state): State<AppState>,
+    Json(announcement): Json<Announcement>,
+) -> StatusCode {
+    if announcement.text.trim().is_empty() {
+        return StatusCode::BAD_REQUEST;
+    }
+
+    let text: Arc<str> = announcement.text.into();
+    state
+        .audit_log
+        .record(AuditEvent::Announcement { text: text.clone() });
+    match state.delivery_service.announce(text).await {
+        Ok(()) => StatusCode::ACCEPTED,
+        Err(error) => actor_error(error),
+    }
+}
+
+/// The latest records of the audit log of this server instance matching the filters, oldest first
+pub(crate) async fn get_audit_log(
+    Query(parameters): Query<AuditParameters>,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<AuditRecord>>, StatusCode> {
+    let query = AuditQuery {
+        user: parameters.user,
+        event: parameters.event,
+        from: parameters.from.map(from_milliseconds),
+        to: parameters.to.map(from_milliseconds),
+        limit: parameters.limit.unwrap_or(DEFAULT_AUDIT_LIMIT),
+    };
+
+    let records = state.audit_log.query(query).await.map_err(actor_error)?;
+    Ok(Json(records))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let value = HeaderValue::from_str(&format!("Bearer {token}")).unwrap();
+        headers.insert(AUTHORIZATION, value);
+        headers
+    }
+
+    #[test]
+    fn accepts_only_the_exact_token() {
+        assert!(is_authorized(&bearer("secret"), "secret"));
+        assert!(!is_authorized(&bearer("secreT"), "secret"));
+        assert!(!is_authorized(&bearer("secret "), "secret"));
+        assert!(!is_authorized(&bearer(""), "secret"));
+        assert!(!is_authorized(&HeaderMap::new(), "secret"));
+    }
+}
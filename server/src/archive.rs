@@ -0,0 +1,258 @@
+use crate::actor::ChatMessage;
+use crate::sessions::Authenticated;
+use crate::storage::archive::{Archive, ContactRequest, ConversationSettings, StoredMessage};
+use crate::storage::retention::Retention;
+use crate::AppState;
+use axum::extract::State;
+use axum::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use thiserror::Error;
+use time::OffsetDateTime;
+
+/// Changed when archives of older versions can't be read like the current ones anymore
+const VERSION: u32 = 1;
+
+/// One line of an archive, which is a JSON lines file starting with the header
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type")]
+enum Record {
+    Header {
+        version: u32,
+        user: Arc<str>,
+        #[serde(with = "crate::actor::milliseconds")]
+        time_utc: OffsetDateTime,
+    },
+    Contact {
+        name: Arc<str>,
+    },
+    Blocked {
+        name: Arc<str>,
+    },
+    Muted {
+        name: Arc<str>,
+    },
+    Request {
+        sender: Arc<str>,
+        recipient: Arc<str>,
+        #[serde(with = "crate::actor::milliseconds")]
+        time_utc: OffsetDateTime,
+    },
+    Retention {
+        name: Arc<str>,
+        retention: Retention,
+    },
+    Conversation {
+        name: Arc<str>,
+        #[serde(default, with = "crate::actor::milliseconds::option")]
+        read_until_utc: Option<OffsetDateTime>,
+        #[serde(default)]
+        draft: Option<String>,
+        #[serde(default)]
+        archived: bool,
+    },
+    Message {
+        /// The id on the server the archive was exported from
+        id: i64,
+        message: ChatMessage,
+        #[serde(default)]
+        is_request: bool,
+        #[serde(default, with = "crate::actor::milliseconds::option")]
+        read_utc: Option<OffsetDateTime>,
+    },
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum ReadError {
+    #[error("Line {0} is not a record: {1}")]
+    Record(usize, serde_json::Error),
+    #[error("The archive does not start with a header")]
+    MissingHeader,
+    #[error("Archive version {0} is not supported")]
+    Version(u32),
+    #[error("Line {0} is a second header")]
+    SecondHeader(usize),
+    #[error("Line {0} is about a conversation the user of the archive is not part of")]
+    OtherConversation(usize),
+}
+
+/// Writes everything stored about the user of the session to an archive that can be imported into
+/// another deployment through the admin API
+pub(crate) async fn get_export(
+    Authenticated(user): Authenticated,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let archive = state.storage.export(user.clone()).await.map_err(|error| {
+        tracing::error!("Error exporting user: {}", error);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let body = write(user, archive).map_err(|error| {
+        tracing::error!("Error serializing archive: {}", error);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let headers = [
+        (CONTENT_TYPE, "application/x-ndjson"),
+        (
+            CONTENT_DISPOSITION,
+            "attachment; filename=\"melt-export.jsonl\"",
+        ),
+    ];
+    Ok((headers, body))
+}
+
+fn write(user: Arc<str>, archive: Archive) -> Result<String, serde_json::Error> {
+    let header = Record::Header {
+        version: VERSION,
+        user,
+        time_utc: OffsetDateTime::now_utc(),
+    };
+    let records = std::iter::once(header)
+        .chain(
+            archive
+                .contacts
+                .into_iter()
+                .map(|name| Record::Contact { name }),
+        )
+        .chain(
+            archive
+                .blocked
+                .into_iter()
+                .map(|name| Record::Blocked { name }),
+        )
+        .chain(archive.muted.into_iter().map(|name| Record::Muted { name }))
+        .chain(archive.requests.into_iter().map(
+            |ContactRequest {
+                 sender,
+                 recipient,
+                 time_utc,
+             }| Record::Request {
+                sender,
+                recipient,
+                time_utc,
+            },
+        ))
+        .chain(
+            archive
+                .retention
+                .into_iter()
+                .map(|(name, retention)| Record::Retention { name, retention }),
+        )
+        .chain(archive.conversations.into_iter().map(
+            |ConversationSettings {
+                 name,
+                 read_until_utc,
+                 draft,
+                 archived,
+             }| Record::Conversation {
+                name,
+                read_until_utc,
+                draft,
+                archived,
+            },
+        ))
+        .chain(archive.messages.into_iter().map(
+            |StoredMessage {
+                 id,
+                 message,
+                 is_request,
+                 read_utc,
+             }| Record::Message {
+                id,
+                message,
+                is_request,
+                read_utc,
+            },
+        ));
+
+    let mut body = String::new();
+    for record in records {
+        body += &serde_json::to_string(&record)?;
+        body.push('\n');
+    }
+    Ok(body)
+}
+
+/// Reads an archive written by [`get_export`] and returns whose it is
+pub(crate) fn read(archive: &str) -> Result<(Arc<str>, Archive), ReadError> {
+    let mut user = None;
+    let mut read = Archive::default();
+    let lines = archive
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line))
+        .filter(|(_, line)| !line.trim().is_empty());
+    for (number, line) in lines {
+        let record =
+            serde_json::from_str(line).map_err(|error| ReadError::Record(number, error))?;
+        let Some(user) = &user else {
+            let Record::Header {
+                version,
+                user: name,
+                ..
+            } = record
+            else {
+                return Err(ReadError::MissingHeader);
+            };
+            if version != VERSION {
+                return Err(ReadError::Version(version));
+            }
+            user = Some(name);
+            continue;
+        };
+
+        match record {
+            Record::Header { .. } => return Err(ReadError::SecondHeader(number)),
+            Record::Contact { name } => read.contacts.push(name),
+            Record::Blocked { name } => read.blocked.push(name),
+            Record::Muted { name } => read.muted.push(name),
+            Record::Request {
+                sender,
+                recipient,
+                time_utc,
+            } => {
+                if sender != *user && recipient != *user {
+                    return Err(ReadError::OtherConversation(number));
+                }
+                read.requests.push(ContactRequest {
+                    sender,
+                    recipient,
+                    time_utc,
+                });
+            }
+            Record::Retention { name, retention } => read.retention.push((name, retention)),
+            Record::Conversation {
+                name,
+                read_until_utc,
+                draft,
+                archived,
+            } => read.conversations.push(ConversationSettings {
+                name,
+                read_until_utc,
+                draft,
+                archived,
+            }),
+            Record::Message {
+                id,
+                message,
+                is_request,
+                read_utc,
+            } => {
+                if message.sender != **user && message.recipient != *user {
+                    return Err(ReadError::OtherConversation(number));
+                }
+                read.messages.push(StoredMessage {
+                    id,
+                    message,
+                    is_request,
+                    read_utc,
+                });
+            }
+        }
+    }
+
+    let user = user.ok_or(ReadError::MissingHeader)?;
+    Ok((user, read))
+}
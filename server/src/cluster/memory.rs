@@ -0,0 +1,49 @@
+use super::{Bus, BusError, Subscription};
+use bytes::Bytes;
+use futures_util::future::BoxFuture;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+/// A bus within the process. With only one server instance this is all that is needed, and
+/// multiple delivery services sharing a clone of it behave like a cluster.
+#[derive(Clone, Default)]
+pub(crate) struct MemoryBus {
+    subscribers_by_subject: Arc<Mutex<HashMap<String, Vec<mpsc::UnboundedSender<Bytes>>>>>,
+}
+
+impl Bus for MemoryBus {
+    fn publish(&self, subject: String, payload: Bytes) -> BoxFuture<'_, Result<(), BusError>> {
+        Box::pin(async move {
+            let subscribers = {
+                // Not holding the lock across the await
+                let mut subscribers_by_subject = self.subscribers_by_subject.lock().unwrap();
+                let Some(subscribers) = subscribers_by_subject.get_mut(&subject) else {
+                    return Ok(());
+                };
+                subscribers.retain(|subscriber| !subscriber.is_closed());
+                subscribers.clone()
+            };
+
+            for subscriber in subscribers {
+                // A subscriber going away in the meantime is the same as it not being there
+                let _ = subscriber.send(payload.clone());
+            }
+
+            Ok(())
+        })
+    }
+
+    fn subscribe(&self, subject: String) -> BoxFuture<'_, Result<Subscription, BusError>> {
+        Box::pin(async move {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            self.subscribers_by_subject
+                .lock()
+                .unwrap()
+                .entry(subject)
+                .or_default()
+                .push(sender);
+            Ok(Subscription { receiver })
+        })
+    }
+}
@@ -0,0 +1,57 @@
+use bytes::Bytes;
+use futures_util::future::BoxFuture;
+use futures_util::stream::BoxStream;
+use futures_util::{stream, Stream, StreamExt};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+pub(crate) mod memory;
+pub(crate) mod nats;
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum BusError {
+    #[error("Error connecting to the bus: {0}")]
+    Connect(#[source] std::io::Error),
+    #[error("Unexpected answer from the bus: {0}")]
+    Protocol(String),
+    #[error("The connection to the bus is closed")]
+    Closed,
+}
+
+/// Payloads published to a subject the subscription is for.
+/// Publishing never waits for subscribers to catch up, as the delivery service subscribes to what
+/// it publishes itself and would end up waiting on its own mailbox.
+pub(crate) struct Subscription {
+    receiver: mpsc::UnboundedReceiver<Bytes>,
+}
+
+impl Subscription {
+    pub(crate) fn into_stream(self) -> impl Stream<Item = Bytes> + Send {
+        stream::unfold(self.receiver, |mut receiver| async move {
+            let payload = receiver.recv().await?;
+            Some((payload, receiver))
+        })
+    }
+}
+
+/// Connects the server instances of a cluster so that they can reach each other's users.
+/// Messages are fire and forget and there is no guarantee that they arrive, just like a websocket
+/// message to a client that is about to disconnect.
+pub(crate) trait Bus: Send + Sync + 'static {
+    fn publish(&self, subject: String, payload: Bytes) -> BoxFuture<'_, Result<(), BusError>>;
+
+    fn subscribe(&self, subject: String) -> BoxFuture<'_, Result<Subscription, BusError>>;
+
+    /// Yields whenever the bus got its connection back after losing it. What was published in
+    /// between is lost, so the nodes need to tell each other about their users again.
+    fn reconnections(&self) -> BoxStream<'static, ()> {
+        stream::pending().boxed()
+    }
+}
+
+/// This server instance as part of the cluster
+#[derive(Clone)]
+pub(crate) struct Node {
+    pub(crate) id: Arc<str>,
+    pub(crate) bus: Arc<dyn Bus>,
+}
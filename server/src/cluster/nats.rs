@@ -0,0 +1,475 @@
+use super::{Bus, BusError, Subscription};
+use bytes::{BufMut, Bytes, BytesMut};
+use futures_util::future::BoxFuture;
+use futures_util::stream::{self, BoxStream};
+use futures_util::StreamExt;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, mpsc};
+
+/// How long to wait before connecting again after the connection was lost. Doubles with every
+/// failed attempt up to [`MAX_RECONNECT_DELAY`].
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+struct Subscriber {
+    /// To subscribe again after reconnecting
+    subject: String,
+    sender: mpsc::UnboundedSender<Bytes>,
+}
+
+type Subscribers = Arc<Mutex<HashMap<u64, Subscriber>>>;
+
+/// A bus using a NATS server (https://docs.nats.io/reference/reference-protocols/nats-protocol).
+/// This only implements the part of the text protocol the cluster needs, which is publishing and
+/// subscribing without queue groups or replies. Anything speaking that part of the protocol works as
+/// a server.
+/// If the connection is lost, the bus connects again and renews its subscriptions. What is published
+/// in the meantime is dropped.
+pub(crate) struct NatsBus {
+    /// Protocol commands to write to the connection
+    commands: mpsc::Sender<Bytes>,
+    subscribers_by_id: Subscribers,
+    next_subscription_id: AtomicU64,
+    reconnections: broadcast::Sender<()>,
+}
+
+impl NatsBus {
+    /// Connects to a server at an address like `nats://localhost:4222`. Only the first connection
+    /// has to succeed right away.
+    pub(crate) async fn connect(url: &str) -> Result<Self, BusError> {
+        let address = url.strip_prefix("nats://").unwrap_or(url).to_owned();
+        let (reader, writer) = connect(&address).await?;
+
+        let (commands, receiver) = mpsc::channel(64);
+        let subscribers_by_id = Subscribers::default();
+        let (reconnections, _) = broadcast::channel(1);
+        let connection = Connection {
+            address,
+            commands: receiver,
+            replies: commands.downgrade(),
+            subscribers_by_id: subscribers_by_id.clone(),
+            reconnections: reconnections.clone(),
+        };
+        tokio::spawn(connection.run(reader, writer));
+
+        Ok(Self {
+            commands,
+            subscribers_by_id,
+            next_subscription_id: AtomicU64::new(1),
+            reconnections,
+        })
+    }
+}
+
+impl Bus for NatsBus {
+    fn publish(&self, subject: String, payload: Bytes) -> BoxFuture<'_, Result<(), BusError>> {
+        Box::pin(async move {
+            let header = format!("PUB {} {}\r\n", subject, payload.len());
+            let mut command = BytesMut::with_capacity(header.len() + payload.len() + 2);
+            command.put(header.as_bytes());
+            command.put(payload);
+            command.put(&b"\r\n"[..]);
+
+            self.commands
+                .send(command.freeze())
+                .await
+                .map_err(|_| BusError::Closed)
+        })
+    }
+
+    fn subscribe(&self, subject: String) -> BoxFuture<'_, Result<Subscription, BusError>> {
+        Box::pin(async move {
+            let id = self.next_subscription_id.fetch_add(1, Ordering::Relaxed);
+            let (sender, receiver) = mpsc::unbounded_channel();
+            let command = format!("SUB {} {}\r\n", subject, id);
+            let subscriber = Subscriber { subject, sender };
+            self.subscribers_by_id
+                .lock()
+                .unwrap()
+                .insert(id, subscriber);
+
+            self.commands
+                .send(command.into())
+                .await
+                .map_err(|_| BusError::Closed)?;
+
+            Ok(Subscription { receiver })
+        })
+    }
+
+    fn reconnections(&self) -> BoxStream<'static, ()> {
+        let receiver = self.reconnections.subscribe();
+        stream::unfold(receiver, |mut receiver| async move {
+            // Missed reconnections are the same as the last one
+            match receiver.recv().await {
+                Ok(()) | Err(broadcast::error::RecvError::Lagged(_)) => Some(((), receiver)),
+                Err(broadcast::error::RecvError::Closed) => None,
+            }
+        })
+        .boxed()
+    }
+}
+
+/// Connects and introduces itself to the server
+async fn connect(address: &str) -> Result<(BufReader<OwnedReadHalf>, OwnedWriteHalf), BusError> {
+    let stream = TcpStream::connect(address)
+        .await
+        .map_err(BusError::Connect)?;
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+
+    // The server greets with its info before anything else
+    let mut line = String::new();
+    reader
+        .read_line(&mut line)
+        .await
+        .map_err(BusError::Connect)?;
+    if !line.starts_with("INFO") {
+        return Err(BusError::Protocol(line));
+    }
+
+    writer
+        .write_all(b"CONNECT {\"verbose\":false,\"pedantic\":false,\"name\":\"melt\"}\r\n")
+        .await
+        .map_err(BusError::Connect)?;
+
+    Ok((reader, writer))
+}
+
+/// Writes the commands of the bus to the server for as long as the bus exists, connecting again
+/// whenever the connection is lost
+struct Connection {
+    address: String,
+    commands: mpsc::Receiver<Bytes>,
+    /// Weak so that the commands end once the bus is dropped
+    replies: mpsc::WeakSender<Bytes>,
+    subscribers_by_id: Subscribers,
+    reconnections: broadcast::Sender<()>,
+}
+
+impl Connection {
+    async fn run(mut self, reader: BufReader<OwnedReadHalf>, writer: OwnedWriteHalf) {
+        let mut connection = Some((reader, writer));
+        let mut delay = RECONNECT_DELAY;
+        loop {
+            if let Some((reader, writer)) = connection.take() {
+                if !self.write_commands(reader, writer).await {
+                    return;
+                }
+                tracing::warn!("NATS connection closed, connecting again");
+                delay = RECONNECT_DELAY;
+            }
+
+            if !self.drop_commands_for(delay).await {
+                return;
+            }
+
+            match self.reconnect().await {
+                Ok(reconnected) => {
+                    tracing::info!("Connected to NATS again");
+                    connection = Some(reconnected);
+                    // Nobody listening is fine
+                    let _ = self.reconnections.send(());
+                }
+                Err(error) => {
+                    tracing::error!("Error connecting to NATS again: {}", error);
+                    delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                }
+            }
+        }
+    }
+
+    async fn reconnect(&self) -> Result<(BufReader<OwnedReadHalf>, OwnedWriteHalf), BusError> {
+        let (reader, mut writer) = connect(&self.address).await?;
+
+        let subscriptions: String = self
+            .subscribers_by_id
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, subscriber)| format!("SUB {} {}\r\n", subscriber.subject, id))
+            .collect();
+        writer
+            .write_all(subscriptions.as_bytes())
+            .await
+            .map_err(BusError::Connect)?;
+
+        Ok((reader, writer))
+    }
+
+    /// Returns once the connection is lost, with false if the bus was dropped instead
+    async fn write_commands(
+        &mut self,
+        reader: BufReader<OwnedReadHalf>,
+        mut writer: OwnedWriteHalf,
+    ) -> bool {
+        let mut reading = tokio::spawn(read_messages(
+            reader,
+            self.replies.clone(),
+            self.subscribers_by_id.clone(),
+        ));
+
+        let is_open = loop {
+            tokio::select! {
+                _ = &mut reading => break true,
+                command = self.commands.recv() => {
+                    let Some(command) = command else {
+                        break false;
+                    };
+                    if let Err(error) = writer.write_all(&command).await {
+                        tracing::error!("Error writing to NATS connection: {:?}", error);
+                        break true;
+                    }
+                }
+            }
+        };
+
+        reading.abort();
+        is_open
+    }
+
+    /// Publishing does not wait for the connection to come back. Subscriptions are renewed from the
+    /// subscribers after reconnecting, so nothing is lost by dropping the commands.
+    /// Returns false if the bus was dropped in the meantime.
+    async fn drop_commands_for(&mut self, delay: Duration) -> bool {
+        let sleep = tokio::time::sleep(delay);
+        tokio::pin!(sleep);
+        loop {
+            tokio::select! {
+                () = &mut sleep => return true,
+                command = self.commands.recv() => {
+                    if command.is_none() {
+                        return false;
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn read_messages(
+    mut reader: BufReader<OwnedReadHalf>,
+    commands: mpsc::WeakSender<Bytes>,
+    subscribers_by_id: Subscribers,
+) {
+    let result = read_until_closed(&mut reader, &commands, &subscribers_by_id).await;
+    if let Err(error) = result {
+        tracing::error!("Error reading from NATS connection: {}", error);
+    }
+}
+
+async fn read_until_closed(
+    reader: &mut BufReader<OwnedReadHalf>,
+    commands: &mpsc::WeakSender<Bytes>,
+    subscribers_by_id: &Subscribers,
+) -> Result<(), BusError> {
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let length = reader
+            .read_line(&mut line)
+            .await
+            .map_err(BusError::Connect)?;
+        if length == 0 {
+            return Ok(());
+        }
+
+        let mut parts = line.split_whitespace();
+        match parts.next() {
+            // MSG <subject> <sid> [reply-to] <#bytes>
+            Some("MSG") => {
+                let arguments: Vec<&str> = parts.collect();
+                let (Some(id), Some(size)) = (arguments.get(1), arguments.last()) else {
+                    return Err(BusError::Protocol(line));
+                };
+                let (Ok(id), Ok(size)) = (id.parse::<u64>(), size.parse::<usize>()) else {
+                    return Err(BusError::Protocol(line));
+                };
+
+                // The payload is followed by a line break
+                let mut payload = vec![0; size + 2];
+                reader
+                    .read_exact(&mut payload)
+                    .await
+                    .map_err(BusError::Connect)?;
+                payload.truncate(size);
+
+                let subscriber = subscribers_by_id
+                    .lock()
+                    .unwrap()
+                    .get(&id)
+                    .map(|subscriber| subscriber.sender.clone());
+                let Some(subscriber) = subscriber else {
+                    continue;
+                };
+
+                if subscriber.send(payload.into()).is_err() {
+                    // Nobody listens anymore
+                    subscribers_by_id.lock().unwrap().remove(&id);
+                    send(commands, format!("UNSUB {}\r\n", id).into()).await;
+                }
+            }
+            Some("PING") => send(commands, Bytes::from_static(b"PONG\r\n")).await,
+            Some("-ERR") => tracing::error!("NATS server error: {}", line.trim_end()),
+            // INFO updates, PONG and +OK need no answer
+            _ => {}
+        }
+    }
+}
+
+async fn send(commands: &mpsc::WeakSender<Bytes>, command: Bytes) {
+    if let Some(commands) = commands.upgrade() {
+        let _ = commands.send(command).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use tokio::time::timeout;
+
+    type Routes = Arc<Mutex<Vec<(u64, String, String, mpsc::UnboundedSender<Bytes>)>>>;
+
+    /// Speaks just enough of the NATS protocol to route what the buses publish to their
+    /// subscriptions. Sending on the returned channel drops all connections.
+    async fn stand_in() -> (String, broadcast::Sender<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("nats://{}", listener.local_addr().unwrap());
+        let (disconnect, _) = broadcast::channel(1);
+        let routes = Routes::default();
+
+        let disconnect_all = disconnect.clone();
+        tokio::spawn(async move {
+            for client in 0.. {
+                let (stream, _) = listener.accept().await.unwrap();
+                let disconnect = disconnect_all.subscribe();
+                tokio::spawn(serve_client(client, stream, routes.clone(), disconnect));
+            }
+        });
+        (url, disconnect)
+    }
+
+    async fn serve_client(
+        client: u64,
+        stream: TcpStream,
+        routes: Routes,
+        mut disconnect: broadcast::Receiver<()>,
+    ) {
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let (sender, mut outgoing) = mpsc::unbounded_channel::<Bytes>();
+        let writing = tokio::spawn(async move {
+            while let Some(bytes) = outgoing.recv().await {
+                if writer.write_all(&bytes).await.is_err() {
+                    break;
+                }
+            }
+        });
+        sender.send(Bytes::from_static(b"INFO {}\r\n")).unwrap();
+
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = tokio::select! {
+                read = reader.read_line(&mut line) => read,
+                _ = disconnect.recv() => break,
+            };
+            if !matches!(read, Ok(length) if length > 0) {
+                break;
+            }
+
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            match parts[..] {
+                ["SUB", subject, id] => routes.lock().unwrap().push((
+                    client,
+                    subject.to_owned(),
+                    id.to_owned(),
+                    sender.clone(),
+                )),
+                ["UNSUB", id] => routes
+                    .lock()
+                    .unwrap()
+                    .retain(|route| route.0 != client || route.2 != id),
+                ["PUB", subject, size] => {
+                    let size: usize = size.parse().unwrap();
+                    let mut payload = vec![0; size + 2];
+                    reader.read_exact(&mut payload).await.unwrap();
+                    for (_, _, id, subscriber) in routes
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .filter(|route| route.1 == subject)
+                    {
+                        let header = format!("MSG {} {} {}\r\n", subject, id, size);
+                        let mut message = BytesMut::from(header.as_bytes());
+                        message.put(&payload[..]);
+                        let _ = subscriber.send(message.freeze());
+                    }
+                }
+                ["PING"] => sender.send(Bytes::from_static(b"PONG\r\n")).unwrap(),
+                _ => {}
+            }
+        }
+
+        routes.lock().unwrap().retain(|route| route.0 != client);
+        writing.abort();
+    }
+
+    async fn next(subscription: &mut Subscription) -> Bytes {
+        timeout(Duration::from_secs(5), subscription.receiver.recv())
+            .await
+            .expect("message should arrive in time")
+            .expect("subscription should still be open")
+    }
+
+    #[tokio::test]
+    async fn routes_published_messages_to_subscribers() {
+        let (url, _disconnect) = stand_in().await;
+        let first = NatsBus::connect(&url).await.unwrap();
+        let second = NatsBus::connect(&url).await.unwrap();
+
+        let mut subscription = first.subscribe("melt.test".to_owned()).await.unwrap();
+        let mut other = first.subscribe("melt.other".to_owned()).await.unwrap();
+        // Round-trips a message through the first bus to know the subscriptions arrived
+        first
+            .publish("melt.other".to_owned(), Bytes::from_static(b"ready"))
+            .await
+            .unwrap();
+        assert_eq!(next(&mut other).await, "ready");
+
+        second
+            .publish(
+                "melt.test".to_owned(),
+                Bytes::from_static(b"hello\r\nthere"),
+            )
+            .await
+            .unwrap();
+        assert_eq!(next(&mut subscription).await, "hello\r\nthere");
+    }
+
+    #[tokio::test]
+    async fn subscribes_again_after_reconnecting() {
+        let (url, disconnect) = stand_in().await;
+        let bus = NatsBus::connect(&url).await.unwrap();
+        let mut subscription = bus.subscribe("melt.test".to_owned()).await.unwrap();
+        let mut reconnections = bus.reconnections();
+
+        disconnect.send(()).unwrap();
+        timeout(Duration::from_secs(5), reconnections.next())
+            .await
+            .expect("bus should reconnect in time")
+            .unwrap();
+
+        bus.publish("melt.test".to_owned(), Bytes::from_static(b"again"))
+            .await
+            .unwrap();
+        assert_eq!(next(&mut subscription).await, "again");
+    }
+}
@@ -0,0 +1,173 @@
+use crate::actor::audit_log::Rotation;
+use crate::actor::supervisor::RestartPolicy;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Server settings that can be changed without rebuilding, e.g. through the container environment
+#[derive(Clone, Debug)]
+pub(crate) struct Config {
+    pub(crate) port: u16,
+    /// The SQLite database file. Created if it does not exist.
+    pub(crate) database_path: PathBuf,
+    /// The file security relevant events are appended to. Rotated files get a number appended.
+    pub(crate) audit_log_path: PathBuf,
+    pub(crate) audit_log_rotation: Rotation,
+    /// How long the server waits for sockets to be drained on shutdown before exiting anyway
+    pub(crate) shutdown_deadline: Duration,
+    /// The delay clients are asked to wait before reconnecting after the server shut down
+    pub(crate) reconnect_after: Duration,
+    /// How long the readiness check waits for the actors to answer before reporting them as stuck
+    pub(crate) probe_timeout: Duration,
+    /// How long requests to actors wait for an answer before giving up
+    pub(crate) request_timeout: Duration,
+    /// How often the delivery service may crash before the server shuts down
+    pub(crate) restart_policy: RestartPolicy,
+    /// How many actors the users are split across in the delivery service
+    pub(crate) delivery_shards: NonZeroUsize,
+    /// The NATS server connecting the server instances of a cluster. Runs standalone if not set.
+    pub(crate) nats_url: Option<String>,
+    /// Identifies this server instance in the cluster. Random if not set.
+    pub(crate) node_id: Option<String>,
+    /// The bearer token for the admin API. The admin API is disabled if not set.
+    pub(crate) admin_token: Option<String>,
+    /// The deflate level from 1 to 9 for websocket clients that ask for compression. Not offered
+    /// to clients if 0.
+    pub(crate) websocket_compression_level: u32,
+    /// The largest message a websocket client may send, also after decompressing it
+    pub(crate) max_message_bytes: usize,
+    /// How often messages past the retention of their conversation are deleted
+    pub(crate) retention_sweep_interval: Duration,
+    /// The base64url encoded private and public key the server signs push messages with. Push
+    /// notifications are disabled if not set.
+    pub(crate) vapid_keys: Option<(String, String)>,
+    /// A `mailto:` or `https:` URL the operators of push services can reach the operators with
+    pub(crate) vapid_subject: Option<String>,
+    /// Accepts push subscriptions over plain HTTP and to local or private addresses, for a mock
+    /// push service in development. Push endpoints could otherwise reach into the server's network.
+    pub(crate) push_allow_insecure_endpoints: bool,
+    /// The port of the gRPC API
+    #[cfg(feature = "grpc")]
+    pub(crate) grpc_port: u16,
+    /// The UDP port of the QUIC listener
+    #[cfg(feature = "quic")]
+    pub(crate) quic_port: u16,
+    /// PEM files with the certificate chain and private key for QUIC. A self-signed certificate for
+    /// localhost is generated if not set.
+    #[cfg(feature = "quic")]
+    pub(crate) quic_certificate: Option<(PathBuf, PathBuf)>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            port: 3000,
+            database_path: PathBuf::from("melt.sqlite"),
+            audit_log_path: PathBuf::from("audit.log"),
+            audit_log_rotation: Rotation {
+                max_bytes: 10 * 1024 * 1024,
+                max_files: 5,
+            },
+            shutdown_deadline: Duration::from_secs(10),
+            reconnect_after: Duration::from_secs(5),
+            probe_timeout: Duration::from_secs(1),
+            request_timeout: Duration::from_secs(5),
+            restart_policy: RestartPolicy {
+                max_restarts: 3,
+                window: Duration::from_secs(60),
+            },
+            // One per core, so that routing messages is spread across all of them
+            delivery_shards: std::thread::available_parallelism().unwrap_or(NonZeroUsize::MIN),
+            nats_url: None,
+            node_id: None,
+            admin_token: None,
+            websocket_compression_level: 6,
+            max_message_bytes: 64 * 1024,
+            // Disappearing messages can be set to go seconds after reading them
+            retention_sweep_interval: Duration::from_secs(10),
+            vapid_keys: None,
+            vapid_subject: None,
+            push_allow_insecure_endpoints: false,
+            #[cfg(feature = "grpc")]
+            grpc_port: 50051,
+            #[cfg(feature = "quic")]
+            quic_port: 4433,
+            #[cfg(feature = "quic")]
+            quic_certificate: None,
+        }
+    }
+}
+
+impl Config {
+    pub(crate) fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            port: from_env("PORT").unwrap_or(default.port),
+            database_path: from_env("DATABASE_PATH").unwrap_or(default.database_path),
+            audit_log_path: from_env("AUDIT_LOG_PATH").unwrap_or(default.audit_log_path),
+            audit_log_rotation: Rotation {
+                max_bytes: from_env("AUDIT_LOG_MAX_BYTES")
+                    .unwrap_or(default.audit_log_rotation.max_bytes),
+                max_files: from_env("AUDIT_LOG_FILES")
+                    .unwrap_or(default.audit_log_rotation.max_files),
+            },
+            shutdown_deadline: seconds_from_env("SHUTDOWN_DEADLINE_SECONDS")
+                .unwrap_or(default.shutdown_deadline),
+            reconnect_after: seconds_from_env("RECONNECT_AFTER_SECONDS")
+                .unwrap_or(default.reconnect_after),
+            probe_timeout: milliseconds_from_env("PROBE_TIMEOUT_MILLISECONDS")
+                .unwrap_or(default.probe_timeout),
+            request_timeout: milliseconds_from_env("REQUEST_TIMEOUT_MILLISECONDS")
+                .unwrap_or(default.request_timeout),
+            restart_policy: RestartPolicy {
+                max_restarts: from_env("MAX_RESTARTS")
+                    .unwrap_or(default.restart_policy.max_restarts),
+                window: seconds_from_env("RESTART_WINDOW_SECONDS")
+                    .unwrap_or(default.restart_policy.window),
+            },
+            delivery_shards: from_env("DELIVERY_SHARDS").unwrap_or(default.delivery_shards),
+            nats_url: from_env("CLUSTER_NATS_URL"),
+            node_id: from_env("NODE_ID"),
+            // An empty token would let everyone in
+            admin_token: from_env::<String>("ADMIN_TOKEN").filter(|token| !token.is_empty()),
+            websocket_compression_level: from_env("WEBSOCKET_COMPRESSION_LEVEL")
+                .filter(|level| *level <= 9)
+                .unwrap_or(default.websocket_compression_level),
+            max_message_bytes: from_env("MAX_MESSAGE_BYTES").unwrap_or(default.max_message_bytes),
+            // Zero would make the interval panic
+            retention_sweep_interval: seconds_from_env("RETENTION_SWEEP_SECONDS")
+                .filter(|interval| !interval.is_zero())
+                .unwrap_or(default.retention_sweep_interval),
+            vapid_keys: from_env("VAPID_PRIVATE_KEY").zip(from_env("VAPID_PUBLIC_KEY")),
+            vapid_subject: from_env("VAPID_SUBJECT"),
+            push_allow_insecure_endpoints: from_env("PUSH_ALLOW_INSECURE_ENDPOINTS")
+                .unwrap_or(default.push_allow_insecure_endpoints),
+            #[cfg(feature = "grpc")]
+            grpc_port: from_env("GRPC_PORT").unwrap_or(default.grpc_port),
+            #[cfg(feature = "quic")]
+            quic_port: from_env("QUIC_PORT").unwrap_or(default.quic_port),
+            #[cfg(feature = "quic")]
+            quic_certificate: from_env("QUIC_CERT_PATH").zip(from_env("QUIC_KEY_PATH")),
+        }
+    }
+}
+
+fn from_env<T: FromStr>(key: &str) -> Option<T> {
+    let value = std::env::var(key).ok()?;
+    match value.parse() {
+        Ok(value) => Some(value),
+        Err(_) => {
+            tracing::warn!("Ignoring invalid value {:?} for {}", value, key);
+            None
+        }
+    }
+}
+
+fn seconds_from_env(key: &str) -> Option<Duration> {
+    from_env(key).map(Duration::from_secs)
+}
+
+fn milliseconds_from_env(key: &str) -> Option<Duration> {
+    from_env(key).map(Duration::from_millis)
+}
@@ -0,0 +1,316 @@
+use crate::actor::{ChatMessage, HandleError, Notice};
+use crate::sessions::Authenticated;
+use crate::storage::contacts::RequestOutcome;
+use crate::storage::retention::Retention;
+use crate::storage::StorageError;
+use crate::AppState;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::Json;
+use serde::Serialize;
+use std::sync::Arc;
+
+#[derive(Serialize)]
+pub(crate) struct Contact {
+    name: Arc<str>,
+    /// Connected with at least one device
+    online: bool,
+}
+
+#[derive(Serialize)]
+pub(crate) struct IncomingRequest {
+    name: Arc<str>,
+    /// Messages the sender sent before being accepted
+    messages: Vec<ChatMessage>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ContactList {
+    contacts: Vec<Contact>,
+    incoming: Vec<IncomingRequest>,
+    outgoing: Vec<Arc<str>>,
+    blocked: Vec<Arc<str>>,
+    /// Messages from these users arrive without notifications
+    muted: Vec<Arc<str>>,
+}
+
+fn internal_error(error: StorageError) -> StatusCode {
+    tracing::error!("Error accessing contacts: {}", error);
+    StatusCode::INTERNAL_SERVER_ERROR
+}
+
+/// Lets all devices of the users know that they need to load their contacts again
+async fn notify_changed(state: &AppState, users: impl IntoIterator<Item = Arc<str>>) {
+    for user in users {
+        let result = state
+            .delivery_service
+            .notify(user, Notice::ContactsChanged)
+            .await;
+        // The change is stored, the clients just don't know about it until they load it again
+        if let Err(error) = result {
+            tracing::error!("Error notifying about changed contacts: {}", error);
+        }
+    }
+}
+
+/// The contacts of the user of the session, including the messages sent with requests to them
+pub(crate) async fn get_contacts(
+    Authenticated(user): Authenticated,
+    State(state): State<AppState>,
+) -> Result<Json<ContactList>, StatusCode> {
+    let list = state
+        .storage
+        .contact_list(user)
+        .await
+        .map_err(internal_error)?;
+
+    let available = state
+        .delivery_service
+        .filter_available(list.contacts.clone())
+        .await;
+    let available = match available {
+        Ok(available) => available,
+        Err(error @ HandleError::Timeout(_)) => {
+            tracing::warn!("Error getting available contacts: {}", error);
+            return Err(StatusCode::SERVICE_UNAVAILABLE);
+        }
+        Err(error) => {
+            tracing::error!("Error getting available contacts: {:?}", error);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    let contacts = list
+        .contacts
+        .into_iter()
+        .map(|name| Contact {
+            online: available.contains(&name),
+            name,
+        })
+        .collect();
+    let incoming = list
+        .incoming
+        .into_iter()
+        .map(|request| IncomingRequest {
+            name: request.sender,
+            messages: request.messages,
+        })
+        .collect();
+
+    Ok(Json(ContactList {
+        contacts,
+        incoming,
+        outgoing: list.outgoing,
+        blocked: list.blocked,
+        muted: list.muted,
+    }))
+}
+
+/// Responds with `201 Created` if the request was sent and `200 OK` if they are contacts now,
+/// because the other user had asked too or they already were
+pub(crate) async fn request_contact(
+    Authenticated(name): Authenticated,
+    Path(contact): Path<String>,
+    State(state): State<AppState>,
+) -> StatusCode {
+    if *name == contact {
+        return StatusCode::BAD_REQUEST;
+    }
+
+    let contact: Arc<str> = contact.into();
+    let result = state
+        .storage
+        .request_contact(name.clone(), contact.clone())
+        .await;
+    let status = match result {
+        Ok(RequestOutcome::Requested) => StatusCode::CREATED,
+        Ok(RequestOutcome::Accepted) => StatusCode::OK,
+        Ok(RequestOutcome::AlreadyContacts) => return StatusCode::OK,
+        Ok(RequestOutcome::Blocked) => return StatusCode::CONFLICT,
+        Err(error) => return internal_error(error),
+    };
+
+    notify_changed(&state, [name, contact]).await;
+    status
+}
+
+pub(crate) async fn accept_request(
+    Authenticated(name): Authenticated,
+    Path(contact): Path<String>,
+    State(state): State<AppState>,
+) -> StatusCode {
+    let contact: Arc<str> = contact.into();
+    let result = state
+        .storage
+        .accept_request(name.clone(), contact.clone())
+        .await;
+    match result {
+        Ok(true) => {
+            notify_changed(&state, [name, contact]).await;
+            StatusCode::NO_CONTENT
+        }
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(error) => internal_error(error),
+    }
+}
+
+pub(crate) async fn decline_request(
+    Authenticated(name): Authenticated,
+    Path(contact): Path<String>,
+    State(state): State<AppState>,
+) -> StatusCode {
+    let contact: Arc<str> = contact.into();
+    let result = state
+        .storage
+        .decline_request(name.clone(), contact.clone())
+        .await;
+    match result {
+        Ok(true) => {
+            notify_changed(&state, [name, contact]).await;
+            StatusCode::NO_CONTENT
+        }
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(error) => internal_error(error),
+    }
+}
+
+pub(crate) async fn remove_contact(
+    Authenticated(name): Authenticated,
+    Path(contact): Path<String>,
+    State(state): State<AppState>,
+) -> StatusCode {
+    let contact: Arc<str> = contact.into();
+    let result = state
+        .storage
+        .remove_contact(name.clone(), contact.clone())
+        .await;
+    match result {
+        Ok(true) => {
+            notify_changed(&state, [name, contact]).await;
+            StatusCode::NO_CONTENT
+        }
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(error) => internal_error(error),
+    }
+}
+
+pub(crate) async fn block(
+    Authenticated(name): Authenticated,
+    Path(contact): Path<String>,
+    State(state): State<AppState>,
+) -> StatusCode {
+    if *name == contact {
+        return StatusCode::BAD_REQUEST;
+    }
+
+    let contact: Arc<str> = contact.into();
+    let result = state.storage.block(name.clone(), contact.clone()).await;
+    match result {
+        Ok(()) => {
+            // The blocked user loses the contact too
+            notify_changed(&state, [name, contact]).await;
+            StatusCode::NO_CONTENT
+        }
+        Err(error) => internal_error(error),
+    }
+}
+
+pub(crate) async fn unblock(
+    Authenticated(name): Authenticated,
+    Path(contact): Path<String>,
+    State(state): State<AppState>,
+) -> StatusCode {
+    let contact: Arc<str> = contact.into();
+    let result = state.storage.unblock(name.clone(), contact.clone()).await;
+    match result {
+        Ok(true) => {
+            notify_changed(&state, [name, contact]).await;
+            StatusCode::NO_CONTENT
+        }
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(error) => internal_error(error),
+    }
+}
+
+pub(crate) async fn mute(
+    Authenticated(name): Authenticated,
+    Path(contact): Path<String>,
+    State(state): State<AppState>,
+) -> StatusCode {
+    if *name == contact {
+        return StatusCode::BAD_REQUEST;
+    }
+
+    let result = state.storage.mute(name.clone(), contact.into()).await;
+    match result {
+        Ok(()) => {
+            // The muted user is not told
+            notify_changed(&state, [name]).await;
+            StatusCode::NO_CONTENT
+        }
+        Err(error) => internal_error(error),
+    }
+}
+
+pub(crate) async fn unmute(
+    Authenticated(name): Authenticated,
+    Path(contact): Path<String>,
+    State(state): State<AppState>,
+) -> StatusCode {
+    let result = state.storage.unmute(name.clone(), contact.into()).await;
+    match result {
+        Ok(true) => {
+            notify_changed(&state, [name]).await;
+            StatusCode::NO_CONTENT
+        }
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(error) => internal_error(error),
+    }
+}
+
+pub(crate) async fn get_retention(
+    Authenticated(name): Authenticated,
+    Path(contact): Path<String>,
+    State(state): State<AppState>,
+) -> Result<Json<Retention>, StatusCode> {
+    let retention = state
+        .storage
+        .retention(name, contact.into())
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(retention))
+}
+
+/// Changes how long the messages of the conversation are kept for both users, which both are told
+/// about
+pub(crate) async fn set_retention(
+    Authenticated(name): Authenticated,
+    Path(contact): Path<String>,
+    State(state): State<AppState>,
+    Json(retention): Json<Retention>,
+) -> StatusCode {
+    if *name == contact {
+        return StatusCode::BAD_REQUEST;
+    }
+
+    let contact: Arc<str> = contact.into();
+    let result = state
+        .storage
+        .set_retention(name.clone(), contact.clone(), retention)
+        .await;
+    if let Err(error) = result {
+        return internal_error(error);
+    }
+
+    for (user, other) in [(name.clone(), contact.clone()), (contact, name)] {
+        let notice = Notice::RetentionChanged {
+            name: other,
+            retention,
+        };
+        // The change is stored, the clients just show the old retention until they load it again
+        if let Err(error) = state.delivery_service.notify(user, notice).await {
+            tracing::error!("Error notifying about changed retention: {}", error);
+        }
+    }
+    StatusCode::NO_CONTENT
+}
@@ -0,0 +1,20 @@
+use crate::sessions::Authenticated;
+use crate::storage::conversations::Conversation;
+use crate::AppState;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+
+/// The conversations of the user of the session with their latest message and unread counter, the
+/// latest activity first. Devices keep the list up to date with the `ConversationUpdated` messages
+/// pushed to them.
+pub(crate) async fn get_conversations(
+    Authenticated(user): Authenticated,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<Conversation>>, StatusCode> {
+    let conversations = state.storage.conversations(user).await.map_err(|error| {
+        tracing::error!("Error loading conversations: {}", error);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Json(conversations))
+}
@@ -0,0 +1,193 @@
+use crate::actor::audit_log::AuditEvent;
+use crate::actor::event_stream;
+use crate::actor::HandleError;
+use crate::AppState;
+use axum::extract::{ConnectInfo, Query, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+#[derive(Deserialize)]
+pub(crate) struct Connect {
+    name: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct Post {
+    /// The id from the `socket` event at the start of the stream
+    socket: String,
+}
+
+/// Streams the same messages as the websocket as server-sent events, for clients behind proxies
+/// that don't let websockets through
+pub(crate) async fn get_events(
+    Query(Connect { name }): Query<Connect>,
+    ConnectInfo(remote): ConnectInfo<SocketAddr>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let name: Arc<str> = name.into();
+    let remote = remote.ip();
+    if let Err(status) = crate::admit(&state, name.clone(), remote).await {
+        return status.into_response();
+    }
+
+    let user = match state.delivery_service.get_or_insert(name.clone()).await {
+        Ok(user) => user,
+        Err(error) => {
+            tracing::error!("Error getting/creating user for event stream: {:?}", error);
+            return match error {
+                HandleError::Timeout(_) => StatusCode::SERVICE_UNAVAILABLE,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            }
+            .into_response();
+        }
+    };
+
+    let (stream, response) = event_stream::Handle::new(
+        user.clone(),
+        state.audit_log.clone(),
+        name.clone(),
+        remote,
+        state.event_streams.clone(),
+    );
+    if let Err(error) = user.add_socket(stream.into()).await {
+        tracing::error!("Error adding event stream: {}", error);
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    state.audit_log.record(AuditEvent::Login {
+        user: name,
+        address: remote,
+    });
+    response.into_response()
+}
+
+/// Takes what a websocket client would send as a frame, a chat message or a command, for the event
+/// stream with the id
+pub(crate) async fn post_message(
+    Query(Post { socket }): Query<Post>,
+    State(state): State<AppState>,
+    body: String,
+) -> StatusCode {
+    // Streams are only known to the instance they are connected to
+    let Some(stream) = state.event_streams.get(&socket) else {
+        return StatusCode::NOT_FOUND;
+    };
+
+    match stream.receive(body).await {
+        Ok(()) => StatusCode::ACCEPTED,
+        Err(error) => {
+            tracing::error!("Error passing message to event stream: {}", error);
+            StatusCode::NOT_FOUND
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::tests::{serve, state};
+    use axum::routing::{get, post};
+    use axum::Router;
+    use serde_json::Value;
+
+    /// Reads the events of a server-sent events response
+    struct Events {
+        response: reqwest::Response,
+        buffer: String,
+    }
+
+    impl Events {
+        async fn connect(address: SocketAddr, name: &str) -> Self {
+            let url = format!("http://{address}/events?name={name}");
+            let response = reqwest::get(url).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            Self {
+                response,
+                buffer: String::new(),
+            }
+        }
+
+        /// The name and data of the next event, skipping comments that keep the response alive
+        async fn next(&mut self) -> (Option<String>, String) {
+            loop {
+                if let Some(end) = self.buffer.find("\n\n") {
+                    let event: String = self.buffer.drain(..end + 2).collect();
+                    let (mut name, mut data) = (None, String::new());
+                    for line in event.lines() {
+                        if let Some(value) = line.strip_prefix("event: ") {
+                            name = Some(value.to_owned());
+                        } else if let Some(value) = line.strip_prefix("data: ") {
+                            data.push_str(value);
+                        }
+                    }
+                    if name.is_some() || !data.is_empty() {
+                        return (name, data);
+                    }
+                    continue;
+                }
+
+                let chunk = self.response.chunk().await.unwrap().expect("stream ended");
+                self.buffer.push_str(std::str::from_utf8(&chunk).unwrap());
+            }
+        }
+
+        /// The next message of the type, skipping the others and named events
+        async fn next_of_type(&mut self, kind: &str) -> Value {
+            loop {
+                let (None, data) = self.next().await else {
+                    continue;
+                };
+                let message: Value = serde_json::from_str(&data).unwrap();
+                if message["type"] == kind {
+                    return message;
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn messages_posted_for_a_stream_arrive_through_the_other_users_stream() {
+        let state = state(Config::default());
+        let app = Router::new()
+            .route("/events", get(get_events))
+            .route("/messages", post(post_message));
+        let address = serve(app, state).await;
+
+        let mut alice = Events::connect(address, "alice").await;
+        let (name, socket) = alice.next().await;
+        assert_eq!(name.as_deref(), Some("socket"));
+        let mut bob = Events::connect(address, "bob").await;
+
+        let client = reqwest::Client::new();
+        let message = r#"{"recipient":"bob","sender":"alice","text":"Hi Bob","time_utc":0}"#;
+        let response = client
+            .post(format!("http://{address}/messages?socket={socket}"))
+            .body(message)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+        // Alice and Bob are not contacts yet
+        let received = tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            bob.next_of_type("MessageRequest"),
+        )
+        .await
+        .expect("message should arrive in time");
+        assert_eq!(received["message"]["sender"], "alice");
+        assert_eq!(received["message"]["text"], "Hi Bob");
+
+        let response = client
+            .post(format!("http://{address}/messages?socket=unknown"))
+            .body(message)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
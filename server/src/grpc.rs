@@ -0,0 +1,298 @@
+use crate::actor::audit_log::AuditEvent;
+use crate::actor::socket::ClientMessage;
+use crate::actor::{grpc, ChatMessage, HandleError};
+use crate::storage::retention::Retention;
+use crate::storage::StorageError;
+use crate::AppState;
+use axum::http::StatusCode;
+use futures_util::Stream;
+use proto::melt_server::{Melt, MeltServer};
+use proto::server_frame::Kind;
+use proto::{
+    ClientFrame, Contact, HistoryRequest, HistoryResponse, ListUsersRequest, ListUsersResponse,
+    SendMessageResponse, ServerFrame,
+};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use time::error::ComponentRange;
+use time::OffsetDateTime;
+use tonic::{Request, Response, Status, Streaming};
+
+pub(crate) mod proto {
+    tonic::include_proto!("melt");
+}
+
+/// The request metadata naming the user of a `Chat` call
+const USER_METADATA: &str = "user";
+/// The request metadata with the session token, sent as bearer token like to the REST API
+const AUTHORIZATION_METADATA: &str = "authorization";
+
+pub(crate) fn chat_message_from_proto(
+    message: proto::ChatMessage,
+) -> Result<ChatMessage, ComponentRange> {
+    let time_utc = OffsetDateTime::from_unix_timestamp_nanos(message.time_utc as i128 * 1_000_000)?;
+    Ok(ChatMessage {
+        recipient: message.recipient.into(),
+        sender: message.sender,
+        text: message.text,
+        time_utc,
+    })
+}
+
+fn chat_message_to_proto(message: &ChatMessage) -> proto::ChatMessage {
+    proto::ChatMessage {
+        recipient: message.recipient.to_string(),
+        sender: message.sender.clone(),
+        text: message.text.clone(),
+        time_utc: milliseconds(message.time_utc),
+    }
+}
+
+fn milliseconds(time: OffsetDateTime) -> i64 {
+    (time.unix_timestamp_nanos() / 1_000_000) as i64
+}
+
+fn retention_to_proto(retention: Retention) -> proto::Retention {
+    let kind = match retention {
+        Retention::Forever => proto::retention::Kind::Forever(proto::Forever {}),
+        Retention::Days { days } => proto::retention::Kind::Days(days.get()),
+        Retention::AfterRead { seconds } => proto::retention::Kind::AfterReadSeconds(seconds.get()),
+    };
+    proto::Retention { kind: Some(kind) }
+}
+
+impl From<&ClientMessage> for ServerFrame {
+    fn from(message: &ClientMessage) -> Self {
+        let incoming = |message: &ChatMessage, muted| proto::IncomingMessage {
+            message: Some(chat_message_to_proto(message)),
+            muted,
+        };
+
+        let kind = match message {
+            ClientMessage::ChatMessage { message, muted } => {
+                Kind::ChatMessage(incoming(message, *muted))
+            }
+            ClientMessage::AddUser { name } => Kind::AddUser(name.to_string()),
+            ClientMessage::RemoveUser { name } => Kind::RemoveUser(name.to_string()),
+            ClientMessage::SynchronizeMessage { message } => {
+                Kind::SynchronizeMessage(chat_message_to_proto(message))
+            }
+            ClientMessage::MessageRequest { message, muted } => {
+                Kind::MessageRequest(incoming(message, *muted))
+            }
+            ClientMessage::ContactsChanged => Kind::ContactsChanged(proto::ContactsChanged {}),
+            ClientMessage::DeliveryFailed { message } => {
+                Kind::DeliveryFailed(chat_message_to_proto(message))
+            }
+            ClientMessage::SystemNotice { text } => Kind::SystemNotice(text.to_string()),
+            ClientMessage::RetentionChanged { name, retention } => {
+                Kind::RetentionChanged(proto::RetentionChanged {
+                    name: name.to_string(),
+                    retention: Some(retention_to_proto(*retention)),
+                })
+            }
+            ClientMessage::MessagesExpired { messages } => {
+                Kind::MessagesExpired(proto::MessagesExpired {
+                    messages: messages
+                        .iter()
+                        .map(|message| chat_message_to_proto(message))
+                        .collect(),
+                })
+            }
+            ClientMessage::ConversationUpdated { conversation } => {
+                Kind::ConversationUpdated(proto::Conversation {
+                    name: conversation.name.to_string(),
+                    last_message: conversation
+                        .last_message
+                        .as_ref()
+                        .map(chat_message_to_proto),
+                    unread: conversation.unread,
+                    last_activity_utc: milliseconds(conversation.last_activity_utc),
+                    read_until_utc: conversation.read_until_utc.map(milliseconds),
+                    draft: conversation.draft.clone(),
+                    archived: conversation.archived,
+                })
+            }
+            ClientMessage::SessionCreated { token } => Kind::SessionCreated(token.to_string()),
+            ClientMessage::ServerShutdown { reconnect_after } => {
+                Kind::ServerShutdown(proto::ServerShutdown {
+                    reconnect_after: *reconnect_after,
+                })
+            }
+        };
+
+        Self { kind: Some(kind) }
+    }
+}
+
+fn storage_error(error: StorageError) -> Status {
+    tracing::error!("Error accessing storage: {}", error);
+    Status::internal("Error accessing storage")
+}
+
+fn actor_error(error: HandleError) -> Status {
+    match error {
+        HandleError::Timeout(_) => {
+            tracing::warn!("Actor did not answer: {}", error);
+            Status::unavailable("Server is busy, try again later")
+        }
+        error => {
+            tracing::error!("Error talking to actor: {:?}", error);
+            Status::internal("Error talking to actor")
+        }
+    }
+}
+
+struct Service {
+    state: AppState,
+}
+
+impl Service {
+    /// The user of the session whose token the request carries
+    async fn authenticate<T>(&self, request: &Request<T>) -> Result<Arc<str>, Status> {
+        let token = request
+            .metadata()
+            .get(AUTHORIZATION_METADATA)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(|| Status::unauthenticated("The session token is missing"))?;
+
+        self.state
+            .storage
+            .session_user(token.to_owned())
+            .await
+            .map_err(storage_error)?
+            .ok_or_else(|| Status::unauthenticated("The session is unknown or ran out"))
+    }
+}
+
+#[tonic::async_trait]
+impl Melt for Service {
+    async fn list_users(
+        &self,
+        request: Request<ListUsersRequest>,
+    ) -> Result<Response<ListUsersResponse>, Status> {
+        let user = self.authenticate(&request).await?;
+        let contacts = self
+            .state
+            .storage
+            .contacts(user)
+            .await
+            .map_err(storage_error)?;
+        let available = self
+            .state
+            .delivery_service
+            .filter_available(contacts.clone())
+            .await
+            .map_err(actor_error)?;
+
+        let contacts = contacts
+            .into_iter()
+            .map(|name| Contact {
+                online: available.contains(&name),
+                name: name.to_string(),
+            })
+            .collect();
+        Ok(Response::new(ListUsersResponse { contacts }))
+    }
+
+    async fn history(
+        &self,
+        request: Request<HistoryRequest>,
+    ) -> Result<Response<HistoryResponse>, Status> {
+        let user = self.authenticate(&request).await?;
+        let HistoryRequest { contact } = request.into_inner();
+        let messages = self
+            .state
+            .storage
+            .history(user, contact.into())
+            .await
+            .map_err(storage_error)?
+            .iter()
+            .map(chat_message_to_proto)
+            .collect();
+        Ok(Response::new(HistoryResponse { messages }))
+    }
+
+    async fn send_message(
+        &self,
+        request: Request<proto::ChatMessage>,
+    ) -> Result<Response<SendMessageResponse>, Status> {
+        let message = chat_message_from_proto(request.into_inner())
+            .map_err(|_| Status::invalid_argument("time_utc is out of range"))?;
+        let user = self
+            .state
+            .delivery_service
+            .get(message.sender.as_str().into())
+            .await
+            .map_err(actor_error)?
+            .ok_or_else(|| {
+                Status::failed_precondition("The sender needs to be connected to this server")
+            })?;
+
+        user.send_message(message.into())
+            .await
+            .map_err(actor_error)?;
+        Ok(Response::new(SendMessageResponse {}))
+    }
+
+    type ChatStream = Pin<Box<dyn Stream<Item = Result<ServerFrame, Status>> + Send>>;
+
+    async fn chat(
+        &self,
+        request: Request<Streaming<ClientFrame>>,
+    ) -> Result<Response<Self::ChatStream>, Status> {
+        let state = &self.state;
+        let name: Arc<str> = request
+            .metadata()
+            .get(USER_METADATA)
+            .and_then(|name| name.to_str().ok())
+            .ok_or_else(|| Status::invalid_argument("The user metadata is missing"))?
+            .into();
+        let remote = request
+            .remote_addr()
+            .ok_or_else(|| Status::internal("The address of the client is unknown"))?
+            .ip();
+
+        if let Err(status) = crate::admit(state, name.clone(), remote).await {
+            return Err(match status {
+                StatusCode::FORBIDDEN => Status::permission_denied("Banned"),
+                _ => Status::unavailable("Server is not available, try again later"),
+            });
+        }
+
+        let user = state
+            .delivery_service
+            .get_or_insert(name.clone())
+            .await
+            .map_err(actor_error)?;
+        let (stream, responses) = grpc::Handle::new(
+            request.into_inner(),
+            user.clone(),
+            state.audit_log.clone(),
+            name.clone(),
+            remote,
+        );
+        user.add_socket(stream.into()).await.map_err(actor_error)?;
+
+        state.audit_log.record(AuditEvent::Login {
+            user: name,
+            address: remote,
+        });
+        Ok(Response::new(Box::pin(responses)))
+    }
+}
+
+/// Serves the gRPC API until the server shuts down. Open `Chat` calls are drained through their
+/// actors like websockets.
+pub(crate) async fn serve(state: AppState) -> Result<(), tonic::transport::Error> {
+    let address = SocketAddr::from(([0, 0, 0, 0], state.config.grpc_port));
+    tracing::debug!("gRPC listening on {}", address);
+
+    let shutdown = state.shutdown.clone().cancelled_owned();
+    tonic::transport::Server::builder()
+        .add_service(MeltServer::new(Service { state }))
+        .serve_with_shutdown(address, shutdown)
+        .await
+}
@@ -0,0 +1,114 @@
+use crate::actor::HandleError;
+use crate::AppState;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use serde::Serialize;
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum Status {
+    Ok,
+    ShuttingDown,
+    Unavailable,
+    TimedOut,
+}
+
+#[derive(Serialize)]
+pub(crate) struct Readiness {
+    status: Status,
+    delivery_service: Status,
+    storage: Status,
+}
+
+#[derive(Serialize)]
+pub(crate) struct Liveness {
+    status: Status,
+    delivery_service: Status,
+}
+
+/// Checks that the actors still process their mailboxes by sending a probe through the delivery
+/// service. A wedged actor loop does not recover by itself, so the container needs to be restarted.
+/// A server that is shutting down is still alive while it drains its connections.
+pub(crate) async fn get_health(State(state): State<AppState>) -> (StatusCode, Json<Liveness>) {
+    let delivery_service = probe_delivery_service(&state).await;
+    let status = if state.shutdown.is_cancelled() {
+        Status::ShuttingDown
+    } else {
+        delivery_service
+    };
+
+    let code = if matches!(status, Status::Unavailable | Status::TimedOut) {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    };
+
+    let liveness = Liveness {
+        status,
+        delivery_service,
+    };
+    (code, Json(liveness))
+}
+
+/// Checks that the server can take on connections by sending a probe through the delivery service
+/// and the database. A delivery service that is stuck would otherwise leave every new connection
+/// hanging.
+pub(crate) async fn get_readiness(State(state): State<AppState>) -> (StatusCode, Json<Readiness>) {
+    let delivery_service = probe_delivery_service(&state).await;
+    let storage = probe_storage(&state).await;
+
+    let status = if state.shutdown.is_cancelled() {
+        Status::ShuttingDown
+    } else if delivery_service != Status::Ok {
+        delivery_service
+    } else {
+        storage
+    };
+
+    let code = if status == Status::Ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    let readiness = Readiness {
+        status,
+        delivery_service,
+        storage,
+    };
+    (code, Json(readiness))
+}
+
+async fn probe_delivery_service(state: &AppState) -> Status {
+    let result = state
+        .delivery_service
+        .probe(state.config.probe_timeout)
+        .await;
+    match result {
+        Ok(()) => Status::Ok,
+        Err(error @ HandleError::Timeout(_)) => {
+            tracing::error!("Delivery service probe failed: {}", error);
+            Status::TimedOut
+        }
+        Err(error) => {
+            tracing::error!("Delivery service is unavailable: {:?}", error);
+            Status::Unavailable
+        }
+    }
+}
+
+async fn probe_storage(state: &AppState) -> Status {
+    let result = tokio::time::timeout(state.config.probe_timeout, state.storage.probe()).await;
+    match result {
+        Ok(Ok(())) => Status::Ok,
+        Ok(Err(error)) => {
+            tracing::error!("Storage is unavailable: {}", error);
+            Status::Unavailable
+        }
+        Err(_) => {
+            tracing::error!("Storage probe timed out");
+            Status::TimedOut
+        }
+    }
+}
@@ -1,23 +1,68 @@
+use std::future::IntoFuture;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 
-use crate::actor::{delivery_service, websocket};
+use crate::actor::audit_log::{self, AuditEvent};
+use crate::actor::compression::Deflate;
+use crate::actor::encoding::Encoding;
+use crate::actor::event_stream::EventStreams;
+use crate::actor::{delivery_service, sweeper, websocket, HandleError};
+use crate::cluster::memory::MemoryBus;
+use crate::cluster::nats::NatsBus;
+use crate::cluster::Bus;
+use crate::config::Config;
+use crate::push::Pusher;
+use crate::storage::Storage;
 use axum::http::StatusCode;
 use axum::{
-    extract::{ws::WebSocket, Path, State, WebSocketUpgrade},
+    extract::{
+        ws::{close_code, CloseFrame, Message as WebSocketMessage, WebSocket},
+        ConnectInfo, Path, State, WebSocketUpgrade,
+    },
     http::{HeaderValue, Method},
     response::IntoResponse,
-    routing::get,
-    Json, Router,
+    routing::{delete, get, post, put},
+    Router,
 };
+use metrics_exporter_prometheus::PrometheusHandle;
+use nanoid::nanoid;
+use tokio_util::sync::CancellationToken;
 use tower_http::cors::CorsLayer;
 use tower_http::services::{ServeDir, ServeFile};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 mod actor;
+mod admin;
+mod archive;
+mod cluster;
+mod config;
+mod contacts;
+mod conversations;
+mod events;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod health;
+mod push;
+#[cfg(feature = "quic")]
+mod quic;
+mod search;
+mod sessions;
+mod storage;
+mod telemetry;
 
-#[derive(Clone, Default)]
+#[derive(Clone)]
 struct AppState {
+    config: Arc<Config>,
     delivery_service: delivery_service::Handle,
+    storage: Storage,
+    audit_log: audit_log::Handle,
+    /// Where messages posted by clients without a websocket go
+    event_streams: EventStreams,
+    /// Cancelled when the server starts shutting down to stop accepting new connections
+    shutdown: CancellationToken,
+    metrics: PrometheusHandle,
+    /// Notifies users without a connected device, if push notifications are enabled
+    pusher: Option<Pusher>,
 }
 
 #[tokio::main]
@@ -31,6 +76,43 @@ async fn main() {
         .init();
 
     tracing::info!("Setting up");
+    let config = Arc::new(Config::from_env());
+    let storage =
+        Storage::open(&config.database_path).expect("should be able to open the database");
+    let node = connect_to_cluster(&config).await;
+    let audit_log = audit_log::Handle::new(
+        config.audit_log_path.clone(),
+        config.audit_log_rotation.clone(),
+        node.id.clone(),
+        config.request_timeout,
+    );
+    let pusher = Pusher::from_config(&config, storage.clone());
+    let (delivery_service, mut supervisor) = delivery_service::Handle::new(
+        config.request_timeout,
+        config.restart_policy,
+        config.delivery_shards,
+        node,
+        storage.clone(),
+        pusher.clone(),
+    );
+    // Runs until it is dropped at the end of main
+    let _sweeper = sweeper::Handle::new(
+        storage.clone(),
+        delivery_service.clone(),
+        config.retention_sweep_interval,
+    );
+    let state = AppState {
+        config: config.clone(),
+        delivery_service,
+        storage,
+        audit_log,
+        event_streams: EventStreams::default(),
+        shutdown: CancellationToken::new(),
+        metrics: telemetry::install(),
+        pusher,
+    };
+    tokio::spawn(listen_for_shutdown_signal(state.shutdown.clone()));
+
     // SPA setup
     // Not used during development where vite hosts the frontend and we use CORS
     let serve_client = ServeDir::new("./client")
@@ -39,8 +121,55 @@ async fn main() {
         .not_found_service(ServeFile::new("./client/index.html"));
 
     let mut app = Router::new()
-        .route("/messages/:name", get(websocket_handler))
-        .route("/users", get(get_users));
+        .route("/messages/{name}", get(websocket_handler))
+        .route("/events", get(events::get_events))
+        .route("/messages", post(events::post_message))
+        .route("/contacts", get(contacts::get_contacts))
+        .route("/contacts/{contact}", delete(contacts::remove_contact))
+        .route(
+            "/contacts/requests/{contact}",
+            post(contacts::request_contact),
+        )
+        .route(
+            "/contacts/requests/{contact}/accept",
+            post(contacts::accept_request),
+        )
+        .route(
+            "/contacts/requests/{contact}/decline",
+            post(contacts::decline_request),
+        )
+        .route(
+            "/contacts/blocked/{contact}",
+            put(contacts::block).delete(contacts::unblock),
+        )
+        .route(
+            "/contacts/muted/{contact}",
+            put(contacts::mute).delete(contacts::unmute),
+        )
+        .route(
+            "/contacts/retention/{contact}",
+            get(contacts::get_retention).put(contacts::set_retention),
+        )
+        .route("/conversations", get(conversations::get_conversations))
+        .route("/search", get(search::search))
+        .route("/session", delete(sessions::delete_session))
+        .route("/export", get(archive::get_export))
+        .route("/metrics", get(get_metrics))
+        .route("/healthz", get(health::get_health))
+        .route("/readyz", get(health::get_readiness));
+
+    if config.admin_token.is_some() {
+        app = app.nest("/admin", admin::router(state.clone()));
+    } else {
+        tracing::info!("No ADMIN_TOKEN set, the admin API is disabled");
+    }
+
+    if state.pusher.is_some() {
+        app = app.route("/push/key", get(push::get_server_key)).route(
+            "/push/subscriptions",
+            post(push::subscribe).delete(push::unsubscribe),
+        );
+    }
 
     // Not looking nice, but functional to have CORS only in development
     #[cfg(debug_assertions)]
@@ -48,41 +177,197 @@ async fn main() {
         app = app.layer(
             CorsLayer::new()
                 .allow_origin("http://localhost:5173".parse::<HeaderValue>().unwrap())
-                .allow_methods([Method::GET]),
+                .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE]),
         );
     }
 
-    let app = app
-        .fallback_service(serve_client)
-        .with_state(Default::default());
+    let app = app.fallback_service(serve_client).with_state(state.clone());
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
+    let listener = tokio::net::TcpListener::bind(("0.0.0.0", config.port))
+        .await
+        .unwrap();
 
     tracing::debug!("listening on {}", listener.local_addr().unwrap());
-    axum::serve(listener, app).await.unwrap();
+    // The address of the client is needed to ban addresses
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
+    let server = axum::serve(listener, app)
+        .with_graceful_shutdown(state.shutdown.clone().cancelled_owned())
+        .into_future();
+    let server = tokio::spawn(server);
+
+    #[cfg(feature = "grpc")]
+    tokio::spawn({
+        let state = state.clone();
+        async move {
+            if let Err(error) = grpc::serve(state).await {
+                tracing::error!("gRPC server failed: {}", error);
+            }
+        }
+    });
+
+    #[cfg(feature = "quic")]
+    {
+        let endpoint =
+            quic::endpoint(&config).expect("should be able to set up the WebTransport listener");
+        tracing::debug!(
+            "WebTransport listening on {}",
+            endpoint.local_addr().unwrap()
+        );
+        tokio::spawn(quic::serve(endpoint, state.clone()));
+    }
+
+    // The delivery service only stops on its own when it is shut down or when it can't be
+    // restarted anymore
+    let mut has_failed = false;
+    tokio::select! {
+        _ = state.shutdown.cancelled() => {},
+        result = &mut supervisor => {
+            match result {
+                Ok(Err(error)) => tracing::error!("{}", error),
+                Ok(Ok(())) => tracing::error!("Delivery service stopped unexpectedly"),
+                Err(error) => tracing::error!("Delivery service supervisor failed: {:?}", error),
+            }
+            has_failed = true;
+            state.shutdown.cancel();
+        },
+    }
+
+    tracing::info!("Shutting down");
+    // Upgraded websocket connections are not tracked by the server's graceful shutdown and event
+    // streams would keep it waiting, so both need to be drained through the actors
+    let drain = async {
+        let result = state
+            .delivery_service
+            .shutdown(config.reconnect_after)
+            .await;
+        if let Err(error) = result {
+            tracing::error!("Error draining connections: {:?}", error);
+        }
+
+        server.await
+    };
+
+    match tokio::time::timeout(config.shutdown_deadline, drain).await {
+        Ok(Ok(Ok(()))) => tracing::info!("Shut down gracefully"),
+        Ok(Ok(Err(error))) => tracing::error!("Server error during shutdown: {:?}", error),
+        Ok(Err(error)) => tracing::error!("Server task failed during shutdown: {:?}", error),
+        Err(_) => tracing::warn!(
+            "Shutdown deadline of {:?} exceeded, exiting with connections still open",
+            config.shutdown_deadline
+        ),
+    }
+
+    if has_failed {
+        std::process::exit(1);
+    }
 }
 
-async fn get_users(State(state): State<AppState>) -> Result<Json<Arc<[Arc<str>]>>, StatusCode> {
-    let result = state.delivery_service.get_users().await;
-    match result {
-        Ok(users) => Ok(Json(users)),
-        Err(error) => {
-            tracing::error!("Error getting users: {:?}", error);
-            Err(StatusCode::INTERNAL_SERVER_ERROR)
+async fn connect_to_cluster(config: &Config) -> cluster::Node {
+    let id: Arc<str> = match &config.node_id {
+        Some(id) => id.as_str().into(),
+        None => nanoid!().into(),
+    };
+
+    let bus: Arc<dyn Bus> = match &config.nats_url {
+        Some(url) => {
+            tracing::info!("Joining cluster through {} as node {}", url, id);
+            let bus = NatsBus::connect(url)
+                .await
+                .expect("should be able to connect to the cluster");
+            Arc::new(bus)
         }
+        None => Arc::new(MemoryBus::default()),
+    };
+
+    cluster::Node { id, bus }
+}
+
+/// Cancels the token on Ctrl+C or SIGTERM, which is what container runtimes send to stop the app
+async fn listen_for_shutdown_signal(shutdown: CancellationToken) {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
     }
+
+    tracing::info!("Received shutdown signal");
+    shutdown.cancel();
+}
+
+async fn get_metrics(State(state): State<AppState>) -> String {
+    state.metrics.render()
 }
 
 async fn websocket_handler(
     Path(name): Path<String>,
+    ConnectInfo(remote): ConnectInfo<SocketAddr>,
     websocket: WebSocketUpgrade,
     state: State<AppState>,
 ) -> impl IntoResponse {
-    websocket.on_upgrade(move |socket| create_actor(socket, state, name.into()))
+    let name: Arc<str> = name.into();
+    let remote = remote.ip();
+    if let Err(status) = admit(&state, name.clone(), remote).await {
+        return status.into_response();
+    }
+
+    // Compressed messages are preferred if the server offers them
+    let compressed = (state.config.websocket_compression_level > 0)
+        .then_some(Encoding::COMPRESSED_PROTOCOLS)
+        .into_iter()
+        .flatten();
+    websocket
+        .max_message_size(state.config.max_message_bytes)
+        .protocols(compressed.chain(Encoding::PROTOCOLS))
+        .on_upgrade(move |socket| create_actor(socket, state, name, remote))
 }
 
-async fn create_actor(stream: WebSocket, State(state): State<AppState>, name: Arc<str>) {
-    let result = state.delivery_service.get_or_insert(name).await;
+/// Checks that the user may connect from the address, whichever transport they use
+async fn admit(state: &AppState, name: Arc<str>, remote: IpAddr) -> Result<(), StatusCode> {
+    if state.shutdown.is_cancelled() {
+        return Err(StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    match state.storage.is_banned(name.clone(), remote).await {
+        Ok(false) => Ok(()),
+        Ok(true) => {
+            tracing::info!("Refusing connection of banned user or address");
+            state.audit_log.record(AuditEvent::LoginRefused {
+                user: name,
+                address: remote,
+            });
+            Err(StatusCode::FORBIDDEN)
+        }
+        // Not letting anyone in while bans can't be checked
+        Err(error) => {
+            tracing::error!("Error checking bans: {}", error);
+            Err(StatusCode::SERVICE_UNAVAILABLE)
+        }
+    }
+}
+
+async fn create_actor(
+    mut stream: WebSocket,
+    State(state): State<AppState>,
+    name: Arc<str>,
+    remote: IpAddr,
+) {
+    let result = state.delivery_service.get_or_insert(name.clone()).await;
 
     let user = match result {
         Ok(user) => user,
@@ -91,14 +376,260 @@ async fn create_actor(stream: WebSocket, State(state): State<AppState>, name: Ar
                 "Error getting/creating user for websocket connection: {:?}",
                 error
             );
+            // Let the client know why the connection is closed right after it was opened
+            let (code, reason) = match error {
+                HandleError::Timeout(_) => (close_code::AGAIN, "Server is busy, try again later"),
+                _ => (close_code::ERROR, "Error setting up user"),
+            };
+            let close = WebSocketMessage::Close(Some(CloseFrame {
+                code,
+                reason: reason.into(),
+            }));
+            let _ = stream.send(close).await;
             return;
         }
     };
 
-    let socket = websocket::Handle::new(stream, user.clone());
-    let result = user.add_socket(socket).await;
+    let (encoding, is_compressed) = Encoding::from_protocol(stream.protocol());
+    let compression = is_compressed.then(|| {
+        Deflate::new(
+            state.config.websocket_compression_level,
+            state.config.max_message_bytes,
+        )
+    });
+    let socket = websocket::Handle::new(
+        stream,
+        user.clone(),
+        state.audit_log.clone(),
+        name.clone(),
+        remote,
+        encoding,
+        compression,
+    );
+    let result = user.add_socket(socket.into()).await;
     let Err(error) = result else {
+        state.audit_log.record(AuditEvent::Login {
+            user: name,
+            address: remote,
+        });
         return;
     };
     tracing::error!("Error adding socket: {}", error);
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use futures_util::{SinkExt, StreamExt};
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use std::time::Duration;
+    use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+    use tokio_tungstenite::tungstenite::Message;
+
+    /// The state of a server without a cluster and push notifications, on an in-memory database
+    pub(crate) fn state(config: Config) -> AppState {
+        let config = Arc::new(config);
+        let storage = crate::storage::tests::storage();
+        let node = cluster::Node {
+            id: nanoid!().into(),
+            bus: Arc::new(MemoryBus::default()),
+        };
+        let audit_log = audit_log::Handle::new(
+            std::env::temp_dir().join(format!("melt-audit-{}.log", node.id)),
+            config.audit_log_rotation.clone(),
+            node.id.clone(),
+            config.request_timeout,
+        );
+        let (delivery_service, _) = delivery_service::Handle::new(
+            config.request_timeout,
+            config.restart_policy,
+            config.delivery_shards,
+            node,
+            storage.clone(),
+            None,
+        );
+        AppState {
+            config,
+            delivery_service,
+            storage,
+            audit_log,
+            event_streams: EventStreams::default(),
+            shutdown: CancellationToken::new(),
+            metrics: PrometheusBuilder::new().build_recorder().handle(),
+            pusher: None,
+        }
+    }
+
+    /// Serves the routes on a free port of localhost
+    pub(crate) async fn serve(app: Router<AppState>, state: AppState) -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let app = app
+            .with_state(state)
+            .into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        address
+    }
+
+    #[tokio::test]
+    async fn shutdown_tells_every_socket_before_closing_it() {
+        let state = state(Config::default());
+        let app = Router::new().route("/messages/{name}", get(websocket_handler));
+        let address = serve(app, state.clone()).await;
+
+        let mut sockets = Vec::new();
+        for name in ["alice", "alice", "bob"] {
+            let url = format!("ws://{address}/messages/{name}");
+            let (socket, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+            sockets.push(socket);
+        }
+        // Every socket is added to its user before the drain starts
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let users = state.delivery_service.connected_users().await.unwrap();
+                let sockets: usize = users.iter().map(|(_, sockets)| sockets.len()).sum();
+                if sockets == 3 {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("sockets should be added in time");
+
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            state.delivery_service.shutdown(Duration::from_millis(1500)),
+        )
+        .await
+        .expect("connections should be drained in time")
+        .unwrap();
+
+        for mut socket in sockets {
+            let mut last_text = None;
+            let close = loop {
+                match socket.next().await {
+                    Some(Ok(Message::Text(text))) => last_text = Some(text),
+                    Some(Ok(Message::Close(close))) => break close,
+                    Some(Ok(_)) => {}
+                    message => panic!("socket should be closed, got {message:?}"),
+                }
+            };
+            assert_eq!(
+                last_text.as_deref(),
+                Some(r#"{"type":"ServerShutdown","reconnect_after":1500}"#)
+            );
+            assert_eq!(close.unwrap().code, CloseCode::Restart);
+        }
+    }
+
+    #[tokio::test]
+    async fn clients_are_asked_to_try_again_when_actors_time_out() {
+        let config = Config {
+            request_timeout: Duration::from_millis(100),
+            ..Config::default()
+        };
+        let mut state = state(config);
+        // The delivery service gets stuck loading new users from a database of its own, while
+        // bans can still be checked
+        let hanging = crate::storage::tests::storage();
+        let _release = crate::storage::tests::hang(&hanging);
+        let node = cluster::Node {
+            id: nanoid!().into(),
+            bus: Arc::new(MemoryBus::default()),
+        };
+        (state.delivery_service, _) = delivery_service::Handle::new(
+            state.config.request_timeout,
+            state.config.restart_policy,
+            state.config.delivery_shards,
+            node,
+            hanging.clone(),
+            None,
+        );
+        let app = Router::new()
+            .route("/messages/{name}", get(websocket_handler))
+            .route("/events", get(events::get_events));
+        let address = serve(app, state).await;
+
+        let url = format!("ws://{address}/messages/alice");
+        let (mut socket, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        let Some(Ok(Message::Close(Some(close)))) = socket.next().await else {
+            panic!("socket should be closed");
+        };
+        assert_eq!(close.code, CloseCode::Again);
+
+        let response = reqwest::get(format!("http://{address}/events?name=bob"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn drafts_and_archiving_sync_to_the_other_devices() {
+        let state = state(Config::default());
+        let app = Router::new().route("/messages/{name}", get(websocket_handler));
+        let address = serve(app, state.clone()).await;
+
+        let url = format!("ws://{address}/messages/alice");
+        let (mut phone, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let (mut laptop, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let users = state.delivery_service.connected_users().await.unwrap();
+                if users.iter().any(|(_, sockets)| sockets.len() == 2) {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("sockets should be added in time");
+
+        for command in [
+            r#"{"type":"SaveDraft","name":"bob","text":"See you at"}"#,
+            r#"{"type":"Archive","name":"bob"}"#,
+            r#"{"type":"CreateSession"}"#,
+        ] {
+            phone.send(Message::Text(command.into())).await.unwrap();
+        }
+
+        let mut updates = Vec::new();
+        while updates.len() < 2 {
+            let Some(Ok(Message::Text(text))) = laptop.next().await else {
+                panic!("laptop should receive the updates");
+            };
+            let message: serde_json::Value = serde_json::from_str(&text).unwrap();
+            if message["type"] == "ConversationUpdated" {
+                updates.push(message["conversation"].clone());
+            }
+        }
+        assert_eq!(updates[0]["name"], "bob");
+        assert_eq!(updates[0]["draft"], "See you at");
+        assert_eq!(updates[0]["archived"], false);
+        assert_eq!(updates[1]["draft"], "See you at");
+        assert_eq!(updates[1]["archived"], true);
+
+        // The commands are run in order, so the phone would have received the updates before
+        // its session
+        loop {
+            let Some(Ok(Message::Text(text))) = phone.next().await else {
+                panic!("phone should receive its session");
+            };
+            let message: serde_json::Value = serde_json::from_str(&text).unwrap();
+            assert_ne!(message["type"], "ConversationUpdated");
+            if message["type"] == "SessionCreated" {
+                break;
+            }
+        }
+
+        let conversations = state.storage.conversations("alice".into()).await.unwrap();
+        let [conversation] = conversations.as_slice() else {
+            panic!(
+                "alice should have one conversation, got {}",
+                conversations.len()
+            );
+        };
+        assert_eq!(conversation.draft.as_deref(), Some("See you at"));
+        assert!(conversation.archived);
+    }
+}
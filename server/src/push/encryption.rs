@@ -0,0 +1,86 @@
+//! Encrypts push messages for the browser they are for as RFC 8291 describes it, with the
+//! `aes128gcm` content coding of RFC 8188 and the whole message in one record
+
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_128_GCM};
+use ring::agreement::{self, EphemeralPrivateKey, UnparsedPublicKey, ECDH_P256};
+use ring::error::Unspecified;
+use ring::hkdf::{KeyType, Prk, Salt, HKDF_SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+
+/// Uncompressed P-256 points, which is how browsers hand out their public key
+pub(super) const PUBLIC_KEY_BYTES: usize = 65;
+pub(super) const AUTH_SECRET_BYTES: usize = 16;
+const SALT_BYTES: usize = 16;
+const TAG_BYTES: usize = 16;
+/// The salt, record size and the server's public key with its length
+const HEADER_BYTES: usize = SALT_BYTES + 4 + 1 + PUBLIC_KEY_BYTES;
+/// Push services accept messages of at least 4096 bytes, which is also the size of the record
+const RECORD_BYTES: usize = 4096;
+/// The longest message that fits into a push message, leaving room for the header, the
+/// authentication tag and the delimiter that ends the record
+pub(super) const MAX_PLAINTEXT_BYTES: usize = RECORD_BYTES - HEADER_BYTES - TAG_BYTES - 1;
+
+/// Returns the body of a push message. A new key pair is made for every message, so that only the
+/// browser with the private key to the public key can read it.
+pub(super) fn encrypt(
+    plaintext: &[u8],
+    user_agent_public_key: &[u8],
+    auth_secret: &[u8],
+) -> Result<Vec<u8>, Unspecified> {
+    let random = SystemRandom::new();
+    let private_key = EphemeralPrivateKey::generate(&ECDH_P256, &random)?;
+    let public_key = private_key.compute_public_key()?;
+    let user_agent = UnparsedPublicKey::new(&ECDH_P256, user_agent_public_key);
+    let shared_secret =
+        agreement::agree_ephemeral(private_key, &user_agent, |secret| secret.to_vec())?;
+
+    let mut key_info = b"WebPush: info\0".to_vec();
+    key_info.extend_from_slice(user_agent_public_key);
+    key_info.extend_from_slice(public_key.as_ref());
+    let input_key: [u8; 32] = expand(
+        &Salt::new(HKDF_SHA256, auth_secret).extract(&shared_secret),
+        &key_info,
+    )?;
+
+    let mut salt = [0; SALT_BYTES];
+    random.fill(&mut salt)?;
+    let pseudorandom_key = Salt::new(HKDF_SHA256, &salt).extract(&input_key);
+    let content_key: [u8; 16] = expand(&pseudorandom_key, b"Content-Encoding: aes128gcm\0")?;
+    let nonce: [u8; 12] = expand(&pseudorandom_key, b"Content-Encoding: nonce\0")?;
+
+    let mut record = Vec::with_capacity(plaintext.len() + 1 + TAG_BYTES);
+    record.extend_from_slice(plaintext);
+    // Ends the last record without padding
+    record.push(2);
+    let content_key = LessSafeKey::new(UnboundKey::new(&AES_128_GCM, &content_key)?);
+    content_key.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::empty(),
+        &mut record,
+    )?;
+
+    let mut body = Vec::with_capacity(HEADER_BYTES + record.len());
+    body.extend_from_slice(&salt);
+    body.extend_from_slice(&(RECORD_BYTES as u32).to_be_bytes());
+    body.push(PUBLIC_KEY_BYTES as u8);
+    body.extend_from_slice(public_key.as_ref());
+    body.extend_from_slice(&record);
+    Ok(body)
+}
+
+fn expand<const N: usize>(pseudorandom_key: &Prk, info: &[u8]) -> Result<[u8; N], Unspecified> {
+    let mut output = [0; N];
+    pseudorandom_key
+        .expand(&[info], Length(N))?
+        .fill(&mut output)?;
+    Ok(output)
+}
+
+/// How many bytes to expand to
+struct Length(usize);
+
+impl KeyType for Length {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
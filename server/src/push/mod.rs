@@ -0,0 +1,497 @@
+//! Web Push for users without a connected device. Browsers subscribe through the REST API and get
+//! the messages sent to the user while none of their devices is connected, encrypted so that the
+//! push services in between can't read them.
+
+mod encryption;
+mod vapid;
+
+use crate::actor::ChatMessage;
+use crate::config::Config;
+use crate::sessions::Authenticated;
+use crate::storage::push::PushSubscription;
+use crate::storage::Storage;
+use crate::telemetry;
+use crate::AppState;
+use axum::extract::{Query, State};
+use axum::http::header::{AUTHORIZATION, CONTENT_ENCODING, CONTENT_TYPE};
+use axum::http::StatusCode;
+use axum::Json;
+use base64::alphabet::URL_SAFE;
+use base64::engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig};
+use base64::Engine;
+use futures_util::future::BoxFuture;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::redirect::Policy;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+
+pub(crate) use vapid::Vapid;
+
+/// Keys are base64url encoded without padding, but some clients pad them anyway
+const BASE64: GeneralPurpose = GeneralPurpose::new(
+    &URL_SAFE,
+    GeneralPurposeConfig::new()
+        .with_encode_padding(false)
+        .with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// How long push services keep a message for a browser that is offline as well. The server keeps
+/// the message itself for when the user comes back, so a notification about it is of little use
+/// much later.
+const TIME_TO_LIVE: Duration = Duration::from_secs(24 * 60 * 60);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Error)]
+pub(crate) enum PushError {
+    #[error("The stored push subscription is invalid")]
+    Subscription,
+    #[error("Error encrypting or signing the push message")]
+    Crypto,
+    #[error("The push endpoint is not a public HTTPS URL")]
+    Endpoint,
+    #[error("The push service has no public address")]
+    PrivateAddress,
+    #[error("Error sending the push message: {0}")]
+    Request(#[from] reqwest::Error),
+}
+
+/// A push message as RFC 8030 has it posted to the push service, encrypted and signed already
+pub(crate) struct PushRequest {
+    pub(crate) endpoint: Url,
+    pub(crate) authorization: String,
+    /// `aes128gcm` encoded
+    pub(crate) body: Vec<u8>,
+}
+
+/// Hands push messages to the push services of the browsers. The server sends them over HTTP,
+/// which reaches whatever the subscriptions point to, like a mock push service in tests.
+pub(crate) trait PushService: Send + Sync + 'static {
+    /// Returns the status the push service answered with
+    fn send(&self, request: PushRequest) -> BoxFuture<'_, Result<StatusCode, PushError>>;
+}
+
+pub(crate) struct HttpPushService {
+    client: reqwest::Client,
+    /// Whether plain HTTP and local addresses can be reached, see [`is_allowed_endpoint`]
+    allow_insecure_endpoints: bool,
+}
+
+impl HttpPushService {
+    pub(crate) fn new(allow_insecure_endpoints: bool) -> Result<Self, PushError> {
+        let mut client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            // Push services answer right away, a redirect could only lead somewhere else
+            .redirect(Policy::none());
+        if !allow_insecure_endpoints {
+            client = client.dns_resolver(Arc::new(PublicResolver));
+        }
+        Ok(Self {
+            client: client.build()?,
+            allow_insecure_endpoints,
+        })
+    }
+}
+
+impl PushService for HttpPushService {
+    fn send(&self, request: PushRequest) -> BoxFuture<'_, Result<StatusCode, PushError>> {
+        Box::pin(async move {
+            // Subscriptions stored before the endpoints were checked as strictly
+            if !is_allowed_endpoint(&request.endpoint, self.allow_insecure_endpoints) {
+                return Err(PushError::Endpoint);
+            }
+            let response = self
+                .client
+                .post(request.endpoint)
+                .header(AUTHORIZATION, request.authorization)
+                .header(CONTENT_ENCODING, "aes128gcm")
+                .header(CONTENT_TYPE, "application/octet-stream")
+                .header("TTL", TIME_TO_LIVE.as_secs())
+                // Chat messages are what users expect to be woken up for
+                .header("Urgency", "high")
+                .body(request.body)
+                .send()
+                .await?;
+            Ok(response.status())
+        })
+    }
+}
+
+/// Resolves the hosts of push services to their public addresses only, so that a name pointing
+/// into the server's network can't get past [`is_allowed_endpoint`]
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addresses: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|address| is_public(address.ip()))
+                .collect();
+            if addresses.is_empty() {
+                return Err(PushError::PrivateAddress.into());
+            }
+            let addresses: Addrs = Box::new(addresses.into_iter());
+            Ok(addresses)
+        })
+    }
+}
+
+/// Sends the messages to users that are not connected to their push subscriptions
+#[derive(Clone)]
+pub(crate) struct Pusher {
+    storage: Storage,
+    vapid: Arc<Vapid>,
+    service: Arc<dyn PushService>,
+}
+
+impl Pusher {
+    pub(crate) fn new(storage: Storage, vapid: Vapid, service: impl PushService) -> Self {
+        Self {
+            storage,
+            vapid: Arc::new(vapid),
+            service: Arc::new(service),
+        }
+    }
+
+    /// Sends push messages through the push services of the browsers if VAPID keys are set
+    pub(crate) fn from_config(config: &Config, storage: Storage) -> Option<Self> {
+        let Some((private_key, public_key)) = &config.vapid_keys else {
+            tracing::info!("No VAPID keys set, push notifications are disabled");
+            return None;
+        };
+
+        let vapid = Vapid::new(private_key, public_key, config.vapid_subject.clone())
+            .expect("should be able to read the VAPID keys");
+        let service = HttpPushService::new(config.push_allow_insecure_endpoints)
+            .expect("should be able to create the HTTP client");
+        Some(Self::new(storage, vapid, service))
+    }
+
+    /// Tells the browsers of the recipient about the message in the background, unless the
+    /// recipient blocked or muted the sender
+    pub(crate) fn push(&self, message: Arc<ChatMessage>) {
+        let pusher = self.clone();
+        tokio::spawn(async move { pusher.push_to_subscriptions(message).await });
+    }
+
+    async fn push_to_subscriptions(&self, message: Arc<ChatMessage>) {
+        let recipient = message.recipient.clone();
+        let filters = match self.storage.delivery_filters(recipient.clone()).await {
+            Ok(filters) => filters,
+            Err(error) => {
+                tracing::error!("Error loading blocked and muted users: {}", error);
+                return;
+            }
+        };
+        let sender = message.sender.as_str();
+        if filters.blocked.contains(sender) || filters.muted.contains(sender) {
+            return;
+        }
+
+        let subscriptions = match self.storage.push_subscriptions(recipient).await {
+            Ok(subscriptions) => subscriptions,
+            Err(error) => {
+                tracing::error!("Error loading push subscriptions: {}", error);
+                return;
+            }
+        };
+        if subscriptions.is_empty() {
+            return;
+        }
+
+        let payload = match payload(&message) {
+            Ok(payload) => payload,
+            Err(error) => {
+                tracing::error!("Error serializing push message: {}", error);
+                return;
+            }
+        };
+        for subscription in subscriptions {
+            self.send(subscription, &payload).await;
+        }
+    }
+
+    async fn send(&self, subscription: PushSubscription, payload: &[u8]) {
+        let result = match self.request(&subscription, payload) {
+            Ok(request) => self.service.send(request).await,
+            Err(error) => Err(error),
+        };
+        let outcome = match result {
+            Ok(status) if status.is_success() => "sent",
+            // The browser unsubscribed or the subscription expired
+            Ok(StatusCode::NOT_FOUND | StatusCode::GONE) => {
+                tracing::debug!("Removing expired push subscription");
+                let endpoint = subscription.endpoint;
+                if let Err(error) = self.storage.delete_push_subscription(endpoint).await {
+                    tracing::error!("Error removing push subscription: {}", error);
+                }
+                "expired"
+            }
+            Ok(status) => {
+                tracing::warn!("Push service rejected push message with {}", status);
+                "rejected"
+            }
+            Err(error) => {
+                tracing::error!("Error pushing message: {}", error);
+                "failed"
+            }
+        };
+        metrics::counter!(telemetry::PUSH_MESSAGES, "outcome" => outcome).increment(1);
+    }
+
+    fn request(
+        &self,
+        subscription: &PushSubscription,
+        payload: &[u8],
+    ) -> Result<PushRequest, PushError> {
+        let endpoint: Url = subscription
+            .endpoint
+            .parse()
+            .map_err(|_| PushError::Subscription)?;
+        let public_key = BASE64
+            .decode(&subscription.p256dh)
+            .map_err(|_| PushError::Subscription)?;
+        let auth_secret = BASE64
+            .decode(&subscription.auth)
+            .map_err(|_| PushError::Subscription)?;
+
+        let body = encryption::encrypt(payload, &public_key, &auth_secret)
+            .map_err(|_| PushError::Crypto)?;
+        let authorization = self
+            .vapid
+            .authorization(&endpoint)
+            .map_err(|_| PushError::Crypto)?;
+        Ok(PushRequest {
+            endpoint,
+            authorization,
+            body,
+        })
+    }
+}
+
+/// The message as JSON like the sockets get it, with the text shortened if it doesn't fit
+fn payload(message: &ChatMessage) -> Result<Vec<u8>, serde_json::Error> {
+    let mut payload = serde_json::to_vec(message)?;
+    let mut text = message.text.as_str();
+    // Cut by what is too much of the JSON, which is more than needed if the text has characters
+    // that are escaped in the JSON, but is done in one try for most texts
+    while payload.len() > encryption::MAX_PLAINTEXT_BYTES {
+        let excess = payload.len() - encryption::MAX_PLAINTEXT_BYTES;
+        let end = text.len().saturating_sub(excess);
+        let end = text
+            .char_indices()
+            .map(|(index, _)| index)
+            .take_while(|index| *index <= end)
+            .last()
+            .unwrap_or(0);
+        text = &text[..end];
+        payload = serde_json::to_vec(&ChatMessage {
+            recipient: message.recipient.clone(),
+            sender: message.sender.clone(),
+            text: text.to_owned(),
+            time_utc: message.time_utc,
+        })?;
+    }
+    Ok(payload)
+}
+
+/// A subscription as browsers serialize it
+#[derive(Deserialize)]
+pub(crate) struct Subscription {
+    endpoint: String,
+    keys: SubscriptionKeys,
+}
+
+#[derive(Deserialize)]
+struct SubscriptionKeys {
+    p256dh: String,
+    auth: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct Unsubscribe {
+    endpoint: String,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ServerKey {
+    /// The `applicationServerKey` to subscribe with, base64url encoded
+    public_key: String,
+}
+
+pub(crate) async fn get_server_key(
+    State(state): State<AppState>,
+) -> Result<Json<ServerKey>, StatusCode> {
+    let pusher = state.pusher.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(ServerKey {
+        public_key: pusher.vapid.public_key().to_owned(),
+    }))
+}
+
+/// Sends push messages to the browser while none of the devices of the user of the session is
+/// connected
+pub(crate) async fn subscribe(
+    Authenticated(user): Authenticated,
+    State(state): State<AppState>,
+    Json(subscription): Json<Subscription>,
+) -> StatusCode {
+    let allow_insecure = state.config.push_allow_insecure_endpoints;
+    let is_valid = subscription
+        .endpoint
+        .parse()
+        .is_ok_and(|endpoint| is_allowed_endpoint(&endpoint, allow_insecure))
+        && has_length(&subscription.keys.p256dh, encryption::PUBLIC_KEY_BYTES)
+        && has_length(&subscription.keys.auth, encryption::AUTH_SECRET_BYTES);
+    if !is_valid {
+        return StatusCode::BAD_REQUEST;
+    }
+
+    let subscription = PushSubscription {
+        endpoint: subscription.endpoint,
+        p256dh: subscription.keys.p256dh,
+        auth: subscription.keys.auth,
+    };
+    let result = state
+        .storage
+        .add_push_subscription(user, subscription)
+        .await;
+    match result {
+        Ok(()) => StatusCode::CREATED,
+        Err(error) => {
+            tracing::error!("Error storing push subscription: {}", error);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+pub(crate) async fn unsubscribe(
+    Authenticated(user): Authenticated,
+    Query(Unsubscribe { endpoint }): Query<Unsubscribe>,
+    State(state): State<AppState>,
+) -> StatusCode {
+    let result = state.storage.remove_push_subscription(user, endpoint).await;
+    match result {
+        Ok(true) => StatusCode::NO_CONTENT,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(error) => {
+            tracing::error!("Error removing push subscription: {}", error);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+/// Push services are reached over HTTPS on public hosts, so that subscriptions can't make the
+/// server post into its own network. Plain HTTP and local hosts are only allowed if the operators
+/// allow them, for a mock push service.
+fn is_allowed_endpoint(endpoint: &Url, allow_insecure: bool) -> bool {
+    let Some(host) = endpoint.host_str() else {
+        return false;
+    };
+    if allow_insecure {
+        return matches!(endpoint.scheme(), "https" | "http");
+    }
+    if endpoint.scheme() != "https" {
+        return false;
+    }
+
+    // IPv6 hosts are in brackets
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    match host.parse::<IpAddr>() {
+        Ok(address) => is_public(address),
+        Err(_) => {
+            let host = host.trim_end_matches('.').to_ascii_lowercase();
+            host != "localhost" && !host.ends_with(".localhost")
+        }
+    }
+}
+
+/// Whether the address is reachable from the internet rather than only from the server's network
+fn is_public(address: IpAddr) -> bool {
+    match address {
+        IpAddr::V4(address) => is_public_ipv4(address),
+        IpAddr::V6(address) => match address.to_ipv4_mapped() {
+            Some(address) => is_public_ipv4(address),
+            None => {
+                let first = address.segments()[0];
+                let is_unique_local = first & 0xfe00 == 0xfc00;
+                let is_link_local = first & 0xffc0 == 0xfe80;
+                !(address.is_loopback()
+                    || address.is_unspecified()
+                    || address.is_multicast()
+                    || is_unique_local
+                    || is_link_local)
+            }
+        },
+    }
+}
+
+fn is_public_ipv4(address: Ipv4Addr) -> bool {
+    let [first, second, ..] = address.octets();
+    // Carrier-grade NAT, which is private to the network of the provider
+    let is_shared = first == 100 && second & 0xc0 == 64;
+    !(address.is_private()
+        || address.is_loopback()
+        || address.is_link_local()
+        || address.is_unspecified()
+        || address.is_broadcast()
+        || address.is_multicast()
+        || address.is_documentation()
+        || is_shared
+        || first == 0)
+}
+
+fn has_length(key: &str, length: usize) -> bool {
+    BASE64.decode(key).is_ok_and(|key| key.len() == length)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_allowed(endpoint: &str, allow_insecure: bool) -> bool {
+        is_allowed_endpoint(&endpoint.parse().unwrap(), allow_insecure)
+    }
+
+    #[test]
+    fn allows_only_public_https_endpoints() {
+        assert!(is_allowed("https://fcm.googleapis.com/fcm/send/abc", false));
+        assert!(is_allowed("https://203.0.113.1.nip.io/push", false));
+        assert!(is_allowed("https://[2606:4700::1111]/push", false));
+
+        for endpoint in [
+            "http://push.example.com/abc",
+            "https://localhost/push",
+            "https://push.localhost./push",
+            "https://127.0.0.1/push",
+            "https://10.0.0.8/push",
+            "https://192.168.1.1/push",
+            "https://169.254.169.254/latest/meta-data",
+            "https://100.64.0.1/push",
+            "https://0.0.0.0/push",
+            "https://[::1]/push",
+            "https://[fd00::1]/push",
+            "https://[fe80::1]/push",
+            "https://[::ffff:127.0.0.1]/push",
+        ] {
+            assert!(!is_allowed(endpoint, false), "{endpoint} should be refused");
+        }
+
+        assert!(is_allowed("http://localhost:8080/push", true));
+        assert!(!is_allowed("ftp://localhost/push", true));
+    }
+
+    #[test]
+    fn shortens_long_texts_at_character_boundaries() {
+        let message = crate::storage::tests::message("alice", "bob", &"ä".repeat(4000));
+
+        let payload = payload(&message).unwrap();
+        assert!(payload.len() <= encryption::MAX_PLAINTEXT_BYTES);
+        let shortened: ChatMessage = serde_json::from_slice(&payload).unwrap();
+        assert!(!shortened.text.is_empty());
+        assert!(message.text.starts_with(&shortened.text));
+    }
+}
@@ -0,0 +1,92 @@
+//! Identifies the server to the push services with VAPID as RFC 8292 describes it, so that only
+//! this server can send to the subscriptions made with its public key
+
+use base64::Engine;
+use reqwest::Url;
+use ring::error::{KeyRejected, Unspecified};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use serde::Serialize;
+use thiserror::Error;
+use time::{Duration, OffsetDateTime};
+
+use super::BASE64;
+
+/// How long push services accept a token. They reject ones valid for more than a day.
+const TOKEN_LIFETIME: Duration = Duration::hours(12);
+
+#[derive(Debug, Error)]
+pub(crate) enum VapidError {
+    #[error("The VAPID keys are not base64url encoded: {0}")]
+    Encoding(#[from] base64::DecodeError),
+    #[error("The VAPID keys are not a P-256 key pair: {0}")]
+    Key(KeyRejected),
+}
+
+#[derive(Serialize)]
+struct Claims<'a> {
+    /// The origin of the push service
+    aud: &'a str,
+    exp: i64,
+    /// How the operators of the push service can reach the operators of this server
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sub: Option<&'a str>,
+}
+
+pub(crate) struct Vapid {
+    key_pair: EcdsaKeyPair,
+    /// What browsers subscribe with, base64url encoded
+    public_key: String,
+    subject: Option<String>,
+    random: SystemRandom,
+}
+
+impl Vapid {
+    /// Takes the keys base64url encoded, the private one as the raw scalar and the public one as
+    /// uncompressed point, which is how common tools generate them
+    pub(crate) fn new(
+        private_key: &str,
+        public_key: &str,
+        subject: Option<String>,
+    ) -> Result<Self, VapidError> {
+        let random = SystemRandom::new();
+        let key_pair = EcdsaKeyPair::from_private_key_and_public_key(
+            &ECDSA_P256_SHA256_FIXED_SIGNING,
+            &BASE64.decode(private_key)?,
+            &BASE64.decode(public_key)?,
+            &random,
+        )
+        .map_err(VapidError::Key)?;
+
+        Ok(Self {
+            key_pair,
+            public_key: public_key.trim_end_matches('=').to_owned(),
+            subject,
+            random,
+        })
+    }
+
+    pub(crate) fn public_key(&self) -> &str {
+        &self.public_key
+    }
+
+    /// The `Authorization` header for a push message to the endpoint
+    pub(super) fn authorization(&self, endpoint: &Url) -> Result<String, Unspecified> {
+        let audience = endpoint.origin().ascii_serialization();
+        let claims = Claims {
+            aud: &audience,
+            exp: (OffsetDateTime::now_utc() + TOKEN_LIFETIME).unix_timestamp(),
+            sub: self.subject.as_deref(),
+        };
+        let claims = serde_json::to_vec(&claims).map_err(|_| Unspecified)?;
+        let header = BASE64.encode(br#"{"typ":"JWT","alg":"ES256"}"#);
+        let token = format!("{header}.{}", BASE64.encode(claims));
+        let signature = self.key_pair.sign(&self.random, token.as_bytes())?;
+
+        Ok(format!(
+            "vapid t={token}.{}, k={}",
+            BASE64.encode(signature),
+            self.public_key
+        ))
+    }
+}
@@ -0,0 +1,502 @@
+use crate::actor::audit_log::AuditEvent;
+use crate::actor::quic::{self, close_code};
+use crate::actor::HandleError;
+use crate::config::Config;
+use crate::AppState;
+use axum::http::{Method, Request, Response, StatusCode};
+use bytes::Bytes;
+use h3::ext::Protocol;
+use h3::quic::BidiStream;
+use h3::server::RequestStream;
+use h3_webtransport::server::{AcceptedBi, WebTransportSession};
+use percent_encoding::percent_decode_str;
+use quinn::crypto::rustls::QuicServerConfig;
+use quinn::{Endpoint, Incoming};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// WebTransport sessions are set up through HTTP/3
+pub(crate) const ALPN: &[u8] = b"h3";
+
+/// The path of the session, like the websocket route, ends with the name of the user
+const PATH_PREFIX: &str = "/messages/";
+
+/// How long a new connection may take to set up the session and open its stream
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a refused client gets to read the response before the connection is closed
+const REFUSAL_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum SetupError {
+    #[error("Error reading the certificate or key: {0}")]
+    Pem(#[from] rustls::pki_types::pem::Error),
+    #[error("Error generating a self-signed certificate: {0}")]
+    SelfSigned(#[from] rcgen::Error),
+    #[error("Error setting up TLS: {0}")]
+    Tls(#[from] rustls::Error),
+    #[error("The TLS configuration does not work with QUIC: {0}")]
+    Quic(#[from] quinn::crypto::rustls::NoInitialCipherSuite),
+    #[error("Error binding the UDP socket: {0}")]
+    Bind(#[from] std::io::Error),
+}
+
+#[derive(Debug, thiserror::Error)]
+enum HandshakeError {
+    #[error("Error establishing connection: {0}")]
+    Connection(#[from] quinn::ConnectionError),
+    #[error("Error setting up HTTP/3: {0}")]
+    Http(#[from] h3::error::ConnectionError),
+    #[error("Error reading the request: {0}")]
+    Request(#[from] h3::error::StreamError),
+    #[error("Connection closed before a session was requested")]
+    NoRequest,
+    #[error("Session ended before the stream was opened")]
+    NoStream,
+}
+
+/// Binds the WebTransport listener with the configured certificate or a self-signed one for development
+pub(crate) fn endpoint(config: &Config) -> Result<Endpoint, SetupError> {
+    let (certificates, key) = match &config.quic_certificate {
+        Some((certificate_path, key_path)) => {
+            let certificates =
+                CertificateDer::pem_file_iter(certificate_path)?.collect::<Result<Vec<_>, _>>()?;
+            (certificates, PrivateKeyDer::from_pem_file(key_path)?)
+        }
+        None => {
+            tracing::warn!(
+                "No QUIC_CERT_PATH and QUIC_KEY_PATH set, using a self-signed certificate for \
+                 localhost"
+            );
+            let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()])?;
+            let key = PrivatePkcs8KeyDer::from(certified.signing_key.serialize_der());
+            (vec![certified.cert.der().clone()], key.into())
+        }
+    };
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut tls = rustls::ServerConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_no_client_auth()
+        .with_single_cert(certificates, key)?;
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+
+    let server_config =
+        quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(tls)?));
+    let address = SocketAddr::from(([0, 0, 0, 0], config.quic_port));
+    Ok(Endpoint::server(server_config, address)?)
+}
+
+/// Accepts connections until the server shuts down. Open connections are drained through their
+/// actors like websockets.
+pub(crate) async fn serve(endpoint: Endpoint, state: AppState) {
+    loop {
+        let incoming = tokio::select! {
+            _ = state.shutdown.cancelled() => break,
+            incoming = endpoint.accept() => incoming,
+        };
+        let Some(incoming) = incoming else {
+            break;
+        };
+
+        tokio::spawn(accept(incoming, state.clone()));
+    }
+}
+
+/// The client requests a WebTransport session at `/messages/{name}` and opens a bidirectional
+/// stream in it. Everything after that is the same as on a websocket.
+async fn accept(incoming: Incoming, state: AppState) {
+    let remote = incoming.remote_address().ip();
+    let request = async {
+        let connection = incoming.await?;
+        let mut http = h3::server::builder()
+            .enable_webtransport(true)
+            .enable_extended_connect(true)
+            .enable_datagram(true)
+            .max_webtransport_sessions(1)
+            .build(h3_quinn::Connection::new(connection.clone()))
+            .await?;
+        let resolver = http.accept().await?.ok_or(HandshakeError::NoRequest)?;
+        let (request, stream) = resolver.resolve_request().await?;
+        Ok::<_, HandshakeError>((connection, http, request, stream))
+    };
+    let (connection, http, request, mut stream) = match timeout(request).await {
+        Some(request) => request,
+        None => return,
+    };
+
+    let name = match user_name(&request) {
+        Ok(name) => name,
+        Err(status) => {
+            refuse(&connection, &mut stream, status).await;
+            return;
+        }
+    };
+    if let Err(status) = crate::admit(&state, name.clone(), remote).await {
+        refuse(&connection, &mut stream, status).await;
+        return;
+    }
+
+    let session = async {
+        let session = WebTransportSession::accept(request, stream, http).await?;
+        match session.accept_bi().await? {
+            Some(AcceptedBi::BidiStream(_, stream)) => Ok((session, stream)),
+            // The only request expected on the connection is the session itself
+            Some(AcceptedBi::Request(_, _)) | None => Err(HandshakeError::NoStream),
+        }
+    };
+    let (session, stream) = match timeout(session).await {
+        Some(session) => session,
+        None => return,
+    };
+
+    let user = match state.delivery_service.get_or_insert(name.clone()).await {
+        Ok(user) => user,
+        Err(error) => {
+            tracing::error!(
+                "Error getting/creating user for WebTransport session: {:?}",
+                error
+            );
+            let (code, reason) = match error {
+                HandleError::Timeout(_) => (close_code::AGAIN, "Server is busy, try again later"),
+                _ => (close_code::ERROR, "Error setting up user"),
+            };
+            connection.close(code, reason.as_bytes());
+            return;
+        }
+    };
+
+    let (send, receive) = BidiStream::split(stream);
+    let socket = quic::Handle::new(
+        connection,
+        session,
+        send,
+        quic::lines(receive),
+        user.clone(),
+        state.audit_log.clone(),
+        name.clone(),
+    );
+    if let Err(error) = user.add_socket(socket.into()).await {
+        tracing::error!("Error adding WebTransport session: {}", error);
+        return;
+    }
+
+    state.audit_log.record(AuditEvent::Login {
+        user: name,
+        address: remote,
+    });
+}
+
+/// Gives up on clients that fail or take too long to set up their session
+async fn timeout<T>(handshake: impl Future<Output = Result<T, HandshakeError>>) -> Option<T> {
+    match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+        Ok(Ok(handshake)) => Some(handshake),
+        Ok(Err(error)) => {
+            tracing::info!("WebTransport handshake failed: {}", error);
+            None
+        }
+        Err(_) => {
+            tracing::info!("WebTransport handshake timed out");
+            None
+        }
+    }
+}
+
+fn user_name(request: &Request<()>) -> Result<Arc<str>, StatusCode> {
+    let is_webtransport = request.method() == Method::CONNECT
+        && request.extensions().get::<Protocol>() == Some(&Protocol::WEB_TRANSPORT);
+    if !is_webtransport {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let name = request
+        .uri()
+        .path()
+        .strip_prefix(PATH_PREFIX)
+        .filter(|name| !name.is_empty() && !name.contains('/'))
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let name = percent_decode_str(name)
+        .decode_utf8()
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    Ok(name.into())
+}
+
+/// Answers the session request with the status a websocket upgrade would get, like `403` for bans
+async fn refuse(
+    connection: &quinn::Connection,
+    stream: &mut RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    status: StatusCode,
+) {
+    let response = Response::builder()
+        .status(status)
+        .body(())
+        .expect("a response with only a status should be valid");
+    let sent = async {
+        stream.send_response(response).await?;
+        stream.finish().await
+    };
+    if let Err(error) = sent.await {
+        tracing::info!("Error refusing WebTransport session: {}", error);
+    }
+
+    // Closing right away would drop the response before the client read it
+    let _ = tokio::time::timeout(REFUSAL_TIMEOUT, connection.closed()).await;
+    connection.close(close_code::POLICY, b"Refused");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quinn::crypto::rustls::QuicClientConfig;
+    use rustls::RootCertStore;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader, Lines};
+
+    /// A listener with a certificate for localhost that the clients trust
+    struct Server {
+        address: SocketAddr,
+        state: AppState,
+        certificate: CertificateDer<'static>,
+    }
+
+    /// Speaks just enough HTTP/3 to set up a WebTransport session like a browser does, as the h3
+    /// client can't ask for one
+    struct Client {
+        _endpoint: Endpoint,
+        connection: quinn::Connection,
+        /// Closing the control stream or the session request would end the session
+        _control: quinn::SendStream,
+        _request: quinn::SendStream,
+        send: quinn::SendStream,
+        lines: Lines<BufReader<quinn::RecvStream>>,
+    }
+
+    async fn server() -> Server {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let directory = std::env::temp_dir().join(format!("melt-quic-{}", nanoid::nanoid!()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let (certificate_path, key_path) = (directory.join("cert.pem"), directory.join("key.pem"));
+        std::fs::write(&certificate_path, certified.cert.pem()).unwrap();
+        std::fs::write(&key_path, certified.signing_key.serialize_pem()).unwrap();
+
+        let config = Config {
+            quic_port: 0,
+            quic_certificate: Some((certificate_path, key_path)),
+            ..Config::default()
+        };
+        let endpoint = endpoint(&config).unwrap();
+        let port = endpoint.local_addr().unwrap().port();
+        let state = crate::tests::state(config);
+        tokio::spawn(serve(endpoint, state.clone()));
+
+        Server {
+            address: SocketAddr::from(([127, 0, 0, 1], port)),
+            state,
+            certificate: certified.cert.der().clone(),
+        }
+    }
+
+    impl Server {
+        /// Fails with the status the server answered the session request with
+        async fn connect(&self, name: &str) -> Result<Client, u16> {
+            let mut roots = RootCertStore::empty();
+            roots.add(self.certificate.clone()).unwrap();
+            let provider = Arc::new(rustls::crypto::ring::default_provider());
+            let mut tls = rustls::ClientConfig::builder_with_provider(provider)
+                .with_protocol_versions(&[&rustls::version::TLS13])
+                .unwrap()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            tls.alpn_protocols = vec![ALPN.to_vec()];
+
+            let mut endpoint = Endpoint::client(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+            endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(
+                QuicClientConfig::try_from(tls).unwrap(),
+            )));
+            let connection = endpoint
+                .connect(self.address, "localhost")
+                .unwrap()
+                .await
+                .unwrap();
+
+            // A control stream with SETTINGS for extended CONNECT, datagrams and WebTransport
+            let mut control = connection.open_uni().await.unwrap();
+            let mut settings = Vec::new();
+            for (id, value) in [(0x08, 1), (0x33, 1), (0x2b60_3742, 1)] {
+                settings.extend(varint(id));
+                settings.extend(varint(value));
+            }
+            control.write_all(&[0x00]).await.unwrap();
+            control.write_all(&frame(0x04, &settings)).await.unwrap();
+
+            // The QPACK field section of the CONNECT request, only using the static table
+            let path = format!("/messages/{name}");
+            let mut headers = vec![0x00, 0x00];
+            // :method CONNECT and :scheme https
+            headers.extend([0xcf, 0xd7]);
+            // :authority and :path with values
+            for (index, value) in [(0x50, "localhost"), (0x51, path.as_str())] {
+                headers.extend([index, value.len() as u8]);
+                headers.extend(value.as_bytes());
+            }
+            // :protocol is not in the static table, its name length of 9 takes two bytes
+            headers.extend([0x27, 0x02]);
+            headers.extend(b":protocol");
+            headers.push(12);
+            headers.extend(b"webtransport");
+
+            let (mut request, mut response) = connection.open_bi().await.unwrap();
+            request.write_all(&frame(0x01, &headers)).await.unwrap();
+            let status = loop {
+                let kind = read_varint(&mut response).await;
+                let mut payload = vec![0; read_varint(&mut response).await as usize];
+                response.read_exact(&mut payload).await.unwrap();
+                if kind == 0x01 {
+                    break status(&payload);
+                }
+            };
+            if status != 200 {
+                return Err(status);
+            }
+
+            // Streams of the session start with their type and the id of the session request
+            let (mut send, receive) = connection.open_bi().await.unwrap();
+            let mut header = varint(0x41);
+            header.extend(varint(request.id().index() << 2));
+            send.write_all(&header).await.unwrap();
+
+            Ok(Client {
+                _endpoint: endpoint,
+                connection,
+                _control: control,
+                _request: request,
+                send,
+                lines: BufReader::new(receive).lines(),
+            })
+        }
+
+        /// Waits until the session of the user was handed to its user actor
+        async fn wait_until_connected(&self, name: &str) {
+            loop {
+                let users = self.state.delivery_service.connected_users().await.unwrap();
+                if users.iter().any(|(user, _)| &**user == name) {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+    }
+
+    impl Client {
+        async fn send(&mut self, json: serde_json::Value) {
+            self.send
+                .write_all(format!("{json}\n").as_bytes())
+                .await
+                .unwrap();
+        }
+
+        async fn receive(&mut self) -> serde_json::Value {
+            let line = self.lines.next_line().await.unwrap().unwrap();
+            serde_json::from_str(&line).unwrap()
+        }
+
+        /// Reads a datagram of the session, which starts with the id of the session request
+        async fn receive_datagram(&self) -> serde_json::Value {
+            let datagram = self.connection.read_datagram().await.unwrap();
+            assert_eq!(datagram[0], 0x00);
+            serde_json::from_slice(&datagram[1..]).unwrap()
+        }
+    }
+
+    fn varint(value: u64) -> Vec<u8> {
+        match value {
+            0..=0x3f => vec![value as u8],
+            0x40..=0x3fff => (value as u16 | 0x4000).to_be_bytes().to_vec(),
+            _ => (value as u32 | 0x8000_0000).to_be_bytes().to_vec(),
+        }
+    }
+
+    async fn read_varint(stream: &mut quinn::RecvStream) -> u64 {
+        let first = stream.read_u8().await.unwrap();
+        let mut value = u64::from(first & 0x3f);
+        for _ in 1..(1 << (first >> 6)) {
+            value = (value << 8) | u64::from(stream.read_u8().await.unwrap());
+        }
+        value
+    }
+
+    fn frame(kind: u64, payload: &[u8]) -> Vec<u8> {
+        let mut frame = varint(kind);
+        frame.extend(varint(payload.len() as u64));
+        frame.extend(payload);
+        frame
+    }
+
+    /// The status of a response, which the server sends as the first field from the static table
+    fn status(headers: &[u8]) -> u16 {
+        let index = match headers[2] {
+            0xff => 0x3f + headers[3],
+            byte => byte & 0x3f,
+        };
+        match index {
+            25 => 200,
+            27 => 404,
+            28 => 503,
+            67 => 400,
+            68 => 403,
+            index => panic!("unexpected status index {index}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn chats_through_webtransport_sessions() {
+        let server = server().await;
+        let storage = &server.state.storage;
+        storage
+            .request_contact("alice".into(), "bob".into())
+            .await
+            .unwrap();
+        storage
+            .accept_request("bob".into(), "alice".into())
+            .await
+            .unwrap();
+
+        let mut bob = server.connect("bob").await.unwrap();
+        server.wait_until_connected("bob").await;
+        let mut alice = server.connect("alice").await.unwrap();
+        server.wait_until_connected("alice").await;
+
+        let presence = bob.receive_datagram().await;
+        assert_eq!(presence["type"], "AddUser");
+        assert_eq!(presence["name"], "alice");
+
+        alice
+            .send(serde_json::json!({
+                "recipient": "bob",
+                "sender": "alice",
+                "text": "Hi Bob",
+                "time_utc": 1_700_000_000_000_u64,
+            }))
+            .await;
+        let message = bob.receive().await;
+        assert_eq!(message["type"], "ChatMessage");
+        assert_eq!(message["message"]["text"], "Hi Bob");
+    }
+
+    #[tokio::test]
+    async fn refuses_sessions_of_banned_users_with_forbidden() {
+        let server = server().await;
+        server
+            .state
+            .storage
+            .ban_name("mallory".into())
+            .await
+            .unwrap();
+
+        assert_eq!(server.connect("mallory").await.err(), Some(403));
+        assert!(server.connect("alice").await.is_ok());
+    }
+}
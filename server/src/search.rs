@@ -0,0 +1,86 @@
+use crate::actor::ChatMessage;
+use crate::sessions::Authenticated;
+use crate::storage::messages::SnippetPart;
+use crate::AppState;
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::Json;
+use serde::{Deserialize, Serialize};
+
+/// How many hits a page has if not asked for a different number
+const DEFAULT_LIMIT: usize = 20;
+const MAX_LIMIT: usize = 100;
+
+#[derive(Deserialize)]
+pub(crate) struct SearchParameters {
+    /// The words to search for
+    q: String,
+    /// Only search the conversation with this user
+    with: Option<String>,
+    limit: Option<usize>,
+    offset: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct Highlight {
+    text: String,
+    /// Matched the query
+    highlighted: bool,
+}
+
+#[derive(Serialize)]
+pub(crate) struct Hit {
+    message: ChatMessage,
+    /// The part of the text around the matches
+    snippet: Vec<Highlight>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct SearchResults {
+    /// The best matches first
+    hits: Vec<Hit>,
+    /// The offset of the next page if there is one
+    next_offset: Option<usize>,
+}
+
+/// Searches the messages the user sent or received. Snippets are split into parts instead of
+/// marking the matches with HTML, so that clients don't need to escape the messages.
+pub(crate) async fn search(
+    Authenticated(name): Authenticated,
+    Query(parameters): Query<SearchParameters>,
+    State(state): State<AppState>,
+) -> Result<Json<SearchResults>, StatusCode> {
+    let limit = parameters.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+    let offset = parameters.offset.unwrap_or(0);
+    let results = state
+        .storage
+        .search(
+            name,
+            parameters.with.map(Into::into),
+            &parameters.q,
+            limit,
+            offset,
+        )
+        .await
+        .map_err(|error| {
+            tracing::error!("Error searching messages: {}", error);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        // Nothing to search for
+        .ok_or(StatusCode::BAD_REQUEST)?;
+
+    let next_offset = results.has_more.then(|| offset + results.hits.len());
+    let hits = results
+        .hits
+        .into_iter()
+        .map(|hit| Hit {
+            message: hit.message,
+            snippet: hit
+                .snippet
+                .into_iter()
+                .map(|SnippetPart { text, highlighted }| Highlight { text, highlighted })
+                .collect(),
+        })
+        .collect();
+    Ok(Json(SearchResults { hits, next_offset }))
+}
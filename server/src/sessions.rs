@@ -0,0 +1,92 @@
+use crate::AppState;
+use axum::extract::{FromRequestParts, State};
+use axum::http::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use std::sync::Arc;
+
+/// The user a request is made by. Clients get the token for it through one of their sockets with
+/// the `CreateSession` command, and send it as bearer token.
+pub(crate) struct Authenticated(pub(crate) Arc<str>);
+
+impl FromRequestParts<AppState> for Authenticated {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let Some(token) = bearer_token(&parts.headers) else {
+            return Err(unauthorized());
+        };
+
+        match state.storage.session_user(token.to_owned()).await {
+            Ok(Some(user)) => Ok(Self(user)),
+            Ok(None) => Err(unauthorized()),
+            Err(error) => {
+                tracing::error!("Error looking up session: {}", error);
+                Err(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+            }
+        }
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+fn unauthorized() -> Response {
+    (StatusCode::UNAUTHORIZED, [(WWW_AUTHENTICATE, "Bearer")]).into_response()
+}
+
+/// Signs the device out, after which its token can't be used anymore
+pub(crate) async fn delete_session(headers: HeaderMap, State(state): State<AppState>) -> Response {
+    let Some(token) = bearer_token(&headers) else {
+        return unauthorized();
+    };
+
+    match state.storage.end_session(token.to_owned()).await {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => unauthorized(),
+        Err(error) => {
+            tracing::error!("Error ending session: {}", error);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use axum::http::Request;
+
+    async fn authenticate(state: &AppState, token: Option<&str>) -> Result<Arc<str>, StatusCode> {
+        let mut request = Request::builder();
+        if let Some(token) = token {
+            request = request.header(AUTHORIZATION, format!("Bearer {token}"));
+        }
+        let (mut parts, ()) = request.body(()).unwrap().into_parts();
+        Authenticated::from_request_parts(&mut parts, state)
+            .await
+            .map(|Authenticated(user)| user)
+            .map_err(|response| response.status())
+    }
+
+    #[tokio::test]
+    async fn authenticates_requests_with_the_token_of_a_session() {
+        let state = crate::tests::state(Config::default());
+        let token = state.storage.create_session("alice".into()).await.unwrap();
+
+        let user = authenticate(&state, Some(&token)).await;
+        assert_eq!(user.as_deref(), Ok("alice"));
+        let unknown = authenticate(&state, Some("alice")).await;
+        assert_eq!(unknown, Err(StatusCode::UNAUTHORIZED));
+        let missing = authenticate(&state, None).await;
+        assert_eq!(missing, Err(StatusCode::UNAUTHORIZED));
+    }
+}
@@ -0,0 +1,463 @@
+use super::contacts::names;
+use super::conversations::{self, Conversation};
+use super::messages::stored_text;
+use super::retention::{self, Retention};
+use super::{from_milliseconds, to_milliseconds, Storage, StorageError};
+use crate::actor::ChatMessage;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::sync::Arc;
+use time::OffsetDateTime;
+
+/// A message with the id it has in the database
+pub(crate) struct StoredMessage {
+    pub(crate) id: i64,
+    pub(crate) message: ChatMessage,
+    /// Sent with a contact request, so it is in the request inbox of the recipient
+    pub(crate) is_request: bool,
+    /// When the recipient read it, which starts the timer of disappearing messages
+    pub(crate) read_utc: Option<OffsetDateTime>,
+}
+
+/// A contact request that was neither accepted nor declined yet
+pub(crate) struct ContactRequest {
+    pub(crate) sender: Arc<str>,
+    pub(crate) recipient: Arc<str>,
+    pub(crate) time_utc: OffsetDateTime,
+}
+
+/// What the user set for a conversation on their side only
+pub(crate) struct ConversationSettings {
+    /// The other user
+    pub(crate) name: Arc<str>,
+    pub(crate) read_until_utc: Option<OffsetDateTime>,
+    pub(crate) draft: Option<String>,
+    pub(crate) archived: bool,
+}
+
+/// Everything stored about a user that can be taken to another deployment
+#[derive(Default)]
+pub(crate) struct Archive {
+    pub(crate) contacts: Vec<Arc<str>>,
+    pub(crate) blocked: Vec<Arc<str>>,
+    pub(crate) muted: Vec<Arc<str>>,
+    /// Sent and received
+    pub(crate) requests: Vec<ContactRequest>,
+    /// The conversations that don't keep their messages forever, with whom they are
+    pub(crate) retention: Vec<(Arc<str>, Retention)>,
+    pub(crate) conversations: Vec<ConversationSettings>,
+    /// Oldest first
+    pub(crate) messages: Vec<StoredMessage>,
+}
+
+pub(crate) struct Imported {
+    pub(crate) messages: usize,
+    /// Messages that are already stored with the same sender, recipient, time and text, most
+    /// likely from the archive of the other user of the conversation
+    pub(crate) skipped_messages: usize,
+    /// Messages whose id was taken by a different message, which were stored with a new id
+    pub(crate) renumbered_messages: usize,
+    /// The conversations that got messages or settings, with whose they are
+    pub(crate) conversations: Vec<(Arc<str>, Conversation)>,
+}
+
+impl Storage {
+    pub(crate) async fn export(&self, user: Arc<str>) -> Result<Archive, StorageError> {
+        self.call(move |connection| {
+            let mut statement = connection.prepare_cached(
+                "SELECT id, sender, recipient, text, time_utc, is_request, read_utc FROM messages
+                WHERE sender = ?1 OR recipient = ?1 ORDER BY time_utc, id",
+            )?;
+            let messages = statement
+                .query_map([user.as_ref()], |row| {
+                    let read_utc: Option<i64> = row.get(6)?;
+                    Ok(StoredMessage {
+                        id: row.get(0)?,
+                        message: ChatMessage {
+                            sender: row.get(1)?,
+                            recipient: row.get::<_, String>(2)?.into(),
+                            text: row.get(3)?,
+                            time_utc: from_milliseconds(row.get(4)?),
+                        },
+                        is_request: row.get(5)?,
+                        read_utc: read_utc.map(from_milliseconds),
+                    })
+                })?
+                .collect::<rusqlite::Result<_>>()?;
+
+            Ok(Archive {
+                contacts: names(
+                    connection,
+                    "SELECT contact FROM contacts WHERE user = ?1",
+                    &user,
+                )?,
+                blocked: names(
+                    connection,
+                    "SELECT blocked FROM blocked_users WHERE user = ?1",
+                    &user,
+                )?,
+                muted: names(
+                    connection,
+                    "SELECT muted FROM muted_users WHERE user = ?1",
+                    &user,
+                )?,
+                requests: requests(connection, &user)?,
+                retention: retention(connection, &user)?,
+                conversations: conversation_settings(connection, &user)?,
+                messages,
+            })
+        })
+        .await
+    }
+
+    /// Adds the archive of the user to what is stored. Messages keep their ids unless a different
+    /// message has it, and messages that are already stored are skipped, so that importing the
+    /// archives of both users of a conversation stores its messages once.
+    pub(crate) async fn import(
+        &self,
+        user: Arc<str>,
+        archive: Archive,
+    ) -> Result<Imported, StorageError> {
+        self.call(move |connection| {
+            let transaction = connection.transaction()?;
+            {
+                let mut statement = transaction
+                    .prepare("INSERT OR IGNORE INTO contacts (user, contact) VALUES (?1, ?2)")?;
+                for contact in &archive.contacts {
+                    statement.execute(params![user, contact])?;
+                    statement.execute(params![contact, user])?;
+                }
+                let mut statement = transaction.prepare(
+                    "INSERT OR IGNORE INTO blocked_users (user, blocked) VALUES (?1, ?2)",
+                )?;
+                for blocked in &archive.blocked {
+                    statement.execute(params![user, blocked])?;
+                }
+                let mut statement = transaction
+                    .prepare("INSERT OR IGNORE INTO muted_users (user, muted) VALUES (?1, ?2)")?;
+                for muted in &archive.muted {
+                    statement.execute(params![user, muted])?;
+                }
+                let mut statement = transaction.prepare(
+                    "INSERT OR IGNORE INTO contact_requests (sender, recipient, time_utc)
+                    VALUES (?1, ?2, ?3)",
+                )?;
+                for request in &archive.requests {
+                    statement.execute(params![
+                        request.sender,
+                        request.recipient,
+                        to_milliseconds(request.time_utc)
+                    ])?;
+                }
+                let mut statement = transaction.prepare(
+                    "INSERT OR REPLACE INTO retention (user_a, user_b, lifetime, after_read)
+                    VALUES (?1, ?2, ?3, ?4)",
+                )?;
+                for (contact, retention) in &archive.retention {
+                    let (user_a, user_b) = retention::conversation(&user, contact);
+                    if let Some((lifetime, after_read)) = retention.to_columns() {
+                        statement.execute(params![user_a, user_b, lifetime, after_read])?;
+                    }
+                }
+                let mut statement = transaction.prepare(
+                    "INSERT INTO conversations
+                        (user, peer, last_activity_utc, unread, read_until_utc, draft, archived)
+                    VALUES (?1, ?2, 0, 0, ?3, ?4, ?5)
+                    ON CONFLICT (user, peer) DO UPDATE SET
+                        read_until_utc = IFNULL(
+                            MAX(read_until_utc, excluded.read_until_utc),
+                            IFNULL(read_until_utc, excluded.read_until_utc)
+                        ),
+                        draft = IFNULL(excluded.draft, draft),
+                        archived = excluded.archived",
+                )?;
+                for settings in &archive.conversations {
+                    statement.execute(params![
+                        user,
+                        settings.name,
+                        settings.read_until_utc.map(to_milliseconds),
+                        settings.draft,
+                        settings.archived
+                    ])?;
+                }
+            }
+
+            let mut imported = Imported {
+                messages: 0,
+                skipped_messages: 0,
+                renumbered_messages: 0,
+                conversations: Vec::new(),
+            };
+            for message in &archive.messages {
+                match import_message(&transaction, message)? {
+                    MessageImport::Stored => imported.messages += 1,
+                    MessageImport::Duplicate => imported.skipped_messages += 1,
+                    MessageImport::Renumbered => {
+                        imported.messages += 1;
+                        imported.renumbered_messages += 1;
+                    }
+                }
+            }
+
+            let pairs = archive
+                .messages
+                .iter()
+                .map(|StoredMessage { message, .. }| {
+                    (message.sender.as_str(), message.recipient.as_ref())
+                })
+                .chain(
+                    archive
+                        .conversations
+                        .iter()
+                        .map(|settings| (user.as_ref(), settings.name.as_ref())),
+                );
+            imported.conversations = conversations::rebuild_all(&transaction, pairs)?;
+            transaction.commit()?;
+            Ok(imported)
+        })
+        .await
+    }
+}
+
+enum MessageImport {
+    Stored,
+    /// The same message is already stored, with any id
+    Duplicate,
+    /// Its id was taken by a different message
+    Renumbered,
+}
+
+fn import_message(
+    connection: &Connection,
+    StoredMessage {
+        id,
+        message,
+        is_request,
+        read_utc,
+    }: &StoredMessage,
+) -> rusqlite::Result<MessageImport> {
+    let text = stored_text(&message.text);
+    let time_utc = to_milliseconds(message.time_utc);
+    let read_utc = read_utc.map(to_milliseconds);
+    let duplicate = connection
+        .prepare_cached(
+            "SELECT id FROM messages
+            WHERE sender = ?1 AND recipient = ?2 AND time_utc = ?3 AND text = ?4",
+        )?
+        .query_row(
+            params![message.sender, message.recipient, time_utc, text],
+            |row| row.get::<_, i64>(0),
+        )
+        .optional()?;
+    if let Some(duplicate) = duplicate {
+        // The archive of the recipient knows best whether it was read
+        connection
+            .prepare_cached("UPDATE messages SET read_utc = IFNULL(read_utc, ?2) WHERE id = ?1")?
+            .execute(params![duplicate, read_utc])?;
+        return Ok(MessageImport::Duplicate);
+    }
+
+    let inserted = connection
+        .prepare_cached(
+            "INSERT OR IGNORE INTO messages
+                (id, sender, recipient, text, time_utc, is_request, read_utc)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        )?
+        .execute(params![
+            id,
+            message.sender,
+            message.recipient,
+            text,
+            time_utc,
+            is_request,
+            read_utc
+        ])?;
+    if inserted > 0 {
+        return Ok(MessageImport::Stored);
+    }
+    connection
+        .prepare_cached(
+            "INSERT INTO messages (sender, recipient, text, time_utc, is_request, read_utc)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        )?
+        .execute(params![
+            message.sender,
+            message.recipient,
+            text,
+            time_utc,
+            is_request,
+            read_utc
+        ])?;
+    Ok(MessageImport::Renumbered)
+}
+
+fn requests(connection: &Connection, user: &str) -> rusqlite::Result<Vec<ContactRequest>> {
+    let mut statement = connection.prepare_cached(
+        "SELECT sender, recipient, time_utc FROM contact_requests
+        WHERE sender = ?1 OR recipient = ?1 ORDER BY time_utc",
+    )?;
+    let requests = statement.query_map([user], |row| {
+        Ok(ContactRequest {
+            sender: row.get::<_, String>(0)?.into(),
+            recipient: row.get::<_, String>(1)?.into(),
+            time_utc: from_milliseconds(row.get(2)?),
+        })
+    })?;
+    requests.collect()
+}
+
+fn retention(connection: &Connection, user: &str) -> rusqlite::Result<Vec<(Arc<str>, Retention)>> {
+    let mut statement = connection.prepare_cached(
+        "SELECT user_a, user_b, lifetime, after_read FROM retention
+        WHERE user_a = ?1 OR user_b = ?1",
+    )?;
+    let retention = statement.query_map([user], |row| {
+        let user_a: String = row.get(0)?;
+        let user_b: String = row.get(1)?;
+        let contact = if user_a == user { user_b } else { user_a };
+        let retention = Retention::from_columns(row.get(2)?, row.get(3)?);
+        Ok((contact.into(), retention))
+    })?;
+    retention.collect()
+}
+
+fn conversation_settings(
+    connection: &Connection,
+    user: &str,
+) -> rusqlite::Result<Vec<ConversationSettings>> {
+    let mut statement = connection.prepare_cached(
+        "SELECT peer, read_until_utc, draft, archived FROM conversations
+        WHERE user = ?1 AND (read_until_utc IS NOT NULL OR draft IS NOT NULL OR archived)",
+    )?;
+    let settings = statement.query_map([user], |row| {
+        let read_until_utc: Option<i64> = row.get(1)?;
+        Ok(ConversationSettings {
+            name: row.get::<_, String>(0)?.into(),
+            read_until_utc: read_until_utc.map(from_milliseconds),
+            draft: row.get(2)?,
+            archived: row.get(3)?,
+        })
+    })?;
+    settings.collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::{message, storage};
+    use std::num::NonZeroU32;
+
+    fn stored(id: i64, sender: &str, recipient: &str, text: &str) -> StoredMessage {
+        StoredMessage {
+            id,
+            message: ChatMessage {
+                recipient: recipient.into(),
+                sender: sender.to_owned(),
+                text: text.to_owned(),
+                time_utc: OffsetDateTime::UNIX_EPOCH,
+            },
+            is_request: false,
+            read_utc: None,
+        }
+    }
+
+    fn texts(archive: &Archive) -> Vec<&str> {
+        let messages = archive.messages.iter();
+        messages
+            .map(|stored| stored.message.text.as_str())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn renumbers_messages_whose_id_is_taken_by_another_message() {
+        let storage = storage();
+        storage
+            .store_message(message("carol", "dave", "Hi Dave"))
+            .await
+            .unwrap();
+        let id = storage.export("carol".into()).await.unwrap().messages[0].id;
+
+        let archive = Archive {
+            messages: vec![
+                stored(id, "alice", "bob", "Hi Bob"),
+                stored(id + 10, "bob", "alice", "Hi Alice"),
+            ],
+            ..Archive::default()
+        };
+        let imported = storage.import("alice".into(), archive).await.unwrap();
+        assert_eq!(imported.messages, 2);
+        assert_eq!(imported.skipped_messages, 0);
+        assert_eq!(imported.renumbered_messages, 1);
+
+        // The same message from the archive of the other user is stored once
+        let archive = Archive {
+            messages: vec![stored(id, "alice", "bob", "Hi Bob")],
+            ..Archive::default()
+        };
+        let imported = storage.import("bob".into(), archive).await.unwrap();
+        assert_eq!(imported.messages, 0);
+        assert_eq!(imported.skipped_messages, 1);
+        assert_eq!(imported.renumbered_messages, 0);
+
+        let carol = storage.export("carol".into()).await.unwrap();
+        assert_eq!(texts(&carol), ["Hi Dave"]);
+        let alice = storage.export("alice".into()).await.unwrap();
+        assert_eq!(texts(&alice), ["Hi Bob", "Hi Alice"]);
+    }
+
+    #[tokio::test]
+    async fn imports_settings_requests_and_read_markers() {
+        let source = storage();
+        source
+            .store_message(message("alice", "bob", "Hi Bob"))
+            .await
+            .unwrap();
+        source
+            .mark_read("bob".into(), "alice".into())
+            .await
+            .unwrap();
+        let seconds = NonZeroU32::new(30).unwrap();
+        let retention = Retention::AfterRead { seconds };
+        source
+            .set_retention("bob".into(), "alice".into(), retention)
+            .await
+            .unwrap();
+        source
+            .save_draft("bob".into(), "alice".into(), "Hi Al".to_owned())
+            .await
+            .unwrap();
+        source
+            .set_archived("bob".into(), "carol".into(), true)
+            .await
+            .unwrap();
+        source
+            .request_contact("dave".into(), "bob".into())
+            .await
+            .unwrap();
+        source
+            .store_message(message("dave", "bob", "Remember me?"))
+            .await
+            .unwrap();
+        let archive = source.export("bob".into()).await.unwrap();
+
+        let target = storage();
+        target.import("bob".into(), archive).await.unwrap();
+        let imported = target.export("bob".into()).await.unwrap();
+
+        assert!(imported.messages[0].read_utc.is_some());
+        assert!(imported.messages[1].is_request);
+        assert_eq!(imported.requests.len(), 1);
+        let retention = target.retention("alice".into(), "bob".into()).await;
+        assert_eq!(retention.unwrap(), Retention::AfterRead { seconds });
+        let conversations = target.conversations("bob".into()).await.unwrap();
+        let with = |name: &str| {
+            let mut conversations = conversations.iter();
+            conversations
+                .find(|conversation| &*conversation.name == name)
+                .unwrap()
+        };
+        assert_eq!(with("alice").draft.as_deref(), Some("Hi Al"));
+        assert!(with("alice").read_until_utc.is_some());
+        assert_eq!(with("alice").unread, 0);
+        assert!(with("carol").archived);
+    }
+}
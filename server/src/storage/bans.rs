@@ -0,0 +1,128 @@
+use super::{from_milliseconds, to_milliseconds, Storage, StorageError};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::net::IpAddr;
+use std::sync::Arc;
+use time::OffsetDateTime;
+
+/// A name or address that is not allowed to connect
+pub(crate) struct Ban<T> {
+    pub(crate) banned: T,
+    pub(crate) time_utc: OffsetDateTime,
+}
+
+pub(crate) struct Bans {
+    pub(crate) names: Vec<Ban<Arc<str>>>,
+    pub(crate) addresses: Vec<Ban<IpAddr>>,
+}
+
+impl Storage {
+    pub(crate) async fn bans(&self) -> Result<Bans, StorageError> {
+        self.call(|connection| {
+            let names = banned(
+                connection,
+                "SELECT name, time_utc FROM banned_names ORDER BY time_utc",
+            )?;
+            let addresses = banned(
+                connection,
+                "SELECT address, time_utc FROM banned_addresses ORDER BY time_utc",
+            )?;
+
+            Ok(Bans {
+                names: names
+                    .into_iter()
+                    .map(|(name, time_utc)| Ban {
+                        banned: name.into(),
+                        time_utc,
+                    })
+                    .collect(),
+                // Only valid addresses are written, so others would come from editing the
+                // database by hand and are skipped
+                addresses: addresses
+                    .into_iter()
+                    .filter_map(|(address, time_utc)| {
+                        let banned = address.parse().ok()?;
+                        Some(Ban { banned, time_utc })
+                    })
+                    .collect(),
+            })
+        })
+        .await
+    }
+
+    /// Whether the user or the address the user connects from is banned
+    pub(crate) async fn is_banned(
+        &self,
+        name: Arc<str>,
+        address: IpAddr,
+    ) -> Result<bool, StorageError> {
+        self.call(move |connection| {
+            let banned = connection
+                .query_row(
+                    "SELECT 1 FROM banned_names WHERE name = ?1
+                    UNION ALL
+                    SELECT 1 FROM banned_addresses WHERE address = ?2",
+                    params![name, address.to_string()],
+                    |_| Ok(()),
+                )
+                .optional()?;
+            Ok(banned.is_some())
+        })
+        .await
+    }
+
+    /// Also ends the sessions of the user, so that the REST API can't be used with them anymore
+    pub(crate) async fn ban_name(&self, name: Arc<str>) -> Result<(), StorageError> {
+        self.call(move |connection| {
+            let transaction = connection.transaction()?;
+            transaction.execute(
+                "INSERT OR IGNORE INTO banned_names (name, time_utc) VALUES (?1, ?2)",
+                params![name, to_milliseconds(OffsetDateTime::now_utc())],
+            )?;
+            transaction.execute("DELETE FROM sessions WHERE user = ?1", [name])?;
+            transaction.commit()
+        })
+        .await
+    }
+
+    /// Returns false if the name was not banned
+    pub(crate) async fn unban_name(&self, name: Arc<str>) -> Result<bool, StorageError> {
+        self.call(move |connection| {
+            let deleted = connection.execute("DELETE FROM banned_names WHERE name = ?1", [name])?;
+            Ok(deleted > 0)
+        })
+        .await
+    }
+
+    pub(crate) async fn ban_address(&self, address: IpAddr) -> Result<(), StorageError> {
+        self.call(move |connection| {
+            connection.execute(
+                "INSERT OR IGNORE INTO banned_addresses (address, time_utc) VALUES (?1, ?2)",
+                params![
+                    address.to_string(),
+                    to_milliseconds(OffsetDateTime::now_utc())
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Returns false if the address was not banned
+    pub(crate) async fn unban_address(&self, address: IpAddr) -> Result<bool, StorageError> {
+        self.call(move |connection| {
+            let deleted = connection.execute(
+                "DELETE FROM banned_addresses WHERE address = ?1",
+                [address.to_string()],
+            )?;
+            Ok(deleted > 0)
+        })
+        .await
+    }
+}
+
+fn banned(connection: &Connection, query: &str) -> rusqlite::Result<Vec<(String, OffsetDateTime)>> {
+    let mut statement = connection.prepare_cached(query)?;
+    let banned =
+        statement.query_map([], |row| Ok((row.get(0)?, from_milliseconds(row.get(1)?))))?;
+    banned.collect()
+}
@@ -0,0 +1,531 @@
+use super::conversations::{self, Conversation};
+use super::{from_milliseconds, to_milliseconds, Storage, StorageError};
+use crate::actor::ChatMessage;
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use std::collections::HashSet;
+use std::sync::Arc;
+use time::OffsetDateTime;
+
+/// What became of a contact request
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum RequestOutcome {
+    /// Waiting for the recipient to accept it
+    Requested,
+    /// The recipient had already asked the sender, so they are contacts now
+    Accepted,
+    AlreadyContacts,
+    /// The sender blocked the recipient and needs to unblock them first
+    Blocked,
+}
+
+/// A request that a user can accept or decline, with the messages sent along with it
+pub(crate) struct IncomingRequest {
+    pub(crate) sender: Arc<str>,
+    pub(crate) messages: Vec<ChatMessage>,
+}
+
+/// Everyone a user is connected to in some way
+pub(crate) struct ContactList {
+    pub(crate) contacts: Vec<Arc<str>>,
+    pub(crate) incoming: Vec<IncomingRequest>,
+    /// Requests the user sent that have not been answered
+    pub(crate) outgoing: Vec<Arc<str>>,
+    pub(crate) blocked: Vec<Arc<str>>,
+    pub(crate) muted: Vec<Arc<str>>,
+}
+
+/// Whose messages a user wants to receive and how
+#[derive(Default)]
+pub(crate) struct DeliveryFilters {
+    /// Messages from these users are not delivered
+    pub(crate) blocked: HashSet<Arc<str>>,
+    /// Messages from these users are delivered without notifying the user
+    pub(crate) muted: HashSet<Arc<str>>,
+}
+
+/// Whom deleting the messages of a user concerns
+pub(crate) struct DeletedMessages {
+    /// Recipients of the messages that were sent with contact requests
+    pub(crate) request_recipients: Vec<Arc<str>>,
+    /// The conversations the messages were deleted from, with whose they are
+    pub(crate) conversations: Vec<(Arc<str>, Conversation)>,
+}
+
+impl Storage {
+    pub(crate) async fn contacts(&self, user: Arc<str>) -> Result<Vec<Arc<str>>, StorageError> {
+        self.call(move |connection| {
+            names(
+                connection,
+                "SELECT contact FROM contacts WHERE user = ?1",
+                &user,
+            )
+        })
+        .await
+    }
+
+    /// Users the user does not want to hear from or not be notified about
+    pub(crate) async fn delivery_filters(
+        &self,
+        user: Arc<str>,
+    ) -> Result<DeliveryFilters, StorageError> {
+        self.call(move |connection| {
+            let blocked = names(
+                connection,
+                "SELECT blocked FROM blocked_users WHERE user = ?1",
+                &user,
+            )?;
+            let muted = names(
+                connection,
+                "SELECT muted FROM muted_users WHERE user = ?1",
+                &user,
+            )?;
+
+            Ok(DeliveryFilters {
+                blocked: blocked.into_iter().collect(),
+                muted: muted.into_iter().collect(),
+            })
+        })
+        .await
+    }
+
+    pub(crate) async fn contact_list(&self, user: Arc<str>) -> Result<ContactList, StorageError> {
+        self.call(move |connection| {
+            let contacts = names(
+                connection,
+                "SELECT contact FROM contacts WHERE user = ?1",
+                &user,
+            )?;
+            let outgoing = names(
+                connection,
+                "SELECT recipient FROM contact_requests WHERE sender = ?1",
+                &user,
+            )?;
+            let blocked = names(
+                connection,
+                "SELECT blocked FROM blocked_users WHERE user = ?1",
+                &user,
+            )?;
+            let muted = names(
+                connection,
+                "SELECT muted FROM muted_users WHERE user = ?1",
+                &user,
+            )?;
+
+            let senders = names(
+                connection,
+                "SELECT sender FROM contact_requests WHERE recipient = ?1 ORDER BY time_utc",
+                &user,
+            )?;
+            let mut statement = connection.prepare_cached(
+                "SELECT text, time_utc FROM messages
+                WHERE recipient = ?1 AND sender = ?2 AND is_request ORDER BY time_utc",
+            )?;
+            let mut incoming = Vec::with_capacity(senders.len());
+            for sender in senders {
+                let messages = statement
+                    .query_map(params![user, sender], |row| {
+                        Ok(ChatMessage {
+                            recipient: user.clone(),
+                            sender: sender.to_string(),
+                            text: row.get(0)?,
+                            time_utc: from_milliseconds(row.get(1)?),
+                        })
+                    })?
+                    .collect::<rusqlite::Result<_>>()?;
+                incoming.push(IncomingRequest { sender, messages });
+            }
+
+            Ok(ContactList {
+                contacts,
+                incoming,
+                outgoing,
+                blocked,
+                muted,
+            })
+        })
+        .await
+    }
+
+    /// Asks the recipient to become a contact of the sender
+    pub(crate) async fn request_contact(
+        &self,
+        sender: Arc<str>,
+        recipient: Arc<str>,
+    ) -> Result<RequestOutcome, StorageError> {
+        self.call(move |connection| {
+            let transaction = connection.transaction()?;
+            let outcome = request_contact(&transaction, &sender, &recipient)?;
+            transaction.commit()?;
+            Ok(outcome)
+        })
+        .await
+    }
+
+    /// Returns false if there was no request from the sender
+    pub(crate) async fn accept_request(
+        &self,
+        user: Arc<str>,
+        sender: Arc<str>,
+    ) -> Result<bool, StorageError> {
+        self.call(move |connection| {
+            let transaction = connection.transaction()?;
+            let deleted = transaction.execute(
+                "DELETE FROM contact_requests WHERE sender = ?1 AND recipient = ?2",
+                params![sender, user],
+            )?;
+            if deleted == 0 {
+                return Ok(false);
+            }
+
+            add_contacts(&transaction, &user, &sender)?;
+            transaction.commit()?;
+            Ok(true)
+        })
+        .await
+    }
+
+    /// Returns false if there was no request from the sender
+    pub(crate) async fn decline_request(
+        &self,
+        user: Arc<str>,
+        sender: Arc<str>,
+    ) -> Result<bool, StorageError> {
+        self.call(move |connection| {
+            let transaction = connection.transaction()?;
+            let deleted = transaction.execute(
+                "DELETE FROM contact_requests WHERE sender = ?1 AND recipient = ?2",
+                params![sender, user],
+            )?;
+            end_request(&transaction, &sender, &user)?;
+            transaction.commit()?;
+            Ok(deleted > 0)
+        })
+        .await
+    }
+
+    /// Returns false if they were not contacts
+    pub(crate) async fn remove_contact(
+        &self,
+        user: Arc<str>,
+        contact: Arc<str>,
+    ) -> Result<bool, StorageError> {
+        self.call(move |connection| {
+            let transaction = connection.transaction()?;
+            let removed = remove_contacts(&transaction, &user, &contact)?;
+            transaction.commit()?;
+            Ok(removed)
+        })
+        .await
+    }
+
+    /// Ends all connections between the users and keeps the blocked user from asking again
+    pub(crate) async fn block(
+        &self,
+        user: Arc<str>,
+        blocked: Arc<str>,
+    ) -> Result<(), StorageError> {
+        self.call(move |connection| {
+            let transaction = connection.transaction()?;
+            transaction.execute(
+                "INSERT OR IGNORE INTO blocked_users (user, blocked) VALUES (?1, ?2)",
+                params![user, blocked],
+            )?;
+            remove_contacts(&transaction, &user, &blocked)?;
+            transaction.execute(
+                "DELETE FROM contact_requests
+                WHERE (sender = ?1 AND recipient = ?2) OR (sender = ?2 AND recipient = ?1)",
+                params![user, blocked],
+            )?;
+            end_request(&transaction, &blocked, &user)?;
+            transaction.commit()
+        })
+        .await
+    }
+
+    /// Returns false if the user was not blocked
+    pub(crate) async fn unblock(
+        &self,
+        user: Arc<str>,
+        blocked: Arc<str>,
+    ) -> Result<bool, StorageError> {
+        self.call(move |connection| {
+            let deleted = connection.execute(
+                "DELETE FROM blocked_users WHERE user = ?1 AND blocked = ?2",
+                params![user, blocked],
+            )?;
+            Ok(deleted > 0)
+        })
+        .await
+    }
+
+    /// Deletes the stored messages of the sender
+    pub(crate) async fn delete_messages_from(
+        &self,
+        sender: Arc<str>,
+    ) -> Result<DeletedMessages, StorageError> {
+        self.call(move |connection| {
+            let transaction = connection.transaction()?;
+            let request_recipients = names(
+                &transaction,
+                "SELECT DISTINCT recipient FROM messages WHERE sender = ?1 AND is_request",
+                &sender,
+            )?;
+            let recipients = names(
+                &transaction,
+                "DELETE FROM messages WHERE sender = ?1 RETURNING recipient",
+                &sender,
+            )?;
+            let pairs = recipients
+                .iter()
+                .map(|recipient| (sender.as_ref(), recipient.as_ref()));
+            let conversations = conversations::rebuild_all(&transaction, pairs)?;
+            transaction.commit()?;
+            Ok(DeletedMessages {
+                request_recipients,
+                conversations,
+            })
+        })
+        .await
+    }
+
+    /// Keeps delivering messages from the user without notifying about them
+    pub(crate) async fn mute(&self, user: Arc<str>, muted: Arc<str>) -> Result<(), StorageError> {
+        self.call(move |connection| {
+            connection.execute(
+                "INSERT OR IGNORE INTO muted_users (user, muted) VALUES (?1, ?2)",
+                params![user, muted],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Returns false if the user was not muted
+    pub(crate) async fn unmute(
+        &self,
+        user: Arc<str>,
+        muted: Arc<str>,
+    ) -> Result<bool, StorageError> {
+        self.call(move |connection| {
+            let deleted = connection.execute(
+                "DELETE FROM muted_users WHERE user = ?1 AND muted = ?2",
+                params![user, muted],
+            )?;
+            Ok(deleted > 0)
+        })
+        .await
+    }
+}
+
+pub(super) fn names(
+    connection: &Connection,
+    query: &str,
+    user: &str,
+) -> rusqlite::Result<Vec<Arc<str>>> {
+    let mut statement = connection.prepare_cached(query)?;
+    let names = statement.query_map([user], |row| Ok(row.get::<_, String>(0)?.into()))?;
+    names.collect()
+}
+
+fn is_blocked(transaction: &Transaction, user: &str, blocked: &str) -> rusqlite::Result<bool> {
+    transaction
+        .query_row(
+            "SELECT 1 FROM blocked_users WHERE user = ?1 AND blocked = ?2",
+            [user, blocked],
+            |_| Ok(()),
+        )
+        .optional()
+        .map(|row| row.is_some())
+}
+
+fn request_contact(
+    transaction: &Transaction,
+    sender: &str,
+    recipient: &str,
+) -> rusqlite::Result<RequestOutcome> {
+    if is_blocked(transaction, sender, recipient)? {
+        return Ok(RequestOutcome::Blocked);
+    }
+
+    let is_contact = transaction
+        .query_row(
+            "SELECT 1 FROM contacts WHERE user = ?1 AND contact = ?2",
+            [sender, recipient],
+            |_| Ok(()),
+        )
+        .optional()?;
+    if is_contact.is_some() {
+        return Ok(RequestOutcome::AlreadyContacts);
+    }
+
+    // Asking each other is as good as accepting
+    let reverse = transaction.execute(
+        "DELETE FROM contact_requests WHERE sender = ?1 AND recipient = ?2",
+        [recipient, sender],
+    )?;
+    if reverse > 0 {
+        add_contacts(transaction, sender, recipient)?;
+        return Ok(RequestOutcome::Accepted);
+    }
+
+    // Blocked senders are told the request was sent to not give away that they were blocked
+    if !is_blocked(transaction, recipient, sender)? {
+        transaction.execute(
+            "INSERT OR IGNORE INTO contact_requests (sender, recipient, time_utc)
+            VALUES (?1, ?2, ?3)",
+            params![
+                sender,
+                recipient,
+                to_milliseconds(OffsetDateTime::now_utc())
+            ],
+        )?;
+    }
+
+    Ok(RequestOutcome::Requested)
+}
+
+fn add_contacts(transaction: &Transaction, user: &str, contact: &str) -> rusqlite::Result<()> {
+    let mut statement = transaction
+        .prepare_cached("INSERT OR IGNORE INTO contacts (user, contact) VALUES (?1, ?2)")?;
+    statement.execute([user, contact])?;
+    statement.execute([contact, user])?;
+    // The messages sent with the request are part of the conversation now
+    end_request(transaction, contact, user)?;
+    end_request(transaction, user, contact)
+}
+
+fn remove_contacts(transaction: &Transaction, user: &str, contact: &str) -> rusqlite::Result<bool> {
+    let removed = transaction.execute(
+        "DELETE FROM contacts
+        WHERE (user = ?1 AND contact = ?2) OR (user = ?2 AND contact = ?1)",
+        [user, contact],
+    )?;
+    Ok(removed > 0)
+}
+
+/// Moves the messages sent with a request out of the requests. They stay in the history of the
+/// conversation like any other message.
+fn end_request(transaction: &Transaction, sender: &str, recipient: &str) -> rusqlite::Result<()> {
+    transaction.execute(
+        "UPDATE messages SET is_request = 0
+        WHERE sender = ?1 AND recipient = ?2 AND is_request",
+        [sender, recipient],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::retention::Retention;
+    use crate::storage::tests::{message, storage};
+    use std::num::NonZeroU32;
+    use time::Duration;
+
+    async fn count_messages(storage: &Storage) -> (i64, i64) {
+        storage
+            .call(|connection| {
+                connection.query_row(
+                    "SELECT COUNT(*), COALESCE(SUM(is_request), 0) FROM messages",
+                    [],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+            })
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn request_messages_are_stored_once_and_kept_after_accepting() {
+        let storage = storage();
+        let outcome = storage
+            .request_contact("alice".into(), "bob".into())
+            .await
+            .unwrap();
+        assert_eq!(outcome, RequestOutcome::Requested);
+        storage
+            .store_message(message("alice", "bob", "hi bob"))
+            .await
+            .unwrap();
+        assert_eq!(count_messages(&storage).await, (1, 1));
+
+        let list = storage.contact_list("bob".into()).await.unwrap();
+        assert_eq!(list.incoming.len(), 1);
+        assert_eq!(&*list.incoming[0].sender, "alice");
+        assert_eq!(list.incoming[0].messages[0].text, "hi bob");
+
+        assert!(storage
+            .accept_request("bob".into(), "alice".into())
+            .await
+            .unwrap());
+        let list = storage.contact_list("bob".into()).await.unwrap();
+        assert!(list.incoming.is_empty());
+        assert_eq!(count_messages(&storage).await, (1, 0));
+
+        // Messages between contacts are no requests
+        storage
+            .store_message(message("bob", "alice", "hi alice"))
+            .await
+            .unwrap();
+        assert_eq!(count_messages(&storage).await, (2, 0));
+    }
+
+    #[tokio::test]
+    async fn request_messages_expire_with_the_retention_of_the_conversation() {
+        let storage = storage();
+        storage
+            .request_contact("alice".into(), "bob".into())
+            .await
+            .unwrap();
+        let request = message("alice", "bob", "hi bob");
+        let time_utc = request.time_utc;
+        storage.store_message(request).await.unwrap();
+        let days = NonZeroU32::new(1).unwrap();
+        storage
+            .set_retention("alice".into(), "bob".into(), Retention::Days { days })
+            .await
+            .unwrap();
+
+        let expired = storage.delete_expired(time_utc).await.unwrap();
+        assert!(expired.messages.is_empty());
+        let expired = storage
+            .delete_expired(time_utc + Duration::days(1))
+            .await
+            .unwrap();
+        assert_eq!(expired.messages.len(), 1);
+        assert_eq!(expired.messages[0].text, "hi bob");
+        assert_eq!(count_messages(&storage).await, (0, 0));
+
+        // The request itself stays until it is answered, just without its messages
+        let list = storage.contact_list("bob".into()).await.unwrap();
+        assert_eq!(list.incoming.len(), 1);
+        assert!(list.incoming[0].messages.is_empty());
+    }
+
+    #[tokio::test]
+    async fn declined_requests_leave_their_messages_in_the_history() {
+        let storage = storage();
+        storage
+            .request_contact("alice".into(), "bob".into())
+            .await
+            .unwrap();
+        storage
+            .store_message(message("alice", "bob", "hi bob"))
+            .await
+            .unwrap();
+
+        assert!(storage
+            .decline_request("bob".into(), "alice".into())
+            .await
+            .unwrap());
+        assert_eq!(count_messages(&storage).await, (1, 0));
+
+        // A new request does not bring the old messages back
+        storage
+            .request_contact("alice".into(), "bob".into())
+            .await
+            .unwrap();
+        let list = storage.contact_list("bob".into()).await.unwrap();
+        assert!(list.incoming[0].messages.is_empty());
+    }
+}
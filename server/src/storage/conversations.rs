@@ -0,0 +1,247 @@
+use super::{from_milliseconds, Storage, StorageError};
+use crate::actor::ChatMessage;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::Arc;
+use time::OffsetDateTime;
+
+/// A conversation as the list of conversations shows it to one of its users
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct Conversation {
+    /// The other user
+    pub(crate) name: Arc<str>,
+    /// The latest message, unless all messages were deleted
+    pub(crate) last_message: Option<ChatMessage>,
+    /// Messages from the other user that were not marked as read
+    pub(crate) unread: u32,
+    /// The time of the latest message, which stays when it is deleted
+    #[serde(with = "crate::actor::milliseconds")]
+    pub(crate) last_activity_utc: OffsetDateTime,
+    /// The time of the latest message from the other user that was marked as read
+    #[serde(default, with = "crate::actor::milliseconds::option")]
+    pub(crate) read_until_utc: Option<OffsetDateTime>,
+    /// A message the user started writing on any of their devices
+    pub(crate) draft: Option<String>,
+    /// Hidden from the list on all devices of the user, but still receiving messages
+    pub(crate) archived: bool,
+}
+
+const SELECT_CONVERSATIONS: &str = "SELECT conversations.user, conversations.peer,
+        conversations.unread, conversations.last_activity_utc,
+        messages.sender, messages.text, messages.time_utc,
+        conversations.read_until_utc, conversations.draft, conversations.archived
+    FROM conversations LEFT JOIN messages ON messages.id = conversations.last_message_id";
+
+impl Storage {
+    /// The conversations of the user, the latest activity first
+    pub(crate) async fn conversations(
+        &self,
+        user: Arc<str>,
+    ) -> Result<Vec<Conversation>, StorageError> {
+        self.call(move |connection| {
+            let mut statement = connection.prepare_cached(&format!(
+                "{SELECT_CONVERSATIONS} WHERE conversations.user = ?1
+                ORDER BY conversations.last_activity_utc DESC"
+            ))?;
+            let conversations = statement.query_map([user.as_ref()], from_row)?;
+            conversations.collect()
+        })
+        .await
+    }
+
+    /// Moves the conversation out of the list or back into it, for the user only
+    pub(crate) async fn set_archived(
+        &self,
+        user: Arc<str>,
+        peer: Arc<str>,
+        archived: bool,
+    ) -> Result<Option<Conversation>, StorageError> {
+        self.call(move |connection| {
+            connection.execute(
+                "INSERT INTO conversations (user, peer, last_activity_utc, unread, archived)
+                VALUES (?1, ?2, 0, 0, ?3)
+                ON CONFLICT (user, peer) DO UPDATE SET archived = excluded.archived",
+                params![user, peer, archived],
+            )?;
+            conversation(connection, &user, &peer)
+        })
+        .await
+    }
+
+    /// Keeps what the user started writing to the peer, or forgets it if it is empty
+    pub(crate) async fn save_draft(
+        &self,
+        user: Arc<str>,
+        peer: Arc<str>,
+        draft: String,
+    ) -> Result<Option<Conversation>, StorageError> {
+        self.call(move |connection| {
+            let draft = Some(draft).filter(|draft| !draft.is_empty());
+            connection.execute(
+                "INSERT INTO conversations (user, peer, last_activity_utc, unread, draft)
+                VALUES (?1, ?2, 0, 0, ?3)
+                ON CONFLICT (user, peer) DO UPDATE SET draft = excluded.draft",
+                params![user, peer, draft],
+            )?;
+            conversation(connection, &user, &peer)
+        })
+        .await
+    }
+}
+
+pub(super) fn conversation(
+    connection: &Connection,
+    user: &str,
+    peer: &str,
+) -> rusqlite::Result<Option<Conversation>> {
+    connection
+        .prepare_cached(&format!(
+            "{SELECT_CONVERSATIONS} WHERE conversations.user = ?1 AND conversations.peer = ?2"
+        ))?
+        .query_row([user, peer], from_row)
+        .optional()
+}
+
+/// Makes the stored message with the id the latest of the conversation for both users and counts
+/// it as unread for the recipient
+pub(super) fn add_message(
+    connection: &Connection,
+    id: i64,
+    sender: &str,
+    recipient: &str,
+    time_utc: i64,
+) -> rusqlite::Result<()> {
+    let mut statement = connection.prepare_cached(
+        "INSERT INTO conversations (user, peer, last_message_id, last_activity_utc, unread)
+        VALUES (?1, ?2, ?3, ?4, ?5)
+        ON CONFLICT (user, peer) DO UPDATE SET
+            last_message_id = excluded.last_message_id,
+            last_activity_utc = excluded.last_activity_utc,
+            unread = unread + excluded.unread",
+    )?;
+    statement.execute(params![sender, recipient, id, time_utc, 0])?;
+    // Messages to oneself are not unread
+    if sender != recipient {
+        statement.execute(params![recipient, sender, id, time_utc, 1])?;
+    }
+    Ok(())
+}
+
+/// Brings the conversation of the user with the peer up to date with the stored messages, after
+/// messages were deleted or imported
+fn rebuild(connection: &Connection, user: &str, peer: &str) -> rusqlite::Result<()> {
+    connection
+        .prepare_cached(
+            "INSERT INTO conversations (user, peer, last_message_id, last_activity_utc, unread)
+            SELECT ?1, ?2, MAX(id), IFNULL(MAX(time_utc), 0),
+                IFNULL(SUM(sender = ?2 AND recipient = ?1 AND read_utc IS NULL), 0)
+            FROM messages
+            WHERE (sender = ?1 AND recipient = ?2) OR (sender = ?2 AND recipient = ?1)
+            ON CONFLICT (user, peer) DO UPDATE SET
+                last_message_id = excluded.last_message_id,
+                last_activity_utc = MAX(last_activity_utc, excluded.last_activity_utc),
+                unread = excluded.unread",
+        )?
+        .execute([user, peer])?;
+    Ok(())
+}
+
+/// Rebuilds the conversations of the pairs of users for both of them and returns them, with whose
+/// they are
+pub(super) fn rebuild_all<'a>(
+    connection: &Connection,
+    pairs: impl IntoIterator<Item = (&'a str, &'a str)>,
+) -> rusqlite::Result<Vec<(Arc<str>, Conversation)>> {
+    let pairs: HashSet<_> = pairs
+        .into_iter()
+        .flat_map(|(user, peer)| [(user, peer), (peer, user)])
+        .collect();
+    let mut rebuilt = Vec::with_capacity(pairs.len());
+    for (user, peer) in pairs {
+        rebuild(connection, user, peer)?;
+        if let Some(conversation) = conversation(connection, user, peer)? {
+            rebuilt.push((user.into(), conversation));
+        }
+    }
+    Ok(rebuilt)
+}
+
+fn from_row(row: &Row) -> rusqlite::Result<Conversation> {
+    let user: String = row.get(0)?;
+    let peer: Arc<str> = row.get::<_, String>(1)?.into();
+    let last_sender: Option<String> = row.get(4)?;
+    let last_message = match last_sender {
+        Some(sender) => Some(ChatMessage {
+            recipient: if sender == user {
+                peer.clone()
+            } else {
+                user.into()
+            },
+            sender,
+            text: row.get(5)?,
+            time_utc: from_milliseconds(row.get(6)?),
+        }),
+        None => None,
+    };
+
+    let read_until_utc: Option<i64> = row.get(7)?;
+    Ok(Conversation {
+        name: peer,
+        last_message,
+        unread: row.get(2)?,
+        last_activity_utc: from_milliseconds(row.get(3)?),
+        read_until_utc: read_until_utc.map(from_milliseconds),
+        draft: row.get(8)?,
+        archived: row.get(9)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::tests::{message, storage};
+
+    #[tokio::test]
+    async fn counts_unread_messages_until_they_are_read() {
+        let storage = storage();
+        for text in ["Hi Bob", "Are you there?"] {
+            storage
+                .store_message(message("alice", "bob", text))
+                .await
+                .unwrap();
+        }
+        storage
+            .store_message(message("alice", "alice", "Note to self"))
+            .await
+            .unwrap();
+
+        let [conversation] = &storage.conversations("bob".into()).await.unwrap()[..] else {
+            panic!("Bob should have one conversation");
+        };
+        assert_eq!(conversation.unread, 2);
+        assert_eq!(conversation.read_until_utc, None);
+        // The sender has nothing to read
+        for conversation in storage.conversations("alice".into()).await.unwrap() {
+            assert_eq!(conversation.unread, 0, "{}", conversation.name);
+        }
+
+        let read = storage
+            .mark_read("bob".into(), "alice".into())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(read.unread, 0);
+        let last_message = read.last_message.unwrap();
+        assert_eq!(read.read_until_utc, Some(last_message.time_utc));
+        // Nothing left to read
+        let again = storage.mark_read("bob".into(), "alice".into()).await;
+        assert!(again.unwrap().is_none());
+
+        storage
+            .store_message(message("alice", "bob", "Hello?"))
+            .await
+            .unwrap();
+        let conversations = storage.conversations("bob".into()).await.unwrap();
+        assert_eq!(conversations[0].unread, 1);
+    }
+}
@@ -0,0 +1,239 @@
+use super::conversations::{self, conversation, Conversation};
+use super::{from_milliseconds, to_milliseconds, Storage, StorageError};
+use crate::actor::ChatMessage;
+use rusqlite::params;
+use std::borrow::Cow;
+use std::sync::Arc;
+
+/// Mark the highlighted terms in snippets. Control characters are unlikely to be typed in a chat
+/// message, unlike any markup that could be used instead, and are taken out of the texts that are
+/// stored so that no one can send highlights of their own.
+const HIGHLIGHT_START: char = '\u{2}';
+const HIGHLIGHT_END: char = '\u{3}';
+
+/// How many words around the matches a snippet shows
+const SNIPPET_TOKENS: i64 = 16;
+
+/// A piece of a snippet, which is highlighted if it matched the query
+pub(crate) struct SnippetPart {
+    pub(crate) text: String,
+    pub(crate) highlighted: bool,
+}
+
+pub(crate) struct SearchHit {
+    pub(crate) message: ChatMessage,
+    pub(crate) snippet: Vec<SnippetPart>,
+}
+
+pub(crate) struct SearchResults {
+    /// The best matches first
+    pub(crate) hits: Vec<SearchHit>,
+    /// Whether there are more hits after these
+    pub(crate) has_more: bool,
+}
+
+impl Storage {
+    /// Adds a routed message to the history of the conversation and to the search index. Returns
+    /// the conversation as its users see it now, with whose it is.
+    /// Messages between users who are not contacts are flagged as sent with a contact request.
+    pub(crate) async fn store_message(
+        &self,
+        message: Arc<ChatMessage>,
+    ) -> Result<Vec<(Arc<str>, Conversation)>, StorageError> {
+        self.call(move |connection| {
+            let transaction = connection.transaction()?;
+            let time_utc = to_milliseconds(message.time_utc);
+            transaction
+                .prepare_cached(
+                    "INSERT INTO messages (sender, recipient, text, time_utc, is_request)
+                    VALUES (?1, ?2, ?3, ?4, NOT EXISTS (
+                        SELECT 1 FROM contacts WHERE user = ?1 AND contact = ?2
+                    ))",
+                )?
+                .execute(params![
+                    message.sender,
+                    message.recipient,
+                    stored_text(&message.text),
+                    time_utc
+                ])?;
+            let id = transaction.last_insert_rowid();
+            let (sender, recipient) = (message.sender.as_str(), message.recipient.as_ref());
+            conversations::add_message(&transaction, id, sender, recipient, time_utc)?;
+
+            let mut updated = Vec::with_capacity(2);
+            for (user, peer) in [(sender, recipient), (recipient, sender)] {
+                if let Some(conversation) = conversation(&transaction, user, peer)? {
+                    updated.push((user.into(), conversation));
+                }
+                if sender == recipient {
+                    break;
+                }
+            }
+            transaction.commit()?;
+            Ok(updated)
+        })
+        .await
+    }
+
+    /// The messages of the conversation between the users that are kept, the oldest first
+    #[cfg(feature = "grpc")]
+    pub(crate) async fn history(
+        &self,
+        user: Arc<str>,
+        with: Arc<str>,
+    ) -> Result<Vec<ChatMessage>, StorageError> {
+        self.call(move |connection| {
+            connection
+                .prepare_cached(
+                    "SELECT sender, recipient, text, time_utc FROM messages
+                    WHERE (sender = ?1 AND recipient = ?2) OR (sender = ?2 AND recipient = ?1)
+                    ORDER BY time_utc, id",
+                )?
+                .query_map(params![user, with], |row| {
+                    Ok(ChatMessage {
+                        sender: row.get(0)?,
+                        recipient: row.get::<_, String>(1)?.into(),
+                        text: row.get(2)?,
+                        time_utc: from_milliseconds(row.get(3)?),
+                    })
+                })?
+                .collect()
+        })
+        .await
+    }
+
+    /// Searches the conversations of the user, or only the one with the other user if given.
+    /// Returns `None` if the query has no words to search for.
+    pub(crate) async fn search(
+        &self,
+        user: Arc<str>,
+        with: Option<Arc<str>>,
+        query: &str,
+        limit: usize,
+        offset: usize,
+    ) -> Result<Option<SearchResults>, StorageError> {
+        let Some(query) = match_expression(query) else {
+            return Ok(None);
+        };
+
+        self.call(move |connection| {
+            let mut statement = connection.prepare_cached(
+                "SELECT messages.sender, messages.recipient, messages.text, messages.time_utc,
+                    snippet(messages_search, 0, ?1, ?2, '…', ?3)
+                FROM messages_search JOIN messages ON messages.id = messages_search.rowid
+                WHERE messages_search MATCH ?4
+                    AND CASE WHEN ?6 IS NULL
+                        THEN messages.sender = ?5 OR messages.recipient = ?5
+                        ELSE (messages.sender = ?5 AND messages.recipient = ?6)
+                            OR (messages.sender = ?6 AND messages.recipient = ?5)
+                    END
+                ORDER BY rank
+                LIMIT ?7 OFFSET ?8",
+            )?;
+            // One more than asked for to know if there is another page
+            let mut hits = statement
+                .query_map(
+                    params![
+                        HIGHLIGHT_START.to_string(),
+                        HIGHLIGHT_END.to_string(),
+                        SNIPPET_TOKENS,
+                        query,
+                        user,
+                        with,
+                        limit as i64 + 1,
+                        offset as i64
+                    ],
+                    |row| {
+                        let message = ChatMessage {
+                            sender: row.get(0)?,
+                            recipient: row.get::<_, String>(1)?.into(),
+                            text: row.get(2)?,
+                            time_utc: from_milliseconds(row.get(3)?),
+                        };
+                        let snippet = snippet_parts(&row.get::<_, String>(4)?);
+                        Ok(SearchHit { message, snippet })
+                    },
+                )?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+
+            let has_more = hits.len() > limit;
+            hits.truncate(limit);
+            Ok(Some(SearchResults { hits, has_more }))
+        })
+        .await
+    }
+}
+
+/// Turns what a user typed into an FTS5 query that matches messages with all of the words. Each
+/// word is quoted so that operators and column filters are searched for like any other text, and
+/// the last one matches as a prefix because it may not be typed out yet.
+fn match_expression(query: &str) -> Option<String> {
+    let words: Vec<_> = query
+        .split_whitespace()
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect();
+    if words.is_empty() {
+        return None;
+    }
+
+    Some(words.join(" ") + "*")
+}
+
+/// The text without the characters that mark highlights in snippets
+pub(super) fn stored_text(text: &str) -> Cow<'_, str> {
+    if text.contains([HIGHLIGHT_START, HIGHLIGHT_END]) {
+        Cow::Owned(text.replace([HIGHLIGHT_START, HIGHLIGHT_END], ""))
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
+fn snippet_parts(snippet: &str) -> Vec<SnippetPart> {
+    let mut parts = Vec::new();
+    let mut highlighted = false;
+    for (index, text) in snippet.split([HIGHLIGHT_START, HIGHLIGHT_END]).enumerate() {
+        // The markers alternate, so every other piece is highlighted
+        if index > 0 {
+            highlighted = !highlighted;
+        }
+        if !text.is_empty() {
+            parts.push(SnippetPart {
+                text: text.to_owned(),
+                highlighted,
+            });
+        }
+    }
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::tests::{message, storage};
+
+    #[tokio::test]
+    async fn only_matches_are_highlighted() {
+        let storage = storage();
+        let text = "see \u{2}everything\u{3} about the weather";
+        storage
+            .store_message(message("alice", "bob", text))
+            .await
+            .unwrap();
+
+        let results = storage
+            .search("bob".into(), None, "weather", 10, 0)
+            .await
+            .unwrap()
+            .unwrap();
+        let [hit] = results.hits.as_slice() else {
+            panic!("there should be exactly one hit");
+        };
+        let highlighted: Vec<_> = hit
+            .snippet
+            .iter()
+            .filter(|part| part.highlighted)
+            .map(|part| part.text.as_str())
+            .collect();
+        assert_eq!(highlighted, ["weather"]);
+        assert_eq!(hit.message.text, "see everything about the weather");
+    }
+}
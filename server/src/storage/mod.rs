@@ -0,0 +1,244 @@
+use rusqlite::Connection;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use time::OffsetDateTime;
+
+pub(crate) mod archive;
+pub(crate) mod bans;
+pub(crate) mod contacts;
+pub(crate) mod conversations;
+pub(crate) mod messages;
+pub(crate) mod push;
+pub(crate) mod retention;
+pub(crate) mod sessions;
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum StorageError {
+    #[error("Database error: {0}")]
+    Database(#[from] rusqlite::Error),
+    #[error("Database task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+}
+
+/// Changes to the schema, applied in order. Only ever append to this list, as the index of a
+/// migration is stored in the database to know which ones have been applied.
+const MIGRATIONS: &[&str] = &[
+    // Contact graph. Contacts are stored in both directions to look them up by either user.
+    "CREATE TABLE contacts (
+        user TEXT NOT NULL,
+        contact TEXT NOT NULL,
+        PRIMARY KEY (user, contact)
+    );
+    CREATE TABLE contact_requests (
+        sender TEXT NOT NULL,
+        recipient TEXT NOT NULL,
+        time_utc INTEGER NOT NULL,
+        PRIMARY KEY (sender, recipient)
+    );
+    CREATE INDEX contact_requests_by_recipient ON contact_requests (recipient);
+    CREATE TABLE blocked_users (
+        user TEXT NOT NULL,
+        blocked TEXT NOT NULL,
+        PRIMARY KEY (user, blocked)
+    );",
+    // Conversations that are delivered without notifications
+    "CREATE TABLE muted_users (
+        user TEXT NOT NULL,
+        muted TEXT NOT NULL,
+        PRIMARY KEY (user, muted)
+    );",
+    // Names and addresses operators keep from connecting
+    "CREATE TABLE banned_names (
+        name TEXT PRIMARY KEY NOT NULL,
+        time_utc INTEGER NOT NULL
+    );
+    CREATE TABLE banned_addresses (
+        address TEXT PRIMARY KEY NOT NULL,
+        time_utc INTEGER NOT NULL
+    );",
+    // Conversation history with a full-text index of the texts, which the triggers keep in sync.
+    // Messages sent with contact requests are flagged until the request is answered.
+    "CREATE TABLE messages (
+        id INTEGER PRIMARY KEY,
+        sender TEXT NOT NULL,
+        recipient TEXT NOT NULL,
+        text TEXT NOT NULL,
+        time_utc INTEGER NOT NULL,
+        is_request INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE INDEX messages_by_sender ON messages (sender, recipient, time_utc);
+    CREATE INDEX messages_by_recipient ON messages (recipient, sender, time_utc);
+    CREATE VIRTUAL TABLE messages_search USING fts5 (
+        text,
+        content = 'messages',
+        content_rowid = 'id',
+        tokenize = 'unicode61 remove_diacritics 2'
+    );
+    CREATE TRIGGER messages_search_insert AFTER INSERT ON messages BEGIN
+        INSERT INTO messages_search (rowid, text) VALUES (new.id, new.text);
+    END;
+    CREATE TRIGGER messages_search_delete AFTER DELETE ON messages BEGIN
+        INSERT INTO messages_search (messages_search, rowid, text)
+        VALUES ('delete', old.id, old.text);
+    END;",
+    // How long conversations keep their messages, and when messages were read to start the timer
+    // of disappearing messages. Conversations without retention keep them forever.
+    "ALTER TABLE messages ADD COLUMN read_utc INTEGER;
+    CREATE TABLE retention (
+        user_a TEXT NOT NULL,
+        user_b TEXT NOT NULL,
+        lifetime INTEGER NOT NULL,
+        after_read INTEGER NOT NULL,
+        PRIMARY KEY (user_a, user_b)
+    );",
+    // The list of conversations of each user, kept up to date as messages are stored and read
+    // instead of being computed from all messages every time it is loaded
+    "CREATE TABLE conversations (
+        user TEXT NOT NULL,
+        peer TEXT NOT NULL,
+        last_message_id INTEGER,
+        last_activity_utc INTEGER NOT NULL,
+        unread INTEGER NOT NULL,
+        PRIMARY KEY (user, peer)
+    );
+    CREATE INDEX conversations_by_activity ON conversations (user, last_activity_utc);
+    INSERT INTO conversations (user, peer, last_message_id, last_activity_utc, unread)
+    SELECT user, peer, MAX(id), MAX(time_utc),
+        SUM(recipient = user AND sender != user AND read_utc IS NULL)
+    FROM (
+        SELECT sender AS user, recipient AS peer, id, sender, recipient, time_utc, read_utc
+        FROM messages
+        UNION ALL
+        SELECT recipient, sender, id, sender, recipient, time_utc, read_utc FROM messages
+    )
+    GROUP BY user, peer;",
+    // What each user sets on their side of a conversation, synchronized to all of their devices
+    "ALTER TABLE conversations ADD COLUMN read_until_utc INTEGER;
+    ALTER TABLE conversations ADD COLUMN draft TEXT;
+    ALTER TABLE conversations ADD COLUMN archived INTEGER NOT NULL DEFAULT 0;
+    UPDATE conversations SET read_until_utc = (
+        SELECT MAX(time_utc) FROM messages
+        WHERE recipient = conversations.user AND sender = conversations.peer
+            AND read_utc IS NOT NULL
+    );",
+    // The browsers that get push messages for a user while none of their devices is connected
+    "CREATE TABLE push_subscriptions (
+        endpoint TEXT PRIMARY KEY,
+        user TEXT NOT NULL,
+        p256dh TEXT NOT NULL,
+        auth TEXT NOT NULL
+    );
+    CREATE INDEX push_subscriptions_by_user ON push_subscriptions (user);",
+    // Tokens of the clients that call the REST API as a user. Only their digests are stored.
+    "CREATE TABLE sessions (
+        token_digest BLOB PRIMARY KEY,
+        user TEXT NOT NULL,
+        created_utc INTEGER NOT NULL
+    );
+    CREATE INDEX sessions_by_age ON sessions (created_utc);
+    CREATE INDEX sessions_by_user ON sessions (user);",
+];
+
+/// The database of everything that needs to outlive the actors, shared by all of them.
+/// SQLite is embedded, so there is no database server to run next to the app.
+#[derive(Clone)]
+pub(crate) struct Storage {
+    /// SQLite connections can only be used by one thread at a time
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl Storage {
+    /// Opens or creates the database at the path and brings its schema up to date
+    pub(crate) fn open(path: &Path) -> Result<Self, StorageError> {
+        let mut connection = Connection::open(path)?;
+        // Writes don't block reads and vice versa
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        migrate(&mut connection)?;
+
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// Runs the queries on a thread for blocking work to not block the actors while waiting for
+    /// the disk
+    async fn call<R, F>(&self, queries: F) -> Result<R, StorageError>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut Connection) -> rusqlite::Result<R> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            // A panic while holding the lock does not leave a transaction open as those are rolled
+            // back when dropped
+            let mut connection = connection.lock().unwrap_or_else(|error| error.into_inner());
+            queries(&mut connection)
+        })
+        .await?;
+
+        Ok(result?)
+    }
+
+    /// Checks that the database answers queries
+    pub(crate) async fn probe(&self) -> Result<(), StorageError> {
+        self.call(|connection| connection.query_row("SELECT 1", [], |_| Ok(())))
+            .await
+    }
+}
+
+fn migrate(connection: &mut Connection) -> rusqlite::Result<()> {
+    let applied: usize = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(applied) {
+        tracing::info!("Applying database migration {}", index + 1);
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.pragma_update(None, "user_version", index + 1)?;
+        transaction.commit()?;
+    }
+
+    Ok(())
+}
+
+fn to_milliseconds(time: OffsetDateTime) -> i64 {
+    (time.unix_timestamp_nanos() / 1_000_000) as i64
+}
+
+pub(crate) fn from_milliseconds(milliseconds: i64) -> OffsetDateTime {
+    OffsetDateTime::from_unix_timestamp_nanos(i128::from(milliseconds) * 1_000_000)
+        .unwrap_or(OffsetDateTime::UNIX_EPOCH)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::actor::ChatMessage;
+    use std::sync::mpsc;
+
+    /// A fresh database for each test
+    pub(crate) fn storage() -> Storage {
+        Storage::open(Path::new(":memory:")).unwrap()
+    }
+
+    /// Keeps every query waiting until the returned sender is dropped, like a database that hangs
+    pub(crate) fn hang(storage: &Storage) -> mpsc::Sender<()> {
+        let connection = storage.connection.clone();
+        let (locked, is_locked) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+        std::thread::spawn(move || {
+            let _connection = connection.lock().unwrap();
+            locked.send(()).unwrap();
+            let _ = released.recv();
+        });
+        is_locked.recv().unwrap();
+        release
+    }
+
+    pub(crate) fn message(sender: &str, recipient: &str, text: &str) -> Arc<ChatMessage> {
+        Arc::new(ChatMessage {
+            recipient: recipient.into(),
+            sender: sender.to_owned(),
+            text: text.to_owned(),
+            time_utc: OffsetDateTime::now_utc(),
+        })
+    }
+}
@@ -0,0 +1,89 @@
+use super::{Storage, StorageError};
+use rusqlite::params;
+use std::sync::Arc;
+
+/// Where a browser receives push messages, as it hands it to the page subscribing to them
+pub(crate) struct PushSubscription {
+    /// The URL of the browser's push service the messages are posted to
+    pub(crate) endpoint: String,
+    /// The browser's public key the messages are encrypted for, base64url encoded
+    pub(crate) p256dh: String,
+    /// The browser's authentication secret, base64url encoded
+    pub(crate) auth: String,
+}
+
+impl Storage {
+    /// Sends push messages for the user to the endpoint from now on. An endpoint belongs to a
+    /// single browser, so it moves over if another user subscribed with it before.
+    pub(crate) async fn add_push_subscription(
+        &self,
+        user: Arc<str>,
+        subscription: PushSubscription,
+    ) -> Result<(), StorageError> {
+        self.call(move |connection| {
+            connection.execute(
+                "INSERT OR REPLACE INTO push_subscriptions (endpoint, user, p256dh, auth)
+                VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    subscription.endpoint,
+                    user,
+                    subscription.p256dh,
+                    subscription.auth
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Returns whether the user was subscribed with the endpoint
+    pub(crate) async fn remove_push_subscription(
+        &self,
+        user: Arc<str>,
+        endpoint: String,
+    ) -> Result<bool, StorageError> {
+        self.call(move |connection| {
+            let deleted = connection.execute(
+                "DELETE FROM push_subscriptions WHERE user = ?1 AND endpoint = ?2",
+                params![user, endpoint],
+            )?;
+            Ok(deleted > 0)
+        })
+        .await
+    }
+
+    /// Forgets an endpoint the push service no longer accepts messages for
+    pub(crate) async fn delete_push_subscription(
+        &self,
+        endpoint: String,
+    ) -> Result<(), StorageError> {
+        self.call(move |connection| {
+            connection.execute(
+                "DELETE FROM push_subscriptions WHERE endpoint = ?1",
+                [endpoint],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub(crate) async fn push_subscriptions(
+        &self,
+        user: Arc<str>,
+    ) -> Result<Vec<PushSubscription>, StorageError> {
+        self.call(move |connection| {
+            let mut statement = connection.prepare_cached(
+                "SELECT endpoint, p256dh, auth FROM push_subscriptions WHERE user = ?1",
+            )?;
+            let subscriptions = statement.query_map([user.as_ref()], |row| {
+                Ok(PushSubscription {
+                    endpoint: row.get(0)?,
+                    p256dh: row.get(1)?,
+                    auth: row.get(2)?,
+                })
+            })?;
+            subscriptions.collect()
+        })
+        .await
+    }
+}
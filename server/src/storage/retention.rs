@@ -0,0 +1,184 @@
+use super::conversations::{self, Conversation};
+use super::{from_milliseconds, to_milliseconds, Storage, StorageError};
+use crate::actor::ChatMessage;
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::num::NonZeroU32;
+use std::sync::Arc;
+use time::OffsetDateTime;
+
+const DAY_MILLISECONDS: i64 = 24 * 60 * 60 * 1000;
+
+/// How long the messages of a conversation are kept. Both users of the conversation share it.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(tag = "type")]
+pub(crate) enum Retention {
+    #[default]
+    Forever,
+    /// Counted from the time of the message
+    Days { days: NonZeroU32 },
+    /// Counted from when the recipient read the message
+    AfterRead { seconds: NonZeroU32 },
+}
+
+impl Retention {
+    /// How it is stored, as the milliseconds messages are kept and whether they are counted from
+    /// reading the message
+    pub(super) fn to_columns(self) -> Option<(i64, bool)> {
+        match self {
+            Self::Forever => None,
+            Self::Days { days } => Some((i64::from(days.get()) * DAY_MILLISECONDS, false)),
+            Self::AfterRead { seconds } => Some((i64::from(seconds.get()) * 1000, true)),
+        }
+    }
+
+    pub(super) fn from_columns(lifetime: i64, after_read: bool) -> Self {
+        let retention = if after_read {
+            NonZeroU32::new((lifetime / 1000) as u32).map(|seconds| Self::AfterRead { seconds })
+        } else {
+            NonZeroU32::new((lifetime / DAY_MILLISECONDS) as u32).map(|days| Self::Days { days })
+        };
+        retention.unwrap_or_default()
+    }
+}
+
+/// Messages that were deleted because the retention of their conversation ran out
+pub(crate) struct Expired {
+    pub(crate) messages: Vec<ChatMessage>,
+    /// The conversations the messages were deleted from, with whose they are
+    pub(crate) conversations: Vec<(Arc<str>, Conversation)>,
+}
+
+/// The users of a conversation in the order they are stored in, so that it is the same for both
+pub(super) fn conversation<'a>(user: &'a str, contact: &'a str) -> (&'a str, &'a str) {
+    if user <= contact {
+        (user, contact)
+    } else {
+        (contact, user)
+    }
+}
+
+impl Storage {
+    pub(crate) async fn retention(
+        &self,
+        user: Arc<str>,
+        contact: Arc<str>,
+    ) -> Result<Retention, StorageError> {
+        self.call(move |connection| {
+            let (user_a, user_b) = conversation(&user, &contact);
+            let retention = connection
+                .query_row(
+                    "SELECT lifetime, after_read FROM retention
+                    WHERE user_a = ?1 AND user_b = ?2",
+                    [user_a, user_b],
+                    |row| Ok(Retention::from_columns(row.get(0)?, row.get(1)?)),
+                )
+                .optional()?;
+            Ok(retention.unwrap_or_default())
+        })
+        .await
+    }
+
+    /// Changes how long the messages of the conversation are kept, including the ones already sent
+    pub(crate) async fn set_retention(
+        &self,
+        user: Arc<str>,
+        contact: Arc<str>,
+        retention: Retention,
+    ) -> Result<(), StorageError> {
+        self.call(move |connection| {
+            let (user_a, user_b) = conversation(&user, &contact);
+            match retention.to_columns() {
+                Some((lifetime, after_read)) => connection.execute(
+                    "INSERT OR REPLACE INTO retention (user_a, user_b, lifetime, after_read)
+                    VALUES (?1, ?2, ?3, ?4)",
+                    params![user_a, user_b, lifetime, after_read],
+                )?,
+                None => connection.execute(
+                    "DELETE FROM retention WHERE user_a = ?1 AND user_b = ?2",
+                    [user_a, user_b],
+                )?,
+            };
+            Ok(())
+        })
+        .await
+    }
+
+    /// Marks the messages the user received from the sender as read, which starts the timer of
+    /// disappearing messages. Returns the conversation if there were unread messages.
+    pub(crate) async fn mark_read(
+        &self,
+        user: Arc<str>,
+        sender: Arc<str>,
+    ) -> Result<Option<Conversation>, StorageError> {
+        self.call(move |connection| {
+            let transaction = connection.transaction()?;
+            let read = transaction.execute(
+                "UPDATE messages SET read_utc = ?3
+                WHERE recipient = ?1 AND sender = ?2 AND read_utc IS NULL",
+                params![user, sender, to_milliseconds(OffsetDateTime::now_utc())],
+            )?;
+            if read == 0 {
+                return Ok(None);
+            }
+
+            transaction.execute(
+                "UPDATE conversations SET unread = 0, read_until_utc = (
+                    SELECT MAX(time_utc) FROM messages
+                    WHERE recipient = ?1 AND sender = ?2 AND read_utc IS NOT NULL
+                )
+                WHERE user = ?1 AND peer = ?2",
+                [user.as_ref(), sender.as_ref()],
+            )?;
+            let conversation = conversations::conversation(&transaction, &user, &sender)?;
+            transaction.commit()?;
+            Ok(conversation)
+        })
+        .await
+    }
+
+    /// Deletes the messages that are past the retention of their conversation
+    pub(crate) async fn delete_expired(
+        &self,
+        now: OffsetDateTime,
+    ) -> Result<Expired, StorageError> {
+        self.call(move |connection| {
+            let transaction = connection.transaction()?;
+            let messages: Vec<ChatMessage> = transaction
+                .prepare_cached(
+                    "DELETE FROM messages WHERE id IN (
+                        SELECT messages.id FROM retention JOIN messages
+                            ON (messages.sender = retention.user_a
+                                AND messages.recipient = retention.user_b)
+                            OR (messages.sender = retention.user_b
+                                AND messages.recipient = retention.user_a)
+                        WHERE CASE WHEN retention.after_read
+                            THEN messages.read_utc
+                            ELSE messages.time_utc
+                        END + retention.lifetime <= ?1
+                    )
+                    RETURNING sender, recipient, text, time_utc",
+                )?
+                .query_map([to_milliseconds(now)], |row| {
+                    Ok(ChatMessage {
+                        sender: row.get(0)?,
+                        recipient: row.get::<_, String>(1)?.into(),
+                        text: row.get(2)?,
+                        time_utc: from_milliseconds(row.get(3)?),
+                    })
+                })?
+                .collect::<rusqlite::Result<_>>()?;
+
+            let pairs = messages
+                .iter()
+                .map(|message| (message.sender.as_str(), message.recipient.as_ref()));
+            let conversations = conversations::rebuild_all(&transaction, pairs)?;
+            transaction.commit()?;
+            Ok(Expired {
+                messages,
+                conversations,
+            })
+        })
+        .await
+    }
+}
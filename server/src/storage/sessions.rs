@@ -0,0 +1,107 @@
+use super::{to_milliseconds, Storage, StorageError};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ring::digest::{self, SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use rusqlite::{params, OptionalExtension};
+use std::sync::Arc;
+use std::time::Duration;
+use time::OffsetDateTime;
+
+/// How long a token can be used after it was created. Clients get a new one whenever they
+/// connect, so this only limits how long a leaked token is of use.
+const SESSION_LIFETIME: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+impl Storage {
+    /// Returns a new random token that authenticates requests as the user. Tokens that ran out
+    /// are deleted on the way.
+    pub(crate) async fn create_session(&self, user: Arc<str>) -> Result<String, StorageError> {
+        let mut token = [0; 32];
+        SystemRandom::new()
+            .fill(&mut token)
+            .expect("the system should provide random bytes");
+        let token = URL_SAFE_NO_PAD.encode(token);
+        let token_digest = token_digest(&token);
+
+        let now = OffsetDateTime::now_utc();
+        self.call(move |connection| {
+            let transaction = connection.transaction()?;
+            transaction.execute(
+                "DELETE FROM sessions WHERE created_utc <= ?1",
+                [to_milliseconds(now - SESSION_LIFETIME)],
+            )?;
+            transaction.execute(
+                "INSERT INTO sessions (token_digest, user, created_utc) VALUES (?1, ?2, ?3)",
+                params![token_digest, user, to_milliseconds(now)],
+            )?;
+            transaction.commit()?;
+            Ok(token)
+        })
+        .await
+    }
+
+    /// The user the token was created for, unless it is unknown or ran out
+    pub(crate) async fn session_user(
+        &self,
+        token: String,
+    ) -> Result<Option<Arc<str>>, StorageError> {
+        let token_digest = token_digest(&token);
+        let oldest = to_milliseconds(OffsetDateTime::now_utc() - SESSION_LIFETIME);
+        self.call(move |connection| {
+            connection
+                .query_row(
+                    "SELECT user FROM sessions WHERE token_digest = ?1 AND created_utc > ?2",
+                    params![token_digest, oldest],
+                    |row| row.get::<_, String>(0),
+                )
+                .optional()
+                .map(|user| user.map(Into::into))
+        })
+        .await
+    }
+
+    /// Returns whether there was a session with the token
+    pub(crate) async fn end_session(&self, token: String) -> Result<bool, StorageError> {
+        let token_digest = token_digest(&token);
+        self.call(move |connection| {
+            let deleted = connection.execute(
+                "DELETE FROM sessions WHERE token_digest = ?1",
+                [token_digest],
+            )?;
+            Ok(deleted > 0)
+        })
+        .await
+    }
+}
+
+/// Tokens are only stored as digests, so that a copy of the database can't be used to log in
+fn token_digest(token: &str) -> Vec<u8> {
+    digest::digest(&SHA256, token.as_bytes()).as_ref().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::tests::storage;
+
+    #[tokio::test]
+    async fn tokens_authenticate_their_user_until_the_session_ends_or_the_user_is_banned() {
+        let storage = storage();
+        let token = storage.create_session("alice".into()).await.unwrap();
+        let other = storage.create_session("alice".into()).await.unwrap();
+        assert_ne!(token, other);
+
+        let user = storage.session_user(token.clone()).await.unwrap();
+        assert_eq!(user.as_deref(), Some("alice"));
+        let unknown = storage.session_user("alice".to_owned()).await.unwrap();
+        assert_eq!(unknown, None);
+
+        assert!(storage.end_session(token.clone()).await.unwrap());
+        assert_eq!(storage.session_user(token).await.unwrap(), None);
+        // Other devices stay signed in
+        let user = storage.session_user(other.clone()).await.unwrap();
+        assert_eq!(user.as_deref(), Some("alice"));
+
+        storage.ban_name("alice".into()).await.unwrap();
+        assert_eq!(storage.session_user(other).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,90 @@
+use crate::actor::framework::MAILBOX_CAPACITY;
+use crate::actor::socket::SEND_QUEUE_CAPACITY;
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use std::time::Duration;
+
+/// Number of open websocket connections
+pub(crate) const WEBSOCKET_CONNECTIONS: &str = "websocket_connections";
+/// Number of open server-sent event streams
+pub(crate) const EVENT_STREAMS: &str = "event_streams";
+/// Number of open gRPC chat streams
+#[cfg(feature = "grpc")]
+pub(crate) const GRPC_STREAMS: &str = "grpc_streams";
+/// Number of open QUIC connections
+#[cfg(feature = "quic")]
+pub(crate) const QUIC_CONNECTIONS: &str = "quic_connections";
+/// Sockets closed because their client did not keep up with the frames sent to it, labeled by
+/// transport
+pub(crate) const SLOW_CLIENTS: &str = "slow_clients_total";
+/// Number of user actors registered in the delivery service
+pub(crate) const USER_ACTORS: &str = "user_actors";
+/// Chat messages the delivery service handed to the recipient's user actor
+pub(crate) const MESSAGES_ROUTED: &str = "messages_routed_total";
+/// Chat messages the delivery service could not route, labeled by reason
+pub(crate) const MESSAGES_DROPPED: &str = "messages_dropped_total";
+/// Messages the sweeper deleted because the retention of their conversation ran out
+pub(crate) const MESSAGES_EXPIRED: &str = "messages_expired_total";
+/// Push messages sent to users without a connected device, labeled by outcome
+pub(crate) const PUSH_MESSAGES: &str = "push_messages_total";
+/// Number of running actors, labeled by actor
+pub(crate) const ACTORS: &str = "actors";
+/// Messages waiting in an actor's mailbox when the actor picks up the next one, labeled by actor
+const ACTOR_MAILBOX_DEPTH: &str = "actor_mailbox_depth";
+/// How long sending to an actor's mailbox waited for space, labeled by actor
+const ACTOR_SEND_DURATION: &str = "actor_send_duration_seconds";
+/// Errors logged in the actor loops, labeled by actor and kind of error
+const ACTOR_ERRORS: &str = "actor_errors_total";
+/// Actors restarted by their supervisor after a crash, labeled by actor
+const ACTOR_RESTARTS: &str = "actor_restarts_total";
+/// Requests to an actor that were not answered in time, labeled by actor
+const ACTOR_REQUEST_TIMEOUTS: &str = "actor_request_timeouts_total";
+
+/// Installs the global metrics recorder. The returned handle renders the metrics for scraping.
+pub(crate) fn install() -> PrometheusHandle {
+    PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Full(ACTOR_SEND_DURATION.to_owned()),
+            &[0.000_01, 0.000_1, 0.001, 0.01, 0.1, 1.0],
+        )
+        .expect("buckets should not be empty")
+        .set_buckets_for_metric(
+            Matcher::Full(ACTOR_MAILBOX_DEPTH.to_owned()),
+            &mailbox_depth_buckets(),
+        )
+        .expect("buckets should not be empty")
+        .install_recorder()
+        .expect("metrics recorder should only be installed once")
+}
+
+/// Powers of two and the capacities of the mailboxes. Notifications don't wait for space, so the
+/// buckets go on past the largest capacity to show how far mailboxes grow beyond it.
+fn mailbox_depth_buckets() -> Vec<f64> {
+    let last = MAILBOX_CAPACITY.max(SEND_QUEUE_CAPACITY) * 16;
+    let mut buckets: Vec<_> = std::iter::successors(Some(1), |depth| Some(depth * 2))
+        .take_while(|depth| *depth <= last)
+        .chain([0, MAILBOX_CAPACITY, SEND_QUEUE_CAPACITY])
+        .collect();
+    buckets.sort_unstable();
+    buckets.dedup();
+    buckets.into_iter().map(|depth| depth as f64).collect()
+}
+
+pub(crate) fn record_mailbox_depth(actor: &'static str, depth: usize) {
+    metrics::histogram!(ACTOR_MAILBOX_DEPTH, "actor" => actor).record(depth as f64);
+}
+
+pub(crate) fn record_send_duration(actor: &'static str, duration: Duration) {
+    metrics::histogram!(ACTOR_SEND_DURATION, "actor" => actor).record(duration.as_secs_f64());
+}
+
+pub(crate) fn record_actor_error(actor: &'static str, kind: &'static str) {
+    metrics::counter!(ACTOR_ERRORS, "actor" => actor, "kind" => kind).increment(1);
+}
+
+pub(crate) fn record_request_timeout(actor: &'static str) {
+    metrics::counter!(ACTOR_REQUEST_TIMEOUTS, "actor" => actor).increment(1);
+}
+
+pub(crate) fn record_actor_restart(actor: &'static str) {
+    metrics::counter!(ACTOR_RESTARTS, "actor" => actor).increment(1);
+}